@@ -0,0 +1,270 @@
+use crate::limits::Meter;
+use crate::passcode::{random_token, same_secret};
+use crate::{BoxBody, full};
+use hyper::header::{AUTHORIZATION, HeaderMap, WWW_AUTHENTICATE};
+use hyper::{Method, Response, StatusCode};
+use serde::Deserialize;
+use std::io;
+use std::sync::Arc;
+
+/// Query parameter carrying a share token, so links work as-is
+const PARAM: &str = "token";
+
+/// Route prefixes of the management APIs, which only the admin token reaches;
+/// registering with a `--relay-hub` is one
+const ADMIN_APIS: [&str; 5] = [
+    "/_admin",
+    "/_trash",
+    "/_rmdir",
+    "/_relay/connect",
+    "/_relay/accept",
+];
+
+/// Route prefix of the alias API, whose changes mint or revoke links
+const ALIAS_API: &str = "/_alias";
+
+/// What a request needs its token to allow, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Downloads and listings
+    Read,
+    /// Uploads and other changes to files, such as renames and new folders
+    Write,
+    /// Deletes, the trash, aliases, relay peers and the `/_admin` API
+    Admin,
+}
+
+impl Role {
+    /// The role a request to `route` with `method` needs.
+    pub fn needed(method: &Method, route: &str) -> Self {
+        let within = |api: &str| {
+            route
+                .strip_prefix(api)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        let reading = *method == Method::GET || *method == Method::HEAD;
+        if *method == Method::DELETE
+            || ADMIN_APIS.into_iter().any(within)
+            || (within(ALIAS_API) && !reading)
+        {
+            Role::Admin
+        } else if reading {
+            Role::Read
+        } else {
+            Role::Write
+        }
+    }
+}
+
+/// Three tokens drawn at startup, for guests and for whoever runs the share: the read
+/// token allows downloads and listings, the write token uploads and changes to files
+/// too, and the admin token everything, including deletes, the trash, minting alias
+/// links and the `/_admin` API. Handing out a guest link never hands out control of
+/// the share.
+///
+/// Tokens go in an `Authorization: Bearer` header or a `token` query parameter.
+///
+/// Downloads made with the read token can be given a byte quota and a speed cap,
+/// with `--read-link-quota` and `--read-link-rate`.
+pub struct ShareTokens {
+    read: String,
+    write: String,
+    admin: String,
+    /// Meters downloads made with the read token, when limited
+    read_meter: Option<Arc<Meter>>,
+}
+
+impl ShareTokens {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self {
+            read: random_token()?,
+            write: random_token()?,
+            admin: random_token()?,
+            read_meter: None,
+        })
+    }
+
+    /// Caps the bytes the read link may download in total and per second.
+    pub fn limit_read(mut self, quota: Option<u64>, rate: Option<u64>) -> Self {
+        if quota.is_some() || rate.is_some() {
+            self.read_meter = Some(Meter::new(quota, rate));
+        }
+        self
+    }
+
+    pub fn read(&self) -> &str {
+        &self.read
+    }
+
+    pub fn write(&self) -> &str {
+        &self.write
+    }
+
+    pub fn admin(&self) -> &str {
+        &self.admin
+    }
+
+    /// The role `token` was drawn for, if it is one of these.
+    pub fn role(&self, token: &str) -> Option<Role> {
+        // Compared in full each time, so timing doesn't tell which one matched
+        [
+            (&self.admin, Role::Admin),
+            (&self.write, Role::Write),
+            (&self.read, Role::Read),
+        ]
+        .into_iter()
+        .fold(None, |found, (secret, role)| {
+            match same_secret(token, secret) {
+                true => Some(role),
+                false => found,
+            }
+        })
+    }
+
+    /// The response to send instead when the request's token doesn't allow what it
+    /// `needs`: `401` without a valid token, `403` with a token for a lesser role.
+    pub fn challenge(
+        &self,
+        needs: Role,
+        headers: &HeaderMap,
+        query: Option<&str>,
+    ) -> Option<Response<BoxBody>> {
+        let Some(token) = presented(headers, query) else {
+            return Some(text(
+                StatusCode::UNAUTHORIZED,
+                "Share token required: use the link you were given\n",
+            ));
+        };
+        match self.role(token) {
+            Some(role) if role >= needs => None,
+            Some(Role::Read) if needs == Role::Write => Some(text(
+                StatusCode::FORBIDDEN,
+                "This link only allows downloads\n",
+            )),
+            Some(_) => Some(text(
+                StatusCode::FORBIDDEN,
+                "This needs the admin link of the share\n",
+            )),
+            None => Some(text(StatusCode::UNAUTHORIZED, "Invalid share token\n")),
+        }
+    }
+
+    /// The meter for downloads made with the read token, if it is limited.
+    pub fn read_meter(&self) -> Option<&Arc<Meter>> {
+        self.read_meter.as_ref()
+    }
+
+    /// The meter for downloads made with the request's token, if that token is limited.
+    pub fn meter(&self, headers: &HeaderMap, query: Option<&str>) -> Option<&Arc<Meter>> {
+        let token = presented(headers, query)?;
+        self.read_meter
+            .as_ref()
+            .filter(|_| same_secret(token, &self.read))
+    }
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<BoxBody> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::UNAUTHORIZED {
+        response = response.header(WWW_AUTHENTICATE, "Bearer");
+    }
+    response.body(full(body)).unwrap()
+}
+
+/// The share token a request carries, if any.
+pub fn presented<'a>(headers: &'a HeaderMap, query: Option<&'a str>) -> Option<&'a str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| {
+            query
+                .into_iter()
+                .flat_map(|query| query.split('&'))
+                .find_map(|pair| pair.strip_prefix(PARAM)?.strip_prefix('='))
+        })
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(tokens: &ShareTokens, method: Method, query: &str) -> Option<StatusCode> {
+        tokens
+            .challenge(
+                Role::needed(&method, "/report.pdf"),
+                &HeaderMap::new(),
+                Some(query),
+            )
+            .map(|response| response.status())
+    }
+
+    #[test]
+    fn read_token_only_downloads() {
+        let tokens = ShareTokens::generate().unwrap();
+        let read = format!("token={}", tokens.read());
+        let write = format!("compress=gzip&token={}", tokens.write());
+
+        assert_eq!(status(&tokens, Method::GET, &read), None);
+        assert_eq!(
+            status(&tokens, Method::POST, &read),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(status(&tokens, Method::POST, &write), None);
+        assert_eq!(
+            status(&tokens, Method::GET, "token=nope"),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(&tokens, Method::GET, ""),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn management_needs_the_admin_token() {
+        assert_eq!(
+            Role::needed(&Method::GET, "/_admin/connections"),
+            Role::Admin
+        );
+        assert_eq!(Role::needed(&Method::GET, "/_trash"), Role::Admin);
+        assert_eq!(Role::needed(&Method::DELETE, "/report.pdf"), Role::Admin);
+        assert_eq!(Role::needed(&Method::PUT, "/_alias/latest"), Role::Admin);
+        assert_eq!(Role::needed(&Method::GET, "/_alias"), Role::Read);
+        assert_eq!(
+            Role::needed(&Method::GET, "/_relay/connect/laptop"),
+            Role::Admin
+        );
+        assert_eq!(
+            Role::needed(&Method::POST, "/_administrivia.txt"),
+            Role::Write
+        );
+        assert_eq!(Role::needed(&Method::POST, "/_mkdir/docs"), Role::Write);
+
+        let tokens = ShareTokens::generate().unwrap();
+        let write = format!("token={}", tokens.write());
+        let admin = format!("token={}", tokens.admin());
+        assert_eq!(
+            status(&tokens, Method::DELETE, &write),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(status(&tokens, Method::DELETE, &admin), None);
+        assert_eq!(status(&tokens, Method::POST, &admin), None);
+        assert_eq!(status(&tokens, Method::GET, &admin), None);
+    }
+
+    #[test]
+    fn only_the_read_token_is_metered() {
+        let tokens = ShareTokens::generate()
+            .unwrap()
+            .limit_read(Some(1024), None);
+        let read = format!("token={}", tokens.read());
+        let write = format!("token={}", tokens.write());
+        let headers = HeaderMap::new();
+        assert!(tokens.meter(&headers, Some(&read)).is_some());
+        assert!(tokens.meter(&headers, Some(&write)).is_none());
+        assert!(tokens.meter(&headers, None).is_none());
+    }
+}
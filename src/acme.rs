@@ -0,0 +1,483 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair};
+use rustls::ServerConfig;
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Let's Encrypt's production directory
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// ALPN protocol a TLS-ALPN-01 validator offers (RFC 8737)
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// How long to wait before retrying after a failed issuance
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps a certificate for `domains` issued and renewed through an ACME CA.
+///
+/// Challenges are answered with TLS-ALPN-01 on the HTTPS listener itself, so the
+/// CA must be able to reach it on port 443.
+pub struct Acme {
+    domains: Vec<String>,
+    email: Option<String>,
+    directory: String,
+    /// Holds the account key and the current certificate and key
+    dir: PathBuf,
+    resolver: Arc<CertResolver>,
+}
+
+/// Hands out the current certificate, or a challenge certificate to validators.
+#[derive(Debug, Default)]
+struct CertResolver {
+    certificate: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let validating = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if validating {
+            let name = hello.server_name()?;
+            return self.challenges.lock().unwrap().get(name).cloned();
+        }
+        self.certificate.read().unwrap().clone()
+    }
+}
+
+impl Acme {
+    /// Loads the certificate from a previous run, if any, so HTTPS works straight away.
+    pub fn new(
+        domains: Vec<String>,
+        email: Option<String>,
+        directory: String,
+        dir: PathBuf,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("Cannot create {}: {}", dir.display(), e))?;
+        let acme = Self {
+            domains,
+            email,
+            directory,
+            dir,
+            resolver: Arc::default(),
+        };
+        if let Ok(certified) = acme.load_certificate() {
+            *acme.resolver.certificate.write().unwrap() = Some(certified);
+        }
+        Ok(acme)
+    }
+
+    /// TLS config serving the managed certificate and answering TLS-ALPN-01 challenges.
+    pub fn tls_config(&self) -> ServerConfig {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+        config
+    }
+
+    /// Issues a certificate if there is none, then renews it two thirds into its
+    /// lifetime, forever.
+    pub async fn run(self) {
+        loop {
+            let renew_at = self
+                .load_certificate()
+                .ok()
+                .and_then(|_| self.renewal_time());
+            let delay = match renew_at {
+                Some(at) => at.duration_since(SystemTime::now()).unwrap_or_default(),
+                None => Duration::ZERO,
+            };
+            tokio::time::sleep(delay).await;
+
+            match self.issue().await {
+                Ok(certified) => {
+                    *self.resolver.certificate.write().unwrap() = Some(certified);
+                    println!("ACME: Certificate issued for {}", self.domains.join(", "));
+                }
+                Err(e) => {
+                    eprintln!(
+                        "ACME: Certificate for {} not issued: {:#}",
+                        self.domains.join(", "),
+                        e
+                    );
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Directory renewals write to
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.crt", self.domains[0]))
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.key", self.domains[0]))
+    }
+
+    fn load_certificate(&self) -> anyhow::Result<Arc<CertifiedKey>> {
+        let certs =
+            CertificateDer::pem_file_iter(self.cert_path())?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(self.key_path())?;
+        Ok(Arc::new(CertifiedKey::new(
+            certs,
+            any_supported_type(&key)?,
+        )))
+    }
+
+    /// When the stored certificate is due for renewal, if it can be parsed.
+    fn renewal_time(&self) -> Option<SystemTime> {
+        let cert = CertificateDer::pem_file_iter(self.cert_path())
+            .ok()?
+            .next()?
+            .ok()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert).ok()?;
+        let validity = cert.validity();
+        let start = validity.not_before.timestamp();
+        let end = validity.not_after.timestamp();
+        let renew_at = start + (end - start) * 2 / 3;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(renew_at.try_into().ok()?))
+    }
+
+    async fn issue(&self) -> anyhow::Result<Arc<CertifiedKey>> {
+        let mut account = Account::open(self).await?;
+
+        let identifiers: Vec<Value> = self
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = account.directory.new_order.clone();
+        let response = account
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Order = response.json().await?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(&mut account, authorization_url).await?;
+        }
+
+        let key = KeyPair::generate()?;
+        let csr = CertificateParams::new(self.domains.clone())?.serialize_request(&key)?;
+        let csr = URL_SAFE_NO_PAD.encode(csr.der());
+        account
+            .post(&order.finalize, Some(json!({ "csr": csr })))
+            .await?;
+        let order: Order = account
+            .wait_for(&order_url, |order: &Order| &order.status)
+            .await?;
+        let certificate_url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => anyhow::bail!("order ended as '{}'", status),
+        };
+        let chain = account.post(&certificate_url, None).await?.text().await?;
+
+        std::fs::write(self.cert_path(), chain)?;
+        write_private(&self.key_path(), key.serialize_pem().as_bytes())?;
+        self.load_certificate()
+    }
+
+    /// Proves control of one identifier by answering its TLS-ALPN-01 challenge.
+    async fn authorize(&self, account: &mut Account, url: &str) -> anyhow::Result<()> {
+        let authorization: Authorization = account.post(url, None).await?.json().await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == "tls-alpn-01")
+            .ok_or_else(|| anyhow::anyhow!("CA offers no tls-alpn-01 challenge for {}", domain))?;
+
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint);
+        let certified = challenge_certificate(&domain, &key_authorization)?;
+        self.resolver
+            .challenges
+            .lock()
+            .unwrap()
+            .insert(domain.clone(), certified);
+
+        let result = async {
+            account.post(&challenge.url, Some(json!({}))).await?;
+            account
+                .wait_for(url, |authorization: &Authorization| &authorization.status)
+                .await
+        }
+        .await;
+        self.resolver.challenges.lock().unwrap().remove(&domain);
+
+        match result?.status.as_str() {
+            "valid" => Ok(()),
+            status => anyhow::bail!("validation of {} ended as '{}'", domain, status),
+        }
+    }
+}
+
+/// Self-signed certificate carrying the `acmeIdentifier` extension (RFC 8737).
+fn challenge_certificate(
+    domain: &str,
+    key_authorization: &str,
+) -> anyhow::Result<Arc<CertifiedKey>> {
+    let digest = Sha256::digest(key_authorization.as_bytes());
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&digest)];
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::try_from(key.serialize_der()).map_err(anyhow::Error::msg)?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![cert.der().clone()],
+        any_supported_type(&key)?,
+    )))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// An ACME account session: signs requests with the account key and tracks nonces.
+struct Account {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    /// base64url SHA-256 of the JWK, part of every key authorization
+    thumbprint: String,
+    /// Account URL, used as `kid` once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Registers the account key (created on first use), or finds the existing account.
+    async fn open(acme: &Acme) -> anyhow::Result<Self> {
+        let key_path = acme.dir.join("account.key");
+        let key_pair = match std::fs::read_to_string(&key_path) {
+            Ok(pem) => KeyPair::from_pem(&pem)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key_pair = KeyPair::generate()?;
+                write_private(&key_path, key_pair.serialize_pem().as_bytes())?;
+                key_pair
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &key_pair.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .map_err(|e| anyhow::anyhow!("Unusable ACME account key: {}", e))?;
+
+        // Uncompressed P-256 point: 0x04 || x || y
+        let point = key_pair.public_key_raw();
+        let (x, y) = (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..]),
+        );
+        // Members in lexicographic order, as the thumbprint requires (RFC 7638)
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()));
+
+        let http = reqwest::Client::new();
+        let directory = http
+            .get(&acme.directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut account = Self {
+            http,
+            directory,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+        let mut registration = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &acme.email {
+            registration["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = account.directory.new_account.clone();
+        let response = account.post(&new_account, Some(registration)).await?;
+        account.kid = Some(location(&response)?);
+        Ok(account)
+    }
+
+    /// Sends a JWS-signed POST; `None` sends a POST-as-GET.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+
+        // A stale nonce is rejected with badNonce; the error response carries a fresh one
+        for _ in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(
+                    &SystemRandom::new(),
+                    format!("{}.{}", protected, payload).as_bytes(),
+                )
+                .map_err(|e| anyhow::anyhow!("Cannot sign ACME request: {}", e))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem: Problem = response.json().await.unwrap_or(Problem {
+                kind: String::new(),
+                detail: status.to_string(),
+            });
+            if problem.kind != "urn:ietf:params:acme:error:badNonce" {
+                anyhow::bail!("{} ({})", problem.detail, problem.kind);
+            }
+        }
+        anyhow::bail!("CA kept rejecting nonces")
+    }
+
+    async fn new_nonce(&self) -> anyhow::Result<String> {
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&response).ok_or_else(|| anyhow::anyhow!("CA sent no Replay-Nonce"))
+    }
+
+    /// Polls `url` until the resource leaves the pending/processing states.
+    async fn wait_for<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        status: impl Fn(&T) -> &String,
+    ) -> anyhow::Result<T> {
+        for _ in 0..30 {
+            let resource: T = self.post(url, None).await?.json().await?;
+            if !matches!(
+                status(&resource).as_str(),
+                "pending" | "processing" | "ready"
+            ) {
+                return Ok(resource);
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        anyhow::bail!("timed out waiting for {}", url)
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("Replay-Nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn location(response: &reqwest::Response) -> anyhow::Result<String> {
+    response
+        .headers()
+        .get("Location")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("CA response to {} has no Location", response.url()))
+}
+
+/// Writes a private key readable by the current user only.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
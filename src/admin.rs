@@ -0,0 +1,170 @@
+use crate::access::Role;
+use crate::history;
+use crate::router::Router;
+use crate::storage;
+use crate::{AppState, BoxBody, full};
+use hyper::header::HeaderMap;
+use hyper::{Method, Response, StatusCode};
+use std::io;
+
+/// Route prefix of the admin API
+pub const API: &str = "/_admin";
+
+/// Registers the admin API, `/_admin` and everything below it.
+pub fn routes(router: &mut Router) {
+    for pattern in [API, "/_admin/{*rest}"] {
+        router.any(pattern, async |req, cx| {
+            let method = req.method().clone();
+            handle(
+                method,
+                &cx.path,
+                req.headers(),
+                req.uri().query(),
+                &cx.state,
+            )
+            .await
+        });
+    }
+}
+
+/// Handles the admin API, enabled with `--admin`:
+///
+/// - `GET /_admin/transfers` lists transfers in flight as
+///   `<id> <direction> <file> <client> <bytes>/<total> <seconds>s` lines
+/// - `POST /_admin/transfers/<id>/cancel` stops one; a download ends at its next
+///   chunk, an upload is discarded like one the client abandoned
+/// - `GET /_admin/connections` counts the HTTP connections accepted so far and how
+///   they ended, as `<name> <count>` lines
+/// - `GET /_admin/storage` reports the free space where the share and in-flight uploads
+///   are stored and how many uploads the disk filling up has failed, as `<name> <value>`
+///   lines
+/// - `GET /_admin/history` lists finished transfers, filtered by `file`, `peer`,
+///   `since` and `limit` as `holodeck history` does
+///
+/// These need the admin token of `--share-links`, as listing shows client addresses
+/// and cancelling stops anyone's transfer; without share links nobody could present
+/// it, so the API refuses everyone.
+pub async fn handle(
+    method: Method,
+    path: &str,
+    headers: &HeaderMap,
+    query: Option<&str>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    if !state.admin {
+        return Ok(crate::not_found());
+    }
+    let Some(tokens) = &state.share_tokens else {
+        return Ok(text(
+            StatusCode::FORBIDDEN,
+            "The admin API needs the admin link of --share-links\n",
+        ));
+    };
+    if let Some(response) = tokens.challenge(Role::Admin, headers, query) {
+        return Ok(response);
+    }
+
+    let rest = path.strip_prefix(API).unwrap_or_default();
+    match (method, rest) {
+        (Method::GET, "/transfers") => Ok(text(StatusCode::OK, state.transfers.list())),
+        (Method::GET, "/connections") => Ok(text(StatusCode::OK, state.connections.report())),
+        (Method::GET, "/storage") => Ok(text(StatusCode::OK, storage_report(state).await)),
+        (Method::GET, "/history") => match state.transfers.history() {
+            Some(history) => history::get(query, history),
+            None => Ok(text(
+                StatusCode::NOT_FOUND,
+                "Transfers aren't recorded (--no-history)",
+            )),
+        },
+        (Method::POST, rest) => {
+            let Some(id) = rest
+                .strip_prefix("/transfers/")
+                .and_then(|rest| rest.strip_suffix("/cancel"))
+            else {
+                return Ok(crate::not_found());
+            };
+            let Ok(id) = id.parse::<u64>() else {
+                return Ok(text(StatusCode::BAD_REQUEST, "Invalid transfer id"));
+            };
+            match state.transfers.cancel(id) {
+                Some(transfer) => {
+                    let message = format!("Cancelled transfer {} ({})", id, transfer);
+                    state.transfers.println(format!("ADMIN: {}", message));
+                    Ok(text(StatusCode::OK, format!("{}\n", message)))
+                }
+                None => Ok(text(
+                    StatusCode::NOT_FOUND,
+                    format!("No transfer {} in flight", id),
+                )),
+            }
+        }
+        _ => Ok(crate::not_found()),
+    }
+}
+
+/// Free bytes of the share's and the staging directory's filesystems, `unknown` where
+/// they can't be determined, and the writes that found the disk full.
+async fn storage_report(state: &AppState) -> String {
+    let mut report = String::new();
+    for (name, dir) in [
+        ("share-free", &state.root),
+        ("staging-free", &state.staging_dir),
+    ] {
+        let free = storage::available_space(dir).await;
+        let free = free.map_or_else(|| "unknown".to_string(), |free| free.to_string());
+        report.push_str(&format!("{} {}\n", name, free));
+    }
+    report.push_str(&format!("disk-full {}\n", state.disk_full.count()));
+    report
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::ShareTokens;
+    use hyper::header::AUTHORIZATION;
+
+    async fn status(state: &AppState, token: Option<&str>) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        }
+        handle(Method::GET, "/_admin/connections", &headers, None, state)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn only_the_admin_token_reaches_the_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = AppState::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(status(&state, None).await, StatusCode::NOT_FOUND);
+
+        // Nobody can be told apart from the admin without share links
+        state.admin = true;
+        assert_eq!(status(&state, None).await, StatusCode::FORBIDDEN);
+
+        let tokens = ShareTokens::generate().unwrap();
+        let (read, admin) = (tokens.read().to_string(), tokens.admin().to_string());
+        state.share_tokens = Some(tokens);
+        assert_eq!(status(&state, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(&state, Some("guess")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(&state, Some(&read)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&state, Some(&admin)).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn admin_needs_share_links() {
+        use clap::Parser;
+        assert!(crate::cli::Cli::try_parse_from(["holodeck", "--admin"]).is_err());
+        assert!(crate::cli::Cli::try_parse_from(["holodeck", "--admin", "--share-links"]).is_ok());
+    }
+}
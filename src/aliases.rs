@@ -0,0 +1,192 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use http_body_util::{BodyExt, Limited};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tokio::sync::Mutex;
+
+/// Route prefix of the alias API
+const API: &str = "/_alias";
+
+/// Longest target name accepted in a `PUT /_alias/<slug>` body
+const MAX_TARGET_LEN: usize = 1024;
+
+/// Stable names for shared files (`/latest` → `build-2024-06-01.tar.gz`), resolved
+/// before the filesystem lookup.
+///
+/// Aliases come from the `[alias]` config table and from the `/_alias` API; API
+/// aliases are kept in the share's metadata directory and take precedence.
+pub struct Aliases {
+    /// From the config file, replaced when it is reloaded
+    configured: RwLock<HashMap<String, String>>,
+    stored: Mutex<BTreeMap<String, String>>,
+    /// Where API aliases are persisted
+    path: PathBuf,
+}
+
+impl Aliases {
+    /// Checks the configured aliases and loads those stored in `root`'s metadata directory.
+    pub fn load(configured: HashMap<String, String>, root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(META_DIR).join("aliases.json");
+        check(&configured)?;
+        let stored = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!("Invalid alias file {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            configured: RwLock::new(configured),
+            stored: Mutex::new(stored),
+            path,
+        })
+    }
+
+    /// The file `name` stands for, if it is an alias.
+    pub async fn resolve(&self, name: &str) -> Option<String> {
+        if let Some(target) = self.stored.lock().await.get(name) {
+            return Some(target.clone());
+        }
+        self.configured.read().unwrap().get(name).cloned()
+    }
+
+    /// Replaces the aliases from the config file, keeping those made through the API.
+    pub fn reconfigure(&self, configured: HashMap<String, String>) -> anyhow::Result<()> {
+        check(&configured)?;
+        *self.configured.write().unwrap() = configured;
+        Ok(())
+    }
+
+    async fn save(&self, stored: &BTreeMap<String, String>) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(stored)?;
+        let temp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, &self.path).await
+    }
+}
+
+/// Checks that configured aliases name files directly inside the share.
+fn check(configured: &HashMap<String, String>) -> anyhow::Result<()> {
+    for (slug, target) in configured {
+        paths::validate_filename(slug)
+            .and(paths::validate_filename(target))
+            .map_err(|e| anyhow::anyhow!("Invalid alias '{}' → '{}': {}", slug, target, e))?;
+    }
+    Ok(())
+}
+
+/// Registers the alias API, `/_alias` and everything below it.
+pub fn routes(router: &mut Router) {
+    for pattern in [API, "/_alias/{*slug}"] {
+        router.any(pattern, async |req, cx| {
+            handle(req.method().clone(), &cx.path, req, &cx.state).await
+        });
+    }
+}
+
+/// Handles the alias API:
+///
+/// - `GET /_alias` lists aliases as `slug -> target` lines
+/// - `PUT /_alias/<slug>` with the target filename as body creates or repoints one
+/// - `DELETE /_alias/<slug>` removes an alias created through the API
+pub async fn handle(
+    method: Method,
+    path: &str,
+    req: Request<BoxBody>,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let aliases = &state.aliases;
+    let slug = path
+        .strip_prefix(API)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    match (method, slug) {
+        (Method::GET, "") => {
+            let stored = aliases.stored.lock().await;
+            let mut all: BTreeMap<_, _> = aliases
+                .configured
+                .read()
+                .unwrap()
+                .clone()
+                .into_iter()
+                .collect();
+            all.extend(stored.clone());
+            let body: String = all
+                .into_iter()
+                .map(|(slug, target)| format!("{} -> {}\n", slug, target))
+                .collect();
+            Ok(text(StatusCode::OK, body))
+        }
+        (Method::PUT, slug) => {
+            if let Err(e) = paths::validate_filename(slug) {
+                return Ok(text(StatusCode::BAD_REQUEST, format!("Alias: {}", e)));
+            }
+            let body = match Limited::new(req.into_body(), MAX_TARGET_LEN)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(text(StatusCode::PAYLOAD_TOO_LARGE, "Target name too long")),
+            };
+            let target = String::from_utf8_lossy(&body).trim().to_string();
+            if let Err(e) = paths::validate_filename(&target) {
+                return Ok(text(StatusCode::BAD_REQUEST, format!("Target: {}", e)));
+            }
+
+            let mut stored = aliases.stored.lock().await;
+            let created = stored.insert(slug.to_string(), target.clone()).is_none();
+            if let Err(e) = aliases.save(&stored).await {
+                eprintln!("ALIAS: Error saving aliases: {}", e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error saving alias: {}", e),
+                ));
+            }
+            state
+                .transfers
+                .println(format!("ALIAS: /{} -> {}", slug, target));
+            let status = if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            Ok(text(status, format!("/{} -> {}\n", slug, target)))
+        }
+        (Method::DELETE, slug) if !slug.is_empty() => {
+            let mut stored = aliases.stored.lock().await;
+            if stored.remove(slug).is_none() {
+                let configured = aliases.configured.read().unwrap().contains_key(slug);
+                return Ok(if configured {
+                    text(
+                        StatusCode::CONFLICT,
+                        format!("Alias '{}' is defined in the config file", slug),
+                    )
+                } else {
+                    text(StatusCode::NOT_FOUND, format!("No alias '{}'", slug))
+                });
+            }
+            if let Err(e) = aliases.save(&stored).await {
+                eprintln!("ALIAS: Error saving aliases: {}", e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error saving alias: {}", e),
+                ));
+            }
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(full(""))
+                .unwrap())
+        }
+        _ => Ok(crate::not_found()),
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
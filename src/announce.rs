@@ -0,0 +1,61 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where `--announce-json` writes the startup document.
+#[derive(Debug, Clone)]
+pub enum AnnounceTarget {
+    /// Written atomically (temp file + rename) so watchers never see a partial document
+    Path(PathBuf),
+    /// An inherited file descriptor, closed after writing so readers see EOF
+    Fd(i32),
+}
+
+impl FromStr for AnnounceTarget {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(fd) => Self::Fd(fd),
+            Err(_) => Self::Path(PathBuf::from(s)),
+        })
+    }
+}
+
+/// Endpoint details for orchestration tooling, emitted once the server is reachable.
+#[derive(Debug, Serialize)]
+pub struct Announcement {
+    pub local_addr: String,
+    pub external_url: Option<String>,
+    pub auth_token: Option<String>,
+    pub pid: u32,
+}
+
+impl AnnounceTarget {
+    pub fn write(&self, announcement: &Announcement) -> anyhow::Result<()> {
+        let mut json = serde_json::to_vec_pretty(announcement)?;
+        json.push(b'\n');
+
+        match self {
+            Self::Path(path) => {
+                let mut tmp = path.clone().into_os_string();
+                tmp.push(".tmp");
+                std::fs::write(&tmp, &json)?;
+                std::fs::rename(&tmp, path)?;
+            }
+            #[cfg(unix)]
+            Self::Fd(fd) => {
+                use std::io::Write;
+                use std::os::fd::FromRawFd;
+                // SAFETY: the fd was handed to us on the command line for exactly this purpose
+                let mut file = unsafe { std::fs::File::from_raw_fd(*fd) };
+                file.write_all(&json)?;
+            }
+            #[cfg(not(unix))]
+            Self::Fd(_) => {
+                anyhow::bail!("Announcing to a file descriptor is only supported on Unix")
+            }
+        }
+        Ok(())
+    }
+}
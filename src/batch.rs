@@ -0,0 +1,429 @@
+use crate::events::Event;
+use crate::executables::Sniffer;
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::storage::{self, StagedDir, StagedFile};
+use crate::{AppState, BoxBody, full, paths};
+use hyper::{Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+/// Route that unpacks an uploaded archive into the share
+pub const ROUTE: &str = "/_batch";
+
+/// Default for `--batch-limit`
+pub const DEFAULT_LIMIT: u64 = 1024 * 1024 * 1024;
+
+/// Most files one archive may unpack, so a flood of empty entries can't exhaust inodes
+const MAX_FILES: usize = 10_000;
+
+/// Why an archive was refused
+enum Rejected {
+    /// Not a zip, tar or gzipped tar
+    Unsupported,
+    /// Damaged, or holding an entry that can't be stored safely
+    Invalid(String),
+    /// Past `--batch-limit` or [`MAX_FILES`]
+    TooLarge(String),
+    /// Clashes with what is already in the share
+    Conflict(String),
+    /// Holds an executable or script while `--block-executables` is set
+    Executable(String),
+    /// Holds a file for a folder a `[[policy]]` closes to uploads
+    Forbidden(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Rejected {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Format {
+    /// Recognises an archive by its magic bytes; the request's Content-Type is ignored.
+    fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Some(Self::Zip)
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::TarGz)
+        } else if head.get(257..262) == Some(b"ustar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+enum Kind {
+    File,
+    Directory,
+    /// Symlinks, hard links, devices and the like, which are never created
+    Other,
+}
+
+/// Registers `POST /_batch`.
+pub fn routes(router: &mut Router) {
+    router.post(ROUTE, async |req, cx| {
+        upload(req, cx.client, &cx.state).await
+    });
+}
+
+/// Unpacks a zip, tar or `.tar.gz` request body into the share, keeping its folders.
+///
+/// The archive is staged and extracted into the staging directory first. Entry names
+/// are checked like uploaded filenames and the unpacked size against `--batch-limit`,
+/// so a rejected archive leaves nothing behind in the share.
+pub async fn upload(
+    req: Request<BoxBody>,
+    client: String,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let limit = state.batch_limit;
+    let role = policy::requester(state, req.headers(), req.uri().query());
+    let too_large = || Rejected::TooLarge(format!("Archive exceeds the {} byte limit", limit));
+
+    let total = storage::declared_length(req.headers());
+    if total.is_some_and(|total| total > limit) {
+        return Ok(reject(state, too_large()));
+    }
+
+    let (staged, file) = match StagedFile::create(&state.staging_dir, "_batch").await {
+        Ok(staged) => staged,
+        Err(e) => return Ok(reject(state, e.into())),
+    };
+    let mut file = BufWriter::with_capacity(state.io_buffer, file);
+
+    let mut progress = state.transfers.start("POST", "_batch", total);
+    progress.set_client(&client);
+    crate::uploads::tag(&progress, req.headers());
+    let mut body = req.into_body();
+    while let Some(frame) = crate::next_frame(&mut body, &progress).await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                let outcome = "partial archive discarded";
+                return Err(crate::upload_aborted(
+                    state, progress, "_batch", client, outcome, e,
+                ));
+            }
+        };
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        let received = progress.bytes() + data.len() as u64;
+        if received > limit {
+            return Ok(reject(state, too_large()));
+        }
+        if let Some(declared) = total
+            && !storage::fits_length(declared, received, false)
+        {
+            let outcome = "partial archive discarded";
+            return Ok(crate::length_mismatch(
+                state, progress, "_batch", client, outcome, received, declared,
+            ));
+        }
+        if let Err(e) = file.write_all(&data).await {
+            return Ok(reject(state, e.into()));
+        }
+        progress.inc(data.len() as u64);
+    }
+    if let Some(declared) = total
+        && !storage::fits_length(declared, progress.bytes(), true)
+    {
+        let received = progress.bytes();
+        let outcome = "partial archive discarded";
+        return Ok(crate::length_mismatch(
+            state, progress, "_batch", client, outcome, received, declared,
+        ));
+    }
+    if let Err(e) = file.flush().await {
+        return Ok(reject(state, e.into()));
+    }
+    drop(file);
+
+    let unpacked = match StagedDir::create(&state.staging_dir, "_batch").await {
+        Ok(dir) => dir,
+        Err(e) => return Ok(reject(state, e.into())),
+    };
+    let archive = staged.path().to_path_buf();
+    let into = unpacked.path().to_path_buf();
+    let fsync = state.fsync;
+    let block_executables = state.block_executables;
+    let files = tokio::task::spawn_blocking(move || {
+        extract(&archive, &into, limit, fsync, block_executables)
+    })
+    .await
+    .map_err(io::Error::other)?;
+    drop(staged);
+    let files = match files {
+        Ok(files) => files,
+        Err(rejected) => return Ok(reject(state, rejected)),
+    };
+    let policies = state.policies();
+    let refused = files.keys().find_map(|name| {
+        let reason = policies.refusal(Action::Upload, name, role)?;
+        Some(format!("{}: '{}'", reason, name))
+    });
+    if let Some(reason) = refused {
+        return Ok(reject(state, Rejected::Forbidden(reason)));
+    }
+    if let Err(rejected) = place(unpacked.path(), &state.root, &files).await {
+        return Ok(reject(state, rejected));
+    }
+    if state.fsync
+        && let Err(e) = storage::sync_dir(&state.root).await
+    {
+        return Ok(reject(state, e.into()));
+    }
+    for name in files.keys() {
+        crate::pack(state, "POST", name).await;
+    }
+
+    progress.finish();
+    let size: u64 = files.values().sum();
+    state.transfers.println(format!(
+        "POST: Extracted {} files from batch upload ({} bytes)",
+        files.len(),
+        size
+    ));
+    let mut summary = format!("Extracted {} files ({} bytes)\n", files.len(), size);
+    for (name, bytes) in files {
+        summary.push_str(&format!("{} ({} bytes)\n", name, bytes));
+        state.events.emit(Event::UploadCompleted {
+            file: name,
+            bytes,
+            peer: client.clone(),
+        });
+    }
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .body(full(summary))
+        .unwrap())
+}
+
+/// Extracts every file of `archive` below `into`, returning their paths and sizes.
+fn extract(
+    archive: &Path,
+    into: &Path,
+    limit: u64,
+    fsync: bool,
+    block_executables: bool,
+) -> Result<BTreeMap<String, u64>, Rejected> {
+    let mut file = std::fs::File::open(archive)?;
+    let mut head = Vec::new();
+    (&mut file).take(262).read_to_end(&mut head)?;
+    file.rewind()?;
+
+    let mut unpacker = Unpacker {
+        into,
+        limit,
+        fsync,
+        block_executables,
+        written: 0,
+        files: BTreeMap::new(),
+    };
+    match Format::detect(&head) {
+        Some(Format::Zip) => {
+            let mut zip = zip::ZipArchive::new(file).map_err(damaged)?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).map_err(damaged)?;
+                let kind = if entry.is_dir() {
+                    Kind::Directory
+                } else if entry.is_file() {
+                    Kind::File
+                } else {
+                    Kind::Other
+                };
+                let name = entry.name().to_string();
+                unpacker.entry(&name, kind, &mut entry)?;
+            }
+        }
+        Some(Format::Tar) => unpacker.tar(file)?,
+        Some(Format::TarGz) => unpacker.tar(flate2::read::GzDecoder::new(file))?,
+        None => return Err(Rejected::Unsupported),
+    }
+    Ok(unpacker.files)
+}
+
+struct Unpacker<'a> {
+    into: &'a Path,
+    limit: u64,
+    fsync: bool,
+    block_executables: bool,
+    /// Unpacked bytes so far, counted as they are decompressed rather than as declared
+    written: u64,
+    files: BTreeMap<String, u64>,
+}
+
+impl Unpacker<'_> {
+    fn tar(&mut self, reader: impl Read) -> Result<(), Rejected> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(damaged)? {
+            let mut entry = entry.map_err(damaged)?;
+            let kind = match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => Kind::File,
+                tar::EntryType::Directory => Kind::Directory,
+                tar::EntryType::XGlobalHeader => continue,
+                _ => Kind::Other,
+            };
+            let path = entry.path().map_err(damaged)?;
+            let name = path
+                .to_str()
+                .ok_or_else(|| Rejected::Invalid(format!("Entry name is not UTF-8: {:?}", path)))?
+                .to_string();
+            self.entry(&name, kind, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, name: &str, kind: Kind, data: &mut impl Read) -> Result<(), Rejected> {
+        let invalid = |e| Rejected::Invalid(format!("{}: '{}'", e, name));
+        if name.starts_with('/') {
+            return Err(invalid(paths::InvalidName::Traversal));
+        }
+        // Archives made with `tar -C dir .` prefix every entry with `./`
+        let relative: Vec<&str> = name.split('/').filter(|s| *s != ".").collect();
+        let path = paths::validate_path(&relative.join("/")).map_err(invalid)?;
+        match kind {
+            Kind::Directory => return Ok(()),
+            Kind::Other => {
+                return Err(Rejected::Invalid(format!(
+                    "Unsupported entry '{}': only files and directories are extracted",
+                    name
+                )));
+            }
+            Kind::File if path.is_empty() => {
+                return Err(Rejected::Invalid(format!("Invalid entry '{}'", name)));
+            }
+            Kind::File => {}
+        }
+        if self.files.len() >= MAX_FILES && !self.files.contains_key(&path) {
+            return Err(Rejected::TooLarge(format!(
+                "Archive holds more than {} files",
+                MAX_FILES
+            )));
+        }
+
+        // A file and a folder of the same name, e.g. `docs` next to `docs/intro.md`
+        let clash = |_| Rejected::Invalid(format!("Entry '{}' clashes with another entry", name));
+        let dest = self.into.join(&path);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent).map_err(clash)?;
+        }
+        let mut out = std::fs::File::create(&dest).map_err(clash)?;
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut size = 0;
+        let mut sniffer = self.block_executables.then(Sniffer::default);
+        loop {
+            let n = data.read(&mut buf).map_err(damaged)?;
+            if n == 0 {
+                break;
+            }
+            self.written += n as u64;
+            if self.written > self.limit {
+                return Err(Rejected::TooLarge(format!(
+                    "Archive unpacks to more than the {} byte limit",
+                    self.limit
+                )));
+            }
+            if let Some(sniffer) = sniffer.as_mut()
+                && let Some(kind) = sniffer.inspect(&buf[..n])
+            {
+                return Err(Rejected::Executable(format!(
+                    "'{}' looks like {}; executables and scripts are not accepted",
+                    name, kind
+                )));
+            }
+            out.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+        if self.fsync {
+            out.sync_all()?;
+        }
+        self.files.insert(path, size);
+        Ok(())
+    }
+}
+
+/// Moves the unpacked files into the share, creating folders as needed.
+///
+/// Everything is checked before the first move, so a clash doesn't leave the archive
+/// half applied. Existing symlinks are never followed.
+async fn place(from: &Path, root: &Path, files: &BTreeMap<String, u64>) -> Result<(), Rejected> {
+    for name in files.keys() {
+        let segments: Vec<&str> = name.split('/').collect();
+        for depth in 1..=segments.len() {
+            let relative = segments[..depth].join("/");
+            let is_last = depth == segments.len();
+            match fs::symlink_metadata(root.join(&relative)).await {
+                Ok(metadata) if is_last && metadata.is_file() => {}
+                Ok(metadata) if !is_last && metadata.is_dir() => {}
+                Ok(_) => {
+                    return Err(Rejected::Conflict(format!(
+                        "'{}' already exists in the share as a different kind of entry",
+                        relative
+                    )));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    for name in files.keys() {
+        let dest = root.join(name);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        storage::move_file(&from.join(name), &dest).await?;
+    }
+    Ok(())
+}
+
+fn damaged(e: impl Display) -> Rejected {
+    Rejected::Invalid(format!("Damaged archive: {}", e))
+}
+
+fn reject(state: &AppState, rejected: Rejected) -> Response<BoxBody> {
+    let (status, message) = match rejected {
+        Rejected::Unsupported => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected a zip, tar or tar.gz archive".to_string(),
+        ),
+        Rejected::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        Rejected::TooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+        Rejected::Conflict(message) => (StatusCode::CONFLICT, message),
+        Rejected::Executable(message) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, message),
+        Rejected::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+        Rejected::Io(e) if storage::is_disk_full(&e) => {
+            state.disk_full.alarm("POST", "_batch", &state.staging_dir);
+            (
+                StatusCode::INSUFFICIENT_STORAGE,
+                "Not enough disk space to unpack the archive".to_string(),
+            )
+        }
+        Rejected::Io(e) => {
+            eprintln!("POST: Error extracting batch upload: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error extracting archive: {}", e),
+            )
+        }
+    };
+    Response::builder()
+        .status(status)
+        .body(full(message))
+        .unwrap()
+}
@@ -0,0 +1,174 @@
+use crate::cli::{BenchArgs, OutputMode};
+use crate::events::Events;
+use crate::tunnel;
+use crate::{AppState, LOCAL_IO_BUFFER, TUNNEL_IO_BUFFER};
+use bytes::Bytes;
+use indicatif::HumanBytes;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// How long to wait for the tunnel provider to report a URL
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `holodeck bench`: serves a scratch directory, then measures throughput and
+/// latency against it locally and, when `SSH_SERVER` is set, through the tunnel.
+pub async fn run(args: BenchArgs, io_buffer: Option<usize>) -> anyhow::Result<()> {
+    let root = std::env::temp_dir().join(format!("holodeck-bench-{}", std::process::id()));
+    let result = bench(&args, io_buffer, root.clone()).await;
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    result
+}
+
+async fn bench(
+    args: &BenchArgs,
+    io_buffer: Option<usize>,
+    root: std::path::PathBuf,
+) -> anyhow::Result<()> {
+    let config = crate::config::Config::load()?;
+    let tunnel_provider = tunnel::from_config(&config.tunnel)?;
+    let mut state = AppState::new(root)?;
+    state.io_buffer = io_buffer.unwrap_or(if tunnel_provider.is_some() {
+        TUNNEL_IO_BUFFER
+    } else {
+        LOCAL_IO_BUFFER
+    });
+    let state = Arc::new(state);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(crate::serve(listener, state.clone()));
+
+    let payload = test_payload(args.size);
+    println!(
+        "Benchmarking with a {} test file, {} rounds, {} pings (I/O buffer {})",
+        HumanBytes(args.size as u64),
+        args.rounds,
+        args.pings,
+        HumanBytes(state.io_buffer as u64)
+    );
+
+    let local = format!("http://{}", local_addr);
+    measure("Local", &local, &payload, args).await?;
+
+    let (url_tx, mut url_rx) = watch::channel(None);
+    if let Some(provider) = tunnel_provider {
+        let tunnel = tunnel::spawn_tunnel(
+            provider,
+            local_addr.port(),
+            OutputMode::Quiet,
+            url_tx,
+            Events::new(),
+            None,
+        );
+        let url = tokio::time::timeout(TUNNEL_TIMEOUT, url_rx.wait_for(Option::is_some)).await;
+        match url {
+            Ok(Ok(url)) => {
+                let url = url.clone().unwrap_or_default();
+                measure("Tunnel", url.trim_end_matches('/'), &payload, args).await?;
+            }
+            _ => eprintln!("\nTunnel did not report a URL within {:?}", TUNNEL_TIMEOUT),
+        }
+        tunnel.abort();
+    } else if tunnel::AVAILABLE {
+        println!("\nSet SSH_SERVER to also benchmark through the tunnel");
+    }
+    Ok(())
+}
+
+async fn measure(label: &str, base: &str, payload: &Bytes, args: &BenchArgs) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/bench.bin", base);
+
+    let mut upload = Duration::ZERO;
+    let mut download = Duration::ZERO;
+    for _ in 0..args.rounds {
+        let start = Instant::now();
+        client
+            .post(&url)
+            .body(payload.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        upload += start.elapsed();
+
+        let start = Instant::now();
+        let received = client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        download += start.elapsed();
+        anyhow::ensure!(
+            received.len() == payload.len(),
+            "downloaded {} bytes, expected {}",
+            received.len(),
+            payload.len()
+        );
+    }
+
+    let ping_url = format!("{}/ping", base);
+    client
+        .post(&ping_url)
+        .body("x")
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut latencies = Vec::with_capacity(args.pings as usize);
+    for _ in 0..args.pings {
+        let start = Instant::now();
+        client
+            .get(&ping_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+
+    let bytes = payload.len() as f64 * args.rounds as f64;
+    println!("\n{} ({})", label, base);
+    println!(
+        "  Upload:   {:>10.1} MB/s",
+        bytes / 1e6 / upload.as_secs_f64()
+    );
+    println!(
+        "  Download: {:>10.1} MB/s",
+        bytes / 1e6 / download.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        println!(
+            "  Latency:  p50 {:.1} ms  p90 {:.1} ms  p99 {:.1} ms",
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99)
+        );
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted samples, in milliseconds
+fn percentile(sorted: &[Duration], p: usize) -> f64 {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1].as_secs_f64() * 1000.0
+}
+
+/// Incompressible test data, so tunnel compression can't flatter the numbers
+fn test_payload(size: usize) -> Bytes {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut data = Vec::with_capacity(size + 8);
+    while data.len() < size {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data.truncate(size);
+    Bytes::from(data)
+}
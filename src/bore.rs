@@ -0,0 +1,152 @@
+use crate::tunnel::{TunnelProvider, UrlReporter};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Port bore servers accept control and data connections on
+const CONTROL_PORT: u16 = 7835;
+
+/// Plain TCP tunnel through a [bore](https://github.com/ekzhang/bore) server.
+///
+/// The server assigns a public port and, for each visitor, asks for a new
+/// connection that is then spliced to the local server.
+pub struct BoreTunnel {
+    server: String,
+    /// The public port to ask for, 0 for any
+    port: u16,
+    /// Whether to settle for another port when `port` is taken
+    flexible: bool,
+}
+
+/// Messages are JSON, each terminated by a NUL byte.
+#[derive(Serialize)]
+enum ClientMessage {
+    /// Request a public port; 0 lets the server choose
+    Hello(u16),
+    Accept(String),
+}
+
+#[derive(Deserialize)]
+enum ServerMessage {
+    Challenge(serde::de::IgnoredAny),
+    Hello(u16),
+    Heartbeat,
+    Connection(String),
+    Error(String),
+}
+
+impl BoreTunnel {
+    pub fn new(server: String) -> Self {
+        Self {
+            server,
+            port: 0,
+            flexible: false,
+        }
+    }
+
+    /// Opens the control connection, asking for `port`.
+    async fn hello(&self, port: u16) -> anyhow::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect((self.server.as_str(), CONTROL_PORT)).await?;
+        let mut control = BufReader::new(stream);
+        send(&mut control, &ClientMessage::Hello(port)).await?;
+        Ok(control)
+    }
+}
+
+impl TunnelProvider for BoreTunnel {
+    fn describe(&self, local_port: u16) -> Vec<String> {
+        vec![format!(
+            "\nConnecting to bore server {}:{} for local port {}",
+            self.server, CONTROL_PORT, local_port
+        )]
+    }
+
+    fn run(
+        &self,
+        local_port: u16,
+        mut report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let mut port = self.port;
+            let mut control = self.hello(port).await?;
+            let mut assigned = false;
+
+            while let Some(message) = recv(&mut control).await? {
+                match message {
+                    ServerMessage::Hello(port) => {
+                        assigned = true;
+                        report_url(&format!("http://{}:{}", self.server, port))
+                    }
+                    ServerMessage::Heartbeat => {}
+                    ServerMessage::Connection(id) => {
+                        let server = self.server.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = accept(&server, id, local_port).await {
+                                tracing::debug!(error = %e, "bore connection failed");
+                            }
+                        });
+                    }
+                    ServerMessage::Error(e) if !assigned && port != 0 && self.flexible => {
+                        eprintln!(
+                            "bore server {} can't give port {} again ({}); taking a new one",
+                            self.server, port, e
+                        );
+                        port = 0;
+                        control = self.hello(port).await?;
+                    }
+                    ServerMessage::Error(e) => anyhow::bail!("bore server error: {}", e),
+                    ServerMessage::Challenge(_) => {
+                        anyhow::bail!("bore server {} requires a secret", self.server)
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn request_name(&mut self, service: Option<&str>, name: &str) -> bool {
+        if service.is_some_and(|service| service != self.server) {
+            return false;
+        }
+        let Ok(port) = name.parse() else {
+            return false;
+        };
+        self.port = port;
+        self.flexible = service.is_some();
+        true
+    }
+}
+
+/// Opens the data connection the server asked for and splices it to the local server.
+async fn accept(server: &str, id: String, local_port: u16) -> anyhow::Result<()> {
+    let mut remote = TcpStream::connect((server, CONTROL_PORT)).await?;
+    send(&mut remote, &ClientMessage::Accept(id)).await?;
+    let mut local = TcpStream::connect(("127.0.0.1", local_port)).await?;
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await?;
+    Ok(())
+}
+
+async fn send<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    message: &ClientMessage,
+) -> anyhow::Result<()> {
+    let mut frame = serde_json::to_vec(message)?;
+    frame.push(0);
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn recv<R: AsyncRead + Unpin>(
+    stream: &mut BufReader<R>,
+) -> anyhow::Result<Option<ServerMessage>> {
+    let mut frame = Vec::new();
+    if stream.read_until(0, &mut frame).await? == 0 {
+        return Ok(None);
+    }
+    if frame.last() == Some(&0) {
+        frame.pop();
+    }
+    Ok(Some(serde_json::from_slice(&frame)?))
+}
@@ -0,0 +1,652 @@
+use crate::announce::AnnounceTarget;
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A simple HTTP file server with built-in reverse SSH tunneling
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Suppress banners, progress bars and transfer logs (errors are still printed)
+    #[arg(long, short, conflicts_with = "porcelain")]
+    pub quiet: bool,
+
+    /// Print only machine-parsable KEY=value lines (e.g. URL=https://...)
+    #[arg(long)]
+    pub porcelain: bool,
+
+    /// Address to listen on, e.g. 0.0.0.0:80 to serve the LAN without a tunnel
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:0")]
+    pub bind: SocketAddr,
+
+    /// Also serve the share read-only over TFTP on this UDP address, e.g. 0.0.0.0:69, for
+    /// bootloaders and firmware updaters. TFTP has no way to log in, so it can't be
+    /// combined with a passcode, password or share links
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["passcode", "password", "share_links"])]
+    pub tftp: Option<SocketAddr>,
+
+    /// Also serve the share over passive-mode FTP on this address, e.g. 0.0.0.0:2121, for
+    /// devices that can only push files that way (FTPS with --tls-cert)
+    #[arg(long, value_name = "ADDR")]
+    pub ftp: Option<SocketAddr>,
+
+    /// Switch to this user (name or uid) once the listener is bound
+    #[cfg(unix)]
+    #[arg(long, value_name = "USER")]
+    pub user: Option<String>,
+
+    /// Switch to this group (name or gid) once the listener is bound
+    /// (default: the primary group of --user)
+    #[cfg(unix)]
+    #[arg(long, value_name = "GROUP")]
+    pub group: Option<String>,
+
+    /// Serve HTTPS with this PEM certificate chain instead of plain HTTP (no tunnel)
+    #[cfg(feature = "https")]
+    #[arg(long, value_name = "PEM", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "https")]
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a Let's Encrypt certificate for this domain, obtained and renewed
+    /// automatically; the CA must reach the server on port 443 (repeatable)
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "DOMAIN", conflicts_with = "tls_cert")]
+    pub acme_domain: Vec<String>,
+
+    /// Contact email registered with the ACME account, for expiry notices
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "EMAIL", requires = "acme_domain")]
+    pub acme_email: Option<String>,
+
+    /// ACME directory to request certificates from
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "URL", default_value = crate::acme::LETS_ENCRYPT)]
+    pub acme_directory: String,
+
+    /// Where the ACME account key and certificates are kept
+    /// (default: ~/.config/holodeck/acme)
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "DIR", requires = "acme_domain")]
+    pub acme_dir: Option<PathBuf>,
+
+    /// Also serve HTTP/3 over QUIC on the same port (UDP), advertised with Alt-Svc
+    /// (needs --tls-cert or --acme-domain)
+    #[cfg(feature = "http3")]
+    #[arg(long)]
+    pub http3: bool,
+
+    /// Experimental: let `holodeck get --p2p` clients connect directly over QUIC, punching
+    /// through NAT, with the tunnel only used to trade addresses
+    #[cfg(feature = "p2p")]
+    #[arg(long)]
+    pub p2p: bool,
+
+    /// STUN server used to learn the public address for --p2p
+    #[cfg(feature = "p2p")]
+    #[arg(long, value_name = "HOST:PORT", default_value = crate::p2p::DEFAULT_STUN_SERVER)]
+    pub stun_server: String,
+
+    /// Sign downloads: minisign signatures at /<file>.sig, checked against the
+    /// public key at /_pubkey
+    #[cfg(feature = "signing")]
+    #[arg(long)]
+    pub sign: bool,
+
+    /// Where the signing key is kept, created on first use
+    /// (default: ~/.config/holodeck/signing.key)
+    #[cfg(feature = "signing")]
+    #[arg(long, value_name = "PATH", requires = "sign")]
+    pub signing_key: Option<PathBuf>,
+
+    /// Only accept uploads carrying a detached OpenPGP signature (base64 in an
+    /// X-Holodeck-Signature header) by this Ed25519 public key, e.g. from
+    /// `gpg --export`; repeatable
+    #[cfg(feature = "signing")]
+    #[arg(long, value_name = "FILE")]
+    pub trusted_key: Vec<PathBuf>,
+
+    /// Load a WebAssembly plugin hooking into requests, finished uploads and
+    /// listings; repeatable, run in the order given
+    #[cfg(feature = "plugins")]
+    #[arg(long, value_name = "FILE")]
+    pub plugin: Vec<PathBuf>,
+
+    /// Rhai script whose on_auth, on_upload and on_download callbacks accept, reject
+    /// or rewrite requests
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Index the share's text, Markdown and log files for full-text search at
+    /// /_search?q=
+    #[cfg(feature = "search")]
+    #[arg(long)]
+    pub search: bool,
+
+    /// Serve every route under this path prefix, e.g. /share behind a reverse proxy
+    #[arg(long, value_name = "PATH", default_value = "", value_parser = parse_base_path)]
+    pub base_path: String,
+
+    /// Serve the share as a static website: index.html for directories, nested paths,
+    /// inline content and cache headers for built frontend bundles
+    #[arg(long)]
+    pub website: bool,
+
+    /// Like --website, and answer unknown page routes with /index.html (single-page apps)
+    #[arg(long)]
+    pub spa: bool,
+
+    /// List files even where a directory has an index.html, instead of serving it as a page
+    #[arg(long)]
+    pub no_auto_index: bool,
+
+    /// Confine the process to the share and staging directory (Linux Landlock)
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Don't check that the tunnel's external URL leads back to this server by fetching
+    /// /_health through it at startup
+    #[arg(long)]
+    pub no_self_test: bool,
+
+    /// Take a fresh tunnel name instead of asking for the one used last time
+    #[arg(long)]
+    pub new_name: bool,
+
+    /// Reach the internet through another holodeck started with --relay-hub instead of
+    /// a tunnel, e.g. https://hub.example/?token=<admin token>; served under
+    /// /_relay/<name> there
+    #[arg(long, value_name = "URL", conflicts_with = "base_path")]
+    pub relay_via: Option<String>,
+
+    /// Let other holodeck instances register with this one (--relay-via) and serve
+    /// them under /_relay/<name>
+    #[arg(long)]
+    pub relay_hub: bool,
+
+    /// Don't record finished transfers in .holodeck/history.jsonl
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Don't point clients at the server's local network address (sent when listening
+    /// on one, e.g. --bind 0.0.0.0:8080) so those on the same network can skip the tunnel
+    #[arg(long)]
+    pub no_lan_hint: bool,
+
+    /// Write a JSON document with the local address, external URL and PID to this
+    /// path (or numeric file descriptor) once the server is reachable
+    #[arg(long, value_name = "PATH|FD")]
+    pub announce_json: Option<AnnounceTarget>,
+
+    /// Sync uploaded files (and their directory entry) to disk before confirming them
+    #[arg(long)]
+    pub fsync: bool,
+
+    /// Re-read each upload after writing it and check its SHA-256 against the bytes received
+    #[arg(long)]
+    pub verify_uploads: bool,
+
+    /// Reject uploads that start like an executable or script (ELF, PE, Mach-O, #!),
+    /// whatever their name
+    #[arg(long)]
+    pub block_executables: bool,
+
+    /// Store uploaded files zstd-compressed when that saves space, decompressing them as
+    /// they are served, or sending them as stored to clients that accept zstd
+    #[cfg(feature = "compression")]
+    #[arg(long)]
+    pub compress_storage: bool,
+
+    /// Directory for in-flight uploads (default: .holodeck/staging inside the share)
+    #[arg(long, value_name = "DIR")]
+    pub staging_dir: Option<PathBuf>,
+
+    /// Delete partial and unfinished resumable uploads in the staging directory once
+    /// nothing has been written to them for this long, e.g. 12h
+    #[arg(long, value_name = "DURATION", default_value = crate::sweeper::DEFAULT_MAX_AGE, value_parser = parse_interval)]
+    pub stale_upload_age: Duration,
+
+    /// Require a six-digit passcode, printed at startup, on every request; browsers
+    /// ask for it once and keep a session cookie
+    #[arg(long)]
+    pub passcode: bool,
+
+    /// Require this password on every request instead of a generated passcode; browsers
+    /// get a login page. Also read from HOLODECK_PASSWORD, which keeps it out of `ps`
+    #[arg(long, value_name = "PASSWORD", conflicts_with = "passcode")]
+    pub password: Option<String>,
+
+    /// Log browsers out this long after they entered the passcode or password, e.g. 30m
+    #[arg(long, value_name = "DURATION", default_value = crate::passcode::DEFAULT_SESSION_TTL, value_parser = parse_interval)]
+    pub session_ttl: Duration,
+
+    /// Require a share token on every request, printing a read-only link, one that also
+    /// allows uploads, and an admin link for deletes, the trash, aliases and /_admin
+    #[arg(long, conflicts_with_all = ["passcode", "password"])]
+    pub share_links: bool,
+
+    /// Most bytes the read-only share link may download in total, e.g. 5GiB; downloads
+    /// that would go over it get 403 Forbidden
+    #[arg(long, value_name = "SIZE", requires = "share_links", value_parser = parse_bytes)]
+    pub read_link_quota: Option<u64>,
+
+    /// Download speed cap for the read-only share link, in bytes per second across
+    /// all its downloads, e.g. 2MiB
+    #[arg(long, value_name = "SIZE", requires = "share_links", value_parser = parse_rate)]
+    pub read_link_rate: Option<u64>,
+
+    /// Ban a client address for --ban-time after this many wrong passcodes or tokens and
+    /// path traversal attempts within ten minutes; they are logged as INTRUSION lines
+    /// either way
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub ban_after: Option<u32>,
+
+    /// How long --ban-after bans last, e.g. 30m
+    #[arg(long, value_name = "DURATION", default_value = "1h", value_parser = parse_interval)]
+    pub ban_time: Duration,
+
+    /// Most downloads a single client address may run at once; more get 429 Too Many Requests
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_downloads_per_client: Option<u32>,
+
+    /// Allow DELETE /<file>; deleted files are kept in .holodeck/trash and can be
+    /// restored through /_trash
+    #[arg(long)]
+    pub allow_delete: bool,
+
+    /// Days deleted files stay restorable before being purged (0 deletes immediately)
+    #[arg(
+        long,
+        value_name = "DAYS",
+        default_value_t = 7,
+        requires = "allow_delete"
+    )]
+    pub trash_days: u64,
+
+    /// Enable /_admin routes listing transfers in flight, cancelling them and counting
+    /// connections, for whoever holds the admin token of --share-links
+    #[arg(long, requires = "share_links")]
+    pub admin: bool,
+
+    /// Largest archive POST /_batch will unpack, measured after decompression, e.g. 512MiB
+    #[cfg(feature = "archives")]
+    #[arg(long, value_name = "SIZE", default_value = "1GiB", value_parser = parse_size)]
+    pub batch_limit: usize,
+
+    /// Read/write chunk size for transfers, e.g. 256KiB or 1MiB
+    /// (default: 64KiB through a tunnel, 1MiB locally)
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_io_buffer)]
+    pub io_buffer: Option<usize>,
+
+    /// Show a desktop notification when an upload completes or a file is downloaded
+    #[cfg(feature = "desktop-notifications")]
+    #[arg(long)]
+    pub notify: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Measure upload/download throughput and latency locally and through the configured tunnel
+    #[cfg(feature = "client")]
+    Bench(BenchArgs),
+    /// Expose another local TCP port through the tunnel instead of serving files
+    Forward(ForwardArgs),
+    /// Forward HTTP traffic to another local server (e.g. a dev server) instead of serving files
+    Proxy(ProxyArgs),
+    /// Download a file from another holodeck, in parallel segments and resuming a partial download
+    #[cfg(feature = "client")]
+    Get(GetArgs),
+    /// Upload a file to another holodeck, resuming where an earlier attempt stopped
+    #[cfg(feature = "client")]
+    Put(PutArgs),
+    /// Keep the local share a copy of another holodeck's, downloading new and changed files
+    #[cfg(feature = "client")]
+    Mirror(MirrorArgs),
+    /// Sync the local share with another holodeck in both directions, keeping conflict copies
+    #[cfg(feature = "client")]
+    Sync(SyncArgs),
+    /// Upload a directory's new and changed files to another holodeck, optionally as they change
+    #[cfg(feature = "client")]
+    Push(PushArgs),
+    /// Print a QR code of a file's link in the terminal, for handing it to a phone
+    #[cfg(feature = "qr")]
+    Qr(QrArgs),
+    /// List the transfers the share in the current directory has served, newest last
+    History(HistoryArgs),
+    /// Write the share in the current directory, with its tags, metadata, aliases and
+    /// trash, to a .tar.zst snapshot
+    #[cfg(feature = "archives")]
+    Export(ExportArgs),
+    /// Restore a snapshot made with `holodeck export` into the current directory
+    #[cfg(feature = "archives")]
+    Import(ImportArgs),
+    /// Set up the tunnel interactively: choose a provider, find or generate an SSH key,
+    /// check the server can be reached and write the config file
+    Init,
+    /// Generate an ed25519 SSH key for the SSH tunnel providers and print its public key
+    #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+    Keygen(KeygenArgs),
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct GetArgs {
+    /// URL of the file, including any ?token= or ?passcode= it was shared with
+    pub url: String,
+
+    /// Where to save the file (default: its name, in the current directory)
+    #[arg(long, short, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Connections to download a large file over at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..=32))]
+    pub segments: u64,
+
+    /// Don't compare the file's SHA-256 with the server's afterwards
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Switch to the server's local network address when this machine is on the same
+    /// network, skipping the tunnel. Any token or passcode in the URL then goes there
+    /// over plain HTTP, to whichever machine answers as the server
+    #[arg(long)]
+    pub lan: bool,
+
+    /// Download even when the copy kept in ~/.cache/holodeck is still current, and
+    /// don't keep this one
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Experimental: first try a direct QUIC connection to a server started with --p2p,
+    /// falling back to the tunnel when NAT hole punching fails
+    #[cfg(feature = "p2p")]
+    #[arg(long)]
+    pub p2p: bool,
+
+    /// STUN server used to learn the public address for --p2p
+    #[cfg(feature = "p2p")]
+    #[arg(long, value_name = "HOST:PORT", default_value = crate::p2p::DEFAULT_STUN_SERVER)]
+    pub stun_server: String,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct PutArgs {
+    /// URL to upload to: a share (ending in /) or a file name on it, with any ?token=
+    pub url: String,
+
+    /// File to upload
+    pub file: PathBuf,
+
+    /// Don't compare the file's SHA-256 with the server's afterwards
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Switch to the server's local network address when this machine is on the same
+    /// network, skipping the tunnel. Any token or passcode in the URL then goes there
+    /// over plain HTTP, to whichever machine answers as the server
+    #[arg(long)]
+    pub lan: bool,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct MirrorArgs {
+    /// URL of the remote share, including any ?token= or ?passcode= it was shared with
+    pub url: String,
+
+    /// How often to check the remote share, e.g. 30s, 5m or 1h
+    #[arg(long, value_name = "DURATION", default_value = "5m", value_parser = parse_interval)]
+    pub interval: Duration,
+
+    /// Connections to download a large file over at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..=32))]
+    pub segments: u64,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct SyncArgs {
+    /// URL of the remote share, with a ?token= that allows uploads if it has --share-links
+    pub url: String,
+
+    /// Keep syncing at this interval, e.g. 30s or 5m, instead of once
+    #[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+    pub interval: Option<Duration>,
+
+    /// Connections to download a large file over at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..=32))]
+    pub segments: u64,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct PushArgs {
+    /// Keep watching the directory and upload files as they change
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Directory whose files are uploaded (top level only)
+    pub dir: PathBuf,
+
+    /// URL of the remote share, with a ?token= that allows uploads if it has --share-links
+    pub url: String,
+
+    /// How often --watch checks the directory, e.g. 2s or 1m
+    #[arg(long, value_name = "DURATION", default_value = "2s", value_parser = parse_interval)]
+    pub interval: Duration,
+}
+
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+#[derive(Debug, Args)]
+pub struct KeygenArgs {
+    /// Where to write the private key, the public key going next to it with .pub added
+    /// (default: id_ed25519 in the config directory)
+    #[arg(long, short, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Comment at the end of the public key
+    #[arg(long, short = 'C', default_value = "holodeck")]
+    pub comment: String,
+
+    /// Sign up to pico.sh with the new key right away, by running `ssh pico.sh`
+    #[arg(long)]
+    pub pico: bool,
+}
+
+#[cfg(feature = "qr")]
+#[derive(Debug, Args)]
+pub struct QrArgs {
+    /// Link to encode, e.g. a file's URL including any ?token= it is shared with
+    pub url: String,
+}
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Only transfers of this file, by its path in the share
+    #[arg(long, value_name = "PATH")]
+    pub file: Option<String>,
+
+    /// Only transfers with this client address
+    #[arg(long, value_name = "ADDR")]
+    pub peer: Option<String>,
+
+    /// Only transfers that ended within this long, e.g. 2d
+    #[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+    pub since: Option<Duration>,
+
+    /// Show at most this many of the newest matching transfers
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+
+    /// Print one JSON object per line instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(feature = "archives")]
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Where to write the snapshot, e.g. share.tar.zst
+    pub archive: PathBuf,
+}
+
+#[cfg(feature = "archives")]
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// The snapshot to restore
+    pub archive: PathBuf,
+
+    /// Import into a folder that already holds files, replacing those the snapshot
+    /// also holds
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ProxyArgs {
+    /// URL of the local app, e.g. http://127.0.0.1:3000
+    pub target: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ForwardArgs {
+    /// Local port to forward (on 127.0.0.1)
+    pub port: u16,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Size of the generated test file, e.g. 32MiB
+    #[arg(long, default_value = "32MiB", value_parser = parse_size)]
+    pub size: usize,
+
+    /// Upload/download rounds per endpoint
+    #[arg(long, default_value_t = 3)]
+    pub rounds: u32,
+
+    /// Small requests sent to measure latency percentiles
+    #[arg(long, default_value_t = 50)]
+    pub pings: u32,
+}
+
+impl Cli {
+    pub fn output_mode(&self) -> OutputMode {
+        if self.porcelain {
+            OutputMode::Porcelain
+        } else if self.quiet {
+            OutputMode::Quiet
+        } else {
+            OutputMode::Normal
+        }
+    }
+}
+
+/// How much holodeck writes to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Banners, progress bars and transfer logs
+    Normal,
+    /// Errors only
+    Quiet,
+    /// `LOCAL=` and `URL=` lines only, for scripts capturing the endpoint
+    Porcelain,
+}
+
+/// Parses a byte size such as `4096`, `64K`, `256KiB` or `1GiB` (all binary multiples).
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit '{}'", unit)),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// [`parse_bytes`] for sizes held in memory, which must fit the address space.
+fn parse_size(s: &str) -> Result<usize, String> {
+    usize::try_from(parse_bytes(s)?).map_err(|_| format!("size '{}' is too large", s.trim()))
+}
+
+/// Parses a duration such as `90`, `90s`, `5m`, `2h` or `1d` (seconds without a unit).
+pub(crate) fn parse_interval(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit '{}'", unit)),
+    };
+    match value.checked_mul(multiplier) {
+        Some(0) => Err("duration must be at least one second".to_string()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("duration '{}' is too large", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match parse_bytes(s)? {
+        0 => Err("rate must be at least 1 byte per second".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_io_buffer(s: &str) -> Result<usize, String> {
+    match parse_size(s)? {
+        size if (4 * 1024..=64 * 1024 * 1024).contains(&size) => Ok(size),
+        _ => Err("size must be between 4KiB and 64MiB".to_string()),
+    }
+}
+
+/// Normalizes a path prefix to `/segment/...` without a trailing slash (`/` means none).
+fn parse_base_path(s: &str) -> Result<String, String> {
+    let trimmed = s.trim().trim_end_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if !trimmed.starts_with('/') {
+        return Err("base path must start with '/'".to_string());
+    }
+    if trimmed.contains(['?', '#']) || trimmed.split('/').any(|segment| segment == "..") {
+        return Err(format!("invalid base path '{}'", s));
+    }
+    Ok(trimmed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_go_beyond_four_gigabytes() {
+        assert_eq!(parse_bytes("5GiB"), Ok(5 << 30));
+        assert_eq!(parse_bytes("64K"), Ok(64 << 10));
+        assert!(parse_bytes("99999999999999999999G").is_err());
+        assert!(parse_bytes("18014398509481984G").is_err());
+    }
+}
@@ -0,0 +1,577 @@
+use crate::cli::{GetArgs, OutputMode, PutArgs};
+use crate::config;
+use crate::lan;
+#[cfg(feature = "p2p")]
+use crate::p2p;
+use crate::paths;
+use crate::progress::{TransferProgress, Transfers};
+use crate::storage;
+use anyhow::{Context, bail};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_NONE_MATCH, RANGE, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Tries per segment or chunk before giving up; running the command again still resumes
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Smallest piece worth its own connection
+const MIN_SEGMENT: u64 = 1024 * 1024;
+
+/// Bytes sent per request, so an interrupted upload loses at most this much
+const UPLOAD_CHUNK: u64 = 8 * 1024 * 1024;
+
+/// Runs `holodeck get`: downloads a file over several ranged connections into
+/// `<output>.part-<i>-of-<n>` files, which a later run picks up where they stopped,
+/// then checks the result against the server's SHA-256.
+///
+/// Each download is kept in the cache directory with its ETag, and fetching the same
+/// URL again only asks the server whether it changed, copying the kept one if not.
+pub async fn get(args: GetArgs, output: OutputMode) -> anyhow::Result<()> {
+    let mut url = Url::parse(&args.url)?;
+    let name = file_name(&url)?;
+    let dest = args.output.unwrap_or_else(|| PathBuf::from(&name));
+    let cached = Cached::for_url(&url).filter(|_| !args.no_cache);
+    let client = Client::new();
+    if args.lan {
+        url = same_network(&client, url, output).await;
+    }
+    if let Some(cached) = &cached
+        && cached.is_current(&client, &url).await?
+    {
+        let size = fs::copy(&cached.data, &dest).await?;
+        if output != OutputMode::Quiet {
+            println!(
+                "Saved '{}' ({} bytes, unchanged since the last download)",
+                dest.display(),
+                size
+            );
+        }
+        return Ok(());
+    }
+    let transfers = Transfers::new(output == OutputMode::Normal);
+
+    #[cfg(feature = "p2p")]
+    if args.p2p {
+        match p2p::Peer::connect(&client, &url, &args.stun_server).await {
+            Ok(peer) => {
+                if output == OutputMode::Normal {
+                    println!("Connected directly to {}", peer.remote_address());
+                }
+                peer.download(&url, &name, &dest, &transfers).await?;
+                return verify_download(&client, &url, &name, &dest, args.no_verify, output).await;
+            }
+            Err(e) => eprintln!(
+                "Direct connection failed: {}; downloading through the tunnel",
+                e
+            ),
+        }
+    }
+
+    let etag = download(&client, &url, &name, &dest, args.segments, &transfers).await?;
+    verify_download(&client, &url, &name, &dest, args.no_verify, output).await?;
+    if let (Some(cached), Some(etag)) = (&cached, etag)
+        && let Err(e) = cached.store(&dest, &etag).await
+    {
+        eprintln!("Cannot keep '{}' in the download cache: {}", name, e);
+    }
+    Ok(())
+}
+
+/// A download `holodeck get` keeps in the cache directory, with the ETag it was
+/// served with.
+struct Cached {
+    data: PathBuf,
+    etag: PathBuf,
+}
+
+impl Cached {
+    /// The entry for `url`, whose `token` and `passcode` are left out of the key so a
+    /// server restarted with new credentials still finds it.
+    fn for_url(url: &Url) -> Option<Self> {
+        let mut key = url.clone();
+        key.set_fragment(None);
+        let query: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| name != "token" && name != "passcode")
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        key.set_query(None);
+        if !query.is_empty() {
+            key.query_pairs_mut().extend_pairs(query);
+        }
+        let key = hex::encode(Sha256::digest(key.as_str()));
+        let dir = config::cache_dir()?.join("get");
+        Some(Self {
+            data: dir.join(&key),
+            etag: dir.join(format!("{}.etag", key)),
+        })
+    }
+
+    /// Whether the server still has the version kept here, asked with `If-None-Match`
+    /// so it answers `304 Not Modified` instead of sending it again.
+    async fn is_current(&self, client: &Client, url: &Url) -> anyhow::Result<bool> {
+        let Ok(etag) = fs::read_to_string(&self.etag).await else {
+            return Ok(false);
+        };
+        if !fs::try_exists(&self.data).await? {
+            return Ok(false);
+        }
+        let request = client
+            .get(url.clone())
+            .header(IF_NONE_MATCH, etag)
+            .header(RANGE, "bytes=0-0");
+        Ok(send(request).await?.status() == StatusCode::NOT_MODIFIED)
+    }
+
+    /// Keeps a copy of `file`, as served with `etag`, in place of the previous one.
+    async fn store(&self, file: &Path, etag: &str) -> io::Result<()> {
+        if let Some(dir) = self.data.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        // Without its tag a half-written copy is never taken for current
+        match fs::remove_file(&self.etag).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::copy(file, &self.data).await?;
+        fs::write(&self.etag, etag).await
+    }
+}
+
+/// Downloads `url` into `dest` over up to `segments` ranged connections, resuming
+/// from any `<dest>.part-<i>-of-<n>` files an earlier attempt left behind, and gives
+/// the file's ETag if the server sent one.
+pub async fn download(
+    client: &Client,
+    url: &Url,
+    name: &str,
+    dest: &Path,
+    segments: u64,
+    transfers: &Transfers,
+) -> anyhow::Result<Option<String>> {
+    let (total, etag) = probe(client, url).await?;
+    let ranges: Vec<Option<(u64, u64)>> = match total {
+        Some(total) => {
+            let count = (total / MIN_SEGMENT).clamp(1, segments);
+            let size = total.div_ceil(count);
+            (0..count)
+                .map(|i| i * size)
+                .filter(|&start| start < total)
+                .map(|start| Some((start, (start + size).min(total) - 1)))
+                .collect()
+        }
+        // No range support: one plain GET, restarted from scratch each time
+        None => vec![None],
+    };
+    let parts: Vec<PathBuf> = (0..ranges.len())
+        .map(|i| part_path(dest, i, ranges.len()))
+        .collect();
+
+    let mut progress = transfers.start("GET", name, total);
+    futures_util::future::try_join_all(
+        ranges
+            .iter()
+            .zip(&parts)
+            .map(|(range, part)| fetch(client, url, *range, part, &progress)),
+    )
+    .await?;
+    assemble(&parts, dest).await?;
+    progress.finish();
+    Ok(etag)
+}
+
+/// Compares a finished download with the server's SHA-256, removing it on a mismatch.
+async fn verify_download(
+    client: &Client,
+    url: &Url,
+    name: &str,
+    dest: &Path,
+    no_verify: bool,
+    output: OutputMode,
+) -> anyhow::Result<()> {
+    let size = fs::metadata(dest).await?.len();
+    let verified = if no_verify {
+        false
+    } else {
+        let local = hex::encode(storage::sha256_file(dest).await?);
+        match remote_sha256(client, url, name).await? {
+            Some(remote) if remote == local => true,
+            Some(remote) => {
+                fs::remove_file(dest).await?;
+                bail!(
+                    "Checksum mismatch for '{}': server has {}, received {}; the download was \
+                     removed, run the command again",
+                    name,
+                    remote,
+                    local
+                );
+            }
+            None => {
+                eprintln!("The server can't report checksums; skipped verification");
+                false
+            }
+        }
+    };
+    if output != OutputMode::Quiet {
+        println!(
+            "Saved '{}' ({} bytes{})",
+            dest.display(),
+            size,
+            if verified { ", SHA-256 verified" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+/// Runs `holodeck put`: uploads a file in chunks with `PATCH` and `Content-Range`,
+/// first asking the server how much of an earlier attempt it already holds.
+pub async fn put(args: PutArgs, output: OutputMode) -> anyhow::Result<()> {
+    let mut url = Url::parse(&args.url)?;
+    if url.path().ends_with('/') {
+        let local_name = args
+            .file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("File has no usable name")?;
+        url.set_path(&format!(
+            "{}{}",
+            url.path(),
+            paths::percent_encode(local_name)
+        ));
+    }
+    let name = file_name(&url)?;
+    let client = Client::new();
+    if args.lan {
+        url = same_network(&client, url, output).await;
+    }
+    let total = fs::metadata(&args.file)
+        .await
+        .with_context(|| format!("Cannot read '{}'", args.file.display()))?
+        .len();
+
+    let transfers = Transfers::new(output == OutputMode::Normal);
+    let mut progress = transfers.start("PUT", &name, Some(total));
+    if total == 0 {
+        check(send(client.post(url.clone())).await?).await?;
+    } else {
+        upload(&client, &url, &args.file, total, &progress).await?;
+    }
+    progress.finish();
+
+    let verified = if args.no_verify {
+        false
+    } else {
+        let local = hex::encode(storage::sha256_file(&args.file).await?);
+        match remote_sha256(&client, &url, &name).await? {
+            Some(remote) if remote == local => true,
+            Some(remote) => bail!(
+                "Checksum mismatch for '{}': server has {}, sent {}",
+                name,
+                remote,
+                local
+            ),
+            None => {
+                eprintln!("The server can't report checksums; skipped verification");
+                false
+            }
+        }
+    };
+    if output != OutputMode::Quiet {
+        println!(
+            "Uploaded '{}' ({} bytes{})",
+            name,
+            total,
+            if verified { ", SHA-256 verified" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+/// The server's local network address in place of `url`'s when this machine is on the
+/// same network, so the transfer skips the tunnel.
+async fn same_network(client: &Client, url: Url, output: OutputMode) -> Url {
+    let Some(direct) = lan::fast_path(client, &url).await else {
+        return url;
+    };
+    if output == OutputMode::Normal {
+        println!(
+            "Same network as the server: transferring directly from {}",
+            direct.origin().ascii_serialization()
+        );
+    }
+    direct
+}
+
+/// The last segment of the URL's path, which names the file on both ends.
+fn file_name(url: &Url) -> anyhow::Result<String> {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default();
+    match paths::validate_filename(name) {
+        Ok(name) => Ok(name.to_string()),
+        Err(e) => bail!("{}: the URL must name a file", e),
+    }
+}
+
+fn part_path(dest: &Path, index: usize, count: usize) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(format!(".part-{}-of-{}", index, count));
+    PathBuf::from(part)
+}
+
+/// The file's size, or `None` when the server doesn't answer range requests, and its
+/// ETag if it has one.
+async fn probe(client: &Client, url: &Url) -> anyhow::Result<(Option<u64>, Option<String>)> {
+    let response = send(client.get(url.clone()).header(RANGE, "bytes=0-0")).await?;
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let total = match response.status() {
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+            let total = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit_once('/'))
+                .and_then(|(_, total)| total.parse().ok())
+                .context("Server sent an invalid Content-Range")?;
+            Some(total)
+        }
+        _ => {
+            check(response).await?;
+            None
+        }
+    };
+    Ok((total, etag))
+}
+
+/// Downloads one range into `part`, continuing from what it already holds and
+/// retrying after dropped connections.
+async fn fetch(
+    client: &Client,
+    url: &Url,
+    range: Option<(u64, u64)>,
+    part: &Path,
+    progress: &TransferProgress,
+) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part)
+        .await?;
+    let Some((start, end)) = range else {
+        file.set_len(0).await?;
+        let mut response = check(send(client.get(url.clone())).await?).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            progress.inc(chunk.len() as u64);
+        }
+        file.flush().await?;
+        return Ok(());
+    };
+
+    let len = end - start + 1;
+    let mut have = file.metadata().await?.len();
+    if have > len {
+        file.set_len(0).await?;
+        have = 0;
+    }
+    progress.inc(have);
+    let mut attempts = 0;
+    while have < len {
+        let result = async {
+            let request = client
+                .get(url.clone())
+                .header(RANGE, format!("bytes={}-{}", start + have, end));
+            let mut response = send(request).await?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                check(response).await?;
+                bail!("Server ignored the range request");
+            }
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+                progress.inc(chunk.len() as u64);
+            }
+            Ok(())
+        }
+        .await;
+        file.flush().await?;
+        have = file.metadata().await?.len();
+        if let Err(e) = result {
+            attempts += 1;
+            if attempts >= MAX_ATTEMPTS {
+                return Err(e);
+            }
+            progress.println(format!("GET: {}; retrying", e));
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    Ok(())
+}
+
+/// Joins the downloaded parts into `dest` and removes them.
+async fn assemble(parts: &[PathBuf], dest: &Path) -> anyhow::Result<()> {
+    if let [part] = parts {
+        fs::rename(part, dest).await?;
+        return Ok(());
+    }
+    let mut file = fs::File::create(dest).await?;
+    for part in parts {
+        tokio::io::copy(&mut fs::File::open(part).await?, &mut file).await?;
+    }
+    file.flush().await?;
+    for part in parts {
+        fs::remove_file(part).await?;
+    }
+    Ok(())
+}
+
+/// Sends the file from where the server's partial copy ends, chunk by chunk.
+async fn upload(
+    client: &Client,
+    url: &Url,
+    path: &Path,
+    total: u64,
+    progress: &TransferProgress,
+) -> anyhow::Result<()> {
+    let mut file = fs::File::open(path).await?;
+    let mut offset = upload_offset(client, url, total).await?;
+    progress.inc(offset);
+    let mut attempts = 0;
+    while offset < total {
+        let len = UPLOAD_CHUNK.min(total - offset);
+        let mut chunk = vec![0; len as usize];
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        file.read_exact(&mut chunk).await?;
+
+        let request = client.patch(url.clone()).header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+        );
+        match send(request.body(chunk)).await {
+            Ok(response) if response.status().is_success() => {
+                offset += len;
+                progress.inc(len);
+                attempts = 0;
+            }
+            Ok(response) => match resume_offset(&response) {
+                // The server holds a different amount than assumed; continue from there
+                Some(server_offset) if response.status() == StatusCode::CONFLICT => {
+                    offset = server_offset;
+                }
+                _ => {
+                    check(response).await?;
+                }
+            },
+            Err(e) => {
+                attempts += 1;
+                if attempts >= MAX_ATTEMPTS {
+                    return Err(e.into());
+                }
+                progress.println(format!("PUT: {}; retrying", e));
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                offset = upload_offset(client, url, total).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Uploads `path` in one streamed `POST`, replacing any file of that name on the
+/// server, and gives its size.
+pub async fn post_file(client: &Client, url: &Url, path: &Path) -> anyhow::Result<u64> {
+    let file = fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    // Streamed from disk, so this can't go through send(), which needs to clone the body
+    let response = client
+        .post(url.clone())
+        .header(CONTENT_LENGTH, len)
+        .body(file)
+        .send()
+        .await?;
+    check(response).await?;
+    Ok(len)
+}
+
+/// How many bytes of an earlier upload the server kept.
+///
+/// Asks with a one-byte range at the very end and an empty body, which the server
+/// turns down either way, reporting its current offset.
+async fn upload_offset(client: &Client, url: &Url, total: u64) -> anyhow::Result<u64> {
+    let request = client.patch(url.clone()).header(
+        CONTENT_RANGE,
+        format!("bytes {}-{}/{}", total - 1, total - 1, total),
+    );
+    let response = send(request).await?;
+    match resume_offset(&response) {
+        Some(offset) => Ok(offset),
+        None => {
+            check(response).await?;
+            bail!("Server did not report an upload offset; is it holodeck?")
+        }
+    }
+}
+
+fn resume_offset(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get("X-Upload-Offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// The server's SHA-256 of `name`, or `None` if it can't tell, as for a folder or a
+/// server without checksum support.
+pub async fn remote_sha256(
+    client: &Client,
+    url: &Url,
+    name: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut sha_url = url.clone();
+    let dir = &url.path()[..url.path().rfind('/').unwrap_or(0) + 1];
+    sha_url.set_path(&format!("{}_sha256/{}", dir, name));
+    let response = send(client.get(sha_url)).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(
+        check(response).await?.text().await?.trim().to_string(),
+    ))
+}
+
+/// Sends a request, waiting out `429 Too Many Requests` answers such as a server's
+/// per-client download cap.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    loop {
+        let retry = request.try_clone().expect("request bodies are buffered");
+        let response = retry.send().await?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(response);
+        }
+        let wait = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+/// Turns an error status into an error carrying the server's explanation.
+pub async fn check(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let reason = response.text().await.unwrap_or_default();
+    bail!("Server answered {}: {}", status, reason.trim())
+}
@@ -0,0 +1,108 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, access, full, paths};
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use std::io;
+use tokio::fs;
+
+/// Route prefix answering with download commands, as in `/_cmd/report.pdf`
+const CMD: &str = "/_cmd/";
+
+/// Registers `GET /_cmd/<file>`.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/_cmd/{*name}", async |req, cx| {
+            get(req.headers(), &cx.path, req.uri().query(), &cx.state).await
+        })
+        .scoped("name");
+}
+
+/// Ready-to-paste `curl`, `wget` and PowerShell commands downloading a shared file,
+/// for recipients who'd rather not look up flags.
+pub async fn get(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let filename = path.strip_prefix(CMD).unwrap_or_default();
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    let is_file = fs::symlink_metadata(state.root.join(filename))
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    if filename == crate::META_DIR || !is_file {
+        return Ok(text(
+            StatusCode::NOT_FOUND,
+            format!("File '{}' not found", filename),
+        ));
+    }
+
+    let url = file_url(headers, filename, query, state);
+    let body = format!(
+        "# curl\ncurl -fL -o {name} {url}\n\
+         # wget\nwget -O {name} {url}\n\
+         # PowerShell\nInvoke-WebRequest -Uri {ps_url} -OutFile {ps_name}\n",
+        name = shell_quote(filename),
+        url = shell_quote(&url),
+        ps_name = powershell_quote(filename),
+        ps_url = powershell_quote(&url),
+    );
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full(body))
+        .unwrap())
+}
+
+/// The full URL of the shared file `filename` for the client making this request.
+///
+/// It's built from the URL the request came in on, so through a tunnel it's the public
+/// one, and carries the share token or passcode the request used, if any.
+pub fn file_url(
+    headers: &HeaderMap,
+    filename: &str,
+    query: Option<&str>,
+    state: &AppState,
+) -> String {
+    let mut url = format!(
+        "{}/{}",
+        crate::base_url(headers, state),
+        paths::percent_encode(filename)
+    );
+    // The holder of a read-only link gets a link with that link's token, never more
+    if state.share_tokens.is_some()
+        && let Some(token) = access::presented(headers, query)
+    {
+        url.push_str(&format!("?token={}", token));
+    } else if let Some(passcode) = &state.passcode {
+        url.push_str(&format!("?passcode={}", passcode.code()));
+    }
+    url
+}
+
+/// Single-quotes `arg` for POSIX shells, where nothing inside single quotes is special.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Single-quotes `arg` for PowerShell, which escapes a quote by doubling it.
+fn powershell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "''"))
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_survive_each_shell() {
+        assert_eq!(shell_quote("it's.txt"), r"'it'\''s.txt'");
+        assert_eq!(powershell_quote("it's.txt"), "'it''s.txt'");
+    }
+}
@@ -0,0 +1,171 @@
+use bytes::Bytes;
+#[cfg(feature = "compression")]
+use flate2::write::GzEncoder;
+use std::io;
+#[cfg(feature = "compression")]
+use std::io::Write;
+
+/// Compression a download can be streamed through with `?compress=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Reads `compress=<gzip|zstd>` from a query string; `Ok(None)` when absent.
+    pub fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let Some(value) = query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| pair.strip_prefix("compress="))
+        else {
+            return Ok(None);
+        };
+        let encoding = match value {
+            "gzip" | "gz" => Self::Gzip,
+            "zstd" | "zst" => Self::Zstd,
+            _ => {
+                return Err(format!(
+                    "Unsupported compression '{}': use gzip or zstd",
+                    value
+                ));
+            }
+        };
+        match cfg!(feature = "compression") {
+            true => Ok(Some(encoding)),
+            false => Err(
+                "holodeck was built without compression (the `compression` feature)".to_string(),
+            ),
+        }
+    }
+
+    /// Suffix added to the downloaded file's name
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Gzip => "application/gzip",
+            Self::Zstd => "application/zstd",
+        }
+    }
+}
+
+/// Streaming compressor fed one download chunk at a time.
+///
+/// Output is taken as it becomes available, so memory stays bounded by the
+/// compressor's window rather than the file size.
+#[cfg(feature = "compression")]
+pub enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+#[cfg(feature = "compression")]
+impl Compressor {
+    pub fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::fast())),
+            // Level 3 is zstd's default; faster than gzip while compressing better
+            Encoding::Zstd => Self::Zstd(zstd::Encoder::new(Vec::new(), 3)?),
+        })
+    }
+
+    /// Compresses `data`, returning whatever output is ready (possibly nothing yet).
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    /// Flushes the remaining output and the format's trailer.
+    pub fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Never made: [`Encoding::from_query`] refuses `?compress=` in builds without the
+/// `compression` feature.
+#[cfg(not(feature = "compression"))]
+pub enum Compressor {}
+
+#[cfg(not(feature = "compression"))]
+impl Compressor {
+    pub fn new(_encoding: Encoding) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "holodeck was built without the `compression` feature",
+        ))
+    }
+
+    pub fn compress(&mut self, _data: &[u8]) -> io::Result<Bytes> {
+        match *self {}
+    }
+
+    pub fn finish(self) -> io::Result<Bytes> {
+        match self {}
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn round_trip(encoding: Encoding, decode: impl Fn(&[u8]) -> Vec<u8>) {
+        let input = "a log line that repeats\n".repeat(10_000);
+        let mut compressor = Compressor::new(encoding).unwrap();
+        let mut compressed = Vec::new();
+        for chunk in input.as_bytes().chunks(4096) {
+            compressed.extend_from_slice(&compressor.compress(chunk).unwrap());
+        }
+        compressed.extend_from_slice(&compressor.finish().unwrap());
+
+        assert!(compressed.len() < input.len() / 10);
+        assert_eq!(decode(&compressed), input.as_bytes());
+    }
+
+    #[test]
+    fn gzip_round_trips_in_chunks() {
+        round_trip(Encoding::Gzip, |data| {
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut out)
+                .unwrap();
+            out
+        });
+    }
+
+    #[test]
+    fn zstd_round_trips_in_chunks() {
+        round_trip(Encoding::Zstd, |data| zstd::decode_all(data).unwrap());
+    }
+
+    #[test]
+    fn parses_query() {
+        assert_eq!(Encoding::from_query(None), Ok(None));
+        assert_eq!(Encoding::from_query(Some("x=1")), Ok(None));
+        assert_eq!(
+            Encoding::from_query(Some("x=1&compress=zstd")),
+            Ok(Some(Encoding::Zstd))
+        );
+        assert!(Encoding::from_query(Some("compress=brotli")).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{BoxBody, full};
+use hyper::header::{ETAG, HeaderMap, IF_MATCH, IF_NONE_MATCH};
+use hyper::{Response, StatusCode};
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// The entity tag of a file's current contents, from its size and its modification time
+/// to the nanosecond, so it changes whenever the file is replaced or written to.
+///
+/// Timestamps only advance once per kernel tick, so on Unix the inode is part of it too:
+/// an upload replaces the file with a new one, even within the same tick.
+pub fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_nanos());
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    format!("\"{:x}-{:x}-{:x}\"", modified, metadata.len(), inode)
+}
+
+/// The entity tag of a file's zstd-compressed bytes, sent as stored with
+/// `Content-Encoding: zstd` from the file's tag `etag`. They aren't the decoded bytes,
+/// so sharing a strong tag would let a cache or `If-Range` splice one into the other.
+pub fn zstd_etag(etag: &str) -> String {
+    format!("{}-zstd\"", etag.trim_end_matches('"'))
+}
+
+/// Whether a download's `If-None-Match` names the current tag `etag`, so the client's
+/// copy is current and `304 Not Modified` will do.
+pub fn is_fresh(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|tags| {
+            tags.trim() == "*" || list(tags).any(|tag| tag.trim_start_matches("W/") == etag)
+        })
+}
+
+/// The `If-Match` and `If-None-Match` conditions of an upload, which keep two people
+/// re-uploading the same document from silently overwriting each other's changes:
+///
+/// - `If-None-Match: *` only creates the file, failing if it exists
+/// - `If-Match: <etag>` only replaces the version with that tag, as last downloaded
+/// - `If-Match: *` only replaces a file that exists
+pub struct Preconditions {
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl Preconditions {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            if_match: value(IF_MATCH),
+            if_none_match: value(IF_NONE_MATCH),
+        }
+    }
+
+    /// Checks the conditions against the file at `path`, answering `412 Precondition
+    /// Failed`, with the current tag if any, when they don't hold.
+    pub async fn check(&self, filename: &str, path: &Path) -> Result<(), Response<BoxBody>> {
+        if self.if_match.is_none() && self.if_none_match.is_none() {
+            return Ok(());
+        }
+        let current = tokio::fs::symlink_metadata(path)
+            .await
+            .ok()
+            .filter(Metadata::is_file)
+            .map(|metadata| etag(&metadata));
+        match self.evaluate(current.as_deref()) {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                let mut response = Response::builder().status(StatusCode::PRECONDITION_FAILED);
+                if let Some(current) = &current {
+                    response = response.header(ETAG, current);
+                }
+                Err(response
+                    .body(full(format!("'{}' {}", filename, reason)))
+                    .unwrap())
+            }
+        }
+    }
+
+    /// Whether the conditions hold for a file tagged `current`, `None` when there is no
+    /// file; `If-Match` takes precedence, as HTTP specifies.
+    fn evaluate(&self, current: Option<&str>) -> Result<(), &'static str> {
+        if let Some(tags) = &self.if_match {
+            return match current {
+                None => Err("doesn't exist"),
+                // Weak tags never match here: the write must replace exactly this version
+                Some(current) if tags.trim() == "*" || list(tags).any(|tag| tag == current) => {
+                    Ok(())
+                }
+                Some(_) => {
+                    Err("has changed since that version; download it again and redo the changes")
+                }
+            };
+        }
+        if let Some(tags) = &self.if_none_match
+            && let Some(current) = current
+            && (tags.trim() == "*" || list(tags).any(|tag| tag.trim_start_matches("W/") == current))
+        {
+            return Err("already exists");
+        }
+        Ok(())
+    }
+}
+
+fn list(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(',').map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preconditions(if_match: Option<&str>, if_none_match: Option<&str>) -> Preconditions {
+        Preconditions {
+            if_match: if_match.map(str::to_string),
+            if_none_match: if_none_match.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn if_none_match_star_only_creates() {
+        let create_only = preconditions(None, Some("*"));
+        assert_eq!(create_only.evaluate(None), Ok(()));
+        assert_eq!(create_only.evaluate(Some("\"1-2\"")), Err("already exists"));
+    }
+
+    #[test]
+    fn if_match_only_replaces_the_version_seen() {
+        let update = preconditions(Some("\"0-5\", \"1-2\""), None);
+        assert_eq!(update.evaluate(Some("\"1-2\"")), Ok(()));
+        assert!(update.evaluate(Some("\"1-3\"")).is_err());
+        assert_eq!(update.evaluate(None), Err("doesn't exist"));
+        assert!(
+            preconditions(Some("W/\"1-2\""), None)
+                .evaluate(Some("\"1-2\""))
+                .is_err()
+        );
+
+        let any = preconditions(Some("*"), Some("*"));
+        assert_eq!(any.evaluate(Some("\"1-2\"")), Ok(()));
+        assert_eq!(any.evaluate(None), Err("doesn't exist"));
+    }
+
+    #[test]
+    fn compressed_bytes_have_their_own_tag() {
+        let plain = "\"1-2\"";
+        let compressed = zstd_etag(plain);
+        assert_eq!(compressed, "\"1-2-zstd\"");
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, plain.parse().unwrap());
+        assert!(is_fresh(&headers, plain));
+        assert!(!is_fresh(&headers, &compressed));
+        headers.insert(IF_NONE_MATCH, compressed.parse().unwrap());
+        assert!(is_fresh(&headers, &compressed));
+        assert!(!is_fresh(&headers, plain));
+        assert!(
+            preconditions(Some(&compressed), None)
+                .evaluate(Some(plain))
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn tags_follow_changes_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "first").unwrap();
+        let seen = etag(&std::fs::metadata(&path).unwrap());
+
+        let update = preconditions(Some(&seen), None);
+        assert!(update.check("notes.txt", &path).await.is_ok());
+        std::fs::write(&path, "second draft").unwrap();
+        let response = update.check("notes.txt", &path).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_ne!(response.headers()[ETAG], seen.as_str());
+    }
+}
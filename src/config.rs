@@ -0,0 +1,218 @@
+use crate::headers::HeaderConfig;
+use crate::policy::PolicyConfig;
+use crate::webhook::WebhookConfig;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+/// Settings loaded from the optional TOML config file.
+///
+/// The file is looked up at `HOLODECK_CONFIG` if set, otherwise at
+/// `~/.config/holodeck/config.toml`. A missing file simply yields defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Extension → MIME type overrides and additions (e.g. `gcode = "text/x-gcode"`)
+    pub mime: HashMap<String, String>,
+    /// Slack/Discord webhooks notified on transfer events
+    pub webhook: Vec<WebhookConfig>,
+    /// MQTT broker receiving transfer and tunnel events (requires the `mqtt` feature)
+    pub mqtt: Option<MqttConfig>,
+    pub tunnel: TunnelConfig,
+    /// Static headers added to all responses or those matching a path pattern
+    pub header: Vec<HeaderConfig>,
+    /// Vanity slug → shared filename (e.g. `latest = "build-2024-06-01.tar.gz"`)
+    pub alias: HashMap<String, String>,
+    /// What each folder of the share allows, the deepest matching rule winning
+    pub policy: Vec<PolicyConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// Regular expressions matching the external URL in the provider's banner, tried
+    /// before the built-in domains (e.g. for self-hosted sish on a custom domain)
+    #[serde(deserialize_with = "one_or_more_patterns")]
+    #[cfg_attr(
+        not(any(feature = "tunnel-lhr", feature = "tunnel-pico")),
+        allow(dead_code)
+    )]
+    pub url_pattern: Vec<UrlPattern>,
+    /// Custom domain for pico.sh tunnels (e.g. `files.mydomain.com`), CNAMEd to tuns.sh
+    pub domain: Option<String>,
+    /// Providers tried in order until one yields a URL: `pico`, `localhost.run`,
+    /// `bore`, or `ssh` for the server in `SSH_SERVER`
+    pub providers: Vec<String>,
+    /// Seconds to wait for each provider's URL before trying the next
+    pub provider_timeout: u64,
+    /// bore server used by the `bore` provider
+    #[cfg_attr(not(feature = "tunnel-bore"), allow(dead_code))]
+    pub bore_server: String,
+    /// SSH server for the `ssh` provider, such as a self-hosted sish; `SSH_SERVER`
+    /// takes precedence
+    pub ssh_server: Option<String>,
+    /// SSH port, `SSH_PORT` taking precedence
+    #[cfg_attr(
+        not(any(feature = "tunnel-lhr", feature = "tunnel-pico")),
+        allow(dead_code)
+    )]
+    pub ssh_port: Option<u16>,
+    /// SSH username, `SSH_USER` taking precedence
+    #[cfg_attr(
+        not(any(feature = "tunnel-lhr", feature = "tunnel-pico")),
+        allow(dead_code)
+    )]
+    pub ssh_user: Option<String>,
+    /// SSH private key, `SSH_KEY_PATH` taking precedence
+    #[cfg_attr(
+        not(any(feature = "tunnel-lhr", feature = "tunnel-pico")),
+        allow(dead_code)
+    )]
+    pub ssh_key: Option<PathBuf>,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            url_pattern: Vec::new(),
+            domain: None,
+            providers: Vec::new(),
+            provider_timeout: 20,
+            bore_server: "bore.pub".to_string(),
+            ssh_server: None,
+            ssh_port: None,
+            ssh_user: None,
+            ssh_key: None,
+        }
+    }
+}
+
+/// A compiled `url_pattern`, kept as written in builds without SSH tunnels, the only
+/// providers that scan a banner for their URL
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+pub type UrlPattern = regex::Regex;
+#[cfg(not(any(feature = "tunnel-lhr", feature = "tunnel-pico")))]
+pub type UrlPattern = String;
+
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+impl TunnelConfig {
+    /// All patterns identifying the external URL, the custom domain's first
+    pub fn url_patterns(&self) -> Vec<UrlPattern> {
+        let domain = self.domain.as_ref().map(|domain| {
+            regex::Regex::new(&format!(r"https?://{}\S*", regex::escape(domain)))
+                .expect("escaped domain is a valid pattern")
+        });
+        domain
+            .into_iter()
+            .chain(self.url_pattern.iter().cloned())
+            .collect()
+    }
+}
+
+/// Accepts `url_pattern = "..."` as well as a list of patterns.
+fn one_or_more_patterns<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<UrlPattern>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+
+    let patterns = match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(pattern) => vec![pattern],
+        OneOrMore::More(patterns) => patterns,
+    };
+    patterns
+        .into_iter()
+        .map(|pattern| compile(pattern).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+fn compile(pattern: String) -> Result<UrlPattern, regex::Error> {
+    regex::Regex::new(&pattern)
+}
+
+#[cfg(not(any(feature = "tunnel-lhr", feature = "tunnel-pico")))]
+fn compile(pattern: String) -> Result<UrlPattern, std::convert::Infallible> {
+    Ok(pattern)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_topic() -> String {
+    "holodeck/events".to_string()
+}
+
+fn default_mqtt_client_id() -> String {
+    format!("holodeck-{}", std::process::id())
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        let explicit = env::var("HOLODECK_CONFIG").is_ok();
+        let Some(path) = path() else {
+            return Ok(Self::default());
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e)),
+            // Only an explicitly requested config file is required to exist
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Ok(Self::default()),
+            Err(e) => Err(anyhow::anyhow!(
+                "Cannot read config file {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+}
+
+/// Where the config file is looked up: `HOLODECK_CONFIG`, or `config.toml` in
+/// [`config_dir`]
+pub fn path() -> Option<PathBuf> {
+    env::var("HOLODECK_CONFIG")
+        .ok()
+        .map(PathBuf::from)
+        .or_else(|| Some(config_dir()?.join("config.toml")))
+}
+
+/// `~/.cache/holodeck`, or `$XDG_CACHE_HOME/holodeck` when set
+#[cfg(feature = "client")]
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok()?;
+    Some(base.join("holodeck"))
+}
+
+/// `~/.config/holodeck`, or `$XDG_CONFIG_HOME/holodeck` when set
+pub fn config_dir() -> Option<PathBuf> {
+    let base = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(base.join("holodeck"))
+}
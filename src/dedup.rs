@@ -0,0 +1,270 @@
+use crate::events::Event;
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::storage::{self, StagedFile};
+use crate::{AppState, BoxBody, META_DIR, full, paths, sandbox};
+use http_body_util::{BodyExt, Limited};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Route answering whether content with a given SHA-256 is already shared
+pub const ROUTE: &str = "/_exists";
+
+/// Route prefix answering with a shared file's SHA-256, as in `/_sha256/report.pdf`
+const SHA256: &str = "/_sha256/";
+
+/// Largest `POST /_exists` body accepted; a hash and a filename fit easily
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Deserialize)]
+struct ExistsRequest {
+    /// Hex SHA-256 of the content the client is about to upload
+    sha256: String,
+    /// Name to store the content under when it is found
+    name: Option<String>,
+}
+
+/// SHA-256 of the shared files, computed on first use and reused while a file's
+/// size and modification time are unchanged.
+#[derive(Default)]
+pub struct HashIndex {
+    entries: Mutex<HashMap<String, Hashed>>,
+}
+
+struct Hashed {
+    len: u64,
+    modified: Option<SystemTime>,
+    sha256: Vec<u8>,
+}
+
+impl HashIndex {
+    /// A shared file whose content hashes to `sha256`, with its size.
+    async fn find(&self, state: &AppState, sha256: &[u8]) -> io::Result<Option<(String, u64)>> {
+        let mut entries = fs::read_dir(&state.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // Not followed through symlinks, like every other read of the share
+            let metadata = entry.metadata().await?;
+            if name == META_DIR || !metadata.is_file() {
+                continue;
+            }
+            if self.hash(state, &name, &metadata).await? == sha256 {
+                let packed = state.packed.size(&name, &metadata).await;
+                return Ok(Some((name, packed.unwrap_or(metadata.len()))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// SHA-256 of the shared file `name` as uploaded, from the cache while `metadata`
+    /// still matches.
+    pub async fn hash(
+        &self,
+        state: &AppState,
+        name: &str,
+        metadata: &Metadata,
+    ) -> io::Result<Vec<u8>> {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let cached = self
+            .entries
+            .lock()
+            .await
+            .get(name)
+            .filter(|hashed| hashed.len == len && hashed.modified == modified)
+            .map(|hashed| hashed.sha256.clone());
+        if let Some(sha256) = cached {
+            return Ok(sha256);
+        }
+        let opened = state.packed.open(&state.root, name).await?;
+        let sha256 = storage::sha256(opened.contents).await?;
+        self.entries.lock().await.insert(
+            name.to_string(),
+            Hashed {
+                len,
+                modified,
+                sha256: sha256.clone(),
+            },
+        );
+        Ok(sha256)
+    }
+}
+
+/// Registers `GET /_sha256/<file>` and `POST /_exists`.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/_sha256/{*name}", async |_, cx| {
+            sha256(&cx.path, &cx.state).await
+        })
+        .scoped("name");
+    router.post(ROUTE, async |req, cx| {
+        handle(req, cx.client, &cx.state).await
+    });
+}
+
+/// Answers with the hex SHA-256 of a shared file, so a client can check a download.
+pub async fn sha256(path: &str, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let filename = path.strip_prefix(SHA256).unwrap_or_default();
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    let metadata = match fs::symlink_metadata(state.root.join(filename)).await {
+        Ok(metadata) if metadata.is_file() && filename != META_DIR => metadata,
+        _ => {
+            return Ok(text(
+                StatusCode::NOT_FOUND,
+                format!("File '{}' not found", filename),
+            ));
+        }
+    };
+    match state.hashes.hash(state, filename, &metadata).await {
+        Ok(sha256) => Ok(text(StatusCode::OK, format!("{}\n", hex::encode(sha256)))),
+        Err(e) => {
+            eprintln!("GET: Error hashing '{}': {}", filename, e);
+            Ok(text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error reading file: {}", e),
+            ))
+        }
+    }
+}
+
+/// Lets a client skip uploading content the share already holds.
+///
+/// Takes `{"sha256": "<hex>", "name": "<file>"}`. When a shared file has that hash it
+/// is copied to `name` (`201 Created`), or just reported without a `name` (`200 OK`);
+/// otherwise `404 Not Found` tells the client to upload as usual. The copy is a real
+/// one rather than a hard link, since appending to one name must not change the other.
+pub async fn handle(
+    req: Request<BoxBody>,
+    client: String,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let role = policy::requester(state, req.headers(), req.uri().query());
+    let body = match Limited::new(req.into_body(), MAX_REQUEST_LEN)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(text(StatusCode::PAYLOAD_TOO_LARGE, "Request too large")),
+    };
+    let request: ExistsRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Ok(text(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid request: {}", e),
+            ));
+        }
+    };
+    let sha256 = match hex::decode(&request.sha256) {
+        Ok(sha256) if sha256.len() == 32 => sha256,
+        _ => {
+            return Ok(text(
+                StatusCode::BAD_REQUEST,
+                "sha256 must be 64 hexadecimal characters",
+            ));
+        }
+    };
+    if let Some(name) = &request.name {
+        if let Err(e) = paths::validate_filename(name) {
+            return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+        }
+        if name == META_DIR {
+            return Ok(text(StatusCode::BAD_REQUEST, "Invalid filename"));
+        }
+    }
+    let policies = state.policies();
+    if let Some(name) = &request.name
+        && let Some(response) = policies.challenge(Action::Upload, name, role)
+    {
+        return Ok(response);
+    }
+
+    // Content the requester may not download is as good as absent
+    let (existing, len) = match state.hashes.find(state, &sha256).await {
+        Ok(Some((existing, len))) if policies.allows(Action::Download, &existing, role) => {
+            (existing, len)
+        }
+        Ok(_) => {
+            return Ok(text(
+                StatusCode::NOT_FOUND,
+                format!("No file with sha256 {}", request.sha256),
+            ));
+        }
+        Err(e) => {
+            eprintln!("POST: Error looking up sha256 {}: {}", request.sha256, e);
+            return Ok(text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error reading files: {}", e),
+            ));
+        }
+    };
+    let Some(name) = request.name.filter(|name| *name != existing) else {
+        return Ok(text(
+            StatusCode::OK,
+            format!("File '{}' has this content ({} bytes)", existing, len),
+        ));
+    };
+
+    let Some(_lock) = state.writes.try_lock(&name) else {
+        return Ok(crate::write_in_progress(&name));
+    };
+    if let Err(e) = copy(state, &existing, &name).await {
+        eprintln!("POST: Error copying '{}' to '{}': {}", existing, name, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error storing file: {}", e),
+        ));
+    }
+    state.transfers.println(format!(
+        "POST: Stored '{}' as a copy of '{}' ({} bytes, upload skipped)",
+        name, existing, len
+    ));
+    state.events.emit(Event::UploadCompleted {
+        file: name.clone(),
+        bytes: len,
+        peer: client,
+    });
+    Ok(text(
+        StatusCode::CREATED,
+        format!(
+            "File '{}' created from existing content ({} bytes)",
+            name, len
+        ),
+    ))
+}
+
+/// Copies through the staging directory, so `name` appears complete or not at all. A
+/// file stored compressed is copied as it is.
+async fn copy(state: &AppState, existing: &str, name: &str) -> io::Result<()> {
+    let mut source = sandbox::open_beneath(&state.root, existing, sandbox::Access::Read).await?;
+    let metadata = source.metadata().await?;
+    let packed = state.packed.size(existing, &metadata).await;
+    let (staged, mut file) = StagedFile::create(&state.staging_dir, name).await?;
+    tokio::io::copy(&mut source, &mut file).await?;
+    if state.fsync {
+        file.sync_all().await?;
+    }
+    drop(file);
+    staged.persist(&state.root.join(name)).await?;
+    if state.fsync {
+        storage::sync_dir(&state.root).await?;
+    }
+    match packed {
+        Some(size) => state.packed.record(&state.root, name, size).await,
+        None => Ok(()),
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
@@ -0,0 +1,32 @@
+use crate::events::Events;
+use notify_rust::Notification;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Shows a desktop notification for every transfer event.
+pub fn spawn_notifier(events: &Events) {
+    let mut rx = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            // D-Bus/OS notification calls block, keep them off the runtime threads
+            let body = event.summary();
+            let result = tokio::task::spawn_blocking(move || {
+                Notification::new()
+                    .appname("holodeck")
+                    .summary("Holodeck")
+                    .body(&body)
+                    .show()
+                    .map(|_| ())
+            })
+            .await;
+            if let Ok(Err(e)) = result {
+                eprintln!("Error showing desktop notification: {}", e);
+            }
+        }
+    });
+}
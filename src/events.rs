@@ -0,0 +1,84 @@
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Something worth telling the outside world about.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    UploadCompleted {
+        file: String,
+        bytes: u64,
+        peer: String,
+    },
+    /// The client went away before the upload finished; its partial data was dropped
+    UploadAborted {
+        file: String,
+        bytes: u64,
+        peer: String,
+    },
+    DownloadCompleted {
+        file: String,
+        bytes: u64,
+        peer: String,
+    },
+    TunnelUp {
+        url: String,
+    },
+    TunnelDown {
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    /// One-line human-readable description, used by chat and desktop notifiers
+    pub fn summary(&self) -> String {
+        match self {
+            Self::UploadCompleted { file, bytes, peer } => {
+                format!("{} uploaded by {}, {}", file, peer, HumanBytes(*bytes))
+            }
+            Self::UploadAborted { file, bytes, peer } => format!(
+                "{} upload by {} aborted after {}",
+                file,
+                peer,
+                HumanBytes(*bytes)
+            ),
+            Self::DownloadCompleted { file, bytes, peer } => {
+                format!("{} downloaded by {}, {}", file, peer, HumanBytes(*bytes))
+            }
+            Self::TunnelUp { url } => format!("Tunnel active at {}", url),
+            Self::TunnelDown { error: None } => "Tunnel closed".to_string(),
+            Self::TunnelDown { error: Some(e) } => format!("Tunnel failed: {}", e),
+        }
+    }
+}
+
+/// Fan-out of server events to any number of notifier tasks.
+///
+/// Emitting never blocks; a notifier that falls too far behind skips events.
+#[derive(Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(64);
+        Self { tx }
+    }
+
+    pub fn emit(&self, event: Event) {
+        // No subscribers simply means no notifiers are configured
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{BoxBody, full};
+use hyper::{Response, StatusCode};
+
+/// Magic bytes of the formats `--block-executables` refuses, with how to name them
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x7fELF", "an ELF executable"),
+    (b"MZ", "a Windows executable"),
+    (&[0xfe, 0xed, 0xfa, 0xce], "a Mach-O executable"),
+    (&[0xfe, 0xed, 0xfa, 0xcf], "a Mach-O executable"),
+    (&[0xce, 0xfa, 0xed, 0xfe], "a Mach-O executable"),
+    (&[0xcf, 0xfa, 0xed, 0xfe], "a Mach-O executable"),
+    (&[0xca, 0xfe, 0xba, 0xbe], "a Mach-O universal binary"),
+    (b"#!", "a script"),
+];
+
+/// Longest magic number, and so how much of an upload needs inspecting
+const HEAD_LEN: usize = 4;
+
+/// Recognizes executables and scripts from the first bytes of an upload as it
+/// streams in, whatever its name or Content-Type.
+#[derive(Default)]
+pub struct Sniffer {
+    head: Vec<u8>,
+}
+
+impl Sniffer {
+    /// Looks at the next chunk of the upload, naming the kind of executable once
+    /// the bytes so far give it away.
+    pub fn inspect(&mut self, data: &[u8]) -> Option<&'static str> {
+        if self.head.len() >= HEAD_LEN {
+            return None;
+        }
+        let n = data.len().min(HEAD_LEN - self.head.len());
+        self.head.extend_from_slice(&data[..n]);
+        MAGIC
+            .iter()
+            .find(|(magic, _)| self.head.starts_with(magic))
+            .map(|(_, kind)| *kind)
+    }
+}
+
+/// Refuses the upload of `name`, which turned out to be `kind`.
+pub fn refuse(name: &str, kind: &str) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .body(full(format!(
+            "'{}' looks like {}; executables and scripts are not accepted",
+            name, kind
+        )))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspect(chunks: &[&[u8]]) -> Option<&'static str> {
+        let mut sniffer = Sniffer::default();
+        chunks.iter().find_map(|chunk| sniffer.inspect(chunk))
+    }
+
+    #[test]
+    fn recognizes_executables_across_chunks() {
+        assert_eq!(inspect(&[b"\x7fELF\x02\x01"]), Some("an ELF executable"));
+        assert_eq!(inspect(&[b"\x7f", b"E", b"LF"]), Some("an ELF executable"));
+        assert_eq!(inspect(&[b"M", b"Z\x90\x00"]), Some("a Windows executable"));
+        assert_eq!(
+            inspect(&[&[0xcf, 0xfa], &[0xed, 0xfe, 0x07]]),
+            Some("a Mach-O executable")
+        );
+        assert_eq!(inspect(&[b"#!/bin/sh\n"]), Some("a script"));
+
+        assert_eq!(inspect(&[b"%PDF-1.7"]), None);
+        assert_eq!(inspect(&[b"\x7fEL"]), None);
+        // Only the start of the file counts
+        assert_eq!(inspect(&[b"text", b"#!/bin/sh"]), None);
+    }
+}
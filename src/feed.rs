@@ -0,0 +1,118 @@
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use std::io;
+use std::time::SystemTime;
+use tokio::fs;
+
+/// Where the feed is served, unless the share has a file of that name
+pub const ROUTE: &str = "/feed.xml";
+
+/// Most recent files listed in the feed
+const MAX_ITEMS: usize = 50;
+
+/// Registers `GET /feed.xml`.
+pub fn routes(router: &mut Router) {
+    router.get(ROUTE, async |req, cx| {
+        get(req.headers(), req.uri().query(), cx.client, &cx.state).await
+    });
+}
+
+/// An RSS 2.0 feed of the most recently modified files, newest first, each with an
+/// enclosure so feed readers and podcast-style clients can fetch it directly.
+///
+/// A file's item id includes its modification time, so a nightly build re-uploaded
+/// under the same name shows up as a new item. Files a `[[policy]]` keeps the
+/// requester from downloading are left out.
+pub async fn get(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    client: String,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let role = policy::requester(state, headers, query);
+    let policies = state.policies();
+    // A feed.xml the user shared takes precedence
+    if fs::try_exists(state.root.join(&ROUTE[1..])).await? {
+        if let Some(response) = policies.challenge(Action::Download, &ROUTE[1..], role) {
+            return Ok(response);
+        }
+        return crate::get_file(headers, ROUTE, None, client, state).await;
+    }
+
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(&state.root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let metadata = entry.metadata().await?;
+        if name == META_DIR
+            || !metadata.is_file()
+            || !policies.allows(Action::Download, &name, role)
+        {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let len = state.packed.size(&name, &metadata).await;
+        files.push((name, len.unwrap_or(metadata.len()), modified));
+    }
+    files.sort_by_key(|&(_, _, modified)| std::cmp::Reverse(modified));
+    files.truncate(MAX_ITEMS);
+
+    let base = crate::base_url(headers, state);
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n",
+    );
+    xml.push_str("  <title>holodeck: new files</title>\n");
+    xml.push_str(&format!("  <link>{}/</link>\n", escape(&base)));
+    xml.push_str("  <description>Files recently added to this share</description>\n");
+    if let Some((_, _, newest)) = files.first() {
+        xml.push_str(&format!(
+            "  <lastBuildDate>{}</lastBuildDate>\n",
+            httpdate::fmt_http_date(*newest)
+        ));
+    }
+    for (name, len, modified) in &files {
+        let url = format!("{}/{}", base, paths::percent_encode(name));
+        let secs = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        xml.push_str("  <item>\n");
+        xml.push_str(&format!("    <title>{}</title>\n", escape(name)));
+        xml.push_str(&format!("    <link>{}</link>\n", escape(&url)));
+        xml.push_str(&format!("    <description>{} bytes</description>\n", len));
+        xml.push_str(&format!(
+            "    <pubDate>{}</pubDate>\n",
+            httpdate::fmt_http_date(*modified)
+        ));
+        xml.push_str(&format!(
+            "    <guid isPermaLink=\"false\">{}@{}</guid>\n",
+            escape(name),
+            secs
+        ));
+        xml.push_str(&format!(
+            "    <enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+            escape(&url),
+            len,
+            escape(&state.mime_types().content_type(name))
+        ));
+        xml.push_str("  </item>\n");
+    }
+    xml.push_str("</channel>\n</rss>\n");
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/rss+xml; charset=utf-8")
+        .body(full(xml))
+        .unwrap())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,314 @@
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::storage::{self, StagedFile};
+use crate::{AppState, BoxBody, full, paths};
+use http_body_util::{BodyExt, Limited};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::io;
+use std::path::Path;
+use tokio::fs;
+
+/// Route renaming or moving a file within the share
+pub const RENAME: &str = "/_rename";
+
+/// Route copying a file within the share
+pub const COPY: &str = "/_copy";
+
+/// Route prefix creating a folder, as in `/_mkdir/reports/2024`
+const MKDIR: &str = "/_mkdir/";
+
+/// Largest request body accepted; two paths fit easily
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// `{"from": "...", "to": "..."}`, both relative to the share and possibly nested
+#[derive(Deserialize)]
+struct PathsRequest {
+    from: String,
+    to: String,
+}
+
+/// Renames a file or moves it to another folder of the share, creating the folder
+/// if needed. An existing file at the destination is never replaced.
+pub async fn rename(req: Request<BoxBody>, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let role = policy::requester(state, req.headers(), req.uri().query());
+    let (from, to) = match read_request(req).await {
+        Ok(paths) => paths,
+        Err((status, reason)) => return Ok(text(status, reason)),
+    };
+    let policies = state.policies();
+    if let Some(response) = policies
+        .challenge(Action::Upload, &from, role)
+        .or_else(|| policies.challenge(Action::Upload, &to, role))
+    {
+        return Ok(response);
+    }
+    let Some(_from_lock) = state.writes.try_lock(&from) else {
+        return Ok(crate::write_in_progress(&from));
+    };
+    let Some(_to_lock) = state.writes.try_lock(&to) else {
+        return Ok(crate::write_in_progress(&to));
+    };
+
+    let source = state.root.join(&from);
+    let is_file = parents_are_dirs(&state.root, &from).await?
+        && fs::symlink_metadata(&source)
+            .await
+            .is_ok_and(|metadata| metadata.is_file());
+    if !is_file {
+        return Ok(text(
+            StatusCode::NOT_FOUND,
+            format!("File '{}' not found", from),
+        ));
+    }
+    let dest = state.root.join(&to);
+    if !parents_are_dirs(&state.root, &to).await? || fs::symlink_metadata(&dest).await.is_ok() {
+        return Ok(text(
+            StatusCode::CONFLICT,
+            format!("'{}' already exists", to),
+        ));
+    }
+
+    if let Err(e) = move_into_place(&source, &dest).await {
+        eprintln!("RENAME: Error moving '{}' to '{}': {}", from, to, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error renaming file: {}", e),
+        ));
+    }
+
+    if let Err(e) = state.user_meta.rename(&from, &to).await {
+        eprintln!("RENAME: Error moving the metadata of '{}': {}", from, e);
+    }
+    if let Err(e) = state.packed.rename(&from, &to).await {
+        eprintln!(
+            "RENAME: Error moving the compression record of '{}': {}",
+            from, e
+        );
+    }
+    if let Err(e) = state.tags.rename(&from, &to).await {
+        eprintln!("RENAME: Error moving the tags of '{}': {}", from, e);
+    }
+    state
+        .transfers
+        .println(format!("RENAME: Moved '{}' to '{}'", from, to));
+    Ok(text(
+        StatusCode::OK,
+        format!("Renamed '{}' to '{}'\n", from, to),
+    ))
+}
+
+/// Copies a file to a new name or folder of the share without it passing through the
+/// client. An existing file at the destination is never replaced.
+///
+/// The copy is made with `std::fs::copy`, which clones the data where the filesystem
+/// allows (`copy_file_range` on Linux, `clonefile` on macOS) instead of reading it.
+pub async fn copy(req: Request<BoxBody>, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let role = policy::requester(state, req.headers(), req.uri().query());
+    let (from, to) = match read_request(req).await {
+        Ok(paths) => paths,
+        Err((status, reason)) => return Ok(text(status, reason)),
+    };
+    let policies = state.policies();
+    if let Some(response) = policies
+        .challenge(Action::Download, &from, role)
+        .or_else(|| policies.challenge(Action::Upload, &to, role))
+    {
+        return Ok(response);
+    }
+    let Some(_to_lock) = state.writes.try_lock(&to) else {
+        return Ok(crate::write_in_progress(&to));
+    };
+
+    let source = state.root.join(&from);
+    // A file stored compressed is copied as it is, and listed as compressed too
+    let (len, packed) = match fs::symlink_metadata(&source).await {
+        Ok(metadata) if metadata.is_file() && parents_are_dirs(&state.root, &from).await? => {
+            (metadata.len(), state.packed.size(&from, &metadata).await)
+        }
+        _ => {
+            return Ok(text(
+                StatusCode::NOT_FOUND,
+                format!("File '{}' not found", from),
+            ));
+        }
+    };
+    let dest = state.root.join(&to);
+    if !parents_are_dirs(&state.root, &to).await? || fs::symlink_metadata(&dest).await.is_ok() {
+        return Ok(text(
+            StatusCode::CONFLICT,
+            format!("'{}' already exists", to),
+        ));
+    }
+    if !storage::has_room_for(&state.staging_dir, &dest, len).await {
+        return Ok(text(
+            StatusCode::INSUFFICIENT_STORAGE,
+            format!("Not enough disk space to copy '{}' ({} bytes)", from, len),
+        ));
+    }
+
+    // Staged like an upload, so `to` appears complete or not at all
+    let result = async {
+        let name = to.rsplit('/').next().unwrap_or(&to);
+        let (staged, file) = StagedFile::create(&state.staging_dir, name).await?;
+        drop(file);
+        fs::copy(&source, staged.path()).await?;
+        if state.fsync {
+            fs::File::open(staged.path()).await?.sync_all().await?;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        staged.persist(&dest).await?;
+        match packed {
+            Some(size) => state.packed.record(&state.root, &to, size).await,
+            None => Ok(()),
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        if storage::is_disk_full(&e) {
+            state.disk_full.alarm("COPY", &to, &state.staging_dir);
+            return Ok(text(
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("Not enough disk space to copy '{}'", from),
+            ));
+        }
+        eprintln!("COPY: Error copying '{}' to '{}': {}", from, to, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error copying file: {}", e),
+        ));
+    }
+
+    let len = packed.unwrap_or(len);
+    state.transfers.println(format!(
+        "COPY: Copied '{}' to '{}' ({} bytes)",
+        from, to, len
+    ));
+    Ok(text(
+        StatusCode::CREATED,
+        format!("Copied '{}' to '{}' ({} bytes)\n", from, to, len),
+    ))
+}
+
+/// Registers the rename, copy and `/_mkdir/<folder>` routes.
+pub fn routes(router: &mut Router) {
+    router.post(RENAME, async |req, cx| rename(req, &cx.state).await);
+    router.post(COPY, async |req, cx| copy(req, &cx.state).await);
+    router
+        .post("/_mkdir/{*folder}", async |_, cx| {
+            mkdir(&cx.path, &cx.state).await
+        })
+        .scoped("folder");
+}
+
+/// Creates a folder and any missing parents, answering `201 Created`, or `200 OK`
+/// when it already exists.
+pub async fn mkdir(path: &str, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let requested = path.strip_prefix(MKDIR).unwrap_or_default();
+    let relative = match paths::validate_path(requested) {
+        Ok(relative) if relative.is_empty() => {
+            return Ok(text(
+                StatusCode::BAD_REQUEST,
+                "Folder name required in path",
+            ));
+        }
+        Ok(relative) => relative,
+        Err(e) => return Ok(text(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let dir = state.root.join(&relative);
+    if !parents_are_dirs(&state.root, &relative).await? {
+        return Ok(text(
+            StatusCode::CONFLICT,
+            format!("A parent of '{}' is not a folder", relative),
+        ));
+    }
+    match fs::symlink_metadata(&dir).await {
+        Ok(metadata) if metadata.is_dir() => {
+            return Ok(text(
+                StatusCode::OK,
+                format!("Folder '{}' already exists\n", relative),
+            ));
+        }
+        Ok(_) => {
+            return Ok(text(
+                StatusCode::CONFLICT,
+                format!("'{}' already exists", relative),
+            ));
+        }
+        Err(_) => {}
+    }
+    if let Err(e) = fs::create_dir_all(&dir).await {
+        eprintln!("MKDIR: Error creating folder '{}': {}", relative, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error creating folder: {}", e),
+        ));
+    }
+
+    state
+        .transfers
+        .println(format!("MKDIR: Created folder '{}'", relative));
+    Ok(text(
+        StatusCode::CREATED,
+        format!("Folder '{}' created\n", relative),
+    ))
+}
+
+async fn move_into_place(source: &Path, dest: &Path) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    storage::move_file(source, dest).await
+}
+
+/// Parses and checks the `from`/`to` paths, or gives the status and reason they can't be used.
+async fn read_request(req: Request<BoxBody>) -> Result<(String, String), (StatusCode, String)> {
+    let body = match Limited::new(req.into_body(), MAX_REQUEST_LEN)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request too large".to_string(),
+            ));
+        }
+    };
+    let request: PathsRequest = serde_json::from_slice(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)))?;
+
+    let check = |path: &str| match paths::validate_path(path) {
+        Ok(path) if path.is_empty() => Err((
+            StatusCode::BAD_REQUEST,
+            paths::InvalidName::Empty.to_string(),
+        )),
+        Ok(path) => Ok(path),
+        Err(e) => Err((StatusCode::BAD_REQUEST, format!("{}: '{}'", e, path))),
+    };
+    Ok((check(&request.from)?, check(&request.to)?))
+}
+
+/// Whether every existing folder on the way to `relative` is a real directory, not a
+/// file or a symlink that could lead out of the share. Missing folders are fine.
+async fn parents_are_dirs(root: &Path, relative: &str) -> io::Result<bool> {
+    let segments: Vec<&str> = relative.split('/').collect();
+    let mut dir = root.to_path_buf();
+    for segment in &segments[..segments.len() - 1] {
+        dir.push(segment);
+        match fs::symlink_metadata(&dir).await {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
@@ -0,0 +1,276 @@
+use crate::limits::Meter;
+use crate::packing::{Contents, Opened};
+use crate::progress::TransferProgress;
+use crate::router::Router;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+/// How often a followed file is checked for new bytes once the end is reached
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How a followed file is sent, chosen with `?follow` or `?follow=sse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// The raw bytes in a chunked body, for `curl --no-buffer`
+    Raw,
+    /// One server-sent event per line, for `EventSource` in a browser
+    Sse,
+}
+
+impl Mode {
+    fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let Some(value) = query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| match pair.split_once('=') {
+                Some(("follow", value)) => Some(value),
+                None if pair == "follow" => Some(""),
+                _ => None,
+            })
+        else {
+            return Ok(None);
+        };
+        match value {
+            "" | "raw" => Ok(Some(Self::Raw)),
+            "sse" => Ok(Some(Self::Sse)),
+            other => Err(format!("Unknown follow mode '{}': use raw or sse", other)),
+        }
+    }
+}
+
+/// Registers `GET /<file>?follow`.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/{*path}", async |req, cx| {
+            get(
+                req.headers(),
+                &cx.path,
+                req.uri().query(),
+                cx.client,
+                cx.state.clone(),
+            )
+            .await
+        })
+        .when(|req, _| is_follow(req.uri().query()))
+        .scoped("path");
+}
+
+/// Whether the query asks to follow the file as it grows.
+fn is_follow(query: Option<&str>) -> bool {
+    Mode::from_query(query) != Ok(None)
+}
+
+/// A file being followed, threaded through the response body stream.
+struct Follow {
+    file: Contents,
+    state: Arc<AppState>,
+    filename: String,
+    /// Bytes read from the current file so far
+    position: u64,
+    /// Size and modification time of the current file when it is stored compressed,
+    /// which doesn't grow but is replaced once something appends to it
+    packed: Option<(u64, Option<SystemTime>)>,
+    mode: Mode,
+    /// The end of the last line read, held back in SSE mode until it is complete
+    partial: Vec<u8>,
+    progress: TransferProgress,
+    /// The read link's meter, charged as bytes are sent
+    meter: Option<Arc<Meter>>,
+    chunk_size: usize,
+}
+
+/// Answers `GET /<file>?follow` like `tail -f`: the file's contents, then whatever is
+/// appended to it for as long as the client stays connected.
+///
+/// A file that shrinks (truncated or rotated) is followed again from its start, and
+/// one that is removed ends the response.
+pub async fn get(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    client: String,
+    state: Arc<AppState>,
+) -> io::Result<Response<BoxBody>> {
+    let mode = match Mode::from_query(query) {
+        Ok(Some(mode)) => mode,
+        Ok(None) => return Ok(crate::not_found()),
+        Err(e) => return Ok(text(StatusCode::BAD_REQUEST, e)),
+    };
+    let filename = path.trim_start_matches('/');
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    let opened = match state.packed.open(&state.root, filename).await {
+        Ok(opened) if filename != META_DIR && opened.metadata.is_file() => opened,
+        _ => return Ok(crate::file_not_found(filename)),
+    };
+
+    let progress = state.transfers.start("GET", filename, None);
+    progress.set_client(&client);
+    let _ = crate::CLOSE_CONNECTION.try_with(|close| progress.close_on_cancel(close.clone()));
+    state
+        .transfers
+        .println(format!("GET: Following '{}' for {}", filename, client));
+    let content_type = match mode {
+        Mode::Raw => state.mime_types().content_type(filename),
+        Mode::Sse => "text/event-stream".to_string(),
+    };
+    let follow = Follow {
+        packed: stamp(&opened),
+        file: opened.contents,
+        filename: filename.to_string(),
+        position: 0,
+        mode,
+        partial: Vec::new(),
+        progress,
+        meter: state
+            .share_tokens
+            .as_ref()
+            .and_then(|tokens| tokens.meter(headers, query))
+            .cloned(),
+        chunk_size: state.io_buffer,
+        state,
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, "no-cache")
+        // Asks reverse proxies in front of the tunnel not to hold chunks back
+        .header("X-Accel-Buffering", "no")
+        .body(stream(follow))
+        .unwrap())
+}
+
+fn stream(follow: Follow) -> BoxBody {
+    let stream = futures_util::stream::try_unfold(follow, |mut follow| async move {
+        loop {
+            if follow.progress.is_cancelled() {
+                return Err(io::Error::other("transfer cancelled by the server"));
+            }
+            let mut buf = BytesMut::with_capacity(follow.chunk_size);
+            if follow.file.read_buf(&mut buf).await? == 0 {
+                if !follow.wait_for_more().await? {
+                    follow.progress.println(format!(
+                        "GET: '{}' was removed, stopped following it after {} bytes",
+                        follow.filename,
+                        follow.progress.bytes()
+                    ));
+                    follow.progress.finish();
+                    return Ok(None);
+                }
+                continue;
+            }
+            follow.position += buf.len() as u64;
+            if let Some(meter) = &follow.meter {
+                let Ok(mut reservation) = meter.reserve(buf.len() as u64) else {
+                    return Err(io::Error::other("download quota used up"));
+                };
+                reservation.send(buf.len() as u64).await;
+            }
+            follow.progress.inc(buf.len() as u64);
+            let data = match follow.mode {
+                Mode::Raw => buf.freeze(),
+                Mode::Sse => match events(&mut follow.partial, &buf) {
+                    Some(events) => events,
+                    None => continue,
+                },
+            };
+            return Ok(Some((Frame::data(data), follow)));
+        }
+    });
+    StreamBody::new(stream).boxed()
+}
+
+impl Follow {
+    /// Waits at the end of the file until it grows, reopening it from the start if it
+    /// got shorter; `false` once it no longer exists.
+    async fn wait_for_more(&mut self) -> io::Result<bool> {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let state = &self.state;
+        let Ok(metadata) = fs::symlink_metadata(state.root.join(&self.filename)).await else {
+            return Ok(false);
+        };
+        let size = state.packed.size(&self.filename, &metadata).await;
+        let replaced = self
+            .packed
+            .is_some_and(|packed| packed != (metadata.len(), metadata.modified().ok()));
+        if size.unwrap_or(metadata.len()) < self.position || replaced {
+            let opened = state.packed.open(&state.root, &self.filename).await?;
+            self.packed = stamp(&opened);
+            self.file = opened.contents;
+            // A compressed file decompressed to be appended to picks up where it was
+            match opened.size < self.position {
+                true => {
+                    self.position = 0;
+                    self.partial.clear();
+                }
+                false => self.file.skip(self.position).await?,
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The size and modification time of `opened` if it is stored compressed.
+fn stamp(opened: &Opened) -> Option<(u64, Option<SystemTime>)> {
+    let metadata = &opened.metadata;
+    opened
+        .contents
+        .is_packed()
+        .then(|| (metadata.len(), metadata.modified().ok()))
+}
+
+/// Turns the complete lines in `partial` plus `data` into `data:` events, keeping an
+/// unfinished last line in `partial`; `None` until a line is complete.
+fn events(partial: &mut Vec<u8>, data: &[u8]) -> Option<Bytes> {
+    partial.extend_from_slice(data);
+    let end = partial.iter().rposition(|&b| b == b'\n')? + 1;
+    let lines: Vec<u8> = partial.drain(..end).collect();
+    let mut events = String::new();
+    for line in String::from_utf8_lossy(&lines).lines() {
+        events.push_str("data: ");
+        events.push_str(line);
+        events.push_str("\n\n");
+    }
+    Some(Bytes::from(events))
+}
+
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_parameter_picks_the_mode() {
+        assert_eq!(Mode::from_query(None), Ok(None));
+        assert_eq!(Mode::from_query(Some("follow")), Ok(Some(Mode::Raw)));
+        assert_eq!(
+            Mode::from_query(Some("token=abc&follow=sse")),
+            Ok(Some(Mode::Sse))
+        );
+        assert!(Mode::from_query(Some("follow=xml")).is_err());
+    }
+
+    #[test]
+    fn sse_events_wait_for_whole_lines() {
+        let mut partial = Vec::new();
+        assert_eq!(events(&mut partial, b"build sta"), None);
+        assert_eq!(
+            events(&mut partial, b"rted\r\nstep 1\nstep").unwrap(),
+            "data: build started\n\ndata: step 1\n\n"
+        );
+        assert_eq!(partial, b"step");
+    }
+}
@@ -0,0 +1,43 @@
+use crate::announce::AnnounceTarget;
+use crate::cli::{ForwardArgs, OutputMode};
+use crate::config::TunnelConfig;
+use crate::events::Events;
+use crate::tunnel;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+/// Runs `holodeck forward`: exposes an arbitrary local TCP port through the
+/// configured tunnel instead of serving files.
+pub async fn run(
+    args: ForwardArgs,
+    config: &TunnelConfig,
+    output: OutputMode,
+    announce: Option<AnnounceTarget>,
+) -> anyhow::Result<()> {
+    let Some(provider) = tunnel::from_config(config)? else {
+        anyhow::bail!(
+            "No tunnel configured: set SSH_SERVER or [tunnel] providers in the config file"
+        );
+    };
+
+    let local_addr = format!("127.0.0.1:{}", args.port);
+    if let Err(e) = TcpStream::connect(&local_addr).await {
+        eprintln!(
+            "Warning: nothing is accepting connections on {} yet ({})",
+            local_addr, e
+        );
+    }
+    match output {
+        OutputMode::Normal => println!("Forwarding local port {} through the tunnel", args.port),
+        OutputMode::Porcelain => println!("LOCAL=tcp://{}", local_addr),
+        OutputMode::Quiet => {}
+    }
+
+    let (url_tx, url_rx) = watch::channel(None);
+    let tunnel = tunnel::spawn_tunnel(provider, args.port, output, url_tx, Events::new(), None);
+    if let Some(target) = announce {
+        crate::announce_when_ready(target, local_addr, None, url_rx, true);
+    }
+    tunnel.await?;
+    Ok(())
+}
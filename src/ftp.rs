@@ -0,0 +1,787 @@
+use crate::access::Role;
+use crate::events::Event;
+use crate::intrusion::Violation;
+use crate::policy::Action;
+use crate::storage::{self, StagedFile};
+use crate::{AppState, META_DIR, executables, listing, paths};
+use hyper::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::net::IpAddr;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "https")]
+use tokio_rustls::TlsAcceptor;
+
+/// Longest command line accepted
+const MAX_LINE: u64 = 4096;
+
+/// How long a client has to open the data connection it asked for
+const DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listings show the year instead of the time for files older than this, like `ls -l`
+const RECENT: Duration = Duration::from_secs(180 * 24 * 60 * 60);
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// The control connection, plain or upgraded to TLS by `AUTH TLS`
+type Control = BufReader<Box<dyn Stream>>;
+
+/// What a logged-in FTP client may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// Serves the share over passive-mode FTP for `--ftp`, for scanners, CNC machines
+/// and instruments that can only push files that way.
+///
+/// Only the top level of the share is visible, as over HTTP. The password is the
+/// passcode with `--passcode`, a share token with `--share-links` (the admin token
+/// allowing uploads, as FTP can't delete), and anything otherwise. Wrong passcodes
+/// count towards the same lockout as over HTTP. With a certificate
+/// (`https` feature), clients can switch to TLS with `AUTH TLS` and protect data
+/// connections with `PROT P`.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    #[cfg(feature = "https")] tls: Option<TlsAcceptor>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("FTP: Error accepting a connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        #[cfg(feature = "https")]
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let session = Session {
+                state: &state,
+                client: peer.ip().to_string(),
+                peer: peer.ip(),
+                access: None,
+                role: None,
+                user: None,
+                passive: None,
+                #[cfg(feature = "https")]
+                tls,
+                #[cfg(feature = "https")]
+                protect: false,
+            };
+            if let Err(e) = session.run(stream).await {
+                eprintln!("FTP: Error serving {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+struct Session<'a> {
+    state: &'a AppState,
+    client: String,
+    peer: IpAddr,
+    access: Option<Access>,
+    /// The share token's role with `--share-links`, for `[[policy]]` rules
+    role: Option<Role>,
+    /// Name given with `USER`, until `PASS` follows
+    user: Option<String>,
+    /// Listener opened by `PASV`/`EPSV` for the next data connection
+    passive: Option<TcpListener>,
+    #[cfg(feature = "https")]
+    tls: Option<TlsAcceptor>,
+    /// Set by `PROT P`: data connections are wrapped in TLS too
+    #[cfg(feature = "https")]
+    protect: bool,
+}
+
+impl Session<'_> {
+    async fn run(mut self, stream: TcpStream) -> io::Result<()> {
+        let local = stream.local_addr()?.ip();
+        let mut control: Control = BufReader::new(Box::new(stream));
+        if self.state.intrusions.check(&self.client).is_some() {
+            return reply(
+                &mut control,
+                421,
+                "Too many suspicious requests from this address",
+            )
+            .await;
+        }
+        reply(&mut control, 220, "holodeck ready").await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if (&mut control).take(MAX_LINE).read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let (command, arg) = parse_command(&line);
+            match command.as_str() {
+                "QUIT" => return reply(&mut control, 221, "Bye").await,
+                "PASS" => {
+                    if self.login(arg, &mut control).await?.is_break() {
+                        return Ok(());
+                    }
+                }
+                #[cfg(feature = "https")]
+                "AUTH"
+                    if self.tls.is_some()
+                        && matches!(arg.to_ascii_uppercase().as_str(), "TLS" | "SSL") =>
+                {
+                    reply(&mut control, 234, "Switching to TLS").await?;
+                    let acceptor = self.tls.clone().expect("checked above");
+                    // The client waits for the reply, so nothing is left in the buffer
+                    let stream = acceptor.accept(control.into_inner()).await?;
+                    control = BufReader::new(Box::new(stream));
+                }
+                _ => self.command(&command, arg, local, &mut control).await?,
+            }
+        }
+    }
+
+    async fn command(
+        &mut self,
+        command: &str,
+        arg: &str,
+        local: IpAddr,
+        control: &mut Control,
+    ) -> io::Result<()> {
+        match command {
+            "USER" => {
+                self.user = Some(arg.to_string());
+                self.access = None;
+                return reply(control, 331, "Password required").await;
+            }
+            "SYST" => return reply(control, 215, "UNIX Type: L8").await,
+            "NOOP" => return reply(control, 200, "OK").await,
+            "FEAT" => {
+                let mut lines =
+                    String::from("211-Features:\r\n SIZE\r\n MDTM\r\n PASV\r\n EPSV\r\n UTF8\r\n");
+                if self.offers_tls() {
+                    lines.push_str(" AUTH TLS\r\n PBSZ\r\n PROT\r\n");
+                }
+                lines.push_str("211 End\r\n");
+                control.write_all(lines.as_bytes()).await?;
+                return control.flush().await;
+            }
+            "OPTS" if arg.eq_ignore_ascii_case("UTF8 ON") => {
+                return reply(control, 200, "Always in UTF-8 mode").await;
+            }
+            #[cfg(feature = "https")]
+            "PBSZ" if self.tls.is_some() => return reply(control, 200, "PBSZ=0").await,
+            #[cfg(feature = "https")]
+            "PROT" if self.tls.is_some() => {
+                return match arg.to_ascii_uppercase().as_str() {
+                    "P" => {
+                        self.protect = true;
+                        reply(control, 200, "Data connections protected").await
+                    }
+                    "C" => {
+                        self.protect = false;
+                        reply(control, 200, "Data connections in the clear").await
+                    }
+                    _ => reply(control, 504, "Only PROT C and P are supported").await,
+                };
+            }
+            _ => {}
+        }
+        let Some(access) = self.access else {
+            return reply(control, 530, "Please log in with USER and PASS").await;
+        };
+        match command {
+            "PWD" | "XPWD" => reply(control, 257, "\"/\" is the current directory").await,
+            "CWD" | "XCWD" if matches!(arg, "/" | "" | ".") => {
+                reply(control, 250, "Directory unchanged").await
+            }
+            "CWD" | "XCWD" => {
+                reply(control, 550, "Only the top level of the share is served").await
+            }
+            "CDUP" | "XCUP" => reply(control, 250, "Directory unchanged").await,
+            "TYPE" => reply(control, 200, "Type set").await,
+            "MODE" if arg.eq_ignore_ascii_case("S") => reply(control, 200, "Mode set").await,
+            "STRU" if arg.eq_ignore_ascii_case("F") => reply(control, 200, "Structure set").await,
+            "PASV" => {
+                let IpAddr::V4(ip) = local else {
+                    return reply(control, 522, "Use EPSV over IPv6").await;
+                };
+                let port = self.open_passive(local).await?;
+                let [a, b, c, d] = ip.octets();
+                let text = format!(
+                    "Entering Passive Mode ({},{},{},{},{},{})",
+                    a,
+                    b,
+                    c,
+                    d,
+                    port >> 8,
+                    port & 0xff
+                );
+                reply(control, 227, &text).await
+            }
+            "EPSV" => {
+                let port = self.open_passive(local).await?;
+                let text = format!("Entering Extended Passive Mode (|||{}|)", port);
+                reply(control, 229, &text).await
+            }
+            "PORT" | "EPRT" => reply(control, 502, "Only passive mode is supported").await,
+            "LIST" | "NLST" => self.list(command == "NLST", control).await,
+            "SIZE" | "MDTM" => {
+                let Some(name) = file_name(arg) else {
+                    return reply(control, 550, "Invalid file name").await;
+                };
+                if let Some(reason) = self.refusal(Action::Download, name) {
+                    return reply(control, 550, &reason).await;
+                }
+                match tokio::fs::symlink_metadata(self.state.root.join(name)).await {
+                    Ok(metadata) if metadata.is_file() && command == "SIZE" => {
+                        let packed = self.state.packed.size(name, &metadata).await;
+                        let size = packed.unwrap_or(metadata.len());
+                        reply(control, 213, &size.to_string()).await
+                    }
+                    Ok(metadata) if metadata.is_file() => {
+                        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        let digits: String = listing::rfc3339(modified)
+                            .chars()
+                            .filter(char::is_ascii_digit)
+                            .collect();
+                        reply(control, 213, &digits).await
+                    }
+                    _ => reply(control, 550, "File not found").await,
+                }
+            }
+            "RETR" => self.retrieve(arg, access, control).await,
+            "STOR" => self.store(arg, access, control).await,
+            _ => reply(control, 502, "Command not implemented").await,
+        }
+    }
+
+    /// Why the login may not take `action` on `name`, under the `[[policy]]` rules.
+    fn refusal(&self, action: Action, name: &str) -> Option<String> {
+        self.state.policies().refusal(action, name, self.role)
+    }
+
+    fn offers_tls(&self) -> bool {
+        #[cfg(feature = "https")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "https"))]
+        false
+    }
+
+    /// Handles `PASS`, breaking once the client has sent too many wrong passcodes so
+    /// the connection is closed.
+    async fn login(
+        &mut self,
+        password: &str,
+        control: &mut Control,
+    ) -> io::Result<ControlFlow<()>> {
+        if self.user.is_none() {
+            reply(control, 503, "Send USER first").await?;
+            return Ok(ControlFlow::Continue(()));
+        }
+        let state = self.state;
+        self.role = state.share_tokens.as_ref().and_then(|t| t.role(password));
+        self.access = if let Some(passcode) = &state.passcode {
+            match passcode.verify(password, &self.client) {
+                Ok(()) => Some(Access::Write),
+                Err(StatusCode::TOO_MANY_REQUESTS) => {
+                    reply(control, 421, "Too many wrong passwords, try again later").await?;
+                    return Ok(ControlFlow::Break(()));
+                }
+                Err(_) => None,
+            }
+        } else if state.share_tokens.is_some() {
+            self.role.map(|role| match role {
+                Role::Read => Access::Read,
+                Role::Write | Role::Admin => Access::Write,
+            })
+        } else {
+            Some(Access::Write)
+        };
+        match self.access {
+            Some(Access::Write) => reply(control, 230, "Logged in").await?,
+            Some(Access::Read) => reply(control, 230, "Logged in, downloads only").await?,
+            None => {
+                let pass = Method::from_bytes(b"PASS").expect("valid method");
+                state
+                    .intrusions
+                    .record(Violation::AuthFailure, &self.client, &pass, "/");
+                reply(control, 530, "Login incorrect").await?
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn open_passive(&mut self, local: IpAddr) -> io::Result<u16> {
+        let listener = TcpListener::bind((local, 0)).await?;
+        let port = listener.local_addr()?.port();
+        self.passive = Some(listener);
+        Ok(port)
+    }
+
+    /// Accepts the data connection opened by `PASV`/`EPSV`, only from the client's own
+    /// address so nobody else can grab the transfer.
+    async fn data_connection(&mut self) -> io::Result<Box<dyn Stream>> {
+        let Some(listener) = self.passive.take() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "use PASV first",
+            ));
+        };
+        let accept = async {
+            loop {
+                let (stream, from) = listener.accept().await?;
+                if from.ip() == self.peer {
+                    return Ok::<_, io::Error>(stream);
+                }
+            }
+        };
+        let stream = tokio::time::timeout(DATA_TIMEOUT, accept)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no data connection"))??;
+        #[cfg(feature = "https")]
+        if self.protect
+            && let Some(tls) = &self.tls
+        {
+            return Ok(Box::new(tls.accept(stream).await?));
+        }
+        Ok(Box::new(stream))
+    }
+
+    async fn list(&mut self, names_only: bool, control: &mut Control) -> io::Result<()> {
+        let mut entries = tokio::fs::read_dir(&self.state.root).await?;
+        let mut lines = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            if name == META_DIR
+                || !metadata.is_file()
+                || self.refusal(Action::Download, &name).is_some()
+            {
+                continue;
+            }
+            let packed = self.state.packed.size(&name, &metadata).await;
+            let size = packed.unwrap_or(metadata.len());
+            lines.push(match names_only {
+                true => format!("{}\r\n", name),
+                false => list_line(&name, size, metadata.modified().ok()),
+            });
+        }
+        lines.sort();
+
+        let Ok(mut data) = self.data_connection().await else {
+            return reply(control, 425, "Can't open data connection").await;
+        };
+        reply(control, 150, "Here comes the listing").await?;
+        data.write_all(lines.concat().as_bytes()).await?;
+        data.shutdown().await?;
+        reply(control, 226, "Listing sent").await
+    }
+
+    async fn retrieve(
+        &mut self,
+        arg: &str,
+        access: Access,
+        control: &mut Control,
+    ) -> io::Result<()> {
+        let state = self.state;
+        let Some(name) = file_name(arg) else {
+            return reply(control, 550, "Invalid file name").await;
+        };
+        if let Some(reason) = self.refusal(Action::Download, name) {
+            return reply(control, 550, &reason).await;
+        }
+        let (mut file, len) = match state.packed.open(&state.root, name).await {
+            Ok(opened) if opened.metadata.is_file() => (opened.contents, opened.size),
+            _ => return reply(control, 550, "File not found").await,
+        };
+        // The read token's quota and speed cap apply however it's used
+        let mut quota = None;
+        if access == Access::Read
+            && let Some(meter) = state.share_tokens.as_ref().and_then(|t| t.read_meter())
+        {
+            match meter.reserve(len) {
+                Ok(reservation) => quota = Some(reservation),
+                Err(left) => {
+                    let text = format!("Download quota used up: {} bytes left", left);
+                    return reply(control, 550, &text).await;
+                }
+            }
+        }
+
+        let Ok(mut data) = self.data_connection().await else {
+            return reply(control, 425, "Can't open data connection").await;
+        };
+        reply(control, 150, "Sending file").await?;
+        let mut progress = state.transfers.start("FTP", name, Some(len));
+        progress.set_client(&self.client);
+        let mut buf = vec![0; state.io_buffer];
+        loop {
+            if progress.is_cancelled() {
+                return reply(control, 426, "Transfer cancelled by the server").await;
+            }
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            if let Some(quota) = &mut quota {
+                quota.send(read as u64).await;
+            }
+            if data.write_all(&buf[..read]).await.is_err() {
+                return reply(control, 426, "Connection closed; transfer aborted").await;
+            }
+            progress.inc(read as u64);
+        }
+        data.shutdown().await?;
+        progress.println(format!("FTP: Served file '{}' ({} bytes)", name, len));
+        state.events.emit(Event::DownloadCompleted {
+            file: name.to_string(),
+            bytes: progress.bytes(),
+            peer: self.client.clone(),
+        });
+        progress.finish();
+        reply(control, 226, "Transfer complete").await
+    }
+
+    async fn store(&mut self, arg: &str, access: Access, control: &mut Control) -> io::Result<()> {
+        let state = self.state;
+        if access != Access::Write {
+            return reply(control, 550, "This login only allows downloads").await;
+        }
+        if let Some(check) = http_only_checks(state) {
+            let text = format!("Uploads must go through HTTP ({})", check);
+            return reply(control, 550, &text).await;
+        }
+        let Some(name) = file_name(arg) else {
+            return reply(control, 553, "Invalid file name").await;
+        };
+        if let Some(reason) = self.refusal(Action::Upload, name) {
+            return reply(control, 550, &reason).await;
+        }
+        let Some(_lock) = state.writes.try_lock(name) else {
+            return reply(control, 450, "Another upload to this file is in progress").await;
+        };
+        let (staged, file) = match StagedFile::create(&state.staging_dir, name).await {
+            Ok(staged) => staged,
+            Err(e) => {
+                eprintln!("FTP: Error creating file '{}': {}", name, e);
+                return reply(control, 451, "Error creating file").await;
+            }
+        };
+        let mut file = tokio::io::BufWriter::with_capacity(state.io_buffer, file);
+
+        let Ok(mut data) = self.data_connection().await else {
+            return reply(control, 425, "Can't open data connection").await;
+        };
+        reply(control, 150, "Ready to receive").await?;
+        // Dropping `staged` on the way out deletes what arrived so far
+        let mut progress = state.transfers.start("FTP", name, None);
+        progress.set_client(&self.client);
+        let mut sniffer = state.block_executables.then(executables::Sniffer::default);
+        let mut hasher = state.verify_uploads.then(Sha256::new);
+        let mut buf = vec![0; state.io_buffer];
+        loop {
+            if progress.is_cancelled() {
+                return reply(control, 426, "Transfer cancelled by the server").await;
+            }
+            let read = match data.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(_) => return reply(control, 426, "Connection closed; transfer aborted").await,
+            };
+            if let Some(sniffer) = sniffer.as_mut()
+                && let Some(kind) = sniffer.inspect(&buf[..read])
+            {
+                eprintln!("FTP: Rejected '{}' from {}: {}", name, self.client, kind);
+                let text = format!("Upload rejected: looks like {}", kind);
+                return reply(control, 553, &text).await;
+            }
+            if let Err(e) = file.write_all(&buf[..read]).await {
+                if storage::is_disk_full(&e) {
+                    state.disk_full.alarm("FTP", name, &state.staging_dir);
+                    return reply(control, 452, "Insufficient storage space").await;
+                }
+                eprintln!("FTP: Error writing file '{}': {}", name, e);
+                return reply(control, 451, "Error writing file").await;
+            }
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buf[..read]);
+            }
+            progress.inc(read as u64);
+        }
+
+        let stored = async {
+            file.flush().await?;
+            if state.fsync {
+                file.get_ref().sync_all().await?;
+            }
+            drop(file);
+            if let Some(hasher) = hasher
+                && storage::sha256_file(staged.path()).await?[..] != hasher.finalize()[..]
+            {
+                return Err(io::Error::other("checksum mismatch after writing"));
+            }
+            staged.persist(&state.root.join(name)).await?;
+            if state.fsync {
+                storage::sync_dir(&state.root).await?;
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        if let Err(e) = stored.await {
+            if storage::is_disk_full(&e) {
+                state.disk_full.alarm("FTP", name, &state.staging_dir);
+                return reply(control, 452, "Insufficient storage space").await;
+            }
+            eprintln!("FTP: Error storing file '{}': {}", name, e);
+            return reply(control, 451, "Error storing file").await;
+        }
+        crate::pack(state, "FTP", name).await;
+        let size = progress.bytes();
+        progress.finish();
+        state
+            .transfers
+            .println(format!("FTP: Received file '{}' ({} bytes)", name, size));
+        state.events.emit(Event::UploadCompleted {
+            file: name.to_string(),
+            bytes: size,
+            peer: self.client.clone(),
+        });
+        reply(control, 226, "Transfer complete").await
+    }
+}
+
+/// Splits a command line into its upper-cased verb and argument.
+fn parse_command(line: &str) -> (String, &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    (command.to_ascii_uppercase(), arg.trim())
+}
+
+/// A top-level file of the share named by a command argument, with any leading `/`.
+fn file_name(arg: &str) -> Option<&str> {
+    let name = arg.trim_start_matches('/');
+    paths::validate_filename(name)
+        .ok()
+        .filter(|name| *name != META_DIR)
+}
+
+/// What uploads must pass over HTTP that FTP has no way to apply, if anything.
+#[cfg_attr(
+    not(any(feature = "signing", feature = "plugins", feature = "scripting")),
+    allow(unused_variables)
+)]
+fn http_only_checks(state: &AppState) -> Option<&'static str> {
+    #[cfg(feature = "signing")]
+    if state.trusted_keys.is_some() {
+        return Some("uploads must be signed");
+    }
+    #[cfg(feature = "plugins")]
+    if state.plugins.is_some() {
+        return Some("plugins vet uploads");
+    }
+    #[cfg(feature = "scripting")]
+    if state.script.is_some() {
+        return Some("a policy script vets uploads");
+    }
+    None
+}
+
+/// A `ls -l` style line, which is what FTP clients parse `LIST` output as.
+fn list_line(name: &str, len: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified.unwrap_or(SystemTime::UNIX_EPOCH);
+    let stamp = listing::rfc3339(modified);
+    let month = stamp[5..7]
+        .parse::<usize>()
+        .map_or("Jan", |m| MONTHS[m - 1]);
+    let recent = SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age < RECENT);
+    let time_or_year = match recent {
+        true => &stamp[11..16],
+        false => &stamp[..4],
+    };
+    format!(
+        "-rw-r--r-- 1 holodeck holodeck {:>12} {} {:>2} {:>5} {}\r\n",
+        len,
+        month,
+        stamp[8..10].trim_start_matches('0'),
+        time_or_year,
+        name
+    )
+}
+
+async fn reply(control: &mut Control, code: u16, text: &str) -> io::Result<()> {
+    control
+        .write_all(format!("{} {}\r\n", code, text).as_bytes())
+        .await?;
+    control.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// The data address a `227` reply announces.
+    fn pasv_addr(reply: &str) -> SocketAddr {
+        let numbers: Vec<u16> = reply
+            .split(['(', ')'])
+            .nth(1)
+            .unwrap()
+            .split(',')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let ip = IpAddr::from([
+            numbers[0] as u8,
+            numbers[1] as u8,
+            numbers[2] as u8,
+            numbers[3] as u8,
+        ]);
+        SocketAddr::new(ip, numbers[4] << 8 | numbers[5])
+    }
+
+    #[test]
+    fn parses_commands_and_names() {
+        assert_eq!(
+            parse_command("stor scan 01.pdf\r\n"),
+            ("STOR".to_string(), "scan 01.pdf")
+        );
+        assert_eq!(parse_command("PASV\r\n"), ("PASV".to_string(), ""));
+        assert_eq!(file_name("/scan.pdf"), Some("scan.pdf"));
+        assert_eq!(file_name("../etc/passwd"), None);
+        assert_eq!(file_name(META_DIR), None);
+    }
+
+    #[test]
+    fn lists_like_ls() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(
+            list_line("scan.pdf", 1234, Some(at)),
+            "-rw-r--r-- 1 holodeck holodeck         1234 Sep  9  2001 scan.pdf\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_and_retrieves_over_passive_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(dir.path().to_path_buf()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        #[cfg(feature = "https")]
+        tokio::spawn(serve(listener, state, None));
+        #[cfg(not(feature = "https"))]
+        tokio::spawn(serve(listener, state));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut control = BufReader::new(stream);
+        let mut line = String::new();
+        let mut send = async |control: &mut BufReader<TcpStream>, command: &str| {
+            if !command.is_empty() {
+                control
+                    .write_all(format!("{}\r\n", command).as_bytes())
+                    .await
+                    .unwrap();
+            }
+            line.clear();
+            control.read_line(&mut line).await.unwrap();
+            line.clone()
+        };
+        assert!(send(&mut control, "").await.starts_with("220"));
+        assert!(send(&mut control, "LIST").await.starts_with("530"));
+        assert!(send(&mut control, "USER scanner").await.starts_with("331"));
+        assert!(send(&mut control, "PASS anything").await.starts_with("230"));
+
+        let pasv = send(&mut control, "PASV").await;
+        let mut data = TcpStream::connect(pasv_addr(&pasv)).await.unwrap();
+        assert!(send(&mut control, "STOR scan.pdf").await.starts_with("150"));
+        data.write_all(b"%PDF-1.7 scanned page").await.unwrap();
+        drop(data);
+        assert!(send(&mut control, "").await.starts_with("226"));
+        assert_eq!(
+            std::fs::read(dir.path().join("scan.pdf")).unwrap(),
+            b"%PDF-1.7 scanned page"
+        );
+        assert_eq!(send(&mut control, "SIZE scan.pdf").await, "213 21\r\n");
+
+        let epsv = send(&mut control, "EPSV").await;
+        let port: u16 = epsv.split('|').nth(3).unwrap().parse().unwrap();
+        let mut data = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(send(&mut control, "RETR scan.pdf").await.starts_with("150"));
+        let mut received = Vec::new();
+        data.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"%PDF-1.7 scanned page");
+        assert!(send(&mut control, "").await.starts_with("226"));
+        assert!(
+            send(&mut control, "RETR ../secret")
+                .await
+                .starts_with("550")
+        );
+        assert!(send(&mut control, "QUIT").await.starts_with("221"));
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_lock_the_client_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = AppState::new(dir.path().to_path_buf()).unwrap();
+        state.passcode = Some(crate::passcode::Passcode::password("secret".to_string()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        #[cfg(feature = "https")]
+        tokio::spawn(serve(listener, Arc::new(state), None));
+        #[cfg(not(feature = "https"))]
+        tokio::spawn(serve(listener, Arc::new(state)));
+
+        let login = async |password: &str| {
+            let mut control = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            let mut replies = Vec::new();
+            for command in ["", "USER scanner", &format!("PASS {}", password)] {
+                if !command.is_empty() {
+                    let line = format!("{}\r\n", command);
+                    control.write_all(line.as_bytes()).await.unwrap();
+                }
+                let mut line = String::new();
+                control.read_line(&mut line).await.unwrap();
+                replies.push(line);
+            }
+            (control, replies.pop().unwrap())
+        };
+        let (_, reply) = login("secret").await;
+        assert!(reply.starts_with("230"), "{}", reply);
+        let (mut control, reply) = login("guess").await;
+        assert!(reply.starts_with("530"), "{}", reply);
+        let mut refused = None;
+        for attempt in 0..10 {
+            control
+                .write_all(format!("PASS guess{}\r\n", attempt).as_bytes())
+                .await
+                .unwrap();
+            let mut line = String::new();
+            control.read_line(&mut line).await.unwrap();
+            if !line.starts_with("530") {
+                refused = Some(line);
+                break;
+            }
+        }
+        assert!(refused.unwrap().starts_with("421"));
+        let mut rest = String::new();
+        assert_eq!(control.read_line(&mut rest).await.unwrap(), 0);
+
+        // The right password no longer helps, even on a new connection
+        let (_, reply) = login("secret").await;
+        assert!(reply.starts_with("421"), "{}", reply);
+    }
+}
@@ -0,0 +1,210 @@
+use hyper::HeaderMap;
+use hyper::header::{CACHE_CONTROL, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Static headers added to responses, configured as `[[header]]` in the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderConfig {
+    /// Request paths the headers apply to, with `*` matching anything (e.g. `/assets/*`);
+    /// every response when omitted
+    pub path: Option<String>,
+    /// Header name → value, replacing any value holodeck set itself
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    /// Caching policy for the matching paths: `immutable`, `no-store`, `no-cache` or
+    /// `max-age=<duration>` (e.g. `max-age=5m`), a shorthand for `Cache-Control`
+    pub cache: Option<String>,
+}
+
+/// The configured header rules, checked once at startup.
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    rules: Vec<(Option<Glob>, HeaderMap)>,
+}
+
+impl HeaderRules {
+    pub fn new(configs: &[HeaderConfig]) -> anyhow::Result<Self> {
+        let rules = configs
+            .iter()
+            .map(|config| {
+                let mut headers = HeaderMap::new();
+                for (name, value) in &config.set {
+                    let name = HeaderName::try_from(name.as_str()).map_err(|_| {
+                        anyhow::anyhow!("Invalid header name '{}' in [[header]]", name)
+                    })?;
+                    let value = HeaderValue::try_from(value.as_str()).map_err(|_| {
+                        anyhow::anyhow!("Invalid value for header '{}' in [[header]]", name)
+                    })?;
+                    headers.insert(name, value);
+                }
+                if let Some(preset) = &config.cache {
+                    headers.insert(CACHE_CONTROL, cache_control(preset)?);
+                }
+                Ok((config.path.clone().map(Glob), headers))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Adds the headers of every rule matching `path`, later rules winning.
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for (pattern, set) in &self.rules {
+            if pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(path))
+            {
+                for (name, value) in set {
+                    headers.insert(name, value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The `Cache-Control` value of a `cache` preset.
+fn cache_control(preset: &str) -> anyhow::Result<HeaderValue> {
+    let value = match preset.trim() {
+        // A year, the longest lifetime caches are expected to honour
+        "immutable" => "public, max-age=31536000, immutable".to_string(),
+        "no-store" => "no-store".to_string(),
+        "no-cache" => "no-cache".to_string(),
+        preset => {
+            let age = preset
+                .strip_prefix("max-age=")
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid cache preset '{}' in [[header]]: use immutable, no-store, no-cache or max-age=<duration>",
+                        preset
+                    )
+                })?;
+            let age = crate::cli::parse_interval(age).map_err(|e| {
+                anyhow::anyhow!("Invalid cache preset '{}' in [[header]]: {}", preset, e)
+            })?;
+            format!("public, max-age={}", age.as_secs())
+        }
+    };
+    Ok(HeaderValue::try_from(value).expect("preset is a valid header value"))
+}
+
+/// A path pattern where `*` matches any run of characters, `/` included.
+#[derive(Debug)]
+struct Glob(String);
+
+impl Glob {
+    /// Whether the whole of `path` matches.
+    fn is_match(&self, path: &str) -> bool {
+        let mut parts = self.0.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = path.strip_prefix(first) else {
+            return false;
+        };
+        let Some(last) = parts.next_back() else {
+            return rest.is_empty();
+        };
+        // Each literal between stars as early as possible leaves the most room for the
+        // ones after it
+        for part in parts {
+            match rest.find(part) {
+                Some(start) => rest = &rest[start + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> HeaderRules {
+        #[derive(Deserialize)]
+        struct Config {
+            header: Vec<HeaderConfig>,
+        }
+        let config: Config = toml::from_str(toml).unwrap();
+        HeaderRules::new(&config.header).unwrap()
+    }
+
+    #[test]
+    fn applies_matching_rules_in_order() {
+        let rules = rules(
+            r#"
+            [[header]]
+            set = { "X-Frame-Options" = "DENY", "Cache-Control" = "no-store" }
+
+            [[header]]
+            path = "/assets/*"
+            set = { "Cache-Control" = "public, max-age=31536000, immutable" }
+            "#,
+        );
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/assets/js/app.js", &mut headers);
+        assert_eq!(
+            headers["cache-control"],
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(headers["x-frame-options"], "DENY");
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/report.pdf", &mut headers);
+        assert_eq!(headers["cache-control"], "no-store");
+    }
+
+    #[test]
+    fn cache_presets_set_cache_control() {
+        let rules = rules(
+            r#"
+            [[header]]
+            path = "/releases/*"
+            cache = "immutable"
+
+            [[header]]
+            path = "/status/*"
+            cache = "max-age=5m"
+
+            [[header]]
+            path = "/status/live.json"
+            cache = "no-store"
+            "#,
+        );
+        let cache_control = |path| {
+            let mut headers = HeaderMap::new();
+            rules.apply(path, &mut headers);
+            headers.get(CACHE_CONTROL).cloned()
+        };
+        assert_eq!(
+            cache_control("/releases/app-1.0.zip").unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            cache_control("/status/build.log").unwrap(),
+            "public, max-age=300"
+        );
+        assert_eq!(cache_control("/status/live.json").unwrap(), "no-store");
+        assert_eq!(cache_control("/other.txt"), None);
+
+        let config = HeaderConfig {
+            path: None,
+            set: HashMap::new(),
+            cache: Some("forever".into()),
+        };
+        assert!(HeaderRules::new(&[config]).is_err());
+    }
+
+    #[test]
+    fn glob_is_anchored_and_literal() {
+        let glob = |pattern: &str| Glob(pattern.to_string());
+        let pattern = glob("/docs/*.html");
+        assert!(pattern.is_match("/docs/guide/intro.html"));
+        assert!(!pattern.is_match("/docs/intro.htmlx"));
+        assert!(!pattern.is_match("/other/docs/a.html"));
+        assert!(!glob("/a.b").is_match("/axb"));
+        assert!(glob("/a*b*b").is_match("/abb"));
+        assert!(!glob("/a*bb*b").is_match("/abb"));
+        assert!(glob("*").is_match(""));
+    }
+}
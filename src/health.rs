@@ -0,0 +1,26 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, full};
+use hyper::header::CACHE_CONTROL;
+use hyper::{Response, StatusCode};
+
+/// Route answering that the server is up, also fetched through the tunnel at startup
+/// to check it really leads here
+pub const ROUTE: &str = "/_health";
+
+/// Header carrying the random id of this process, so the tunnel self-test can tell
+/// this server from another one the tunnel service routes the URL to
+pub const INSTANCE_HEADER: &str = "x-holodeck-instance";
+
+/// Registers `GET /_health`, which needs no passcode or share token.
+pub fn routes(router: &mut Router) {
+    router.get(ROUTE, async |_, cx| Ok(get(&cx.state)));
+}
+
+fn get(state: &AppState) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-store")
+        .header(INSTANCE_HEADER, &state.instance)
+        .body(full("ok\n"))
+        .unwrap()
+}
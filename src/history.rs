@@ -0,0 +1,333 @@
+use crate::cli::HistoryArgs;
+use crate::listing::rfc3339;
+use crate::{BoxBody, META_DIR, full};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Transfers kept; older ones are dropped when the server starts
+const MAX_RECORDS: usize = 10_000;
+
+/// Records shown when no limit is given
+const DEFAULT_LIMIT: usize = 50;
+
+/// How a transfer ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Completed,
+    /// Stopped through `/_admin`
+    Cancelled,
+    /// The client went away, or the transfer failed midway
+    Interrupted,
+}
+
+/// A finished transfer, as kept in the history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// When the transfer ended, in seconds since the Unix epoch
+    pub ended: u64,
+    /// `GET` for downloads; `POST`, `PATCH`, `FTP` or `TFTP` as they are shown in flight
+    pub direction: String,
+    pub file: String,
+    pub peer: Option<String>,
+    pub bytes: u64,
+    /// The size expected when the transfer started, if known
+    pub total: Option<u64>,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+}
+
+impl Record {
+    /// `<ended> <direction> <file> <peer> <bytes>/<total> <seconds>s <outcome>`
+    fn line(&self) -> String {
+        let ended = UNIX_EPOCH + Duration::from_secs(self.ended);
+        let outcome = serde_json::to_value(self.outcome).unwrap_or_default();
+        format!(
+            "{} {} {} {} {}/{} {:.1}s {}\n",
+            rfc3339(ended),
+            self.direction,
+            self.file,
+            self.peer.as_deref().unwrap_or("-"),
+            self.bytes,
+            self.total
+                .map_or_else(|| "?".to_string(), |total| total.to_string()),
+            self.duration_ms as f64 / 1000.0,
+            outcome.as_str().unwrap_or_default()
+        )
+    }
+}
+
+/// Which records to show, newest `limit` of those matching.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only transfers of this file, by its path in the share
+    pub file: Option<String>,
+    /// Only transfers with this client address
+    pub peer: Option<String>,
+    /// Only transfers that ended this long ago at most
+    pub since: Option<Duration>,
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    /// Reads `file=`, `peer=`, `since=` (such as `2d`) and `limit=` from a query string.
+    pub fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for (key, value) in query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+        {
+            let value = crate::paths::query_decode(value);
+            match key {
+                "file" => filter.file = Some(value),
+                "peer" => filter.peer = Some(value),
+                "since" => filter.since = Some(crate::cli::parse_interval(&value)?),
+                "limit" => {
+                    let limit = value
+                        .parse()
+                        .map_err(|_| format!("invalid limit '{}'", value))?;
+                    filter.limit = Some(limit);
+                }
+                _ => {}
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, record: &Record, now: u64) -> bool {
+        self.file.as_ref().is_none_or(|file| *file == record.file)
+            && self
+                .peer
+                .as_ref()
+                .is_none_or(|peer| record.peer.as_ref() == Some(peer))
+            && self
+                .since
+                .is_none_or(|since| record.ended + since.as_secs() >= now)
+    }
+}
+
+/// Finished transfers, appended one JSON object per line to `history.jsonl` in the
+/// share's metadata directory, so whether a client really downloaded a file can be
+/// checked long after the progress display has forgotten it.
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// The history of the share at `root`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            path: root.join(META_DIR).join("history.jsonl"),
+        }
+    }
+
+    pub fn record(&self, record: &Record) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+
+    /// The newest records matching `filter`, oldest first. Lines that don't parse,
+    /// such as one cut short by a crash, are skipped.
+    pub fn read(&self, filter: &Filter) -> io::Result<Vec<Record>> {
+        let now = now();
+        let mut records: Vec<Record> = self
+            .records()?
+            .into_iter()
+            .filter(|record| filter.matches(record, now))
+            .collect();
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT);
+        records.drain(..records.len().saturating_sub(limit));
+        Ok(records)
+    }
+
+    /// Drops all but the newest [`MAX_RECORDS`], so the file doesn't grow forever.
+    pub fn trim(&self) -> io::Result<()> {
+        let records = self.records()?;
+        if records.len() <= MAX_RECORDS {
+            return Ok(());
+        }
+        let mut kept = Vec::new();
+        for record in &records[records.len() - MAX_RECORDS..] {
+            serde_json::to_writer(&mut kept, record)?;
+            kept.push(b'\n');
+        }
+        let temp = self.path.with_extension("jsonl.tmp");
+        std::fs::write(&temp, kept)?;
+        std::fs::rename(&temp, &self.path)
+    }
+
+    fn records(&self) -> io::Result<Vec<Record>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for line in io::BufReader::new(file).lines() {
+            if let Ok(record) = serde_json::from_str(&line?) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Runs `holodeck history`: prints the transfers recorded for the share in the current
+/// directory.
+pub fn run(args: HistoryArgs) -> anyhow::Result<()> {
+    let filter = Filter {
+        file: args.file,
+        peer: args.peer,
+        since: args.since,
+        limit: Some(args.limit),
+    };
+    let records = History::new(Path::new(".")).read(&filter)?;
+    if records.is_empty() && !args.json {
+        eprintln!(
+            "No transfers recorded (looked in ./{}/history.jsonl)",
+            META_DIR
+        );
+    }
+    for record in records {
+        match args.json {
+            true => println!("{}", serde_json::to_string(&record)?),
+            false => print!("{}", record.line()),
+        }
+    }
+    Ok(())
+}
+
+/// Answers `GET /_admin/history`, as text lines or, with `format=ndjson`, JSON ones.
+pub fn get(query: Option<&str>, history: &History) -> io::Result<Response<BoxBody>> {
+    let filter = match Filter::from_query(query) {
+        Ok(filter) => filter,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(e))
+                .unwrap());
+        }
+    };
+    let ndjson = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .any(|pair| pair == "format=ndjson");
+    let records = history.read(&filter)?;
+    let (content_type, body) = match ndjson {
+        true => {
+            let mut body = Vec::new();
+            for record in &records {
+                serde_json::to_writer(&mut body, record)?;
+                body.push(b'\n');
+            }
+            ("application/x-ndjson", body)
+        }
+        false => (
+            "text/plain; charset=utf-8",
+            records.iter().map(Record::line).collect::<String>().into(),
+        ),
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(full(body))
+        .unwrap())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ended: u64, file: &str, peer: &str, outcome: Outcome) -> Record {
+        Record {
+            ended,
+            direction: "GET".to_string(),
+            file: file.to_string(),
+            peer: Some(peer.to_string()),
+            bytes: 100,
+            total: Some(100),
+            duration_ms: 2500,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn records_are_filtered_by_file_peer_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(dir.path());
+        let now = now();
+        let old = record(
+            now - 3 * 86_400,
+            "deliverable.zip",
+            "203.0.113.7",
+            Outcome::Completed,
+        );
+        let recent = record(
+            now - 60,
+            "deliverable.zip",
+            "198.51.100.2",
+            Outcome::Interrupted,
+        );
+        let other = record(now - 30, "notes.txt", "203.0.113.7", Outcome::Completed);
+        for record in [&old, &recent, &other] {
+            history.record(record).unwrap();
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(META_DIR).join("history.jsonl"))
+            .unwrap()
+            .write_all(b"{\"ended\":")
+            .unwrap();
+
+        let filter = Filter::from_query(Some("file=deliverable.zip&since=1d")).unwrap();
+        assert_eq!(
+            history.read(&filter).unwrap(),
+            std::slice::from_ref(&recent)
+        );
+        let filter = Filter::from_query(Some("peer=203.0.113.7")).unwrap();
+        assert_eq!(history.read(&filter).unwrap(), [old, other.clone()]);
+        let filter = Filter::from_query(Some("limit=1")).unwrap();
+        assert_eq!(history.read(&filter).unwrap(), [other]);
+        assert!(Filter::from_query(Some("since=yesterday")).is_err());
+
+        assert!(
+            recent
+                .line()
+                .ends_with(" GET deliverable.zip 198.51.100.2 100/100 2.5s interrupted\n")
+        );
+    }
+
+    #[test]
+    fn trimming_keeps_the_newest_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(dir.path());
+        for ended in 0..MAX_RECORDS as u64 + 3 {
+            history
+                .record(&record(ended, "a.bin", "203.0.113.7", Outcome::Completed))
+                .unwrap();
+        }
+        history.trim().unwrap();
+        let records = history.records().unwrap();
+        assert_eq!(records.len(), MAX_RECORDS);
+        assert_eq!(records[0].ended, 3);
+    }
+}
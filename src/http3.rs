@@ -0,0 +1,91 @@
+use crate::{AppState, BoxBody};
+use bytes::{Buf, Bytes};
+use h3::server::RequestResolver;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::Response;
+use hyper::body::Frame;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::ServerConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Protocol name negotiated during the TLS handshake
+pub const ALPN: &[u8] = b"h3";
+
+/// Binds the QUIC endpoint on the UDP side of `addr`.
+pub fn bind(addr: SocketAddr, tls: ServerConfig) -> anyhow::Result<quinn::Endpoint> {
+    Ok(quinn::Endpoint::server(server_config(tls)?, addr)?)
+}
+
+/// QUIC settings serving HTTP/3 with the certificate in `tls`.
+pub fn server_config(mut tls: ServerConfig) -> anyhow::Result<quinn::ServerConfig> {
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// `Alt-Svc` value pointing browsers at the HTTP/3 listener on `port`.
+pub fn alt_svc(port: u16) -> String {
+    format!("h3=\":{}\"; ma=86400", port)
+}
+
+/// Accepts QUIC connections and serves the share over HTTP/3 until the endpoint closes.
+pub async fn serve(endpoint: quinn::Endpoint, state: Arc<AppState>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, state).await {
+                tracing::debug!(error = %e, "HTTP/3 connection ended");
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, state: Arc<AppState>) -> anyhow::Result<()> {
+    let connection = incoming.await?;
+    let peer = connection.remote_address();
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+
+    while let Some(resolver) = connection.accept().await? {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(resolver, peer, state).await {
+                eprintln!("Error serving HTTP/3 request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Bridges one HTTP/3 request stream to the same handler the TCP listener uses.
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    peer: SocketAddr,
+    state: Arc<AppState>,
+) -> anyhow::Result<()> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    let body = futures_util::stream::try_unfold(recv, |mut recv| async move {
+        match recv.recv_data().await.map_err(std::io::Error::other)? {
+            Some(mut data) => {
+                let data = data.copy_to_bytes(data.remaining());
+                Ok(Some((Frame::data(data), recv)))
+            }
+            None => Ok(None),
+        }
+    });
+    let request = request.map(|()| -> BoxBody { StreamBody::new(body).boxed() });
+
+    let (parts, mut body) = crate::handle_request(request, peer, state)
+        .await?
+        .into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            send.send_data(data).await?;
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use crate::AppState;
+use crate::supervisor::Failure;
+use hyper_util::rt::TokioIo;
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// Builds a TLS server config from a PEM certificate chain and private key.
+///
+/// No ALPN protocols are set; callers add the ones their listener speaks.
+pub fn load_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Cannot read certificate {}: {}", cert.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow::anyhow!("Cannot read private key {}: {}", key.display(), e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// Accepts TLS connections and serves the share, each connection in a task of its own.
+///
+/// HTTP/1.1 is added to the ALPN protocols `config` already offers.
+pub async fn serve(
+    listener: TcpListener,
+    mut config: ServerConfig,
+    state: Arc<AppState>,
+) -> anyhow::Result<()> {
+    config.alpn_protocols.push(b"http/1.1".to_vec());
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, peer) = state.connections.accept(&listener).await;
+        let _ = stream.set_nodelay(true);
+        let acceptor = acceptor.clone();
+        let connection_state = state.clone();
+
+        state.connections.spawn(peer, async move {
+            match acceptor.accept(stream).await {
+                // ACME validation connections end after the handshake
+                Ok(stream) => {
+                    let alpn = stream.get_ref().1.alpn_protocol();
+                    if alpn.is_none_or(|p| p == b"http/1.1") {
+                        let io = TokioIo::new(stream);
+                        return crate::serve_connection(io, peer, connection_state).await;
+                    }
+                    Ok(())
+                }
+                // Scanners and plain-HTTP clients; not worth a line on stderr
+                Err(e) => {
+                    tracing::debug!(%peer, error = %e, "TLS handshake failed");
+                    Err(Failure::Tls)
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,442 @@
+use crate::{config, tunnel};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long the connectivity check waits for the tunnel server
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Port bore servers listen on for control connections
+const BORE_PORT: u16 = 7835;
+
+/// What each provider needs, shown when choosing one
+fn provider_summary(name: &str) -> &'static str {
+    match name {
+        "pico" => "tuns.sh: stable https://<user>-<name>.tuns.sh URLs, needs an SSH key",
+        "localhost.run" => "no account needed, a new random https URL each time",
+        "bore" => "plain TCP through bore.pub or your own bore server, no account",
+        "ssh" => "your own SSH tunnel server, such as a self-hosted sish",
+        _ => "",
+    }
+}
+
+/// The tunnel settings chosen in the wizard.
+#[derive(Debug, Default, PartialEq)]
+struct Answers {
+    providers: Vec<String>,
+    bore_server: Option<String>,
+    ssh_server: Option<String>,
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    ssh_key: Option<PathBuf>,
+}
+
+/// Runs `holodeck init`: asks which tunnel provider to use, finds or generates an SSH
+/// key when it needs one, checks the server can be reached and writes the `[tunnel]`
+/// settings to the config file.
+pub fn run() -> anyhow::Result<()> {
+    if !tunnel::AVAILABLE {
+        anyhow::bail!(
+            "This build has no tunnel support (features `tunnel-lhr`, `tunnel-pico`, `tunnel-bore`)"
+        );
+    }
+    let path = config::path().ok_or_else(|| {
+        anyhow::anyhow!("Can't tell where the config file goes: set HOLODECK_CONFIG or HOME")
+    })?;
+    let stdin = io::stdin();
+    let mut prompt = Prompt {
+        input: stdin.lock(),
+        output: io::stdout(),
+    };
+
+    writeln!(
+        prompt.output,
+        "Setting up holodeck's tunnel in {}\n",
+        path.display()
+    )?;
+    let answers = ask(&mut prompt)?;
+
+    writeln!(prompt.output)?;
+    let mut reachable = true;
+    for (host, port, ssh) in servers(&answers) {
+        match check(&host, port, ssh) {
+            Ok(found) => writeln!(prompt.output, "✓ {}:{} answers: {}", host, port, found)?,
+            Err(e) => {
+                reachable = false;
+                writeln!(prompt.output, "✗ Can't reach {}:{}: {}", host, port, e)?;
+            }
+        }
+    }
+    if !reachable && !prompt.confirm("Save the settings anyway?", false)? {
+        anyhow::bail!("Nothing was written");
+    }
+
+    let mut table = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents
+            .parse::<toml::Table>()
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if !table.is_empty() {
+        let backup = path.with_extension("toml.bak");
+        std::fs::copy(&path, &backup)?;
+        writeln!(
+            prompt.output,
+            "Updating [tunnel] in the existing file; the previous one is kept as {}",
+            backup.display()
+        )?;
+    }
+    apply(&mut table, &answers);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, toml::to_string_pretty(&table)?)?;
+    writeln!(
+        prompt.output,
+        "\nWrote {}. Run `holodeck` in the directory to share.",
+        path.display()
+    )?;
+    Ok(())
+}
+
+/// Walks through choosing providers and what they need.
+fn ask<R: BufRead, W: Write>(prompt: &mut Prompt<R, W>) -> anyhow::Result<Answers> {
+    let names = tunnel::PROVIDER_NAMES;
+    writeln!(
+        prompt.output,
+        "Tunnel providers, tried in the order given until one comes up:"
+    )?;
+    for (i, name) in names.iter().enumerate() {
+        writeln!(
+            prompt.output,
+            "  {}) {:<14} {}",
+            i + 1,
+            name,
+            provider_summary(name)
+        )?;
+    }
+    let providers = loop {
+        let answer = prompt.ask("Providers, e.g. 1 or 1,3", "1")?;
+        match parse_choices(&answer, names.len()) {
+            Some(choices) => break choices.into_iter().map(|i| names[i].to_string()).collect(),
+            None => writeln!(prompt.output, "Enter numbers from 1 to {}", names.len())?,
+        }
+    };
+    let mut answers = Answers {
+        providers,
+        ..Answers::default()
+    };
+
+    let uses = |name: &str| answers.providers.iter().any(|chosen| chosen == name);
+    let (bore, ssh, pico) = (uses("bore"), uses("ssh"), uses("pico"));
+    if bore {
+        let server = prompt.ask("bore server", "bore.pub")?;
+        answers.bore_server = (server != "bore.pub").then_some(server);
+    }
+    if ssh {
+        answers.ssh_server = Some(loop {
+            let server = prompt.ask("SSH tunnel server", "")?;
+            if !server.is_empty() {
+                break server;
+            }
+        });
+        let port = prompt.ask("SSH port", "22")?;
+        answers.ssh_port = port.parse().ok().filter(|&port| port != 22);
+    }
+    if pico || ssh {
+        let user = prompt.ask("SSH user (your pico.sh user name for pico)", "")?;
+        answers.ssh_user = (!user.is_empty()).then_some(user);
+        answers.ssh_key = Some(choose_key(prompt)?);
+    }
+    if pico {
+        writeln!(
+            prompt.output,
+            "\npico.sh only accepts registered keys: if you have no account yet, run\n  \
+             ssh -i {} pico.sh\nonce to sign up with this key.",
+            answers
+                .ssh_key
+                .as_deref()
+                .unwrap_or(Path::new(""))
+                .display()
+        )?;
+    }
+    Ok(answers)
+}
+
+/// Offers the SSH keys found in `~/.ssh`, generating one or typing a path.
+fn choose_key<R: BufRead, W: Write>(prompt: &mut Prompt<R, W>) -> anyhow::Result<PathBuf> {
+    let found = existing_keys();
+    let generated = config::config_dir().unwrap_or_default().join("id_ed25519");
+    writeln!(prompt.output, "SSH key:")?;
+    for (i, key) in found.iter().enumerate() {
+        writeln!(prompt.output, "  {}) {}", i + 1, key.display())?;
+    }
+    let generate = found.len() + 1;
+    writeln!(
+        prompt.output,
+        "  {}) Generate a new ed25519 key as {}",
+        generate,
+        generated.display()
+    )?;
+    writeln!(prompt.output, "  {}) Enter a path", generate + 1)?;
+    loop {
+        let answer = prompt.ask("Key", "1")?;
+        match answer.parse::<usize>() {
+            Ok(i) if (1..generate).contains(&i) => return Ok(found[i - 1].clone()),
+            Ok(i) if i == generate => {
+                if generated.exists() {
+                    writeln!(prompt.output, "Using the existing {}", generated.display())?;
+                } else {
+                    let public = generate_key(&generated)?;
+                    writeln!(prompt.output, "Its public key:\n{}", public)?;
+                }
+                return Ok(generated);
+            }
+            Ok(i) if i == generate + 1 => {
+                let path = PathBuf::from(prompt.ask("Path to the private key", "")?);
+                if path.is_file() {
+                    return Ok(path);
+                }
+                writeln!(prompt.output, "No file at {}", path.display())?;
+            }
+            _ => writeln!(prompt.output, "Enter a number from 1 to {}", generate + 1)?,
+        }
+    }
+}
+
+/// Private keys in `~/.ssh` with the usual names.
+fn existing_keys() -> Vec<PathBuf> {
+    let Some(home) = std::env::var_os("HOME") else {
+        return Vec::new();
+    };
+    let dir = Path::new(&home).join(".ssh");
+    ["id_ed25519", "id_ecdsa", "id_rsa"]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|key| key.is_file())
+        .collect()
+}
+
+/// Creates an ed25519 key pair without a passphrase at `path`, as `holodeck keygen` does.
+#[cfg_attr(
+    not(any(feature = "tunnel-lhr", feature = "tunnel-pico")),
+    allow(unused_variables)
+)]
+fn generate_key(path: &Path) -> anyhow::Result<String> {
+    #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+    return Ok(crate::keygen::generate(path, "holodeck")?.public);
+    #[cfg(not(any(feature = "tunnel-lhr", feature = "tunnel-pico")))]
+    anyhow::bail!("This build has no SSH tunnel support")
+}
+
+/// The servers the chosen providers connect to, as host, port and whether it speaks SSH.
+fn servers(answers: &Answers) -> Vec<(String, u16, bool)> {
+    answers
+        .providers
+        .iter()
+        .map(|name| match name.as_str() {
+            "pico" => ("tuns.sh".to_string(), 22, true),
+            "localhost.run" => ("localhost.run".to_string(), 22, true),
+            "bore" => (
+                answers
+                    .bore_server
+                    .clone()
+                    .unwrap_or_else(|| "bore.pub".to_string()),
+                BORE_PORT,
+                false,
+            ),
+            _ => (
+                answers.ssh_server.clone().unwrap_or_default(),
+                answers.ssh_port.unwrap_or(22),
+                true,
+            ),
+        })
+        .collect()
+}
+
+/// Connects to the server, reading its banner when it speaks SSH.
+fn check(host: &str, port: u16, ssh: bool) -> Result<String, String> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or("no address found")?;
+    let stream = TcpStream::connect_timeout(&addr, CHECK_TIMEOUT).map_err(|e| e.to_string())?;
+    if !ssh {
+        return Ok("connection accepted".to_string());
+    }
+    stream
+        .set_read_timeout(Some(CHECK_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let mut banner = String::new();
+    BufReader::new(stream)
+        .read_line(&mut banner)
+        .map_err(|e| e.to_string())?;
+    let banner = banner.trim();
+    if banner.starts_with("SSH-") {
+        Ok(banner.to_string())
+    } else {
+        Err(format!("not an SSH server ({:?})", banner))
+    }
+}
+
+/// Writes the answers into the `[tunnel]` table, leaving other settings alone.
+fn apply(table: &mut toml::Table, answers: &Answers) {
+    let tunnel = table
+        .entry("tunnel")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    let Some(tunnel) = tunnel.as_table_mut() else {
+        return;
+    };
+    tunnel.insert(
+        "providers".to_string(),
+        toml::Value::Array(
+            answers
+                .providers
+                .iter()
+                .map(|name| toml::Value::String(name.clone()))
+                .collect(),
+        ),
+    );
+    let strings = [
+        ("bore_server", answers.bore_server.clone()),
+        ("ssh_server", answers.ssh_server.clone()),
+        ("ssh_user", answers.ssh_user.clone()),
+        (
+            "ssh_key",
+            answers
+                .ssh_key
+                .as_ref()
+                .map(|key| key.to_string_lossy().into_owned()),
+        ),
+    ];
+    for (key, value) in strings {
+        match value {
+            Some(value) => tunnel.insert(key.to_string(), toml::Value::String(value)),
+            None => tunnel.remove(key),
+        };
+    }
+    match answers.ssh_port {
+        Some(port) => tunnel.insert("ssh_port".to_string(), toml::Value::Integer(port.into())),
+        None => tunnel.remove("ssh_port"),
+    };
+}
+
+/// Parses `1,3` into indexes below `count`, in order and without repeats.
+fn parse_choices(answer: &str, count: usize) -> Option<Vec<usize>> {
+    let mut choices = Vec::new();
+    for choice in answer.split(',') {
+        let i = choice.trim().parse::<usize>().ok()?.checked_sub(1)?;
+        if i >= count {
+            return None;
+        }
+        if !choices.contains(&i) {
+            choices.push(i);
+        }
+    }
+    Some(choices)
+}
+
+/// Questions on `output`, answered a line at a time on `input`.
+struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    /// The answer to `question`, `default` when left empty.
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if default.is_empty() {
+            write!(self.output, "{}: ", question)?;
+        } else {
+            write!(self.output, "{} [{}]: ", question, default)?;
+        }
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "setup cancelled",
+            ));
+        }
+        let answer = line.trim();
+        Ok(if answer.is_empty() { default } else { answer }.to_string())
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let answer = self.ask(question, if default { "Y/n" } else { "y/N" })?;
+        Ok(match answer.to_ascii_lowercase().as_str() {
+            "y" | "yes" => true,
+            "n" | "no" => false,
+            _ => default,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(input: &str) -> Prompt<&[u8], Vec<u8>> {
+        Prompt {
+            input: input.as_bytes(),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn choices_are_numbers_in_range() {
+        assert_eq!(parse_choices("1", 3), Some(vec![0]));
+        assert_eq!(parse_choices("3, 1,3", 3), Some(vec![2, 0]));
+        assert_eq!(parse_choices("4", 3), None);
+        assert_eq!(parse_choices("0", 3), None);
+        assert_eq!(parse_choices("bore", 3), None);
+    }
+
+    #[test]
+    fn empty_answers_take_the_default() {
+        let mut prompt = prompt("\nbore.example.com\n\n");
+        assert_eq!(prompt.ask("bore server", "bore.pub").unwrap(), "bore.pub");
+        assert_eq!(
+            prompt.ask("bore server", "bore.pub").unwrap(),
+            "bore.example.com"
+        );
+        assert!(!prompt.confirm("Save anyway?", false).unwrap());
+        assert!(prompt.ask("SSH user", "").is_err());
+        assert_eq!(
+            String::from_utf8(prompt.output).unwrap(),
+            "bore server [bore.pub]: bore server [bore.pub]: Save anyway? [y/N]: SSH user: "
+        );
+    }
+
+    #[test]
+    fn answers_replace_only_the_tunnel_settings() {
+        let mut table: toml::Table = r#"
+            [mime]
+            gcode = "text/x-gcode"
+
+            [tunnel]
+            provider_timeout = 30
+            ssh_server = "old.example.com"
+        "#
+        .parse()
+        .unwrap();
+        apply(
+            &mut table,
+            &Answers {
+                providers: vec!["bore".to_string()],
+                bore_server: Some("bore.example.com".to_string()),
+                ..Answers::default()
+            },
+        );
+
+        let config: config::Config = toml::from_str(&toml::to_string(&table).unwrap()).unwrap();
+        assert_eq!(config.mime["gcode"], "text/x-gcode");
+        assert_eq!(config.tunnel.providers, ["bore"]);
+        assert_eq!(config.tunnel.bore_server, "bore.example.com");
+        assert_eq!(config.tunnel.provider_timeout, 30);
+        assert_eq!(config.tunnel.ssh_server, None);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{BoxBody, full, listing};
+use hyper::header::{AUTHORIZATION, HeaderMap, RETRY_AFTER};
+use hyper::{Method, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a violation counts towards a ban
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Header and query parameters that carry a passcode or share token
+const PASSCODE_HEADER: &str = "x-holodeck-passcode";
+const CREDENTIAL_PARAMS: &[&str] = &["token", "passcode"];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Something a well-behaved client doesn't do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A wrong passcode or share token
+    AuthFailure,
+    /// A path climbing out of the share with `..`, plain or percent-encoded
+    Traversal,
+}
+
+impl Violation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthFailure => "auth-failure",
+            Self::Traversal => "traversal",
+        }
+    }
+}
+
+/// Logs wrong credentials and path traversal attempts in a fixed format fail2ban can
+/// match, and with `--ban-after` turns away clients that keep at it:
+///
+/// ```text
+/// INTRUSION: 2026-10-16T09:12:44Z client=203.0.113.7 kind=traversal method=GET path="/../etc/passwd"
+/// INTRUSION: 2026-10-16T09:12:45Z client=203.0.113.7 kind=banned violations=5 seconds=3600
+/// ```
+///
+/// Behind a tunnel every request comes from loopback, so a firewall can't tell
+/// clients apart; the ban list keys on the forwarded address instead, see
+/// [`forwarded_client`].
+pub struct Guard {
+    /// Violations within [`WINDOW`] that get a client banned, `None` to only log
+    ban_after: Option<u32>,
+    ban_time: Duration,
+    clients: Mutex<HashMap<String, Record>>,
+}
+
+#[derive(Default)]
+struct Record {
+    /// When each recent violation happened
+    violations: Vec<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Guard {
+    pub fn new(ban_after: Option<u32>, ban_time: Duration) -> Self {
+        Self {
+            ban_after,
+            ban_time,
+            clients: Mutex::default(),
+        }
+    }
+
+    /// The `403` to send instead while `client` is banned.
+    pub fn check(&self, client: &str) -> Option<Response<BoxBody>> {
+        let clients = self.clients.lock().unwrap();
+        let left = clients
+            .get(client)?
+            .banned_until?
+            .checked_duration_since(Instant::now())?;
+        Some(
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(RETRY_AFTER, left.as_secs_f64().ceil() as u64)
+                .body(full("Too many suspicious requests from this address\n"))
+                .unwrap(),
+        )
+    }
+
+    /// Logs a violation by `client` and bans it once it has made too many.
+    pub fn record(&self, violation: Violation, client: &str, method: &Method, path: &str) {
+        let now = SystemTime::now();
+        // Debug formatting escapes quotes and control characters, so a crafted path
+        // can't forge a log line
+        eprintln!(
+            "INTRUSION: {} client={} kind={} method={} path={:?}",
+            listing::rfc3339(now),
+            client,
+            violation.as_str(),
+            method,
+            path
+        );
+        let Some(ban_after) = self.ban_after else {
+            return;
+        };
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, record| {
+            record.violations.retain(|at| at.elapsed() < WINDOW);
+            !record.violations.is_empty()
+                || record
+                    .banned_until
+                    .is_some_and(|until| until > Instant::now())
+        });
+        let record = clients.entry(client.to_string()).or_default();
+        record.violations.push(Instant::now());
+        let count = record.violations.len() as u32;
+        if count >= ban_after
+            && record
+                .banned_until
+                .is_none_or(|until| until <= Instant::now())
+        {
+            record.banned_until = Some(Instant::now() + self.ban_time);
+            eprintln!(
+                "INTRUSION: {} client={} kind=banned violations={} seconds={}",
+                listing::rfc3339(now),
+                client,
+                count,
+                self.ban_time.as_secs()
+            );
+        }
+    }
+}
+
+/// The client address a tunnel provider forwarded: the right-most `X-Forwarded-For`
+/// hop, which the provider appends itself. The hops before it are whatever the
+/// client chose to send, so trusting them would let it dodge a ban or get someone
+/// else banned.
+pub fn forwarded_client(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(X_FORWARDED_FOR)?
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .next()
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+}
+
+/// Whether the request presents a passcode or share token, so that being refused
+/// means it was wrong rather than missing.
+pub fn carries_credentials(headers: &HeaderMap, query: Option<&str>) -> bool {
+    headers.contains_key(AUTHORIZATION)
+        || headers.contains_key(PASSCODE_HEADER)
+        || query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter_map(|pair| pair.split_once('=').map(|(key, _)| key))
+            .any(|key| CREDENTIAL_PARAMS.contains(&key))
+}
+
+/// Whether `path` has a `..` segment, also spelled with percent-encoded dots,
+/// slashes or backslashes, or an encoded NUL.
+pub fn is_traversal(path: &str) -> bool {
+    let path = path
+        .to_ascii_lowercase()
+        .replace("%2e", ".")
+        .replace('\\', "/")
+        .replace("%2f", "/")
+        .replace("%5c", "/");
+    path.contains("%00") || path.split('/').any(|segment| segment == "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spots_traversal_in_any_spelling() {
+        assert!(is_traversal("/../etc/passwd"));
+        assert!(is_traversal("/%2e%2e/%2E%2E/etc/passwd"));
+        assert!(is_traversal("/docs%2f..%2fsecret"));
+        assert!(is_traversal("/..\\windows\\win.ini"));
+        assert!(is_traversal("/report.pdf%00.txt"));
+        assert!(!is_traversal("/notes..txt"));
+        assert!(!is_traversal("/.../report.pdf"));
+    }
+
+    #[test]
+    fn bans_after_repeated_violations() {
+        let guard = Guard::new(Some(3), Duration::from_secs(60));
+        for _ in 0..2 {
+            guard.record(Violation::AuthFailure, "203.0.113.7", &Method::GET, "/");
+        }
+        assert!(guard.check("203.0.113.7").is_none());
+        guard.record(Violation::Traversal, "203.0.113.7", &Method::GET, "/../x");
+        let response = guard.check("203.0.113.7").unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+        assert!(guard.check("203.0.113.8").is_none());
+    }
+
+    #[test]
+    fn spoofed_hops_do_not_change_the_client() {
+        let forwarded = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_FORWARDED_FOR, value.parse().unwrap());
+            forwarded_client(&headers).map(str::to_string)
+        };
+        assert_eq!(forwarded("203.0.113.7").as_deref(), Some("203.0.113.7"));
+        assert_eq!(
+            forwarded("198.51.100.1, 203.0.113.7").as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            forwarded("192.0.2.99,203.0.113.7").as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(forwarded("203.0.113.7, "), None);
+        assert_eq!(forwarded_client(&HeaderMap::new()), None);
+
+        // Rotating the spoofed hop still counts against the same client
+        let guard = Guard::new(Some(2), Duration::from_secs(60));
+        for spoofed in ["198.51.100.1, 203.0.113.7", "198.51.100.2, 203.0.113.7"] {
+            let client = forwarded(spoofed).unwrap();
+            guard.record(Violation::AuthFailure, &client, &Method::GET, "/");
+        }
+        assert!(guard.check("203.0.113.7").is_some());
+        assert!(guard.check("198.51.100.1").is_none());
+    }
+
+    #[test]
+    fn credentials_in_headers_or_query() {
+        let mut headers = HeaderMap::new();
+        assert!(!carries_credentials(&headers, Some("compress=gzip")));
+        assert!(carries_credentials(
+            &headers,
+            Some("compress=gzip&token=abc")
+        ));
+        headers.insert(PASSCODE_HEADER, "123456".parse().unwrap());
+        assert!(carries_credentials(&headers, None));
+    }
+}
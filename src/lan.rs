@@ -0,0 +1,173 @@
+use crate::BoxBody;
+use crate::health;
+use hyper::header::HeaderValue;
+use hyper::{Response, StatusCode};
+#[cfg(feature = "client")]
+use reqwest::{Client, Url};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+#[cfg(feature = "client")]
+use std::time::Duration;
+
+/// Response header pointing clients at the share's address on the local network
+pub const HEADER: &str = "x-holodeck-lan";
+
+/// How long a client gives the local address to answer; on another network it
+/// usually doesn't answer at all
+#[cfg(feature = "client")]
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where the share can be reached without the tunnel, for clients on the same network
+/// as the server, such as a colleague downloading from the next desk.
+///
+/// Responses reached some other way carry the address in an `X-Holodeck-Lan` header
+/// next to `X-Holodeck-Instance`; a client run with `--lan` that gets an answer from
+/// the same instance at `/_health` there is on the same network and switches to it.
+pub struct Lan {
+    /// `http://192.168.1.20:8080`, with the base path
+    url: HeaderValue,
+    /// `192.168.1.20:8080`, the Host of requests already made directly
+    authority: String,
+}
+
+impl Lan {
+    /// The local network address of a server listening on `bind`: the interface
+    /// holding the default route when bound to all of them, none on loopback.
+    pub fn new(bind: SocketAddr, scheme: &str, base_path: &str) -> Option<Self> {
+        let ip = match bind.ip() {
+            ip if ip.is_loopback() => return None,
+            ip if ip.is_unspecified() => default_route_ip()?,
+            ip => ip,
+        };
+        let authority = SocketAddr::new(ip, bind.port()).to_string();
+        let url = format!("{}://{}{}", scheme, authority, base_path);
+        Some(Self {
+            url: HeaderValue::from_str(&url).ok()?,
+            authority,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        self.url.to_str().unwrap_or_default()
+    }
+
+    /// Adds the hint, with the `instance` to recognize the server by, to a response for
+    /// a request made to `host`, unless it was made directly already or refused for
+    /// lack of credentials.
+    pub fn hint(
+        &self,
+        instance: &str,
+        host: Option<&HeaderValue>,
+        response: &mut Response<BoxBody>,
+    ) {
+        let direct = host.is_some_and(|host| host.as_bytes() == self.authority.as_bytes());
+        let refused = matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        );
+        if !direct
+            && !refused
+            && let Ok(instance) = HeaderValue::from_str(instance)
+        {
+            let headers = response.headers_mut();
+            headers.insert(HEADER, self.url.clone());
+            headers.insert(health::INSTANCE_HEADER, instance);
+        }
+    }
+}
+
+/// The address of the interface holding the default route.
+fn default_route_ip() -> Option<IpAddr> {
+    // Connecting a UDP socket sends nothing; it only picks the outgoing interface
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    probe.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    Some(probe.local_addr().ok()?.ip()).filter(|ip| !ip.is_unspecified())
+}
+
+/// `url` moved to the server's local network address when this machine turns out to
+/// be on the same network, so the transfer skips the tunnel; `None` to stay on `url`.
+///
+/// The instance id is public, so this can't tell the server from another machine at
+/// that address answering as it; that's why the fast path is only taken with `--lan`.
+#[cfg(feature = "client")]
+pub async fn fast_path(client: &Client, url: &Url) -> Option<Url> {
+    if is_local(url) {
+        return None;
+    }
+    let response = client.head(url.clone()).send().await.ok()?;
+    let header = |name| response.headers().get(name)?.to_str().ok();
+    let lan = Url::parse(header(HEADER)?).ok()?;
+    let instance = header(health::INSTANCE_HEADER)?;
+
+    let probe = format!("{}{}", lan.as_str().trim_end_matches('/'), health::ROUTE);
+    let answer = client.get(probe).timeout(PROBE_TIMEOUT).send().await.ok()?;
+    let same = answer
+        .headers()
+        .get(health::INSTANCE_HEADER)
+        .is_some_and(|probed| probed.as_bytes() == instance.as_bytes());
+    same.then(|| direct(url, &lan))?
+}
+
+/// Whether `url` already leads to this machine or its network.
+#[cfg(feature = "client")]
+fn is_local(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => ip.is_loopback() || ip.is_unicast_link_local(),
+        Err(_) => host == "localhost",
+    }
+}
+
+/// `url` with the scheme, host and port of `lan`; the path, including any base path,
+/// and the query carrying a token stay as they are.
+#[cfg(feature = "client")]
+fn direct(url: &Url, lan: &Url) -> Option<Url> {
+    let mut direct = url.clone();
+    direct.set_scheme(lan.scheme()).ok()?;
+    direct.set_host(lan.host_str()).ok()?;
+    direct.set_port(lan.port()).ok()?;
+    Some(direct)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::full;
+
+    #[test]
+    fn only_reachable_bind_addresses_are_advertised() {
+        assert!(Lan::new("127.0.0.1:8080".parse().unwrap(), "http", "").is_none());
+        let lan = Lan::new("192.168.1.20:8080".parse().unwrap(), "https", "/share").unwrap();
+        assert_eq!(lan.url(), "https://192.168.1.20:8080/share");
+
+        let hinted = |host: &str, status| {
+            let mut response = Response::builder().status(status).body(full("")).unwrap();
+            lan.hint(
+                "0f3a",
+                Some(&HeaderValue::from_str(host).unwrap()),
+                &mut response,
+            );
+            response.headers().contains_key(HEADER)
+        };
+        assert!(hinted("abc123.lhr.life", StatusCode::OK));
+        assert!(hinted("abc123.lhr.life", StatusCode::NOT_FOUND));
+        assert!(!hinted("abc123.lhr.life", StatusCode::UNAUTHORIZED));
+        assert!(!hinted("192.168.1.20:8080", StatusCode::OK));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn direct_urls_keep_the_path_and_token() {
+        let url = Url::parse("https://abc123.lhr.life/share/big.iso?token=ad17").unwrap();
+        let lan = Url::parse("http://192.168.1.20:8080/share").unwrap();
+        assert_eq!(
+            direct(&url, &lan).unwrap().as_str(),
+            "http://192.168.1.20:8080/share/big.iso?token=ad17"
+        );
+        assert!(!is_local(&url));
+        assert!(is_local(&lan));
+        assert!(is_local(&Url::parse("http://localhost:8080/").unwrap()));
+    }
+}
@@ -0,0 +1,2506 @@
+mod access;
+#[cfg(feature = "acme")]
+mod acme;
+mod admin;
+mod aliases;
+mod announce;
+#[cfg(feature = "archives")]
+mod batch;
+#[cfg(feature = "client")]
+mod bench;
+#[cfg(feature = "tunnel-bore")]
+mod bore;
+pub mod cli;
+#[cfg(feature = "client")]
+mod client;
+mod commands;
+mod compress;
+mod conditional;
+mod config;
+mod dedup;
+#[cfg(feature = "desktop-notifications")]
+mod desktop;
+pub mod events;
+mod executables;
+mod feed;
+mod fileops;
+mod follow;
+mod forward;
+mod ftp;
+mod headers;
+mod health;
+mod history;
+#[cfg(feature = "http3")]
+mod http3;
+#[cfg(feature = "https")]
+mod https;
+mod init;
+mod intrusion;
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+mod keygen;
+mod lan;
+mod limits;
+mod listing;
+mod mime;
+#[cfg(feature = "client")]
+mod mirror;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod openapi;
+#[cfg(feature = "signing")]
+mod openpgp;
+#[cfg(feature = "p2p")]
+mod p2p;
+mod packing;
+mod passcode;
+mod paths;
+#[cfg(feature = "tunnel-pico")]
+mod pico;
+#[cfg(feature = "plugins")]
+mod plugins;
+mod policy;
+#[cfg(unix)]
+mod privileges;
+mod progress;
+mod proxy;
+#[cfg(feature = "client")]
+mod push;
+#[cfg(feature = "qr")]
+mod qr;
+mod relay;
+mod reload;
+mod resumable;
+mod rmdir;
+mod router;
+mod sandbox;
+#[cfg(feature = "scripting")]
+mod scripting;
+#[cfg(feature = "search")]
+mod search;
+#[cfg(feature = "signing")]
+mod signing;
+#[cfg(feature = "archives")]
+mod snapshot;
+mod storage;
+mod supervisor;
+mod sweeper;
+#[cfg(feature = "client")]
+mod sync;
+mod tags;
+pub mod telemetry;
+mod tftp;
+mod trash;
+pub mod tunnel;
+mod tunnel_names;
+mod uploads;
+mod user_meta;
+mod webhook;
+mod website;
+
+use bytes::{Bytes, BytesMut};
+use cli::{Cli, Command, OutputMode};
+use events::{Event, Events};
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{
+    ACCEPT_RANGES, ALT_SVC, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, ETAG, HOST,
+    LAST_MODIFIED, RANGE, RETRY_AFTER, VARY,
+};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode, body::Incoming};
+use hyper_util::rt::TokioIo;
+use progress::{TransferProgress, Transfers};
+use router::Router;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use storage::StagedFile;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, std::io::Error>;
+
+const SHARED_DIR: &str = ".";
+
+/// Holodeck's own bookkeeping directory inside the share, hidden from listings
+const META_DIR: &str = ".holodeck";
+
+/// Default transfer chunk size through the tunnel: small chunks keep progress smooth
+/// and memory low, since the SSH channel window caps throughput anyway
+const TUNNEL_IO_BUFFER: usize = 64 * 1024;
+
+/// Default transfer chunk size when serving locally, where fewer syscalls pay off
+const LOCAL_IO_BUFFER: usize = 1024 * 1024;
+
+/// State shared by all connection handlers.
+pub struct AppState {
+    /// Directory being shared
+    root: PathBuf,
+    /// From the config file, replaced when it is reloaded
+    mime_types: RwLock<Arc<mime::MimeTypes>>,
+    transfers: Transfers,
+    events: Events,
+    /// Sync uploads to stable storage before answering 201
+    fsync: bool,
+    /// Read uploads back and compare checksums before answering 201
+    verify_uploads: bool,
+    /// Refuse uploads recognized as executables or scripts
+    block_executables: bool,
+    /// Compress finished uploads on disk with zstd when that saves space
+    compress_storage: bool,
+    /// Where in-flight uploads are written before being moved into the share
+    staging_dir: PathBuf,
+    /// Read/write chunk size for streaming transfers
+    io_buffer: usize,
+    /// `Alt-Svc` value sent with every response when an HTTP/3 listener is running
+    alt_svc: Option<hyper::header::HeaderValue>,
+    /// Serve GETs as a static website instead of file downloads
+    website: bool,
+    /// In website mode, answer unknown page routes with the root `index.html`
+    spa: bool,
+    /// Serve directories holding an `index.html` (and their contents) as pages
+    auto_index: bool,
+    /// Extra headers from the config file, replaced when it is reloaded
+    headers: RwLock<Arc<headers::HeaderRules>>,
+    /// Per-folder `[[policy]]` rules from the config file, replaced when it is reloaded
+    policies: RwLock<Arc<policy::Policies>>,
+    /// Prefix all routes live under, without a trailing slash (empty for none)
+    base_path: String,
+    aliases: aliases::Aliases,
+    /// `X-Meta-*` headers files were uploaded with
+    user_meta: user_meta::UserMeta,
+    /// Files stored zstd-compressed, read back decompressed
+    packed: packing::Packed,
+    /// Resumable uploads waiting for more bytes, kept across restarts
+    resumable: resumable::Resumable,
+    tags: tags::Tags,
+    /// Largest unpacked size accepted by `POST /_batch`
+    #[cfg(feature = "archives")]
+    batch_limit: u64,
+    hashes: dedup::HashIndex,
+    writes: storage::WriteLocks,
+    /// Set when `--allow-delete` enables DELETE
+    trash: Option<trash::Trash>,
+    confirmations: rmdir::Confirmations,
+    /// Set by `--admin`; enables the `/_admin` routes
+    admin: bool,
+    /// `https` when serving TLS directly, for links the server generates
+    scheme: &'static str,
+    download_slots: limits::ClientSlots,
+    /// Logs intrusion attempts and holds the `--ban-after` ban list
+    intrusions: intrusion::Guard,
+    /// Runs and counts the accepted HTTP connections
+    connections: Arc<supervisor::Supervisor>,
+    /// Counts and warns about writes that ran out of disk space
+    disk_full: storage::DiskFull,
+    /// Random id of this process, sent by `/_health`
+    instance: String,
+    /// Set by `--passcode` or `--password`; requests must then present it or a session cookie
+    passcode: Option<passcode::Passcode>,
+    /// Set by `--share-links`; requests must then present the read, write or admin token
+    share_tokens: Option<access::ShareTokens>,
+    /// Set by `--relay-hub`; other instances registered with this one, served under `/_relay`
+    relay_hub: Option<relay::RelayHub>,
+    /// The share's local network address, pointed to by responses reached another way
+    lan: Option<lan::Lan>,
+    /// Set by `--p2p`; the QUIC endpoint `holodeck get --p2p` clients connect to directly
+    #[cfg(feature = "p2p")]
+    direct: Option<p2p::Direct>,
+    /// Set by `--sign`; serves minisign signatures next to every file
+    #[cfg(feature = "signing")]
+    signer: Option<signing::Signer>,
+    /// Set by `--trusted-key`; uploads must then be signed by one of these keys
+    #[cfg(feature = "signing")]
+    trusted_keys: Option<openpgp::TrustedKeys>,
+    /// Set by `--plugin`; WebAssembly hooks run on requests, uploads and listings
+    #[cfg(feature = "plugins")]
+    plugins: Option<plugins::Plugins>,
+    /// Set by `--script`; Rhai callbacks vetting requests, uploads and downloads
+    #[cfg(feature = "scripting")]
+    script: Option<scripting::Script>,
+    /// Set by `--search`; full-text index of the share's text files
+    #[cfg(feature = "search")]
+    search: Option<search::SearchIndex>,
+}
+
+impl AppState {
+    /// Serves `root` with default settings, staging uploads in `root/.holodeck/staging`
+    /// and drawing no progress bars.
+    pub fn new(root: PathBuf) -> std::io::Result<Self> {
+        let staging_dir = root.join(META_DIR).join("staging");
+        std::fs::create_dir_all(&staging_dir)?;
+        Ok(Self {
+            mime_types: RwLock::default(),
+            transfers: Transfers::recording(false, history::History::new(&root)),
+            events: Events::new(),
+            fsync: false,
+            verify_uploads: false,
+            block_executables: false,
+            compress_storage: false,
+            staging_dir,
+            io_buffer: LOCAL_IO_BUFFER,
+            alt_svc: None,
+            website: false,
+            spa: false,
+            auto_index: true,
+            headers: RwLock::default(),
+            policies: RwLock::default(),
+            base_path: String::new(),
+            aliases: aliases::Aliases::load(Default::default(), &root)
+                .map_err(std::io::Error::other)?,
+            user_meta: user_meta::UserMeta::load(&root).map_err(std::io::Error::other)?,
+            packed: packing::Packed::load(&root).map_err(std::io::Error::other)?,
+            resumable: resumable::Resumable::load(
+                &root,
+                cli::parse_interval(sweeper::DEFAULT_MAX_AGE).map_err(std::io::Error::other)?,
+            )
+            .map_err(std::io::Error::other)?,
+            tags: tags::Tags::load(&root).map_err(std::io::Error::other)?,
+            #[cfg(feature = "archives")]
+            batch_limit: batch::DEFAULT_LIMIT,
+            hashes: dedup::HashIndex::default(),
+            writes: storage::WriteLocks::default(),
+            trash: None,
+            admin: false,
+            confirmations: rmdir::Confirmations::default(),
+            scheme: "http",
+            download_slots: limits::ClientSlots::default(),
+            intrusions: intrusion::Guard::new(None, Duration::ZERO),
+            connections: Arc::default(),
+            disk_full: storage::DiskFull::default(),
+            instance: passcode::random_token()?,
+            passcode: None,
+            share_tokens: None,
+            relay_hub: None,
+            lan: None,
+            #[cfg(feature = "p2p")]
+            direct: None,
+            #[cfg(feature = "signing")]
+            signer: None,
+            #[cfg(feature = "signing")]
+            trusted_keys: None,
+            #[cfg(feature = "plugins")]
+            plugins: None,
+            #[cfg(feature = "scripting")]
+            script: None,
+            #[cfg(feature = "search")]
+            search: None,
+            root,
+        })
+    }
+
+    /// Transfer and tunnel events published while serving
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// What the tunnel self-test expects to find at `/_health` through the tunnel
+    pub fn self_test(&self) -> tunnel::SelfTest {
+        tunnel::SelfTest::new(self.instance.clone(), &self.base_path)
+    }
+
+    /// Applies the reloadable settings of a config file, given as TOML, the way
+    /// SIGHUP does; on error nothing changes.
+    pub fn reconfigure(&self, config: &str) -> anyhow::Result<()> {
+        let config =
+            toml::from_str(config).map_err(|e| anyhow::anyhow!("Invalid config file: {}", e))?;
+        reload::apply(self, config)
+    }
+
+    fn mime_types(&self) -> Arc<mime::MimeTypes> {
+        self.mime_types.read().unwrap().clone()
+    }
+
+    fn headers(&self) -> Arc<headers::HeaderRules> {
+        self.headers.read().unwrap().clone()
+    }
+
+    fn policies(&self) -> Arc<policy::Policies> {
+        self.policies.read().unwrap().clone()
+    }
+}
+
+/// Runs holodeck as configured on the command line.
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    let output = cli.output_mode();
+
+    match cli.command {
+        #[cfg(feature = "client")]
+        Some(Command::Bench(args)) => return bench::run(args, cli.io_buffer).await,
+        #[cfg(feature = "client")]
+        Some(Command::Get(args)) => return client::get(args, output).await,
+        #[cfg(feature = "client")]
+        Some(Command::Put(args)) => return client::put(args, output).await,
+        #[cfg(feature = "client")]
+        Some(Command::Mirror(args)) => return mirror::run(args, output).await,
+        #[cfg(feature = "client")]
+        Some(Command::Sync(args)) => return sync::run(args, output).await,
+        #[cfg(feature = "client")]
+        Some(Command::Push(args)) => return push::run(args, output).await,
+        #[cfg(feature = "qr")]
+        Some(Command::Qr(args)) => return qr::run(args),
+        Some(Command::History(args)) => return history::run(args),
+        #[cfg(feature = "archives")]
+        Some(Command::Export(args)) => return snapshot::export(args),
+        #[cfg(feature = "archives")]
+        Some(Command::Import(args)) => return snapshot::import(args).await,
+        Some(Command::Init) => return init::run(),
+        #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+        Some(Command::Keygen(args)) => return keygen::run(args),
+        _ => {}
+    }
+
+    let config = config::Config::load()?;
+
+    match cli.command {
+        Some(Command::Forward(args)) => {
+            return forward::run(args, &config.tunnel, output, cli.announce_json).await;
+        }
+        Some(Command::Proxy(args)) => {
+            return proxy::run(args, cli.bind, &config.tunnel, output, cli.announce_json).await;
+        }
+        Some(Command::History(_) | Command::Init) | None => {}
+        #[cfg(feature = "client")]
+        Some(
+            Command::Bench(_)
+            | Command::Get(_)
+            | Command::Put(_)
+            | Command::Mirror(_)
+            | Command::Sync(_)
+            | Command::Push(_),
+        ) => {}
+        #[cfg(feature = "qr")]
+        Some(Command::Qr(_)) => {}
+        #[cfg(feature = "archives")]
+        Some(Command::Export(_) | Command::Import(_)) => {}
+        #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+        Some(Command::Keygen(_)) => {}
+    }
+
+    // Bind first (a random available port by default), so a low port can be taken as root
+    let listener = TcpListener::bind(cli.bind).await?;
+    let local_addr = listener.local_addr()?;
+    let local_port = local_addr.port();
+    let ftp = match cli.ftp {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let tftp = match cli.tftp {
+        Some(addr) => Some(UdpSocket::bind(addr).await?),
+        None => None,
+    };
+
+    // Certificates are often readable by root only, so load them before dropping privileges
+    #[cfg(feature = "https")]
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(https::load_config(cert, key)?),
+        _ => None,
+    };
+    #[cfg(feature = "acme")]
+    let acme = if cli.acme_domain.is_empty() {
+        None
+    } else {
+        let Some(dir) = cli
+            .acme_dir
+            .clone()
+            .or_else(|| Some(config::config_dir()?.join("acme")))
+        else {
+            anyhow::bail!("Cannot locate the config directory; pass --acme-dir");
+        };
+        Some(acme::Acme::new(
+            cli.acme_domain.clone(),
+            cli.acme_email.clone(),
+            cli.acme_directory.clone(),
+            dir,
+        )?)
+    };
+    #[cfg(feature = "acme")]
+    let tls = tls.or_else(|| acme.as_ref().map(acme::Acme::tls_config));
+    #[cfg(feature = "https")]
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    #[cfg(not(feature = "https"))]
+    let scheme = "http";
+
+    #[cfg(feature = "http3")]
+    let quic = match &tls {
+        Some(tls) if cli.http3 => Some(http3::bind(local_addr, tls.clone())?),
+        None if cli.http3 => anyhow::bail!("--http3 needs --tls-cert or --acme-domain"),
+        _ => None,
+    };
+    #[cfg(feature = "http3")]
+    let alt_svc = match &quic {
+        Some(_) => Some(http3::alt_svc(local_port).parse()?),
+        None => None,
+    };
+    #[cfg(not(feature = "http3"))]
+    let alt_svc = None;
+    #[cfg(feature = "p2p")]
+    let direct = if cli.p2p {
+        Some(p2p::Direct::bind(&cli.stun_server).await?)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "signing")]
+    let signer = if cli.sign {
+        let Some(path) = cli
+            .signing_key
+            .clone()
+            .or_else(|| Some(config::config_dir()?.join("signing.key")))
+        else {
+            anyhow::bail!("Cannot locate the config directory; pass --signing-key");
+        };
+        Some(signing::Signer::load_or_create(&path)?)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "signing")]
+    let trusted_keys = if cli.trusted_key.is_empty() {
+        None
+    } else {
+        Some(openpgp::TrustedKeys::load(&cli.trusted_key)?)
+    };
+
+    #[cfg(feature = "plugins")]
+    let plugins = if cli.plugin.is_empty() {
+        None
+    } else {
+        Some(plugins::Plugins::load(&cli.plugin)?)
+    };
+
+    #[cfg(feature = "scripting")]
+    let script = cli
+        .script
+        .as_deref()
+        .map(scripting::Script::load)
+        .transpose()?;
+
+    #[cfg(feature = "search")]
+    let search = if cli.search {
+        Some(search::SearchIndex::new()?)
+    } else {
+        None
+    };
+
+    // ...and give up root before touching the share or serving anything
+    #[cfg(unix)]
+    if cli.user.is_some() || cli.group.is_some() {
+        privileges::drop_privileges(cli.user.as_deref(), cli.group.as_deref())?;
+    }
+
+    let staging_dir = cli
+        .staging_dir
+        .clone()
+        .unwrap_or_else(|| Path::new(SHARED_DIR).join(META_DIR).join("staging"));
+    fs::create_dir_all(&staging_dir).await?;
+
+    // Names are asked for up front, as a relayed share is served under its name
+    let names = tunnel_names::TunnelNames::load(Path::new(SHARED_DIR));
+    let (tunnel_provider, name_requests, base_path) = match cli.relay_via.as_deref() {
+        #[cfg(feature = "client")]
+        Some(hub) => {
+            let mut relay = relay::RelayTunnel::new(hub)?;
+            let requests = names.reuse(&mut relay, cli.new_name);
+            let base_path = relay.base_path();
+            (
+                Some(Box::new(relay) as Box<dyn tunnel::TunnelProvider>),
+                requests,
+                base_path,
+            )
+        }
+        #[cfg(not(feature = "client"))]
+        Some(_) => {
+            anyhow::bail!(
+                "--relay-via needs the `client` feature, which holodeck was built without"
+            )
+        }
+        None => {
+            let mut provider = tunnel::from_config(&config.tunnel)?;
+            let requests = provider
+                .as_mut()
+                .map(|provider| names.reuse(provider.as_mut(), cli.new_name))
+                .unwrap_or_default();
+            (provider, requests, cli.base_path.clone())
+        }
+    };
+    if scheme == "https" && tunnel_provider.is_some() {
+        anyhow::bail!(
+            "--tls-cert serves HTTPS directly and can't be combined with a tunnel; \
+             unset SSH_SERVER and [tunnel] providers"
+        );
+    }
+    let io_buffer = cli.io_buffer.unwrap_or(if tunnel_provider.is_some() {
+        TUNNEL_IO_BUFFER
+    } else {
+        LOCAL_IO_BUFFER
+    });
+
+    let password = cli
+        .password
+        .clone()
+        .or_else(|| std::env::var("HOLODECK_PASSWORD").ok())
+        .filter(|password| !password.is_empty());
+    anyhow::ensure!(
+        !(password.is_some() && cli.share_links),
+        "HOLODECK_PASSWORD can't be combined with --share-links"
+    );
+    anyhow::ensure!(
+        !(password.is_some() && cli.tftp.is_some()),
+        "HOLODECK_PASSWORD can't be combined with --tftp, which would serve files without it"
+    );
+    let passcode = match password {
+        Some(password) => Some(passcode::Passcode::password(password)),
+        None => cli
+            .passcode
+            .then(passcode::Passcode::generate)
+            .transpose()?,
+    }
+    .map(|passcode| passcode.session_lifetime(cli.session_ttl));
+
+    let visible = output == OutputMode::Normal;
+    let transfers = match cli.no_history {
+        true => Transfers::new(visible),
+        false => {
+            let history = history::History::new(Path::new(SHARED_DIR));
+            if let Err(e) = history.trim() {
+                eprintln!("Can't trim the transfer history: {}", e);
+            }
+            Transfers::recording(visible, history)
+        }
+    };
+
+    let lan = match cli.no_lan_hint {
+        true => None,
+        false => lan::Lan::new(local_addr, scheme, &base_path),
+    };
+
+    let state = Arc::new(AppState {
+        root: paths::long_path(Path::new(SHARED_DIR)),
+        mime_types: RwLock::new(Arc::new(mime::MimeTypes::new(&config.mime)?)),
+        transfers,
+        events: Events::new(),
+        fsync: cli.fsync,
+        verify_uploads: cli.verify_uploads,
+        block_executables: cli.block_executables,
+        #[cfg(feature = "compression")]
+        compress_storage: cli.compress_storage,
+        #[cfg(not(feature = "compression"))]
+        compress_storage: false,
+        staging_dir: paths::long_path(&staging_dir),
+        io_buffer,
+        alt_svc,
+        website: cli.website || cli.spa,
+        spa: cli.spa,
+        auto_index: !cli.no_auto_index,
+        headers: RwLock::new(Arc::new(headers::HeaderRules::new(&config.header)?)),
+        policies: RwLock::new(Arc::new(policy::Policies::new(
+            &config.policy,
+            cli.share_links,
+        )?)),
+        base_path,
+        aliases: aliases::Aliases::load(config.alias, Path::new(SHARED_DIR))?,
+        user_meta: user_meta::UserMeta::load(Path::new(SHARED_DIR))?,
+        packed: packing::Packed::load(Path::new(SHARED_DIR))?,
+        resumable: resumable::Resumable::load(Path::new(SHARED_DIR), cli.stale_upload_age)?,
+        tags: tags::Tags::load(Path::new(SHARED_DIR))?,
+        #[cfg(feature = "archives")]
+        batch_limit: cli.batch_limit as u64,
+        hashes: dedup::HashIndex::default(),
+        writes: storage::WriteLocks::default(),
+        admin: cli.admin,
+        trash: cli.allow_delete.then(|| {
+            let retention = Duration::from_secs(cli.trash_days * 24 * 60 * 60);
+            trash::Trash::new(&paths::long_path(Path::new(SHARED_DIR)), retention)
+        }),
+        confirmations: rmdir::Confirmations::default(),
+        scheme,
+        download_slots: limits::ClientSlots::new(cli.max_downloads_per_client),
+        intrusions: intrusion::Guard::new(cli.ban_after, cli.ban_time),
+        connections: Arc::default(),
+        disk_full: storage::DiskFull::default(),
+        instance: passcode::random_token()?,
+        passcode,
+        share_tokens: cli
+            .share_links
+            .then(access::ShareTokens::generate)
+            .transpose()?
+            .map(|tokens| tokens.limit_read(cli.read_link_quota, cli.read_link_rate)),
+        relay_hub: cli.relay_hub.then(relay::RelayHub::default),
+        lan,
+        #[cfg(feature = "p2p")]
+        direct,
+        #[cfg(feature = "signing")]
+        signer,
+        #[cfg(feature = "signing")]
+        trusted_keys,
+        #[cfg(feature = "plugins")]
+        plugins,
+        #[cfg(feature = "scripting")]
+        script,
+        #[cfg(feature = "search")]
+        search,
+    });
+
+    #[cfg(feature = "client")]
+    webhook::spawn_notifier(&state.events, config.webhook);
+    #[cfg(not(feature = "client"))]
+    for webhook in config.webhook {
+        eprintln!(
+            "Ignoring [[webhook]] '{}': holodeck was built without the `client` feature",
+            webhook.url
+        );
+    }
+    #[cfg(feature = "desktop-notifications")]
+    if cli.notify {
+        desktop::spawn_notifier(&state.events);
+    }
+    if let Some(mqtt) = config.mqtt {
+        #[cfg(feature = "mqtt")]
+        mqtt::spawn_publisher(&state.events, mqtt);
+        #[cfg(not(feature = "mqtt"))]
+        eprintln!(
+            "Ignoring [mqtt] config for broker '{}': holodeck was built without the `mqtt` feature",
+            mqtt.host
+        );
+    }
+
+    // Create shared directory if it doesn't exist
+    fs::create_dir_all(SHARED_DIR).await?;
+
+    if cli.sandbox {
+        #[cfg(feature = "acme")]
+        let acme_dir = acme.as_ref().map(acme::Acme::dir);
+        #[cfg(not(feature = "acme"))]
+        let acme_dir = None;
+        sandbox_process(&state, cli.announce_json.as_ref(), acme_dir.as_slice())?;
+    }
+
+    // Get absolute path of shared directory
+    let shared_path =
+        std::fs::canonicalize(SHARED_DIR).unwrap_or_else(|_| PathBuf::from(SHARED_DIR));
+
+    let local_url = format!("{}://{}{}", scheme, local_addr, state.base_path);
+    let example_url = format!("{}://localhost:{}{}", scheme, local_port, state.base_path);
+    match output {
+        OutputMode::Normal => {
+            println!("HTTP File Server running on {}", local_url);
+            if state.alt_svc.is_some() {
+                println!("HTTP/3 on UDP port {}", local_port);
+            }
+            if let Some(addr) = ftp.as_ref().and_then(|ftp| ftp.local_addr().ok()) {
+                println!("FTP on {}", addr);
+            }
+            if let Some(addr) = tftp.as_ref().and_then(|socket| socket.local_addr().ok()) {
+                println!("TFTP (read-only) on {}", addr);
+            }
+            if let Some(lan) = &state.lan {
+                println!("Same-network clients are pointed at {}", lan.url());
+            }
+            println!("Shared directory: {}", shared_path.display());
+            println!("\nUsage:");
+            println!("  GET file:  curl {}/<filename>", example_url);
+            println!(
+                "  POST file: curl -X POST --data-binary @<file> {}/<filename>",
+                example_url
+            );
+            println!("  List files: curl {}/", example_url);
+            if let Some(tokens) = &state.share_tokens {
+                println!("\nRead-only link: {}/?token={}", local_url, tokens.read());
+                println!("Upload link:    {}/?token={}", local_url, tokens.write());
+                println!("Admin link:     {}/?token={}", local_url, tokens.admin());
+                println!("(add the same ?token= to the tunnel URL)");
+            }
+            if state.relay_hub.is_some() {
+                println!(
+                    "\nRelay hub: other holodecks can serve through this one with --relay-via {}/",
+                    local_url
+                );
+            }
+            #[cfg(feature = "p2p")]
+            if let Some(direct) = &state.direct {
+                println!(
+                    "\nDirect transfers (experimental): QUIC on {}",
+                    join_addrs(direct.candidates())
+                );
+            }
+            #[cfg(feature = "signing")]
+            if let Some(signer) = &state.signer {
+                println!(
+                    "\nSigning downloads with key {}: {}/<filename>.sig, public key at {}{}",
+                    signer.key_id(),
+                    example_url,
+                    example_url,
+                    signing::PUBKEY
+                );
+            }
+            #[cfg(feature = "signing")]
+            if let Some(keys) = &state.trusted_keys {
+                println!(
+                    "\nUploads must be signed by a trusted key ({} loaded), with the detached signature base64-encoded in an {} header",
+                    keys.len(),
+                    openpgp::HEADER
+                );
+            }
+            #[cfg(feature = "plugins")]
+            if let Some(plugins) = &state.plugins {
+                println!("\nPlugins loaded: {}", plugins.len());
+            }
+            #[cfg(feature = "scripting")]
+            if let Some(script) = &cli.script {
+                println!("\nPolicy script: {}", script.display());
+            }
+            #[cfg(feature = "search")]
+            if state.search.is_some() {
+                println!(
+                    "\nFull-text search: curl '{}{}?q=<words>'",
+                    example_url,
+                    search::ROUTE
+                );
+            }
+            if let Some(passcode) = &state.passcode {
+                if passcode.is_password() {
+                    println!(
+                        "\nPassword protected (browsers get a login page; scripts send an X-Holodeck-Passcode header)"
+                    );
+                } else {
+                    println!(
+                        "\nPasscode: {} (browsers ask for it; scripts send an X-Holodeck-Passcode header)",
+                        passcode.code()
+                    );
+                }
+            }
+        }
+        OutputMode::Porcelain => {
+            println!("LOCAL={}", local_url);
+            if let Some(lan) = &state.lan {
+                println!("LAN={}", lan.url());
+            }
+            if let Some(passcode) = &state.passcode
+                && !passcode.is_password()
+            {
+                println!("PASSCODE={}", passcode.code());
+            }
+            if let Some(tokens) = &state.share_tokens {
+                println!("READ_TOKEN={}", tokens.read());
+                println!("WRITE_TOKEN={}", tokens.write());
+                println!("ADMIN_TOKEN={}", tokens.admin());
+            }
+            #[cfg(feature = "p2p")]
+            if let Some(direct) = &state.direct {
+                println!("P2P={}", join_addrs(direct.candidates()));
+            }
+            #[cfg(feature = "signing")]
+            if let Some(signer) = &state.signer {
+                println!("SIGNING_KEY_ID={}", signer.key_id());
+            }
+        }
+        OutputMode::Quiet => {}
+    }
+
+    #[cfg(feature = "tunnel-pico")]
+    if let Some(domain) = &config.tunnel.domain
+        && tunnel_provider.is_some()
+        && output == OutputMode::Normal
+    {
+        let key_path = std::env::var_os("SSH_KEY_PATH").map(PathBuf::from);
+        println!(
+            "\nServing on custom domain {}; pico.sh requires these DNS records:",
+            domain
+        );
+        for record in pico::required_records(domain, key_path.as_deref()) {
+            println!("  {}", record);
+        }
+        println!("DNS check: {}", pico::check_dns(domain).await);
+    }
+
+    let interrupted = state.resumable.list().await;
+    if !interrupted.is_empty() && output == OutputMode::Normal {
+        println!("\nResumable uploads waiting for their client to continue:");
+        for (name, session) in interrupted {
+            println!("  {} ({} of {} bytes)", name, session.offset, session.total);
+        }
+    }
+
+    // Spawn reverse SSH tunnel if configuration is provided; the task runs on its own
+    let (url_tx, url_rx) = watch::channel(None);
+    if tunnel_provider.is_some() {
+        for line in name_requests {
+            if output == OutputMode::Normal {
+                println!("{}", line);
+            }
+        }
+        names.record(url_rx.clone());
+    }
+    let tunnel = tunnel_provider.map(|provider| {
+        // The hub's URL for a relayed share already leads to its base path
+        let self_test = (!cli.no_self_test).then(|| match cli.relay_via {
+            Some(_) => tunnel::SelfTest::new(state.instance.clone(), ""),
+            None => state.self_test(),
+        });
+        tunnel::spawn_tunnel(
+            provider,
+            local_port,
+            output,
+            url_tx,
+            state.events.clone(),
+            self_test,
+        )
+    });
+    if let Some(target) = cli.announce_json {
+        announce_when_ready(
+            target,
+            local_addr.to_string(),
+            state
+                .passcode
+                .as_ref()
+                .map(|p| p.code().to_string())
+                .or_else(|| state.share_tokens.as_ref().map(|t| t.write().to_string())),
+            url_rx,
+            tunnel.is_some(),
+        );
+    }
+    if output == OutputMode::Normal {
+        if tunnel.is_some() {
+            println!("\n=== Tunnel Active ===");
+            println!("Your server is now accessible externally!");
+        } else if !tunnel::AVAILABLE {
+            println!("\n=== Running in Local Mode ===");
+            println!("This build has no tunnel support (features `tunnel-lhr`, `tunnel-pico`)");
+        } else {
+            println!("\n=== Running in Local Mode ===");
+            println!("To enable external access, run `holodeck init` to set up a tunnel,");
+            println!("or set these environment variables:");
+            println!("  SSH_SERVER   - SSH server address (e.g., ssh.localhost.run)");
+            println!("  SSH_USER     - SSH username (optional, defaults to 'localhost')");
+            println!("  SSH_PORT     - SSH server port (optional, defaults to 22)");
+            println!("  SSH_KEY_PATH - Path to SSH private key (required for key auth)");
+            println!("  SSH_PASSWORD - SSH password (alternative to key auth)");
+            println!("  REMOTE_PORT  - Remote port to listen on (optional, defaults to 80)");
+            println!("\nExample with localhost.run:");
+            println!("  SSH_SERVER=ssh.localhost.run SSH_KEY_PATH=~/.ssh/id_ed25519 cargo run");
+        }
+    }
+
+    let purge_state = state.clone();
+    tokio::spawn(async move {
+        if let Some(trash) = &purge_state.trash {
+            trash.run().await;
+        }
+    });
+    tokio::spawn(sweeper::run(state.clone(), cli.stale_upload_age));
+    #[cfg(unix)]
+    tokio::spawn(reload::run(state.clone()));
+    if let Some(socket) = tftp {
+        tokio::spawn(tftp::serve(socket, state.clone()));
+    }
+    #[cfg(feature = "https")]
+    if let Some(listener) = ftp {
+        let tls = tls
+            .clone()
+            .map(|tls| tokio_rustls::TlsAcceptor::from(Arc::new(tls)));
+        tokio::spawn(ftp::serve(listener, state.clone(), tls));
+    }
+    #[cfg(not(feature = "https"))]
+    if let Some(listener) = ftp {
+        tokio::spawn(ftp::serve(listener, state.clone()));
+    }
+    #[cfg(feature = "acme")]
+    if let Some(acme) = acme {
+        tokio::spawn(acme.run());
+    }
+    #[cfg(feature = "http3")]
+    if let Some(endpoint) = quic {
+        tokio::spawn(http3::serve(endpoint, state.clone()));
+    }
+    #[cfg(feature = "p2p")]
+    if let Some(direct) = &state.direct {
+        tokio::spawn(http3::serve(direct.endpoint(), state.clone()));
+    }
+    #[cfg(feature = "https")]
+    if let Some(tls) = tls {
+        return https::serve(listener, tls, state).await;
+    }
+    serve(listener, state).await
+}
+
+/// `a, b, c` for startup output.
+#[cfg(feature = "p2p")]
+fn join_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Confines the process to the share, the staging directory and whatever else it
+/// still needs once running: the announce file's directory, `extra_writable`, the SSH
+/// key, the config file for SIGHUP reloads, and `/etc` for name resolution and TLS
+/// roots.
+fn sandbox_process(
+    state: &AppState,
+    announce: Option<&announce::AnnounceTarget>,
+    extra_writable: &[&Path],
+) -> anyhow::Result<()> {
+    let mut writable = vec![state.root.as_path(), state.staging_dir.as_path()];
+    writable.extend_from_slice(extra_writable);
+    if let Some(announce::AnnounceTarget::Path(path)) = announce {
+        writable.push(match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        });
+    }
+    let key_path = std::env::var_os("SSH_KEY_PATH").map(PathBuf::from);
+    // The file alone, not its directory, which may well be the home directory
+    let config_path = config::path().filter(|path| path.is_file());
+    let mut readable = vec![Path::new("/etc")];
+    readable.extend(key_path.as_deref());
+    readable.extend(config_path.as_deref());
+
+    if !sandbox::restrict(&writable, &readable)? {
+        eprintln!(
+            "Warning: this kernel only partially supports Landlock; the sandbox is incomplete"
+        );
+    }
+    Ok(())
+}
+
+/// Accepts connections and serves the share, each connection in a task of its own.
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = state.connections.accept(&listener).await;
+        // Headers and small bodies go out as separate writes; don't let Nagle hold them back
+        let _ = stream.set_nodelay(true);
+        let connection = serve_connection(TokioIo::new(stream), peer, state.clone());
+        state.connections.spawn(peer, connection);
+    }
+}
+
+tokio::task_local! {
+    /// Closes the HTTP/1.1 connection being served once set. A download cancelled
+    /// through `/_admin` uses it, as a client that reads slowly leaves hyper with
+    /// nothing to ask the body for until its buffers drain.
+    static CLOSE_CONNECTION: watch::Sender<bool>;
+}
+
+/// Serves HTTP/1.1 requests on one accepted connection, plain or TLS.
+async fn serve_connection<I>(
+    io: I,
+    peer: SocketAddr,
+    state: Arc<AppState>,
+) -> Result<(), supervisor::Failure>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        let req = req.map(|body| body.map_err(std::io::Error::other).boxed());
+        handle_request(req, peer, state.clone())
+    });
+    let (close, mut closed) = watch::channel(false);
+    // Upgrades carry `--relay-hub` peers' connections
+    let connection = http1::Builder::new()
+        .serve_connection(io, service)
+        .with_upgrades();
+    let served = CLOSE_CONNECTION.scope(close, async {
+        tokio::select! {
+            served = connection => served,
+            _ = closed.wait_for(|closed| *closed) => Ok(()),
+        }
+    });
+    Ok(served.await?)
+}
+
+/// Writes the `--announce-json` document once the server is reachable: immediately in
+/// local mode, or as soon as the tunnel reports its external URL.
+fn announce_when_ready(
+    target: announce::AnnounceTarget,
+    local_addr: String,
+    auth_token: Option<String>,
+    mut url_rx: watch::Receiver<Option<String>>,
+    tunneled: bool,
+) {
+    tokio::spawn(async move {
+        let external_url = if tunneled {
+            match url_rx.wait_for(Option::is_some).await {
+                Ok(url) => url.clone(),
+                // Tunnel ended without ever reporting a URL
+                Err(_) => return,
+            }
+        } else {
+            None
+        };
+
+        let announcement = announce::Announcement {
+            local_addr,
+            external_url,
+            auth_token,
+            pid: std::process::id(),
+        };
+        if let Err(e) = target.write(&announcement) {
+            eprintln!("Error writing startup announcement: {}", e);
+        }
+    });
+}
+
+async fn handle_request(
+    req: Request<BoxBody>,
+    peer: SocketAddr,
+    state: Arc<AppState>,
+) -> std::io::Result<Response<BoxBody>> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let client = client_addr(&req, peer);
+    let host = req.headers().get(HOST).cloned();
+    let span = tracing::info_span!(
+        "request",
+        http.request.method = %method,
+        url.path = %path,
+        client.address = %client,
+        http.response.status_code = tracing::field::Empty,
+    );
+
+    let route = route(&state.base_path, &path);
+    let logging_in =
+        method == Method::POST && route == Some(passcode::LOGIN) || route == Some(passcode::LOGOUT);
+    // Answered without credentials, so the tunnel self-test works behind any of them
+    let checking_health = method == Method::GET && route == Some(health::ROUTE);
+    // Relayed requests are for a peer, which asks for its own credentials
+    let relayed = state.relay_hub.is_some() && route.is_some_and(relay::is_relayed);
+    let mut response = async {
+        if let Some(response) = state.intrusions.check(&client) {
+            return Ok(response);
+        }
+        if intrusion::is_traversal(&path) {
+            state
+                .intrusions
+                .record(intrusion::Violation::Traversal, &client, &method, &path);
+        }
+        if let (Some(passcode), Some(_)) = (&state.passcode, route)
+            && !logging_in
+            && !checking_health
+            && !relayed
+            && let Some(response) =
+                passcode.challenge(req.headers(), req.uri().query(), &path, &client, &state)
+        {
+            refused(&response, &req, &client, &state);
+            return Ok(response);
+        }
+        if let (Some(tokens), Some(route)) = (&state.share_tokens, route)
+            && !checking_health
+            && !relayed
+            && let Some(response) = tokens.challenge(
+                access::Role::needed(&method, route),
+                req.headers(),
+                req.uri().query(),
+            )
+        {
+            refused(&response, &req, &client, &state);
+            return Ok(response);
+        }
+        let policies = state.policies();
+        if let Some(route) = route
+            && !policies.is_empty()
+            && !relayed
+            && let Some(target) = ROUTER.scope(&req, route, &state)
+        {
+            let action = policy::Action::of(&method);
+            // A download through an alias reads the file it points to
+            let target = match action {
+                policy::Action::Download => state.aliases.resolve(&target).await.unwrap_or(target),
+                policy::Action::Upload => target,
+            };
+            let role = policy::requester(&state, req.headers(), req.uri().query());
+            if let Some(response) = policies.challenge(action, &target, role) {
+                return Ok(response);
+            }
+        }
+        #[cfg(feature = "plugins")]
+        if let (Some(plugins), Some(route)) = (&state.plugins, route)
+            && let Some(response) =
+                plugins.on_request(&method, route, req.uri().query(), req.headers(), &client)
+        {
+            return Ok(response);
+        }
+        #[cfg(feature = "scripting")]
+        if let (Some(script), Some(route)) = (&state.script, route)
+            && let Some(response) =
+                script.on_auth(&method, route, req.uri().query(), req.headers(), &client)
+        {
+            return Ok(response);
+        }
+        let Some(route) = route else {
+            // Relative links only resolve inside the prefix with a trailing slash
+            if !state.base_path.is_empty() && path == state.base_path {
+                return Ok(redirect(format!("{}/", path)));
+            }
+            return Ok(not_found());
+        };
+        ROUTER.dispatch(req, route, client, state.clone()).await
+    }
+    .instrument(span.clone())
+    .await;
+    if let Ok(response) = &mut response {
+        span.record("http.response.status_code", response.status().as_u16());
+        if let Some(alt_svc) = &state.alt_svc {
+            response.headers_mut().insert(ALT_SVC, alt_svc.clone());
+        }
+        if let Some(lan) = &state.lan {
+            lan.hint(&state.instance, host.as_ref(), response);
+        }
+        state
+            .headers()
+            .apply(route.unwrap_or(&path), response.headers_mut());
+    }
+    response
+}
+
+/// Every route the server answers, tried in order. Module APIs come before the file
+/// routes they would otherwise be taken for.
+static ROUTER: LazyLock<Router> = LazyLock::new(|| {
+    let mut router = Router::default();
+    passcode::routes(&mut router);
+    aliases::routes(&mut router);
+    tags::routes(&mut router);
+    admin::routes(&mut router);
+    trash::routes(&mut router);
+    health::routes(&mut router);
+    relay::routes(&mut router);
+    #[cfg(feature = "signing")]
+    {
+        let trusting = |_: &Request<BoxBody>, state: &AppState| state.trusted_keys.is_some();
+        #[cfg(feature = "archives")]
+        router
+            .post(batch::ROUTE, async |_, _| Ok(unsigned_upload()))
+            .when(trusting);
+        router
+            .post(dedup::ROUTE, async |_, _| Ok(unsigned_upload()))
+            .when(trusting);
+        router
+            .patch("/{*path}", async |_, _| Ok(unsigned_upload()))
+            .when(trusting);
+    }
+    dedup::routes(&mut router);
+    commands::routes(&mut router);
+    #[cfg(feature = "search")]
+    search::routes(&mut router);
+    uploads::routes(&mut router);
+    #[cfg(feature = "p2p")]
+    p2p::routes(&mut router);
+    #[cfg(feature = "signing")]
+    signing::routes(&mut router);
+    openapi::routes(&mut router);
+    feed::routes(&mut router);
+    #[cfg(feature = "qr")]
+    qr::routes(&mut router);
+    follow::routes(&mut router);
+    website::routes(&mut router);
+    listing::routes(&mut router);
+    router
+        .get("/{*path}", async |req, cx| {
+            get_file(
+                req.headers(),
+                &cx.path,
+                req.uri().query(),
+                cx.client,
+                &cx.state,
+            )
+            .await
+        })
+        .scoped("path");
+    router
+        .head("/{*path}", async |req, cx| {
+            head_file(req.headers(), &cx.path, &cx.state).await
+        })
+        .scoped("path");
+    #[cfg(feature = "archives")]
+    batch::routes(&mut router);
+    fileops::routes(&mut router);
+    router
+        .post("/{*path}", async |req, cx| {
+            post_file(req, &cx.path, cx.client, &cx.state).await
+        })
+        .scoped("path");
+    router
+        .patch("/{*path}", async |req, cx| {
+            patch_file(req, &cx.path, cx.client, &cx.state).await
+        })
+        .scoped("path");
+    rmdir::routes(&mut router);
+    router
+        .delete("/{*path}", async |_, cx| {
+            trash::delete(&cx.path, &cx.state).await
+        })
+        .scoped("path");
+    router
+});
+
+/// With `--trusted-keys`, uploads that can't carry a signature are refused.
+#[cfg(feature = "signing")]
+fn unsigned_upload() -> Response<BoxBody> {
+    openpgp::unsigned("only whole-file POST uploads can carry a signature")
+}
+
+/// The next frame of an upload's body, or an error once the transfer is cancelled.
+async fn next_frame(
+    body: &mut BoxBody,
+    progress: &TransferProgress,
+) -> Option<std::io::Result<Frame<Bytes>>> {
+    tokio::select! {
+        frame = body.frame() => frame,
+        () = progress.cancelled() => Some(Err(std::io::Error::other(
+            "transfer cancelled by the server",
+        ))),
+    }
+}
+
+/// Winds up an upload whose client went away mid-body: the transfer counts as
+/// interrupted and notifiers hear about it. Gives back the error ending the request.
+fn upload_aborted(
+    state: &AppState,
+    progress: TransferProgress,
+    filename: &str,
+    client: String,
+    outcome: &str,
+    error: std::io::Error,
+) -> std::io::Error {
+    state.events.emit(Event::UploadAborted {
+        file: filename.to_string(),
+        bytes: progress.bytes(),
+        peer: client,
+    });
+    progress.abort(outcome);
+    error
+}
+
+/// Winds up an upload whose body didn't match its `Content-Length` like one whose
+/// client went away, answering `400 Bad Request`.
+fn length_mismatch(
+    state: &AppState,
+    progress: TransferProgress,
+    filename: &str,
+    client: String,
+    outcome: &str,
+    received: u64,
+    declared: u64,
+) -> Response<BoxBody> {
+    let message = format!(
+        "Received {} bytes but Content-Length declared {}",
+        received, declared
+    );
+    state.events.emit(Event::UploadAborted {
+        file: filename.to_string(),
+        bytes: progress.bytes(),
+        peer: client,
+    });
+    progress.abort(outcome);
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(full(message))
+        .unwrap()
+}
+
+/// The part of `path` below `--base-path`, or `None` when it lies outside the prefix.
+fn route<'a>(base_path: &str, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(base_path)
+        .filter(|route| route.starts_with('/'))
+}
+
+/// Records a wrong passcode or share token; a request that brought none is only
+/// being asked for one.
+fn refused<B>(response: &Response<BoxBody>, req: &Request<B>, client: &str, state: &AppState) {
+    let status = response.status();
+    if (status == StatusCode::UNAUTHORIZED || status == StatusCode::TOO_MANY_REQUESTS)
+        && intrusion::carries_credentials(req.headers(), req.uri().query())
+    {
+        state.intrusions.record(
+            intrusion::Violation::AuthFailure,
+            client,
+            req.method(),
+            req.uri().path(),
+        );
+    }
+}
+
+/// Best-effort client address for logs, notifications and bans.
+///
+/// Requests arriving through the tunnel come from loopback, so the address the
+/// provider forwarded is preferred in that case.
+fn client_addr<B>(req: &Request<B>, peer: SocketAddr) -> String {
+    if peer.ip().is_loopback()
+        && let Some(forwarded) = intrusion::forwarded_client(req.headers())
+    {
+        return forwarded.to_string();
+    }
+    peer.ip().to_string()
+}
+
+/// Lists the share, leaving out what a `[[policy]]` keeps the requester from
+/// downloading.
+async fn list_files(
+    headers: &hyper::HeaderMap,
+    query: Option<&str>,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let wanted = tags::from_query(query);
+    let role = policy::requester(state, headers, query);
+    let (format, page) = match listing::Format::from_query(query)
+        .and_then(|format| Ok((format, listing::Page::from_query(query)?)))
+    {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(e))
+                .unwrap());
+        }
+    };
+    if let Some(format) = format {
+        return listing::get(format, &wanted, role, page, state).await;
+    }
+    match fs::read_dir(&state.root).await {
+        Ok(mut entries) => {
+            let tagged = state.tags.all().await;
+            let policies = state.policies();
+            let mut files = Vec::new();
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if let Ok(file_name) = entry.file_name().into_string()
+                    && file_name != META_DIR
+                    && tags::matches(tagged.get(&file_name), &wanted)
+                    && policies.allows(policy::Action::Download, &file_name, role)
+                {
+                    files.push(file_name);
+                }
+            }
+            files.sort();
+            #[cfg(feature = "plugins")]
+            let files = match plugins::listing(files, state) {
+                Ok(files) => files,
+                Err(e) => return Ok(plugins::failed(&e)),
+            };
+            let page = page.select(files);
+
+            // Names can't hold a tab, so it sets the tags apart unambiguously
+            let lines: Vec<String> = page
+                .names
+                .iter()
+                .map(|name| match tagged.get(name) {
+                    Some(tags) => format!("{}\t[{}]", name, tags::join(tags)),
+                    None => name.clone(),
+                })
+                .collect();
+            let body = if lines.is_empty() {
+                "No files available\n".to_string()
+            } else {
+                format!("Available files:\n{}\n", lines.join("\n"))
+            };
+
+            let response = Response::builder().status(StatusCode::OK);
+            Ok(page.headers(response).body(full(body)).unwrap())
+        }
+        Err(e) => {
+            eprintln!("Error reading directory: {}", e);
+            Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error listing files: {}", e)))
+                .unwrap())
+        }
+    }
+}
+
+async fn get_file(
+    headers: &hyper::HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    client: String,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let alias = state.aliases.resolve(path.trim_start_matches('/')).await;
+    let filename = alias.as_deref().unwrap_or(path.trim_start_matches('/'));
+
+    if state.auto_index && website::in_site(state, filename).await {
+        return website::get(headers, path, query, client, state).await;
+    }
+    if filename.is_empty() {
+        return list_files(headers, query, state).await;
+    }
+    #[cfg(feature = "scripting")]
+    let rewritten = match scripting::download_name(filename, &client, state) {
+        ControlFlow::Continue(name) => name,
+        ControlFlow::Break(response) => return Ok(response),
+    };
+    #[cfg(feature = "scripting")]
+    let filename = rewritten.as_str();
+
+    // Prevent directory traversal attacks
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(e.to_string()))
+            .unwrap());
+    }
+
+    let encoding = match compress::Encoding::from_query(query) {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(e))
+                .unwrap());
+        }
+    };
+
+    let file = match sandbox::open_beneath(&state.root, filename, sandbox::Access::Read).await {
+        Ok(file) => file,
+        Err(_) => return Ok(file_not_found(filename)),
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(file_not_found(filename)),
+    };
+    // A file stored compressed goes out as it is to clients asking for zstd anyway
+    let packed = state.packed.size(filename, &metadata).await;
+    let stored = packed.is_some()
+        && match encoding {
+            Some(encoding) => encoding == compress::Encoding::Zstd,
+            None => !headers.contains_key(RANGE) && packing::accepts_zstd(headers),
+        };
+
+    // The client's copy is current, as `holodeck get` checks for its cache
+    let etag = match stored {
+        true => conditional::zstd_etag(&conditional::etag(&metadata)),
+        false => conditional::etag(&metadata),
+    };
+    if conditional::is_fresh(headers, &etag) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .body(full(""))
+            .unwrap());
+    }
+    let meta = state.user_meta.get(filename, &metadata).await;
+    let (file, len) = match packed {
+        Some(size) if !stored => (packing::Contents::unpacking(file)?, size),
+        _ => (packing::Contents::Plain(file), metadata.len()),
+    };
+    let modified = metadata.modified().ok();
+
+    // Compressed output can't be addressed by byte offsets, so ranges only apply to plain downloads
+    let range = match headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) if encoding.is_none() => match parse_range(value, len) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(full(""))
+                    .unwrap());
+            }
+        },
+        _ => None,
+    };
+
+    let Some(slot) = state.download_slots.try_acquire(&client) else {
+        return Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, "5")
+            .body(full("Too many downloads in progress from this address"))
+            .unwrap());
+    };
+
+    let mut file = file;
+    let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+    if start > 0 {
+        file.skip(start).await?;
+    }
+    let sent = if len == 0 { 0 } else { end - start + 1 };
+    let quota = match reserve_quota(headers, query, filename, sent, state) {
+        ControlFlow::Continue(quota) => quota,
+        ControlFlow::Break(response) => return Ok(response),
+    };
+
+    let progress = state.transfers.start("GET", filename, Some(sent));
+    progress.set_client(&client);
+    let _ = CLOSE_CONNECTION.try_with(|close| progress.close_on_cancel(close.clone()));
+    let download = Download {
+        file,
+        filename: filename.to_string(),
+        len: sent,
+        progress,
+        events: state.events.clone(),
+        client,
+        chunk_size: state.io_buffer,
+        span: tracing::info_span!("download", file = filename, bytes = len),
+        compressor: None,
+        quota,
+        _slot: Some(slot),
+    };
+
+    // The compressed size isn't known up front, so the body is chunked instead
+    if let Some(encoding) = encoding {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", encoding.content_type())
+            .header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    filename,
+                    encoding.extension()
+                ),
+            )
+            .body(stream_file(Download {
+                compressor: match stored {
+                    true => None,
+                    false => Some(compress::Compressor::new(encoding)?),
+                },
+                ..download
+            }))
+            .unwrap();
+        user_meta::to_headers(&meta, response.headers_mut());
+        return Ok(response);
+    }
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", state.mime_types().content_type(filename))
+        .header(CONTENT_LENGTH, sent)
+        .header(ACCEPT_RANGES, "bytes")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        );
+    if let Some(modified) = modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    response = response.header(ETAG, etag);
+    if packed.is_some() {
+        response = response.header(VARY, "accept-encoding");
+    }
+    if stored {
+        response = response.header(CONTENT_ENCODING, "zstd");
+    }
+    if range.is_some() {
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+    let mut response = response.body(stream_file(download)).unwrap();
+    user_meta::to_headers(&meta, response.headers_mut());
+    Ok(response)
+}
+
+/// Answers `HEAD` for a shared file with what a download would start with, its
+/// `X-Meta-*` metadata included, without reading it.
+async fn head_file(
+    headers: &hyper::HeaderMap,
+    path: &str,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let alias = state.aliases.resolve(path.trim_start_matches('/')).await;
+    let filename = alias.as_deref().unwrap_or(path.trim_start_matches('/'));
+    if filename.is_empty() {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(full(""))
+            .unwrap());
+    }
+    if paths::validate_filename(filename).is_err() || filename == META_DIR {
+        return Ok(not_found());
+    }
+    let metadata = match fs::symlink_metadata(state.root.join(filename)).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(not_found()),
+    };
+
+    let packed = state.packed.size(filename, &metadata).await;
+    let stored = packed.is_some() && packing::accepts_zstd(headers);
+    let etag = match stored {
+        true => conditional::zstd_etag(&conditional::etag(&metadata)),
+        false => conditional::etag(&metadata),
+    };
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", state.mime_types().content_type(filename))
+        .header(
+            CONTENT_LENGTH,
+            packed.filter(|_| !stored).unwrap_or(metadata.len()),
+        )
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, etag);
+    if packed.is_some() {
+        response = response.header(VARY, "accept-encoding");
+    }
+    if stored {
+        response = response.header(CONTENT_ENCODING, "zstd");
+    }
+    if let Ok(modified) = metadata.modified() {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    let mut response = response.body(full("")).unwrap();
+    let meta = state.user_meta.get(filename, &metadata).await;
+    user_meta::to_headers(&meta, response.headers_mut());
+    Ok(response)
+}
+
+/// Sets `len` bytes aside from the quota of the link the request was made with, or
+/// the `403` to send instead when they would go over it.
+fn reserve_quota(
+    headers: &hyper::HeaderMap,
+    query: Option<&str>,
+    filename: &str,
+    len: u64,
+    state: &AppState,
+) -> ControlFlow<Response<BoxBody>, Option<limits::Reservation>> {
+    let Some(meter) = state
+        .share_tokens
+        .as_ref()
+        .and_then(|tokens| tokens.meter(headers, query))
+    else {
+        return ControlFlow::Continue(None);
+    };
+    match meter.reserve(len) {
+        Ok(reservation) => ControlFlow::Continue(Some(reservation)),
+        Err(left) => {
+            eprintln!(
+                "GET: Refused '{}' ({} bytes), {} bytes left on the read link's quota",
+                filename, len, left
+            );
+            ControlFlow::Break(
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(full(format!(
+                        "This link's download quota is used up: {} bytes left, '{}' is {} bytes",
+                        left, filename, len
+                    )))
+                    .unwrap(),
+            )
+        }
+    }
+}
+
+fn file_not_found(filename: &str) -> Response<BoxBody> {
+    eprintln!("GET: File '{}' not found", filename);
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(full(format!("File '{}' not found", filename)))
+        .unwrap()
+}
+
+/// An in-flight download, threaded through the response body stream.
+struct Download {
+    file: packing::Contents,
+    filename: String,
+    len: u64,
+    progress: TransferProgress,
+    events: Events,
+    client: String,
+    chunk_size: usize,
+    /// Kept open until the body has been streamed, not just until headers are sent
+    span: tracing::Span,
+    /// Set for `?compress=` downloads; progress still counts bytes read from the file
+    compressor: Option<compress::Compressor>,
+    /// Counts against the link's `--read-link-quota` and `--read-link-rate`, if any
+    quota: Option<limits::Reservation>,
+    /// Counts against `--max-downloads-per-client` until the body is dropped
+    _slot: Option<limits::Slot>,
+}
+
+impl Download {
+    /// Records the download as served once its last byte has been read.
+    fn complete(&mut self) {
+        let progress = &mut self.progress;
+        progress.println(format!(
+            "GET: Served file '{}' ({} bytes)",
+            self.filename,
+            progress.bytes()
+        ));
+        self.span
+            .in_scope(|| tracing::info!(bytes = progress.bytes(), "download completed"));
+        self.events.emit(Event::DownloadCompleted {
+            file: self.filename.clone(),
+            bytes: progress.bytes(),
+            peer: self.client.clone(),
+        });
+        progress.finish();
+    }
+}
+
+/// Streams a file to the client chunk by chunk, advancing its progress bar.
+fn stream_file(mut download: Download) -> BoxBody {
+    // hyper never polls a body whose Content-Length is zero, so an empty file or
+    // range is served as soon as its headers are
+    if download.len == 0 {
+        download.complete();
+    }
+    let stream = futures_util::stream::try_unfold(download, |mut download| async move {
+        loop {
+            if download.progress.is_cancelled() {
+                return Err(std::io::Error::other("transfer cancelled by the server"));
+            }
+            let mut buf = BytesMut::with_capacity(download.chunk_size);
+            // A range ends before the file does
+            let remaining = download.len - download.progress.bytes();
+            if remaining == 0
+                || (&mut download.file)
+                    .take(remaining)
+                    .read_buf(&mut buf)
+                    .await?
+                    == 0
+            {
+                return match download.compressor.take() {
+                    Some(compressor) => Ok(Some((Frame::data(compressor.finish()?), download))),
+                    None => Ok(None),
+                };
+            }
+            if let Some(quota) = &mut download.quota {
+                quota.send(buf.len() as u64).await;
+            }
+            download.progress.inc(buf.len() as u64);
+            // hyper stops polling once Content-Length bytes are sent, so don't wait for EOF
+            if download.progress.bytes() >= download.len {
+                download.complete();
+            }
+            let data = match &mut download.compressor {
+                Some(compressor) => compressor.compress(&buf)?,
+                None => buf.freeze(),
+            };
+            // The compressor may hold a chunk back until it has enough input
+            if !data.is_empty() {
+                return Ok(Some((Frame::data(data), download)));
+            }
+        }
+    });
+    StreamBody::new(stream).boxed()
+}
+
+async fn post_file(
+    req: Request<BoxBody>,
+    path: &str,
+    client: String,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let filename = path.trim_start_matches('/');
+    #[cfg(feature = "scripting")]
+    let rewritten =
+        match scripting::upload_name(&Method::POST, filename, req.headers(), &client, state) {
+            ControlFlow::Continue(name) => name,
+            ControlFlow::Break(response) => return Ok(response),
+        };
+    #[cfg(feature = "scripting")]
+    let filename = rewritten.as_str();
+
+    // Prevent directory traversal attacks
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(e.to_string()))
+            .unwrap());
+    }
+    if filename == META_DIR {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("Invalid filename"))
+            .unwrap());
+    }
+
+    let Some(_lock) = state.writes.try_lock(filename) else {
+        return Ok(write_in_progress(filename));
+    };
+
+    let file_path = state.root.join(filename);
+    let preconditions = conditional::Preconditions::from_headers(req.headers());
+    if let Err(response) = preconditions.check(filename, &file_path).await {
+        return Ok(response);
+    }
+
+    #[cfg(feature = "signing")]
+    let mut signature = match &state.trusted_keys {
+        Some(_) => match openpgp::Signature::from_headers(req.headers()) {
+            Ok(signature) => Some(signature),
+            Err(e) => return Ok(openpgp::unsigned(&e)),
+        },
+        None => None,
+    };
+
+    let meta = user_meta::from_headers(req.headers());
+
+    let total = storage::declared_length(req.headers());
+
+    // Refuse up front rather than failing halfway through the write
+    if let Some(total) = total
+        && !storage::has_room_for(&state.staging_dir, &file_path, total).await
+    {
+        eprintln!(
+            "POST: Not enough disk space for '{}' ({} bytes)",
+            filename, total
+        );
+        return Ok(Response::builder()
+            .status(StatusCode::INSUFFICIENT_STORAGE)
+            .body(full(format!(
+                "Not enough disk space for '{}' ({} bytes)",
+                filename, total
+            )))
+            .unwrap());
+    }
+
+    // Write to the staging directory first so partial uploads never show up in the share
+    let (staged, file) = match StagedFile::create(&state.staging_dir, filename).await {
+        Ok(staged) => staged,
+        Err(e) if storage::is_disk_full(&e) => {
+            return Ok(write_failed(
+                state,
+                "POST",
+                filename,
+                &state.staging_dir,
+                &e,
+            ));
+        }
+        Err(e) => {
+            eprintln!("POST: Error creating file '{}': {}", filename, e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error creating file: {}", e)))
+                .unwrap());
+        }
+    };
+    let mut file = BufWriter::with_capacity(state.io_buffer, file);
+
+    // Stream the request body to disk as it arrives
+    let mut progress = state.transfers.start("POST", filename, total);
+    progress.set_client(&client);
+    uploads::tag(&progress, req.headers());
+    let mut hasher = state.verify_uploads.then(Sha256::new);
+    let mut sniffer = state.block_executables.then(executables::Sniffer::default);
+    let mut body = req.into_body();
+    while let Some(frame) = next_frame(&mut body, &progress).await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            // Dropping `staged` deletes what arrived so far
+            Err(e) => {
+                let outcome = "partial upload discarded";
+                return Err(upload_aborted(
+                    state, progress, filename, client, outcome, e,
+                ));
+            }
+        };
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        let received = progress.bytes() + data.len() as u64;
+        if let Some(declared) = total
+            && !storage::fits_length(declared, received, false)
+        {
+            let outcome = "partial upload discarded";
+            return Ok(length_mismatch(
+                state, progress, filename, client, outcome, received, declared,
+            ));
+        }
+        if let Some(sniffer) = sniffer.as_mut()
+            && let Some(kind) = sniffer.inspect(&data)
+        {
+            eprintln!("POST: Rejected '{}' from {}: {}", filename, client, kind);
+            return Ok(executables::refuse(filename, kind));
+        }
+        // Dropping `staged` deletes what was written so far
+        if let Err(e) = file.write_all(&data).await {
+            return Ok(write_failed(
+                state,
+                "POST",
+                filename,
+                &state.staging_dir,
+                &e,
+            ));
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&data);
+        }
+        #[cfg(feature = "signing")]
+        if let Some(signature) = signature.as_mut() {
+            signature.update(&data);
+        }
+        progress.inc(data.len() as u64);
+    }
+    // Dropping `staged` deletes a body that ended early
+    if let Some(declared) = total
+        && !storage::fits_length(declared, progress.bytes(), true)
+    {
+        let received = progress.bytes();
+        let outcome = "partial upload discarded";
+        return Ok(length_mismatch(
+            state, progress, filename, client, outcome, received, declared,
+        ));
+    }
+
+    if let Err(e) = file.flush().await {
+        return Ok(write_failed(
+            state,
+            "POST",
+            filename,
+            &state.staging_dir,
+            &e,
+        ));
+    }
+
+    if state.fsync
+        && let Err(e) = file.get_ref().sync_all().await
+    {
+        eprintln!("POST: Error syncing file '{}': {}", filename, e);
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full(format!("Error syncing file: {}", e)))
+            .unwrap());
+    }
+    drop(file);
+
+    if let Some(hasher) = hasher {
+        let expected = hasher.finalize();
+        match storage::sha256_file(staged.path()).await {
+            Ok(actual) if actual[..] == expected[..] => {}
+            Ok(actual) => {
+                eprintln!(
+                    "POST: Verification of '{}' failed: received sha256 {}, stored {}",
+                    filename,
+                    hex::encode(expected),
+                    hex::encode(actual)
+                );
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full("Upload verification failed: checksum mismatch"))
+                    .unwrap());
+            }
+            Err(e) => {
+                eprintln!("POST: Error verifying file '{}': {}", filename, e);
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full(format!("Error verifying file: {}", e)))
+                    .unwrap());
+            }
+        }
+    }
+
+    #[cfg(feature = "signing")]
+    if let (Some(signature), Some(keys)) = (signature, &state.trusted_keys) {
+        match signature.verify(keys) {
+            Ok(fingerprint) => state
+                .transfers
+                .println(format!("POST: '{}' is signed by {}", filename, fingerprint)),
+            Err(e) => {
+                eprintln!("POST: Rejected '{}' from {}: {}", filename, client, e);
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(full(format!("Upload rejected: {}", e)))
+                    .unwrap());
+            }
+        }
+    }
+
+    #[cfg(feature = "plugins")]
+    let (renamed, _renamed_lock) =
+        match plugins::upload_name(filename, progress.bytes(), &client, state) {
+            Ok(renamed) => renamed,
+            Err(e) => return Ok(plugins::failed(&e)),
+        };
+    #[cfg(feature = "plugins")]
+    let (filename, file_path) = (renamed.as_str(), state.root.join(&renamed));
+
+    // The file may have changed some other way while the body was arriving
+    if let Err(response) = preconditions.check(filename, &file_path).await {
+        return Ok(response);
+    }
+    if let Err(e) = staged.persist(&file_path).await {
+        eprintln!("POST: Error moving file '{}' into place: {}", filename, e);
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full(format!("Error storing file: {}", e)))
+            .unwrap());
+    }
+
+    if state.fsync
+        && let Err(e) = storage::sync_dir(&state.root).await
+    {
+        eprintln!("POST: Error syncing file '{}': {}", filename, e);
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full(format!("Error syncing file: {}", e)))
+            .unwrap());
+    }
+
+    // Before the metadata, which is kept for the file as it ends up on disk
+    pack(state, "POST", filename).await;
+    if let Err(e) = state.user_meta.set(&state.root, filename, meta).await {
+        eprintln!("POST: Error saving metadata of '{}': {}", filename, e);
+    }
+
+    let size = progress.bytes();
+    progress.finish();
+    state.transfers.println(format!(
+        "POST: Received file '{}' ({} bytes)",
+        filename, size
+    ));
+    state.events.emit(Event::UploadCompleted {
+        file: filename.to_string(),
+        bytes: size,
+        peer: client,
+    });
+    let mut response = Response::builder().status(StatusCode::CREATED);
+    if let Ok(metadata) = fs::metadata(&file_path).await {
+        response = response.header(ETAG, conditional::etag(&metadata));
+    }
+    Ok(response
+        .body(full(format!(
+            "File '{}' uploaded successfully ({} bytes)",
+            filename, size
+        )))
+        .unwrap())
+}
+
+/// Appends to a file, or resumes a partial upload when `Content-Range` declares a total.
+///
+/// - `Content-Range: bytes <start>-<end>/<total>` resumes an upload kept in the staging
+///   directory; `<start>` must equal the bytes received so far, and the file moves into
+///   the share once `<total>` bytes have arrived.
+/// - `Content-Range: bytes <start>-<end>/*` or no header appends to the shared file itself.
+///
+/// Every response carries `X-Upload-Offset` with the current size, so a client that
+/// lost track can send a mismatched range to find out where to continue.
+async fn patch_file(
+    req: Request<BoxBody>,
+    path: &str,
+    client: String,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let filename = path.trim_start_matches('/');
+    #[cfg(feature = "scripting")]
+    let rewritten =
+        match scripting::upload_name(&Method::PATCH, filename, req.headers(), &client, state) {
+            ControlFlow::Continue(name) => name,
+            ControlFlow::Break(response) => return Ok(response),
+        };
+    #[cfg(feature = "scripting")]
+    let filename = rewritten.as_str();
+
+    // Prevent directory traversal attacks
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(e.to_string()))
+            .unwrap());
+    }
+    if filename == META_DIR {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("Invalid filename"))
+            .unwrap());
+    }
+
+    let range = match req.headers().get(CONTENT_RANGE) {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(parse_content_range) {
+            Some(range) => Some(range),
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(full(
+                        "Invalid Content-Range, expected 'bytes <start>-<end>/<total|*>'",
+                    ))
+                    .unwrap());
+            }
+        },
+    };
+
+    let Some(_lock) = state.writes.try_lock(filename) else {
+        return Ok(write_in_progress(filename));
+    };
+    if let Err(response) = conditional::Preconditions::from_headers(req.headers())
+        .check(filename, &state.root.join(filename))
+        .await
+    {
+        return Ok(response);
+    }
+
+    let total = range.and_then(|r| r.total);
+    if let Some(total) = total
+        && let Some(session) = state.resumable.get(filename).await
+        && session.total != total
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::CONFLICT)
+            .header("X-Upload-Offset", session.offset)
+            .body(full(format!(
+                "An upload of '{}' with {} bytes is in progress; resume it from {}",
+                filename, session.total, session.offset
+            )))
+            .unwrap());
+    }
+    let declared = storage::declared_length(req.headers());
+    let meta = user_meta::from_headers(req.headers());
+    let (target_dir, target_name) = match total {
+        Some(_) => (&state.staging_dir, format!("{}.resume", filename)),
+        None => (&state.root, filename.to_string()),
+    };
+    let target = target_dir.join(&target_name);
+    // A file stored compressed is appended to as it was uploaded
+    if total.is_none()
+        && let Err(e) = state
+            .packed
+            .unpack(&state.root, &state.staging_dir, filename)
+            .await
+    {
+        return Ok(write_failed(state, "PATCH", filename, target_dir, &e));
+    }
+
+    let file = match sandbox::open_beneath(target_dir, &target_name, sandbox::Access::Append).await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("PATCH: Error opening file '{}': {}", filename, e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error opening file: {}", e)))
+                .unwrap());
+        }
+    };
+    let offset = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            eprintln!("PATCH: Error opening file '{}': {}", filename, e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error opening file: {}", e)))
+                .unwrap());
+        }
+    };
+
+    if let Some(range) = range
+        && range.start != offset
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::CONFLICT)
+            .header("X-Upload-Offset", offset)
+            .body(full(format!(
+                "Range starts at {} but '{}' has {} bytes; resume from {}",
+                range.start, filename, offset, offset
+            )))
+            .unwrap());
+    }
+
+    let mut file = BufWriter::with_capacity(state.io_buffer, file);
+    let mut progress = state
+        .transfers
+        .start("PATCH", filename, range.map(|r| r.end - r.start + 1));
+    progress.set_client(&client);
+    uploads::tag(&progress, req.headers());
+    // Only a request starting the file shows what kind of file it is
+    let mut sniffer = (state.block_executables && offset == 0).then(executables::Sniffer::default);
+    let mut body = req.into_body();
+    while let Some(frame) = next_frame(&mut body, &progress).await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            // Like a body shorter than its range, a broken one is rolled back entirely
+            Err(e) => {
+                let _ = file.get_ref().set_len(offset).await;
+                let outcome = "partial data rolled back";
+                return Err(upload_aborted(
+                    state, progress, filename, client, outcome, e,
+                ));
+            }
+        };
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        let received = progress.bytes() + data.len() as u64;
+        if let Some(declared) = declared
+            && !storage::fits_length(declared, received, false)
+        {
+            let _ = file.get_ref().set_len(offset).await;
+            let outcome = "partial data rolled back";
+            return Ok(length_mismatch(
+                state, progress, filename, client, outcome, received, declared,
+            ));
+        }
+        if let Some(sniffer) = sniffer.as_mut()
+            && let Some(kind) = sniffer.inspect(&data)
+        {
+            eprintln!("PATCH: Rejected '{}' from {}: {}", filename, client, kind);
+            // Nothing came before this request, so nothing of the file is kept
+            drop(file);
+            let _ = fs::remove_file(&target).await;
+            if total.is_some() {
+                let _ = state.resumable.finish(filename).await;
+            }
+            return Ok(executables::refuse(filename, kind));
+        }
+        if let Err(e) = file.write_all(&data).await {
+            // Rolled back like a broken body, so a full disk leaves the file as it was
+            let _ = file.get_ref().set_len(offset).await;
+            return Ok(write_failed(state, "PATCH", filename, target_dir, &e));
+        }
+        progress.inc(data.len() as u64);
+    }
+    let written = progress.bytes();
+
+    let flushed = match file.flush().await {
+        Ok(()) if state.fsync => file.get_ref().sync_all().await,
+        result => result,
+    };
+    if let Err(e) = flushed {
+        let _ = file.get_ref().set_len(offset).await;
+        return Ok(write_failed(state, "PATCH", filename, target_dir, &e));
+    }
+
+    // A body that doesn't match its declared length or range is rolled back entirely
+    if let Some(declared) = declared
+        && !storage::fits_length(declared, written, true)
+    {
+        let _ = file.get_ref().set_len(offset).await;
+        let outcome = "partial data rolled back";
+        return Ok(length_mismatch(
+            state, progress, filename, client, outcome, written, declared,
+        ));
+    }
+    if let Some(range) = range
+        && written != range.end - range.start + 1
+    {
+        let _ = file.get_ref().set_len(offset).await;
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header("X-Upload-Offset", offset)
+            .body(full(format!(
+                "Received {} bytes but Content-Range declared {}",
+                written,
+                range.end - range.start + 1
+            )))
+            .unwrap());
+    }
+    drop(file);
+    progress.finish();
+
+    let size = offset + written;
+    if total == Some(size) {
+        // Headers sent when the upload started count for the file unless replaced
+        let meta = match state.resumable.finish(filename).await {
+            Ok(Some(session)) if meta.is_empty() => session.meta,
+            Ok(_) => meta,
+            Err(e) => {
+                eprintln!("PATCH: Error ending the session of '{}': {}", filename, e);
+                meta
+            }
+        };
+        #[cfg(feature = "plugins")]
+        let (renamed, _renamed_lock) = match plugins::upload_name(filename, size, &client, state) {
+            Ok(renamed) => renamed,
+            Err(e) => return Ok(plugins::failed(&e)),
+        };
+        #[cfg(feature = "plugins")]
+        let filename = renamed.as_str();
+
+        if let Err(e) = storage::move_file(&target, &state.root.join(filename)).await {
+            eprintln!("PATCH: Error moving file '{}' into place: {}", filename, e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error storing file: {}", e)))
+                .unwrap());
+        }
+        pack(state, "PATCH", filename).await;
+        if let Err(e) = state.user_meta.set(&state.root, filename, meta).await {
+            eprintln!("PATCH: Error saving metadata of '{}': {}", filename, e);
+        }
+        state.transfers.println(format!(
+            "PATCH: Completed resumable upload of '{}' ({} bytes)",
+            filename, size
+        ));
+        state.events.emit(Event::UploadCompleted {
+            file: filename.to_string(),
+            bytes: size,
+            peer: client,
+        });
+        let mut response = Response::builder().status(StatusCode::CREATED);
+        if let Ok(metadata) = fs::metadata(state.root.join(filename)).await {
+            response = response.header(ETAG, conditional::etag(&metadata));
+        }
+        return Ok(response
+            .header("X-Upload-Offset", size)
+            .body(full(format!(
+                "File '{}' uploaded successfully ({} bytes)",
+                filename, size
+            )))
+            .unwrap());
+    }
+
+    if let Some(total) = total
+        && let Err(e) = state
+            .resumable
+            .progress(filename, total, size, &target, meta)
+            .await
+    {
+        eprintln!("PATCH: Error saving the session of '{}': {}", filename, e);
+    }
+    state.transfers.println(format!(
+        "PATCH: Appended {} bytes to '{}' ({} bytes so far)",
+        written, filename, size
+    ));
+    let mut response = Response::builder().status(StatusCode::OK);
+    if total.is_none()
+        && let Ok(metadata) = fs::metadata(&target).await
+    {
+        response = response.header(ETAG, conditional::etag(&metadata));
+    }
+    Ok(response
+        .header("X-Upload-Offset", size)
+        .body(full(format!(
+            "Appended {} bytes to '{}' ({} bytes so far)",
+            written, filename, size
+        )))
+        .unwrap())
+}
+
+/// Resolves a `Range: bytes=<start>-<end>` header (also `<start>-` and `-<suffix>`)
+/// against a file of `len` bytes, giving the first and last byte to send.
+///
+/// `Ok(None)` means the whole file: a header that can't be parsed or asks for several
+/// ranges is ignored, as HTTP allows. `Err` means no byte of the range exists.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(range) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    let Some((start, end)) = range.split_once('-').filter(|_| !range.contains(',')) else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return Err(()),
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return Ok(None),
+                },
+            };
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// A parsed `Content-Range: bytes <start>-<end>/<total|*>` request header
+#[derive(Debug, Clone, Copy)]
+struct ContentRange {
+    start: u64,
+    end: u64,
+    total: Option<u64>,
+}
+
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+
+    if end < start || total.is_some_and(|total| end >= total) {
+        return None;
+    }
+    Some(ContentRange { start, end, total })
+}
+
+/// The response to an upload that couldn't be written to `dir`: `507 Insufficient
+/// Storage`, with a warning on the console, when the disk is full, `500` otherwise.
+/// The caller discards what was written so far.
+fn write_failed(
+    state: &AppState,
+    method: &str,
+    filename: &str,
+    dir: &Path,
+    e: &std::io::Error,
+) -> Response<BoxBody> {
+    if storage::is_disk_full(e) {
+        state.disk_full.alarm(method, filename, dir);
+        return Response::builder()
+            .status(StatusCode::INSUFFICIENT_STORAGE)
+            .body(full(format!(
+                "Not enough disk space to store '{}'",
+                filename
+            )))
+            .unwrap();
+    }
+    eprintln!("{}: Error writing file '{}': {}", method, filename, e);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(full(format!("Error writing file: {}", e)))
+        .unwrap()
+}
+
+/// Compresses the upload just stored as `filename` with `--compress-storage`. It is
+/// kept as it is if that fails.
+async fn pack(state: &AppState, method: &str, filename: &str) {
+    if !state.compress_storage {
+        return;
+    }
+    if let Err(e) = state
+        .packed
+        .pack(&state.root, &state.staging_dir, filename, state.fsync)
+        .await
+    {
+        eprintln!("{}: Error compressing file '{}': {}", method, filename, e);
+    }
+}
+
+fn write_in_progress(filename: &str) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(full(format!(
+            "File '{}' is being written by another request",
+            filename
+        )))
+        .unwrap()
+}
+
+/// The share's URL as the client reached it, so generated links work through a tunnel too.
+fn base_url(headers: &hyper::HeaderMap, state: &AppState) -> String {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    // Tunnel providers terminate TLS and say so in X-Forwarded-Proto
+    let scheme = header("x-forwarded-proto").unwrap_or(state.scheme);
+    let host = header(hyper::header::HOST.as_str()).unwrap_or("localhost");
+    format!("{}://{}{}", scheme, host, state.base_path)
+}
+
+fn redirect(location: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(hyper::header::LOCATION, location)
+        .body(full(""))
+        .unwrap()
+}
+
+fn not_found() -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(full("Not found"))
+        .unwrap()
+}
+
+fn full<T: Into<Bytes>>(chunk: T) -> BoxBody {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
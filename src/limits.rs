@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Downloads in progress per client address
+type Active = Arc<Mutex<HashMap<String, u32>>>;
+
+/// Caps how many downloads each client address may run at once, so one download
+/// manager opening many connections can't starve everyone else.
+#[derive(Default)]
+pub struct ClientSlots {
+    /// `None` for no limit
+    max: Option<u32>,
+    active: Active,
+}
+
+impl ClientSlots {
+    pub fn new(max: Option<u32>) -> Self {
+        Self {
+            max,
+            active: Arc::default(),
+        }
+    }
+
+    /// Takes one of `client`'s slots until the returned guard is dropped, or `None`
+    /// if all of them are in use.
+    pub fn try_acquire(&self, client: &str) -> Option<Slot> {
+        let Some(max) = self.max else {
+            return Some(Slot { held: None });
+        };
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(client.to_string()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            held: Some((self.active.clone(), client.to_string())),
+        })
+    }
+}
+
+/// A download slot, released when the response body is dropped.
+pub struct Slot {
+    held: Option<(Active, String)>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some((active, client)) = self.held.take() {
+            let mut active = active.lock().unwrap();
+            if let Some(count) = active.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+/// A byte budget and speed cap shared by every download made with one share link.
+///
+/// Downloads reserve their whole length up front, so concurrent ones can't overrun
+/// the budget together, and give back whatever they didn't send when they end.
+pub struct Meter {
+    /// Bytes the link may download in total, `None` for no limit
+    quota: Option<u64>,
+    /// Bytes per second across the link's downloads, `None` for no limit
+    rate: Option<u64>,
+    state: Mutex<Metered>,
+}
+
+struct Metered {
+    /// Bytes reserved by downloads, whether sent yet or not
+    reserved: u64,
+    /// When the chunks sent so far have been spread out to the rate
+    next_send: Instant,
+}
+
+impl Meter {
+    pub fn new(quota: Option<u64>, rate: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            quota,
+            rate,
+            state: Mutex::new(Metered {
+                reserved: 0,
+                next_send: Instant::now(),
+            }),
+        })
+    }
+
+    /// Sets `len` bytes of the budget aside for a download, or returns how many are
+    /// left if that's fewer.
+    pub fn reserve(self: &Arc<Self>, len: u64) -> Result<Reservation, u64> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(quota) = self.quota {
+            let left = quota.saturating_sub(state.reserved);
+            if len > left {
+                return Err(left);
+            }
+        }
+        state.reserved += len;
+        Ok(Reservation {
+            meter: self.clone(),
+            unsent: len,
+        })
+    }
+}
+
+/// Bytes of a [`Meter`]'s budget held by one download; what wasn't sent goes back
+/// when it's dropped.
+pub struct Reservation {
+    meter: Arc<Meter>,
+    unsent: u64,
+}
+
+impl Reservation {
+    /// Waits until `len` more bytes may be sent under the link's rate, then counts them.
+    pub async fn send(&mut self, len: u64) {
+        self.unsent = self.unsent.saturating_sub(len);
+        let Some(rate) = self.meter.rate else {
+            return;
+        };
+        // Held back until the link's earlier chunks and this one fit the rate
+        let at = {
+            let mut state = self.meter.state.lock().unwrap();
+            let at = state.next_send.max(Instant::now())
+                + Duration::from_secs_f64(len as f64 / rate as f64);
+            state.next_send = at;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.meter.state.lock().unwrap();
+        state.reserved -= self.unsent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_per_client_and_released_on_drop() {
+        let slots = ClientSlots::new(Some(2));
+        let first = slots.try_acquire("10.0.0.1").unwrap();
+        let _second = slots.try_acquire("10.0.0.1").unwrap();
+        assert!(slots.try_acquire("10.0.0.1").is_none());
+        assert!(slots.try_acquire("10.0.0.2").is_some());
+        drop(first);
+        assert!(slots.try_acquire("10.0.0.1").is_some());
+    }
+
+    #[test]
+    fn no_limit_by_default() {
+        let slots = ClientSlots::default();
+        let held: Vec<_> = (0..100).map(|_| slots.try_acquire("10.0.0.1")).collect();
+        assert!(held.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn quota_is_reserved_and_unsent_bytes_returned() {
+        let meter = Meter::new(Some(100), None);
+        let first = meter.reserve(60).unwrap();
+        assert_eq!(meter.reserve(50).err(), Some(40));
+
+        let mut second = meter.reserve(40).unwrap();
+        drop(first);
+        assert_eq!(meter.reserve(61).err(), Some(60));
+        futures_util::FutureExt::now_or_never(second.send(10)).unwrap();
+        drop(second);
+        assert_eq!(meter.reserve(91).err(), Some(90));
+    }
+
+    #[tokio::test]
+    async fn rate_spaces_out_chunks_across_downloads() {
+        let meter = Meter::new(None, Some(10_000));
+        let mut first = meter.reserve(10_000).unwrap();
+        let mut second = meter.reserve(10_000).unwrap();
+        let start = Instant::now();
+        first.send(500).await;
+        second.send(500).await;
+        first.send(1000).await;
+        // 2000 bytes in all, 200ms at this rate
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
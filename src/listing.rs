@@ -0,0 +1,480 @@
+use crate::access::Role;
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::user_meta::Meta;
+use crate::{AppState, BoxBody, META_DIR, full, paths, tags, website};
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt, future, stream};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::http::response::Builder;
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs;
+
+/// Header telling a client reading a listing page by page how many entries there are
+pub const TOTAL_COUNT: &str = "X-Total-Count";
+
+/// Header carrying the `?cursor=` of the next page when a listing goes on past this one
+pub const NEXT_CURSOR: &str = "X-Next-Cursor";
+
+/// Files a machine-readable listing stats and checksums at once
+const CONCURRENCY: usize = 64;
+
+/// Machine-readable share inventories, asked for with `?format=` on the listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// Reads `format=<csv|ndjson>` from a query string; `Ok(None)` when absent.
+    pub fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let Some(value) = query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| pair.strip_prefix("format="))
+        else {
+            return Ok(None);
+        };
+        match value {
+            "csv" => Ok(Some(Self::Csv)),
+            "ndjson" | "jsonl" => Ok(Some(Self::Ndjson)),
+            _ => Err(format!(
+                "Unsupported listing format '{}': use csv or ndjson",
+                value
+            )),
+        }
+    }
+}
+
+/// The part of a listing asked for with `?offset=<n>&limit=<n>`, counted in entries
+/// sorted by name, or with `?cursor=<name>&limit=<n>`, starting after the entry named
+/// by the cursor; the whole listing by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    offset: usize,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+impl Page {
+    pub fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let param = |key: &str| {
+            query
+                .into_iter()
+                .flat_map(|query| query.split('&'))
+                .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+        };
+        let offset = match param("offset").map(str::parse) {
+            None => 0,
+            Some(Ok(offset)) => offset,
+            Some(Err(_)) => return Err("offset must be a number of entries".to_string()),
+        };
+        let limit = match param("limit").map(str::parse) {
+            None => None,
+            Some(Ok(limit)) if limit > 0 => Some(limit),
+            Some(_) => return Err("limit must be a positive number of entries".to_string()),
+        };
+        let cursor = param("cursor").map(paths::query_decode);
+        if cursor.is_some() && offset > 0 {
+            return Err("cursor and offset can't be combined".to_string());
+        }
+        Ok(Self {
+            offset,
+            limit,
+            cursor,
+        })
+    }
+
+    /// Whether the whole listing was asked for.
+    pub fn is_whole(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The entries of `names`, sorted by name, that fall on this page.
+    ///
+    /// A cursor is compared by name rather than looked up, so a page still starts
+    /// in the right place when the entry it names has been removed since.
+    pub fn select(&self, names: Vec<String>) -> Selection {
+        let total = names.len();
+        let mut after = names
+            .into_iter()
+            .filter(|name| self.cursor.as_ref().is_none_or(|cursor| name > cursor))
+            .skip(self.offset);
+        let names: Vec<String> = after
+            .by_ref()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        let next = match after.next() {
+            Some(_) => names.last().cloned(),
+            None => None,
+        };
+        Selection {
+            names,
+            total,
+            next,
+            whole: self.is_whole(),
+        }
+    }
+}
+
+/// The entries on a page of a listing and where the next page starts.
+pub struct Selection {
+    pub names: Vec<String>,
+    /// Entries in the whole listing
+    total: usize,
+    /// The cursor of the next page, if there is one
+    next: Option<String>,
+    whole: bool,
+}
+
+impl Selection {
+    /// Adds the [`TOTAL_COUNT`] and [`NEXT_CURSOR`] headers of a paged listing.
+    pub fn headers(&self, mut response: Builder) -> Builder {
+        if !self.whole {
+            response = response.header(TOTAL_COUNT, self.total);
+        }
+        if let Some(next) = &self.next {
+            response = response.header(NEXT_CURSOR, paths::percent_encode(next));
+        }
+        response
+    }
+}
+
+/// One row of the inventory; folders have no size or checksum.
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    size: Option<u64>,
+    /// Modification time in RFC 3339, UTC
+    mtime: Option<String>,
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    tags: BTreeSet<String>,
+    /// `X-Meta-*` headers the file was uploaded with
+    #[serde(skip_serializing_if = "Meta::is_empty")]
+    meta: Meta,
+}
+
+/// Lists the share as CSV or NDJSON with the columns `name,size,mtime,sha256`,
+/// sorted by name, only names carrying all `wanted` tags that a request made with
+/// `role` may download, and only those on `page`.
+/// NDJSON rows also hold their tags and metadata.
+///
+/// Checksums come from the same cache as `/_sha256/`, so only new or changed files
+/// are read. Up to [`CONCURRENCY`] files are looked at together, which is what keeps
+/// shares of many thousands of files quick to list.
+pub async fn get(
+    format: Format,
+    wanted: &[String],
+    role: Option<Role>,
+    page: Page,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let mut tagged = state.tags.all().await;
+    let policies = state.policies();
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(&state.root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Ok(name) = entry.file_name().into_string()
+            && name != META_DIR
+            && tags::matches(tagged.get(&name), wanted)
+            && policies.allows(Action::Download, &name, role)
+        {
+            names.push(name);
+        }
+    }
+    names.sort();
+    #[cfg(feature = "plugins")]
+    let names = match crate::plugins::listing(names, state) {
+        Ok(names) => names,
+        Err(e) => return Ok(crate::plugins::failed(&e)),
+    };
+
+    let page = page.select(names);
+
+    let mut rows: Vec<Entry> = stream::iter(page.names.iter().cloned())
+        .map(|name| entry(name, state))
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+    for row in &mut rows {
+        row.tags = tagged.remove(&row.name).unwrap_or_default();
+    }
+
+    let (content_type, body) = match format {
+        Format::Csv => {
+            let mut csv = String::from("name,size,mtime,sha256\r\n");
+            for row in &rows {
+                csv.push_str(&format!(
+                    "{},{},{},{}\r\n",
+                    csv_field(&row.name),
+                    row.size.map(|size| size.to_string()).unwrap_or_default(),
+                    row.mtime.as_deref().unwrap_or_default(),
+                    row.sha256.as_deref().unwrap_or_default()
+                ));
+            }
+            ("text/csv; charset=utf-8", csv)
+        }
+        Format::Ndjson => {
+            let mut ndjson = String::new();
+            for row in &rows {
+                ndjson.push_str(&serde_json::to_string(row).map_err(io::Error::other)?);
+                ndjson.push('\n');
+            }
+            ("application/x-ndjson", ndjson)
+        }
+    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type);
+    Ok(page.headers(response).body(full(body)).unwrap())
+}
+
+/// Registers `GET /?format=ndjson&stream`.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/{*path}", async |req, cx| {
+            stream(
+                req.headers(),
+                &cx.path,
+                req.uri().query(),
+                cx.client,
+                cx.state.clone(),
+            )
+            .await
+        })
+        .when(|req, _| is_stream(req.uri().query()))
+        .scoped("path");
+}
+
+/// Whether the query asks for the listing to be streamed.
+fn is_stream(query: Option<&str>) -> bool {
+    query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .any(|pair| pair == "stream" || pair.starts_with("stream="))
+}
+
+/// Answers `GET /?format=ndjson&stream`: the NDJSON listing sent row by row as the
+/// share is read, in directory order rather than by name, so a folder of any size
+/// is listed without holding its entries in memory.
+///
+/// Anything but the top of the share is downloaded as if `?stream` weren't there.
+/// Plugins rewrite the listing as a whole, so with plugins loaded it is gathered
+/// first and sorted like any other.
+pub async fn stream(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    client: String,
+    state: Arc<AppState>,
+) -> io::Result<Response<BoxBody>> {
+    let alias = state.aliases.resolve(path.trim_start_matches('/')).await;
+    let filename = alias.as_deref().unwrap_or(path.trim_start_matches('/'));
+    if !filename.is_empty() || (state.auto_index && website::in_site(&state, filename).await) {
+        return crate::get_file(headers, path, query, client, &state).await;
+    }
+    let refusal = match (Format::from_query(query), Page::from_query(query)) {
+        (Err(e), _) | (_, Err(e)) => Some(e),
+        (Ok(Some(Format::Ndjson)), Ok(page)) if page.is_whole() => None,
+        (Ok(Some(Format::Ndjson)), Ok(_)) => Some(
+            "A streamed listing holds every entry: leave out offset, limit and cursor".to_string(),
+        ),
+        (Ok(_), Ok(_)) => Some("Only ?format=ndjson listings can be streamed".to_string()),
+    };
+    if let Some(refusal) = refusal {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(refusal))
+            .unwrap());
+    }
+    let wanted = tags::from_query(query);
+    let role = policy::requester(&state, headers, query);
+    #[cfg(feature = "plugins")]
+    if state.plugins.is_some() {
+        return get(Format::Ndjson, &wanted, role, Page::default(), &state).await;
+    }
+
+    let tagged = Arc::new(state.tags.all().await);
+    let policies = state.policies();
+    let entries = fs::read_dir(&state.root).await?;
+    let rows = stream::unfold(entries, |mut entries| async move {
+        let entry = entries.next_entry().await.transpose()?;
+        Some((entry, entries))
+    })
+    .try_filter_map({
+        let tagged = tagged.clone();
+        move |entry| {
+            let name = entry.file_name().into_string().ok().filter(|name| {
+                name != META_DIR
+                    && tags::matches(tagged.get(name), &wanted)
+                    && policies.allows(Action::Download, name, role)
+            });
+            future::ready(Ok(name))
+        }
+    })
+    .map_ok(move |name| {
+        let state = state.clone();
+        async move {
+            match entry(name, &state).await {
+                // Removed while the share was being read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                row => row.map(Some),
+            }
+        }
+    })
+    .try_buffered(CONCURRENCY)
+    .try_filter_map(|row| future::ready(Ok(row)))
+    .map(move |row| {
+        let mut row = row?;
+        row.tags = tagged.get(&row.name).cloned().unwrap_or_default();
+        let mut line = serde_json::to_vec(&row).map_err(io::Error::other)?;
+        line.push(b'\n');
+        Ok(Frame::data(Bytes::from(line)))
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(BodyExt::boxed(StreamBody::new(rows)))
+        .unwrap())
+}
+
+/// The row of `name`, without its tags.
+async fn entry(name: String, state: &AppState) -> io::Result<Entry> {
+    // Not followed through symlinks, like every other read of the share
+    let metadata = fs::symlink_metadata(state.root.join(&name)).await?;
+    let (size, sha256, meta) = if metadata.is_file() {
+        let sha256 = state.hashes.hash(state, &name, &metadata).await?;
+        let meta = state.user_meta.get(&name, &metadata).await;
+        let packed = state.packed.size(&name, &metadata).await;
+        let size = packed.unwrap_or(metadata.len());
+        (Some(size), Some(hex::encode(sha256)), meta)
+    } else {
+        (None, None, Meta::new())
+    };
+    Ok(Entry {
+        mtime: metadata.modified().ok().map(rfc3339),
+        tags: BTreeSet::new(),
+        name,
+        size,
+        sha256,
+        meta,
+    })
+}
+
+/// Quotes a CSV field when it holds a separator or quote (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `time` as `YYYY-MM-DDTHH:MM:SSZ`.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Days since 1970-01-01 to a civil date, counting in 400-year eras from 0000-03-01
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        let at = |secs| rfc3339(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1_792_150_372), "2026-10-16T11:32:52Z");
+        assert_eq!(at(4_102_444_799), "2099-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn pages_slice_sorted_names() {
+        let names = || ["a", "b", "c", "d"].map(String::from).to_vec();
+        let page = |query| Page::from_query(Some(query));
+        assert!(page("format=csv").unwrap().is_whole());
+        assert_eq!(
+            page("offset=1&limit=2").unwrap().select(names()).names,
+            ["b", "c"]
+        );
+        assert_eq!(
+            page("limit=3").unwrap().select(names()).names,
+            ["a", "b", "c"]
+        );
+        assert_eq!(page("offset=3").unwrap().select(names()).names, ["d"]);
+        assert!(page("offset=9").unwrap().select(names()).names.is_empty());
+        assert!(page("limit=0").is_err());
+        assert!(page("offset=-1").is_err());
+    }
+
+    #[test]
+    fn cursors_start_after_their_entry() {
+        let names = || ["a", "b", "d", "e"].map(String::from).to_vec();
+        let page = |query| Page::from_query(Some(query)).unwrap().select(names());
+        let first = page("limit=2");
+        assert_eq!(first.names, ["a", "b"]);
+        assert_eq!(first.next.as_deref(), Some("b"));
+        assert_eq!(page("cursor=b&limit=2").names, ["d", "e"]);
+        assert_eq!(page("cursor=b&limit=2").next, None);
+        // Removed since the last page
+        assert_eq!(page("cursor=c&limit=1").names, ["d"]);
+        assert_eq!(page("cursor=c&limit=1").next.as_deref(), Some("d"));
+        assert_eq!(page("cursor=e").names, Vec::<String>::new());
+        assert_eq!(
+            Page::from_query(Some("cursor=a%20b"))
+                .unwrap()
+                .cursor
+                .as_deref(),
+            Some("a b")
+        );
+        assert!(Page::from_query(Some("cursor=b&offset=1")).is_err());
+        assert!(is_stream(Some("format=ndjson&stream")));
+        assert!(!is_stream(Some("format=ndjson&streamed=1")));
+    }
+
+    #[test]
+    fn quotes_csv_fields_when_needed() {
+        assert_eq!(csv_field("report.pdf"), "report.pdf");
+        assert_eq!(csv_field("a,b.txt"), "\"a,b.txt\"");
+        assert_eq!(csv_field("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+    }
+}
@@ -0,0 +1,79 @@
+use hyper::header::HeaderValue;
+use std::collections::HashMap;
+use std::path::Path;
+
+const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// Content-Type detection by file extension, with user-defined overrides.
+#[derive(Debug, Default)]
+pub struct MimeTypes {
+    overrides: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    /// Checks the `[mime]` overrides once, as they are sent as `Content-Type` as is.
+    pub fn new(overrides: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let overrides = overrides
+            .iter()
+            .map(|(ext, mime)| {
+                let mime = mime.trim();
+                if mime.is_empty() || HeaderValue::from_str(mime).is_err() {
+                    anyhow::bail!("Invalid Content-Type for '{}' in [mime]: {:?}", ext, mime);
+                }
+                Ok((normalize_extension(ext), mime.to_string()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { overrides })
+    }
+
+    /// Returns the Content-Type to serve `filename` with.
+    ///
+    /// Configured overrides win over the built-in table; unknown extensions
+    /// fall back to `application/octet-stream`.
+    pub fn content_type(&self, filename: &str) -> String {
+        let Some(ext) = Path::new(filename).extension().and_then(|e| e.to_str()) else {
+            return DEFAULT_MIME.to_string();
+        };
+
+        if let Some(mime) = self.overrides.get(&normalize_extension(ext)) {
+            return mime.clone();
+        }
+
+        mime_guess::from_ext(ext)
+            .first_raw()
+            .unwrap_or(DEFAULT_MIME)
+            .to_string()
+    }
+}
+
+fn normalize_extension(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn overrides_win_over_the_builtin_table() {
+        let types = MimeTypes::new(&overrides(&[(".MD", " text/markdown ")])).unwrap();
+        assert_eq!(types.content_type("README.md"), "text/markdown");
+        assert_eq!(types.content_type("notes.txt"), "text/plain");
+        assert_eq!(types.content_type("Makefile"), DEFAULT_MIME);
+    }
+
+    #[test]
+    fn rejects_values_that_are_no_header() {
+        for mime in ["text/plain\nX-Injected: 1", "text/\u{7f}plain", "  "] {
+            let err = MimeTypes::new(&overrides(&[("txt", mime)])).unwrap_err();
+            assert!(err.to_string().contains("Invalid Content-Type for 'txt'"));
+        }
+    }
+}
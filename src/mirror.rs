@@ -0,0 +1,182 @@
+use crate::cli::{MirrorArgs, OutputMode};
+use crate::client;
+use crate::progress::Transfers;
+use crate::{META_DIR, SHARED_DIR, paths, storage};
+use anyhow::bail;
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+
+/// Local files' SHA-256, reused while their size and modification time are unchanged
+#[derive(Default)]
+pub struct LocalHashes {
+    entries: HashMap<String, (u64, Option<SystemTime>, String)>,
+}
+
+impl LocalHashes {
+    /// The hex SHA-256 of `path`, or `None` when there is no such file.
+    pub async fn get(&mut self, name: &str, path: &Path) -> anyhow::Result<Option<String>> {
+        let metadata = match fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => bail!("'{}' exists locally and is not a file", name),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        if let Some((cached_len, cached_modified, sha256)) = self.entries.get(name)
+            && *cached_len == len
+            && *cached_modified == modified
+        {
+            return Ok(Some(sha256.clone()));
+        }
+        let sha256 = hex::encode(storage::sha256_file(path).await?);
+        self.entries
+            .insert(name.to_string(), (len, modified, sha256.clone()));
+        Ok(Some(sha256))
+    }
+}
+
+/// One-way replication of a remote share into the local one.
+struct Mirror {
+    client: Client,
+    /// The remote share, ending in `/`, with any `?token=` it was given
+    base: Url,
+    root: PathBuf,
+    /// Where downloads are assembled before replacing the local file
+    staging: PathBuf,
+    segments: u64,
+    hashes: LocalHashes,
+    transfers: Transfers,
+}
+
+/// Runs `holodeck mirror`: every `--interval`, lists the remote share and downloads
+/// the files whose SHA-256 differs from the local copy, or that are missing locally.
+///
+/// Downloads go to `.holodeck/mirror` and are checked against the remote checksum
+/// before replacing the local file, so the share never holds a partial or stale mix.
+/// Only top-level files are mirrored, and nothing is deleted locally.
+pub async fn run(args: MirrorArgs, output: OutputMode) -> anyhow::Result<()> {
+    let mut base = Url::parse(&args.url)?;
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let root = PathBuf::from(SHARED_DIR);
+    let staging = root.join(META_DIR).join("mirror");
+    fs::create_dir_all(&staging).await?;
+    if output == OutputMode::Normal {
+        println!(
+            "Mirroring {} into {} every {}s",
+            base,
+            std::fs::canonicalize(&root)
+                .unwrap_or_else(|_| root.clone())
+                .display(),
+            args.interval.as_secs()
+        );
+    }
+
+    let mut mirror = Mirror {
+        client: Client::new(),
+        base,
+        root,
+        staging,
+        segments: args.segments,
+        hashes: LocalHashes::default(),
+        transfers: Transfers::new(output == OutputMode::Normal),
+    };
+    let mut interval = tokio::time::interval(args.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        mirror.round().await;
+    }
+}
+
+impl Mirror {
+    /// Lists the remote share once and syncs every file in it.
+    async fn round(&mut self) {
+        let names = match list(&self.client, &self.base).await {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("MIRROR: Error listing {}: {}", self.base, e);
+                return;
+            }
+        };
+        for name in names {
+            if let Err(e) = self.sync(&name).await {
+                eprintln!("MIRROR: Error mirroring '{}': {}", name, e);
+            }
+        }
+    }
+
+    /// Brings the local copy of `name` up to date.
+    async fn sync(&mut self, name: &str) -> anyhow::Result<()> {
+        let url = file_url(&self.base, name);
+        // Folders have no checksum; they are not mirrored
+        let Some(remote) = client::remote_sha256(&self.client, &url, name).await? else {
+            return Ok(());
+        };
+        let dest = self.root.join(name);
+        let local = self.hashes.get(name, &dest).await?;
+        if local.as_ref() == Some(&remote) {
+            return Ok(());
+        }
+
+        let staged = self.staging.join(name);
+        client::download(
+            &self.client,
+            &url,
+            name,
+            &staged,
+            self.segments,
+            &self.transfers,
+        )
+        .await?;
+        let received = hex::encode(storage::sha256_file(&staged).await?);
+        if received != remote {
+            fs::remove_file(&staged).await?;
+            bail!(
+                "checksum mismatch (server has {}, received {}); retrying next round",
+                remote,
+                received
+            );
+        }
+        storage::move_file(&staged, &dest).await?;
+        let size = fs::metadata(&dest).await?.len();
+        self.transfers.println(format!(
+            "MIRROR: {} '{}' ({} bytes)",
+            if local.is_some() { "Updated" } else { "Added" },
+            name,
+            size
+        ));
+        Ok(())
+    }
+}
+
+/// Names in the listing of the remote share at `base`, files and folders alike.
+pub async fn list(client: &Client, base: &Url) -> anyhow::Result<Vec<String>> {
+    let response = client::send(client.get(base.clone())).await?;
+    let body = client::check(response).await?.text().await?;
+    if body.starts_with("No files available") {
+        return Ok(Vec::new());
+    }
+    let Some(names) = body.strip_prefix("Available files:\n") else {
+        bail!("not a holodeck file listing");
+    };
+    // Tags follow a tab
+    Ok(names
+        .lines()
+        .map(|line| line.split('\t').next().unwrap_or(line))
+        .filter(|name| *name != META_DIR && paths::validate_filename(name).is_ok())
+        .map(str::to_string)
+        .collect())
+}
+
+/// The URL of `name` in the remote share at `base`, keeping its query.
+pub fn file_url(base: &Url, name: &str) -> Url {
+    let mut url = base.clone();
+    url.set_path(&format!("{}{}", base.path(), paths::percent_encode(name)));
+    url
+}
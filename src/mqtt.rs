@@ -0,0 +1,51 @@
+use crate::config::MqttConfig;
+use crate::events::Events;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Publishes every event as JSON to the configured MQTT topic.
+pub fn spawn_publisher(events: &Events, config: MqttConfig) {
+    let mut options = MqttOptions::new(config.client_id, config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (config.username, config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+
+    // The event loop drives the connection and reconnects after failures
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                eprintln!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    });
+
+    let mut rx = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let payload = match serde_json::to_vec(&event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Error encoding MQTT event: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = client
+                .publish(&config.topic, QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                eprintln!("Error publishing MQTT event: {}", e);
+            }
+        }
+    });
+}
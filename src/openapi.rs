@@ -0,0 +1,797 @@
+#[cfg(feature = "archives")]
+use crate::batch;
+use crate::router::Router;
+use crate::{AppState, BoxBody, dedup, feed, fileops, full, passcode};
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use serde_json::{Map, Value, json};
+
+/// Where the OpenAPI document is served
+pub const ROUTE: &str = "/_openapi.json";
+
+/// Registers `GET /_openapi.json`.
+pub fn routes(router: &mut Router) {
+    router.get(ROUTE, async |req, cx| Ok(get(req.headers(), &cx.state)));
+}
+
+/// Answers with an OpenAPI 3 document describing the routes this server answers.
+///
+/// It is put together per request from the same state the router checks, so routes
+/// left out by a flag or a disabled feature are left out of the document too, and
+/// the server URL is the one the request came in on.
+pub fn get(headers: &HeaderMap, state: &AppState) -> Response<BoxBody> {
+    let document = document(&crate::base_url(headers, state), Enabled::of(state));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(document.to_string()))
+        .unwrap()
+}
+
+/// The optional parts of the API a server answers.
+#[derive(Debug, Clone, Copy)]
+struct Enabled {
+    /// `--allow-delete`
+    delete: bool,
+    /// `--admin`
+    admin: bool,
+    /// `--relay-hub`
+    relay: bool,
+    /// `--passcode` or `--password`
+    passcode: bool,
+    /// `--share-links`
+    share_links: bool,
+    /// `--search`
+    #[cfg(feature = "search")]
+    search: bool,
+    /// `--sign`
+    #[cfg(feature = "signing")]
+    signing: bool,
+    /// `--p2p`
+    #[cfg(feature = "p2p")]
+    p2p: bool,
+}
+
+impl Enabled {
+    fn of(state: &AppState) -> Self {
+        Self {
+            delete: state.trash.is_some(),
+            admin: state.admin,
+            relay: state.relay_hub.is_some(),
+            passcode: state.passcode.is_some(),
+            share_links: state.share_tokens.is_some(),
+            #[cfg(feature = "search")]
+            search: state.search.is_some(),
+            #[cfg(feature = "signing")]
+            signing: state.signer.is_some(),
+            #[cfg(feature = "p2p")]
+            p2p: state.direct.is_some(),
+        }
+    }
+}
+
+fn document(server: &str, enabled: Enabled) -> Value {
+    let mut paths = Map::new();
+    let mut add = |path: &str, method: &str, operation: Value| {
+        let item = paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[method] = operation;
+    };
+
+    let name = param("name", "File name in the share");
+    add(
+        "/",
+        "get",
+        json!({
+            "summary": "List the shared files",
+            "description": "One name per line, with its tags after a tab, unless `format` asks for CSV or NDJSON rows with size, mtime and sha256. Entries are sorted by name; with `offset` or `limit` only that page is listed and `X-Total-Count` gives the full count, and `X-Next-Cursor` the `cursor` of the next page. `format=ndjson&stream` sends every row as it is read, in directory order.",
+            "parameters": [
+                query("format", "`csv` or `ndjson`"),
+                query("tag", "Only files carrying this tag; repeatable"),
+                query("offset", "Entries to skip"),
+                query("limit", "Most entries to list"),
+                query("cursor", "List the entries after this name, from `X-Next-Cursor`"),
+                query("stream", "Stream an NDJSON listing without sorting or paging it"),
+            ],
+            "responses": responses(&[("200", "The listing"), ("400", "Unknown format, invalid page or unstreamable listing")]),
+        }),
+    );
+    // Parameters of features this build may lack are only listed when it has them
+    let mut download = vec![name.clone()];
+    #[cfg(feature = "compression")]
+    download.push(query("compress", "Compress on the fly: `gzip` or `zstd`"));
+    #[cfg(feature = "qr")]
+    download.push(query(
+        "qr",
+        "Answer with a QR code of the file's URL instead: empty or `svg`, or `png`",
+    ));
+    download.push(query(
+        "follow",
+        "Keep streaming bytes appended to the file: empty or `raw`, or `sse` for one event per line",
+    ));
+    add(
+        "/{name}",
+        "get",
+        json!({
+            "summary": "Download a file",
+            "description": "Supports `Range` requests. `X-Meta-*` headers the file was uploaded with are sent back, and an `ETag` for conditional uploads; `If-None-Match` with the current tag is answered `304 Not Modified`. A file stored compressed with `--compress-storage` is sent as stored with `Content-Encoding: zstd` when `Accept-Encoding` allows it.",
+            "parameters": download,
+            "responses": responses(&[
+                ("200", "The file"),
+                ("206", "The requested range"),
+                ("304", "Unchanged since the version tagged in `If-None-Match`"),
+                ("400", "Invalid name or parameter"),
+                ("404", "No such file"),
+            ]),
+        }),
+    );
+    add(
+        "/{name}",
+        "head",
+        json!({
+            "summary": "Headers of a file's download",
+            "parameters": [name],
+            "responses": responses(&[("200", "The file exists"), ("404", "No such file")]),
+        }),
+    );
+    add(
+        "/{name}",
+        "post",
+        json!({
+            "summary": "Upload a file, replacing any of the same name",
+            "description": "The request body is the file. `X-Meta-*` headers are kept as its metadata. An `X-Upload-Id` header makes the upload's progress readable at `/_progress/{id}`.",
+            "parameters": [name, if_match(), if_none_match()],
+            "requestBody": binary(),
+            "responses": responses(&[
+                ("201", "Uploaded; `ETag` tags the new version"),
+                ("400", "Invalid name"),
+                ("409", "Another write to the file is in progress"),
+                ("412", "The file changed or exists; `ETag` tags its current version"),
+                ("507", "Not enough disk space"),
+            ]),
+        }),
+    );
+    add(
+        "/{name}",
+        "patch",
+        json!({
+            "summary": "Append to a file or resume an upload",
+            "description": "With `Content-Range: bytes <start>-<end>/<total>` the body is written at `start`; without it, it is appended.",
+            "parameters": [name, if_match(), if_none_match()],
+            "requestBody": binary(),
+            "responses": responses(&[
+                ("200", "Appended; `X-Upload-Offset` gives the size so far"),
+                ("201", "The declared total is reached"),
+                ("409", "The range doesn't start where the file ends; resume from `X-Upload-Offset`"),
+                ("412", "The file changed or exists; `ETag` tags its current version"),
+                ("507", "Not enough disk space; the file is left as it was"),
+            ]),
+        }),
+    );
+    add(
+        "/_cmd/{name}",
+        "get",
+        json!({
+            "summary": "Ready-to-paste commands downloading a file",
+            "parameters": [name],
+            "responses": responses(&[("200", "curl, wget and PowerShell commands"), ("404", "No such file")]),
+        }),
+    );
+    add(
+        "/_sha256/{name}",
+        "get",
+        json!({
+            "summary": "SHA-256 of a file, as hex",
+            "parameters": [name],
+            "responses": responses(&[("200", "The checksum"), ("404", "No such file")]),
+        }),
+    );
+    add(
+        "/_health",
+        "get",
+        json!({
+            "summary": "Check the server is up",
+            "description": "Answers `ok` with the process's random id in `X-Holodeck-Instance`, without a passcode or token.",
+            "security": [],
+            "responses": responses(&[("200", "The server is up")]),
+        }),
+    );
+    add(
+        "/_progress/{id}",
+        "get",
+        json!({
+            "summary": "Bytes received so far by the upload sent with `X-Upload-Id: {id}`",
+            "description": "JSON `{bytes, total, state}`, where `state` is `receiving`, `completed` or `interrupted`; with `Accept: text/event-stream`, one event per change until the upload ends. Ended uploads are kept for 10 minutes.",
+            "parameters": [param("id", "The upload id the client chose")],
+            "responses": responses(&[
+                ("200", "The upload's progress"),
+                ("400", "Invalid id"),
+                ("404", "No such upload"),
+            ]),
+        }),
+    );
+    add(
+        dedup::ROUTE,
+        "post",
+        json!({
+            "summary": "Copy content the share already holds instead of uploading it",
+            "requestBody": json_body(json!({
+                "type": "object",
+                "required": ["sha256"],
+                "properties": {"sha256": {"type": "string"}, "name": {"type": "string"}},
+            })),
+            "responses": responses(&[
+                ("200", "The content is shared"),
+                ("201", "Copied to `name`"),
+                ("404", "Unknown content: upload it"),
+            ]),
+        }),
+    );
+    #[cfg(feature = "archives")]
+    add(
+        batch::ROUTE,
+        "post",
+        json!({
+            "summary": "Upload a zip, tar or .tar.gz archive, unpacked into the share",
+            "requestBody": binary(),
+            "responses": responses(&[
+                ("201", "Unpacked"),
+                ("400", "Invalid archive"),
+                ("409", "A file already exists"),
+                ("507", "Not enough disk space"),
+            ]),
+        }),
+    );
+    for (route, summary, done) in [
+        (
+            fileops::RENAME,
+            "Rename or move a file, possibly into a folder",
+            ("200", "Renamed"),
+        ),
+        (
+            fileops::COPY,
+            "Copy a file within the share",
+            ("201", "Copied"),
+        ),
+    ] {
+        add(
+            route,
+            "post",
+            json!({
+                "summary": summary,
+                "requestBody": json_body(json!({
+                    "type": "object",
+                    "required": ["from", "to"],
+                    "properties": {"from": {"type": "string"}, "to": {"type": "string"}},
+                })),
+                "responses": responses(&[done, ("404", "No such file"), ("409", "The destination exists")]),
+            }),
+        );
+    }
+    add(
+        "/_mkdir/{folder}",
+        "post",
+        json!({
+            "summary": "Create a folder and any missing parents",
+            "description": "`folder` may hold `/` between nested folders.",
+            "parameters": [param("folder", "Folder path in the share")],
+            "responses": responses(&[("200", "Already there"), ("201", "Created")]),
+        }),
+    );
+    add(
+        "/_alias",
+        "get",
+        json!({
+            "summary": "List aliases as `slug -> target` lines",
+            "responses": responses(&[("200", "The aliases")]),
+        }),
+    );
+    let slug = param("slug", "Alias");
+    add(
+        "/_alias/{slug}",
+        "put",
+        json!({
+            "summary": "Create or repoint an alias; the body is the target file name",
+            "parameters": [slug],
+            "requestBody": text_body(),
+            "responses": responses(&[("200", "Repointed"), ("201", "Created"), ("400", "Invalid slug or target")]),
+        }),
+    );
+    add(
+        "/_alias/{slug}",
+        "delete",
+        json!({
+            "summary": "Remove an alias created through the API",
+            "parameters": [slug],
+            "responses": responses(&[("204", "Removed"), ("404", "No such alias")]),
+        }),
+    );
+    add(
+        "/_tags",
+        "get",
+        json!({
+            "summary": "List tagged files as `name: tag, tag` lines",
+            "responses": responses(&[("200", "The tags")]),
+        }),
+    );
+    add(
+        "/_tags/{name}",
+        "get",
+        json!({
+            "summary": "Tags of a file, one per line",
+            "parameters": [name],
+            "responses": responses(&[("200", "The tags")]),
+        }),
+    );
+    add(
+        "/_tags/{name}",
+        "post",
+        json!({
+            "summary": "Add tags, separated by commas or spaces, to a file",
+            "parameters": [name],
+            "requestBody": text_body(),
+            "responses": responses(&[("200", "The file's tags"), ("400", "Invalid tag"), ("404", "No such file")]),
+        }),
+    );
+    add(
+        "/_tags/{name}",
+        "delete",
+        json!({
+            "summary": "Remove all tags of a file; `/_tags/{name}/{tag}` removes one",
+            "parameters": [name],
+            "responses": responses(&[("204", "Removed"), ("404", "No such tag")]),
+        }),
+    );
+    add(
+        feed::ROUTE,
+        "get",
+        json!({
+            "summary": "RSS feed of the most recently modified files",
+            "responses": responses(&[("200", "The feed")]),
+        }),
+    );
+    if enabled.delete {
+        add(
+            "/{name}",
+            "delete",
+            json!({
+                "summary": "Delete a file, into the trash while retention allows",
+                "parameters": [name],
+                "responses": responses(&[("204", "Deleted"), ("404", "No such file")]),
+            }),
+        );
+        add(
+            "/_rmdir/{folder}",
+            "delete",
+            json!({
+                "summary": "Delete a folder tree, confirmed by a second call",
+                "description": "The first call answers `202` with a summary and a token; repeating it with `confirm=<token>` deletes the tree.",
+                "parameters": [
+                    param("folder", "Folder path in the share"),
+                    query("confirm", "Token from the first call"),
+                ],
+                "responses": responses(&[
+                    ("200", "Deleted"),
+                    ("202", "Summary and confirmation token"),
+                    ("409", "The tree changed since the first call"),
+                ]),
+            }),
+        );
+        add(
+            "/_trash",
+            "get",
+            json!({
+                "summary": "List deleted files as `<id> <name> (deleted <n>s ago)` lines",
+                "responses": responses(&[("200", "The trash")]),
+            }),
+        );
+        add(
+            "/_trash/{id}",
+            "post",
+            json!({
+                "summary": "Restore a deleted file under its original name",
+                "parameters": [param("id", "Trash entry")],
+                "responses": responses(&[
+                    ("200", "Restored"),
+                    ("404", "No such entry"),
+                    ("409", "The name is taken"),
+                ]),
+            }),
+        );
+    }
+    if enabled.admin {
+        add(
+            "/_admin/transfers",
+            "get",
+            json!({
+                "summary": "List transfers in flight as `<id> <direction> <file> <client> <bytes>/<total> <seconds>s` lines",
+                "responses": responses(&[("200", "The transfers")]),
+            }),
+        );
+        add(
+            "/_admin/connections",
+            "get",
+            json!({
+                "summary": "Count the connections accepted and how they ended, as `<name> <count>` lines",
+                "responses": responses(&[("200", "The counters")]),
+            }),
+        );
+        add(
+            "/_admin/transfers/{id}/cancel",
+            "post",
+            json!({
+                "summary": "Cancel a transfer in flight",
+                "parameters": [param("id", "Transfer id from the list")],
+                "responses": responses(&[("200", "Cancelled"), ("404", "No such transfer in flight")]),
+            }),
+        );
+        add(
+            "/_admin/storage",
+            "get",
+            json!({
+                "summary": "Report free space and uploads failed by a full disk, as `<name> <value>` lines",
+                "responses": responses(&[("200", "The report")]),
+            }),
+        );
+        add(
+            "/_admin/history",
+            "get",
+            json!({
+                "summary": "List finished transfers as `<ended> <direction> <file> <client> <bytes>/<total> <seconds>s <outcome>` lines, oldest first",
+                "parameters": [
+                    query("file", "Only transfers of this file"),
+                    query("peer", "Only transfers with this client address"),
+                    query("since", "Only transfers that ended this long ago at most, such as `2d`"),
+                    query("limit", "Most recent transfers to list (default: 50)"),
+                    query("format", "`ndjson` for one JSON object per line"),
+                ],
+                "responses": responses(&[
+                    ("200", "The transfers"),
+                    ("400", "Invalid filter"),
+                    ("404", "Transfers aren't recorded (`--no-history`)"),
+                ]),
+            }),
+        );
+    }
+    if enabled.relay {
+        add(
+            "/_relay/connect/{peer}",
+            "get",
+            json!({
+                "summary": "Register another holodeck (`--relay-via`) as `peer`",
+                "description": "Needs `Upgrade: holodeck-relay`; the connection then carries NUL-terminated JSON messages from the hub.",
+                "parameters": [param("peer", "Letters, digits, '-' and '_'")],
+                "responses": responses(&[
+                    ("101", "Registered"),
+                    ("400", "Invalid name"),
+                    ("409", "Another holodeck is connected as `peer`"),
+                ]),
+            }),
+        );
+        add(
+            "/_relay/accept/{id}",
+            "get",
+            json!({
+                "summary": "Open the connection a visitor of a registered holodeck waits for",
+                "description": "Called by the peer when the hub asks it to; needs `Upgrade: holodeck-relay`.",
+                "parameters": [param("id", "Connection id from the hub's message")],
+                "responses": responses(&[
+                    ("101", "Connected to the visitor"),
+                    ("404", "Nobody waits for that connection"),
+                    ("426", "Missing `Upgrade: holodeck-relay`"),
+                ]),
+            }),
+        );
+        add(
+            "/_relay/{peer}",
+            "get",
+            json!({
+                "summary": "The top level of a registered holodeck",
+                "description": "The peer redirects to `/_relay/{peer}/`.",
+                "security": [],
+                "parameters": [param("peer", "Name the holodeck registered as")],
+                "responses": responses(&[
+                    ("301", "Redirect to the path with a slash"),
+                    ("502", "No holodeck of that name is connected"),
+                ]),
+            }),
+        );
+        add(
+            "/_relay/{peer}/{path}",
+            "get",
+            json!({
+                "summary": "Anything a registered holodeck serves, with any method",
+                "description": "Passed to the peer untouched; it asks for its own passcode or token.",
+                "security": [],
+                "parameters": [
+                    param("peer", "Name the holodeck registered as"),
+                    param("path", "Path on the peer"),
+                ],
+                "responses": responses(&[
+                    ("502", "No holodeck of that name is connected"),
+                    ("504", "The peer didn't answer in time"),
+                ]),
+            }),
+        );
+    }
+    if enabled.passcode {
+        add(
+            passcode::LOGIN,
+            "post",
+            json!({
+                "summary": "Passcode or password form for browsers, setting a session cookie",
+                "security": [],
+                "requestBody": {
+                    "content": {"application/x-www-form-urlencoded": {"schema": {
+                        "type": "object",
+                        "properties": {"passcode": {"type": "string"}, "next": {"type": "string"}},
+                    }}},
+                },
+                "responses": responses(&[("303", "Signed in"), ("401", "Wrong passcode")]),
+            }),
+        );
+        for method in ["post", "get"] {
+            add(
+                passcode::LOGOUT,
+                method,
+                json!({
+                    "summary": "End the browser's session and clear its cookie",
+                    "security": [],
+                    "responses": responses(&[("303", "Signed out, back to the login page")]),
+                }),
+            );
+        }
+    }
+    #[cfg(feature = "search")]
+    if enabled.search {
+        add(
+            crate::search::ROUTE,
+            "get",
+            json!({
+                "summary": "Full-text search over the share's text files",
+                "parameters": [
+                    query("q", "Search query"),
+                    query("limit", "Most results, 1 to 100 (default 20)"),
+                ],
+                "responses": responses(&[
+                    ("200", "Matches as `[{name, score, snippet}]`"),
+                    ("400", "Missing query"),
+                ]),
+            }),
+        );
+    }
+    #[cfg(feature = "signing")]
+    if enabled.signing {
+        add(
+            crate::signing::PUBKEY,
+            "get",
+            json!({
+                "summary": "minisign public key the downloads are signed with",
+                "responses": responses(&[("200", "The key")]),
+            }),
+        );
+        add(
+            "/{name}.sig",
+            "get",
+            json!({
+                "summary": "minisign signature of a file",
+                "parameters": [name],
+                "responses": responses(&[("200", "The signature"), ("404", "No such file")]),
+            }),
+        );
+    }
+    #[cfg(feature = "p2p")]
+    if enabled.p2p {
+        add(
+            crate::p2p::ROUTE,
+            "get",
+            json!({
+                "summary": "Trade addresses with `holodeck get --p2p` for a direct QUIC connection",
+                "description": "The server starts punching towards the client's candidates and answers with its own.",
+                "parameters": [query("candidate", "`ip:port` the client may be reachable at; repeatable")],
+                "responses": responses(&[
+                    ("200", "`{candidates, sha256}`: the server's addresses and certificate fingerprint"),
+                    ("400", "No valid candidate"),
+                ]),
+            }),
+        );
+    }
+    add(
+        ROUTE,
+        "get",
+        json!({
+            "summary": "This document",
+            "responses": responses(&[("200", "OpenAPI 3 document")]),
+        }),
+    );
+    let mut security_schemes = Map::new();
+    let mut security = Vec::new();
+    if enabled.passcode {
+        security_schemes.insert(
+            "passcode".into(),
+            json!({"type": "apiKey", "in": "header", "name": "X-Holodeck-Passcode"}),
+        );
+        security.push(json!({"passcode": []}));
+    }
+    if enabled.share_links {
+        security_schemes.insert(
+            "shareToken".into(),
+            json!({"type": "http", "scheme": "bearer"}),
+        );
+        security.push(json!({"shareToken": []}));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "holodeck",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+        },
+        "servers": [{"url": server}],
+        "paths": paths,
+        "components": {"securitySchemes": security_schemes},
+        "security": security,
+    })
+}
+
+/// A required path parameter.
+fn param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": {"type": "string"},
+    })
+}
+
+fn query(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "schema": {"type": "string"},
+    })
+}
+
+fn if_match() -> Value {
+    header(
+        "If-Match",
+        "Only write if the file's `ETag` is one of these, or it exists for `*`",
+    )
+}
+
+fn if_none_match() -> Value {
+    header(
+        "If-None-Match",
+        "`*` to only create the file, failing if it exists",
+    )
+}
+
+fn header(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "header",
+        "description": description,
+        "schema": {"type": "string"},
+    })
+}
+
+fn responses(statuses: &[(&str, &str)]) -> Value {
+    statuses
+        .iter()
+        .map(|(status, description)| (status.to_string(), json!({"description": description})))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+fn binary() -> Value {
+    body(
+        "application/octet-stream",
+        json!({"type": "string", "format": "binary"}),
+    )
+}
+
+fn text_body() -> Value {
+    body("text/plain", json!({"type": "string"}))
+}
+
+fn json_body(schema: Value) -> Value {
+    body("application/json", schema)
+}
+
+fn body(content_type: &str, schema: Value) -> Value {
+    json!({"required": true, "content": {content_type: {"schema": schema}}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin;
+    use hyper::Method;
+
+    #[tokio::test]
+    async fn documents_only_enabled_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(dir.path().to_path_buf()).unwrap();
+        let document = document("http://localhost:59830", Enabled::of(&state));
+        assert_eq!(document["openapi"], "3.0.3");
+        assert!(document["paths"]["/{name}"]["post"].is_object());
+        assert!(document["paths"][ROUTE]["get"].is_object());
+        // DELETE needs --allow-delete, the login form --passcode
+        assert!(document["paths"]["/{name}"].get("delete").is_none());
+        assert!(document["paths"].get(passcode::LOGIN).is_none());
+        assert_eq!(document["security"], json!([]));
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let all = Enabled {
+            delete: true,
+            admin: true,
+            relay: true,
+            passcode: true,
+            share_links: true,
+            #[cfg(feature = "search")]
+            search: true,
+            #[cfg(feature = "signing")]
+            signing: true,
+            #[cfg(feature = "p2p")]
+            p2p: true,
+        };
+        let document = document("http://localhost:59830", all);
+        // A path of each documented operation, with `x` for every parameter
+        let documented: Vec<(Method, String)> = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                let mut sample = String::new();
+                let mut rest = path.as_str();
+                while let Some((before, after)) = rest.split_once('{') {
+                    sample.push_str(before);
+                    sample.push('x');
+                    rest = after.split_once('}').unwrap().1;
+                }
+                sample.push_str(rest);
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| (method.to_uppercase().parse().unwrap(), sample.clone()))
+            })
+            .collect();
+        let router = &crate::ROUTER;
+
+        // Anything under `/_` is the API's and not a file the catch-all would serve
+        let unrouted: Vec<_> = documented
+            .iter()
+            .filter(|(method, sample)| {
+                let patterns = router.matching(method, sample);
+                patterns.is_empty()
+                    || (sample.starts_with("/_") && patterns.iter().all(|p| p.starts_with("/{")))
+            })
+            .collect();
+        assert!(
+            unrouted.is_empty(),
+            "Documented without a route: {:?}",
+            unrouted
+        );
+
+        // Only there to answer `404` rather than serve a file named `_admin`
+        let internal = [admin::API];
+        let undocumented: Vec<_> = router
+            .patterns()
+            .into_iter()
+            .filter(|(method, pattern)| {
+                !internal.contains(&pattern.as_str())
+                    && !documented.iter().any(|(documented_method, sample)| {
+                        method.as_ref().is_none_or(|m| m == documented_method)
+                            && router.matching(documented_method, sample).contains(pattern)
+                    })
+            })
+            .collect();
+        assert!(undocumented.is_empty(), "Undocumented: {:?}", undocumented);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{BoxBody, full};
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::header::HeaderMap;
+use hyper::{Response, StatusCode};
+use ring::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
+use ring::signature::{ED25519, UnparsedPublicKey};
+use sha2::digest::DynDigest;
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use std::path::PathBuf;
+
+/// Header carrying an upload's detached signature, base64-encoded
+pub const HEADER: &str = "X-Holodeck-Signature";
+
+/// OpenPGP's public key algorithm id for EdDSA
+const EDDSA: u8 = 22;
+
+/// OID of the Ed25519 curve, as it appears in EdDSA keys
+const ED25519_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+
+const PUBLIC_KEY: u8 = 6;
+const PUBLIC_SUBKEY: u8 = 14;
+const SIGNATURE: u8 = 2;
+
+/// Public keys from `--trusted-key` that uploads must be signed with.
+///
+/// Only Ed25519 keys, GnuPG's default since 2.3, are supported; subkeys count as
+/// well, since that's what `gpg` signs with when the key has a signing subkey.
+pub struct TrustedKeys {
+    keys: Vec<Key>,
+}
+
+struct Key {
+    fingerprint: [u8; 20],
+    point: [u8; 32],
+}
+
+impl TrustedKeys {
+    /// Reads the keys in each file, as written by `gpg --export` with or without `--armor`.
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut keys = Vec::new();
+        for path in paths {
+            let contents =
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            let before = keys.len();
+            let found = dearmor(&contents)
+                .and_then(|data| packets(&data))
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            keys.extend(parse_keys(&found));
+            if keys.len() == before {
+                anyhow::bail!(
+                    "{}: no Ed25519 public key found (only Ed25519 keys are supported)",
+                    path.display()
+                );
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+/// The Ed25519 keys and subkeys among `packets`.
+fn parse_keys(packets: &[(u8, Vec<u8>)]) -> Vec<Key> {
+    packets
+        .iter()
+        .filter(|(tag, _)| *tag == PUBLIC_KEY || *tag == PUBLIC_SUBKEY)
+        .filter_map(|(_, body)| parse_key(body).ok().flatten())
+        .collect()
+}
+
+/// A version 4 Ed25519 key, or `None` for other algorithms.
+fn parse_key(body: &[u8]) -> Result<Option<Key>, String> {
+    let mut reader = Reader(body);
+    if reader.u8()? != 4 {
+        return Ok(None);
+    }
+    reader.take(4)?; // creation time
+    if reader.u8()? != EDDSA {
+        return Ok(None);
+    }
+    let oid_len = reader.u8()?;
+    if reader.take(oid_len as usize)? != ED25519_OID {
+        return Ok(None);
+    }
+    // The point is prefixed with 0x40, meaning "native" encoding
+    let Some(point) = reader.mpi()?.strip_prefix(&[0x40]) else {
+        return Err("malformed Ed25519 key".to_string());
+    };
+    let point = point.try_into().map_err(|_| "malformed Ed25519 key")?;
+
+    let mut hashed = vec![0x99];
+    hashed.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    hashed.extend_from_slice(body);
+    let fingerprint = digest(&SHA1_FOR_LEGACY_USE_ONLY, &hashed)
+        .as_ref()
+        .try_into()
+        .expect("SHA-1 is 20 bytes");
+    Ok(Some(Key { fingerprint, point }))
+}
+
+/// A detached signature received with an upload, hashing the body as it streams in.
+pub struct Signature {
+    hasher: Box<dyn DynDigest + Send>,
+    /// The signature packet from its version through its hashed subpackets, which
+    /// the signature covers after the document
+    hashed: Vec<u8>,
+    /// Key id of the signer, when the signature names it
+    issuer: Option<[u8; 8]>,
+    /// First two bytes of the digest, a quick check before the real one
+    left16: [u8; 2],
+    signature: [u8; 64],
+}
+
+impl Signature {
+    /// Reads the signature from the `X-Holodeck-Signature` header.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+        let value = headers
+            .get(HEADER)
+            .ok_or_else(|| format!("send a detached signature in {}", HEADER))?;
+        let data = value
+            .to_str()
+            .ok()
+            .and_then(|value| STANDARD.decode(value.trim()).ok())
+            .ok_or_else(|| format!("{} is not base64", HEADER))?;
+        Self::parse(&dearmor(&data)?)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let packets = packets(data)?;
+        let Some((SIGNATURE, body)) = packets.first() else {
+            return Err("not an OpenPGP signature".to_string());
+        };
+        let mut reader = Reader(body);
+        if reader.u8()? != 4 {
+            return Err("only version 4 signatures are supported".to_string());
+        }
+        if reader.u8()? != 0 {
+            return Err("not a signature of binary data (sign without --textmode)".to_string());
+        }
+        if reader.u8()? != EDDSA {
+            return Err("only Ed25519 signatures are supported".to_string());
+        }
+        let hasher: Box<dyn DynDigest + Send> = match reader.u8()? {
+            8 => Box::new(Sha256::default()),
+            9 => Box::new(Sha384::default()),
+            10 => Box::new(Sha512::default()),
+            11 => Box::new(Sha224::default()),
+            other => return Err(format!("unsupported hash algorithm {}", other)),
+        };
+        let hashed_len = reader.u16()?;
+        let hashed_subpackets = reader.take(hashed_len)?;
+        let hashed = body[..body.len() - reader.0.len()].to_vec();
+        let unhashed_len = reader.u16()?;
+        let unhashed_subpackets = reader.take(unhashed_len)?;
+        let left16 = reader.take(2)?.try_into().expect("2 bytes");
+
+        // R and S are stored as numbers, losing any leading zero bytes
+        let mut signature = [0; 64];
+        for half in signature.chunks_exact_mut(32) {
+            let mpi = reader.mpi()?;
+            if mpi.len() > 32 {
+                return Err("malformed Ed25519 signature".to_string());
+            }
+            half[32 - mpi.len()..].copy_from_slice(mpi);
+        }
+
+        let mut issuer = None;
+        for (kind, data) in subpackets(hashed_subpackets)?
+            .into_iter()
+            .chain(subpackets(unhashed_subpackets)?)
+        {
+            match (kind, data) {
+                (16, id) if id.len() == 8 => issuer = id.try_into().ok(),
+                (33, [4, fingerprint @ ..]) if fingerprint.len() == 20 => {
+                    issuer = fingerprint[12..].try_into().ok()
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            hasher,
+            hashed,
+            issuer,
+            left16,
+            signature,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Checks the signature over everything passed to `update`, giving the
+    /// fingerprint of the trusted key that made it.
+    pub fn verify(mut self, keys: &TrustedKeys) -> Result<String, String> {
+        self.hasher.update(&self.hashed);
+        self.hasher.update(&[4, 0xff]);
+        self.hasher
+            .update(&(self.hashed.len() as u32).to_be_bytes());
+        let digest = self.hasher.finalize();
+        if digest[..2] != self.left16 {
+            return Err("the signature doesn't match the uploaded file".to_string());
+        }
+        keys.keys
+            .iter()
+            .filter(|key| self.issuer.is_none_or(|id| key.fingerprint[12..] == id))
+            .find(|key| {
+                UnparsedPublicKey::new(&ED25519, key.point)
+                    .verify(&digest, &self.signature)
+                    .is_ok()
+            })
+            .map(|key| hex::encode_upper(key.fingerprint))
+            .ok_or_else(|| "the signature doesn't verify against a trusted key".to_string())
+    }
+}
+
+/// Refuses an upload that isn't, or can't be, signed.
+pub fn unsigned(reason: &str) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(full(format!(
+            "Uploads must be signed by a trusted key: {}",
+            reason
+        )))
+        .unwrap()
+}
+
+/// The binary contents of ASCII-armored `data`, or `data` itself when it isn't armored.
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, String> {
+    let Some(text) = std::str::from_utf8(data)
+        .ok()
+        .filter(|text| text.trim_start().starts_with("-----BEGIN PGP "))
+    else {
+        return Ok(data.to_vec());
+    };
+    // Armor headers end at the first blank line; the checksum line starts with '='
+    let body: String = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.is_empty())
+        .take_while(|line| !line.starts_with('=') && !line.starts_with("-----"))
+        .collect();
+    STANDARD
+        .decode(body)
+        .map_err(|_| "malformed ASCII armor".to_string())
+}
+
+/// Splits `data` into OpenPGP packets, as tag and body.
+fn packets(mut data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, String> {
+    let mut packets = Vec::new();
+    while !data.is_empty() {
+        let mut reader = Reader(data);
+        let header = reader.u8()?;
+        if header & 0x80 == 0 {
+            return Err("not OpenPGP data".to_string());
+        }
+        let (tag, len) = if header & 0x40 != 0 {
+            (header & 0x3f, new_length(&mut reader)?)
+        } else {
+            let len = match header & 3 {
+                0 => reader.u8()? as usize,
+                1 => reader.u16()?,
+                2 => reader.u32()?,
+                _ => reader.0.len(),
+            };
+            ((header >> 2) & 0x0f, len)
+        };
+        packets.push((tag, reader.take(len)?.to_vec()));
+        data = reader.0;
+    }
+    Ok(packets)
+}
+
+/// The subpackets of a signature's subpacket area, as type and data.
+fn subpackets(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    let mut subpackets = Vec::new();
+    while !data.is_empty() {
+        let mut reader = Reader(data);
+        let len = new_length(&mut reader)?;
+        let body = reader.take(len)?;
+        let Some((kind, body)) = body.split_first() else {
+            return Err("empty signature subpacket".to_string());
+        };
+        // The top bit only marks the subpacket as critical
+        subpackets.push((kind & 0x7f, body));
+        data = reader.0;
+    }
+    Ok(subpackets)
+}
+
+/// A length in the one, two or five byte encoding of new-format packets.
+fn new_length(reader: &mut Reader) -> Result<usize, String> {
+    match reader.u8()? {
+        len @ 0..192 => Ok(len as usize),
+        first @ 192..224 => Ok(((first as usize - 192) << 8) + reader.u8()? as usize + 192),
+        255 => reader.u32(),
+        _ => Err("partial packet lengths are not supported".to_string()),
+    }
+}
+
+/// Bounds-checked reads from the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.0.len() {
+            return Err("truncated OpenPGP data".to_string());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    /// A multiprecision integer: its length in bits, then its big-endian bytes.
+    fn mpi(&mut self) -> Result<&'a [u8], String> {
+        let bits = self.u16()?;
+        self.take(bits.div_ceil(8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `gpg --export` of an Ed25519 key
+    const KEY: &str = "mDMEatINCxYJKwYBBAHaRw8BAQdAu9AH4hpkO1UUtwLr+789loDzy5zWK0VsGd1fhPKY9420GUJ1aWxkIDxidWlsZEBleGFtcGxlLmNvbT6IlgQTFggAPhYhBNKG3pejom02Ch2axfja7L9fMxi1BQJq0g0LAhsDBQkAAVGABQsJCAcCBhUKCQgLAgQWAgMBAh4BAheAAAoJEPja7L9fMxi1oq8A/AvE1Wgc/2dJ49SEcIZ451JRe2hsE06Qu/Yy/RHSwI5/AP0ZOwXWmqORbwfVkvE3yblVNh28nSSXwz8//hs91fcmAg==";
+
+    /// `gpg --detach-sign` of "hello\n" with that key
+    const SIGNATURE: &str = "iIgEABYIADAWIQTSht6Xo6JtNgodmsX42uy/XzMYtQUCatINFRIcYnVpbGRAZXhhbXBsZS5jb20ACgkQ+Nrsv18zGLXblAEAjQhBkBnbm27BWan1SX5nEHfGavToNzwVnwmx59qwPIYBALX/49al8LbOOtHlzz46JednyeAHvJ7hkrXfZHRNa+EN";
+
+    fn keys() -> TrustedKeys {
+        let packets = packets(&STANDARD.decode(KEY).unwrap()).unwrap();
+        TrustedKeys {
+            keys: parse_keys(&packets),
+        }
+    }
+
+    fn hello_signature() -> Signature {
+        Signature::parse(&STANDARD.decode(SIGNATURE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn verifies_gpg_detached_signatures() {
+        let keys = keys();
+        assert_eq!(keys.len(), 1);
+
+        let mut signature = hello_signature();
+        signature.update(b"hello\n");
+        assert_eq!(
+            signature.verify(&keys).unwrap(),
+            "D286DE97A3A26D360A1D9AC5F8DAECBF5F3318B5"
+        );
+
+        let mut signature = hello_signature();
+        signature.update(b"hello!\n");
+        assert!(signature.verify(&keys).is_err());
+
+        let other = TrustedKeys {
+            keys: vec![Key {
+                fingerprint: keys.keys[0].fingerprint,
+                point: [9; 32],
+            }],
+        };
+        let mut signature = hello_signature();
+        signature.update(b"hello\n");
+        assert!(signature.verify(&other).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let data = STANDARD.decode(SIGNATURE).unwrap();
+        for len in 0..data.len() {
+            assert!(Signature::parse(&data[..len]).is_err());
+        }
+        assert!(Signature::parse(&STANDARD.decode(KEY).unwrap()).is_err());
+    }
+}
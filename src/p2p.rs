@@ -0,0 +1,535 @@
+use crate::client;
+use crate::http3;
+use crate::progress::Transfers;
+use crate::router::Router;
+use crate::{AppState, BoxBody, full};
+use anyhow::{Context, bail};
+use bytes::Buf;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+use quinn::crypto::rustls::QuicClientConfig;
+use reqwest::Url;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Route trading addresses for a direct connection, as in `/_p2p?candidate=1.2.3.4:5678`
+pub const ROUTE: &str = "/_p2p";
+
+/// Public STUN server asked for this machine's address as seen from the internet
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+/// Name in the self-signed certificate; clients pin its fingerprint instead of checking it
+const SERVER_NAME: &str = "holodeck";
+
+/// Most client addresses the server punches towards per request
+const MAX_CANDIDATES: usize = 8;
+
+/// How long both sides keep sending handshakes before giving up on a direct path
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for each STUN answer, and how many requests are sent before giving up
+const STUN_TIMEOUT: Duration = Duration::from_secs(1);
+const STUN_ATTEMPTS: u32 = 3;
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// What each side of a direct connection tells the other through the tunnel.
+#[derive(Serialize, Deserialize)]
+pub struct Offer {
+    /// Addresses the sender's QUIC socket may be reachable at, public ones first
+    pub candidates: Vec<SocketAddr>,
+    /// Hex SHA-256 of the server's certificate, the only one the client accepts
+    pub sha256: String,
+}
+
+/// The server side of `--p2p`: a QUIC endpoint serving HTTP/3 on a socket whose
+/// public address was learned with STUN, and a fresh self-signed certificate.
+pub struct Direct {
+    endpoint: quinn::Endpoint,
+    candidates: Vec<SocketAddr>,
+    fingerprint: String,
+}
+
+impl Direct {
+    pub async fn bind(stun_server: &str) -> anyhow::Result<Self> {
+        let (socket, candidates) = gather(stun_server).await?;
+
+        let key = rcgen::KeyPair::generate()?;
+        let cert =
+            rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key)?;
+        let fingerprint = hex::encode(Sha256::digest(cert.der()));
+        let key = PrivateKeyDer::try_from(key.serialize_der()).map_err(anyhow::Error::msg)?;
+        let tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)?;
+
+        let mut endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(http3::server_config(tls)?),
+            socket,
+            quinn::default_runtime().context("No async runtime for QUIC")?,
+        )?;
+        // Only used to punch holes: no peer holds a certificate matching an empty pin
+        endpoint.set_default_client_config(client_config(String::new())?);
+        Ok(Self {
+            endpoint,
+            candidates,
+            fingerprint,
+        })
+    }
+
+    pub fn endpoint(&self) -> quinn::Endpoint {
+        self.endpoint.clone()
+    }
+
+    /// Addresses clients are told to try
+    pub fn candidates(&self) -> &[SocketAddr] {
+        &self.candidates
+    }
+
+    /// Sends handshakes to `peer` for a while, so the server's NAT lets the peer's
+    /// own handshakes in. They never complete themselves.
+    fn punch(&self, peer: SocketAddr) {
+        match self.endpoint.connect(peer, SERVER_NAME) {
+            Ok(connecting) => {
+                tokio::spawn(tokio::time::timeout(CONNECT_TIMEOUT, connecting));
+            }
+            Err(e) => tracing::debug!(error = %e, %peer, "Cannot punch towards peer"),
+        }
+    }
+}
+
+/// Registers `GET /_p2p`.
+pub fn routes(router: &mut Router) {
+    router.get(ROUTE, async |req, cx| {
+        Ok(offer(req.uri().query(), &cx.state))
+    });
+}
+
+/// Answers `GET /_p2p?candidate=<ip:port>&...` from a `holodeck get --p2p` client with
+/// the server's own candidates and certificate fingerprint, and starts punching
+/// towards the client's addresses.
+///
+/// Only exists with `--p2p`. It goes through the same passcode or token checks as a
+/// download, so only someone allowed to read the share can point the server's
+/// handshakes at an address.
+pub fn offer(query: Option<&str>, state: &AppState) -> Response<BoxBody> {
+    let Some(direct) = &state.direct else {
+        return crate::not_found();
+    };
+    let peers: Vec<SocketAddr> = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("candidate="))
+        .filter_map(|candidate| candidate.parse().ok())
+        .take(MAX_CANDIDATES)
+        .collect();
+    if peers.is_empty() {
+        return text(
+            StatusCode::BAD_REQUEST,
+            "At least one candidate=<ip:port> is required",
+        );
+    }
+    for &peer in &peers {
+        direct.punch(peer);
+    }
+
+    let offer = Offer {
+        candidates: direct.candidates.clone(),
+        sha256: direct.fingerprint.clone(),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_vec(&offer).unwrap()))
+        .unwrap()
+}
+
+/// A direct QUIC connection from `holodeck get --p2p` to the serving holodeck.
+pub struct Peer {
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+}
+
+impl Peer {
+    /// Trades candidates through the tunnel serving `url`, then tries every server
+    /// candidate at once until one handshake completes.
+    pub async fn connect(
+        http: &reqwest::Client,
+        url: &Url,
+        stun_server: &str,
+    ) -> anyhow::Result<Self> {
+        let (socket, candidates) = gather(stun_server).await?;
+
+        let mut offer_url = url.clone();
+        let dir = &url.path()[..url.path().rfind('/').unwrap_or(0)];
+        offer_url.set_path(&format!("{}{}", dir, ROUTE));
+        let mut query: Vec<String> = url.query().map(str::to_string).into_iter().collect();
+        query.extend(candidates.iter().map(|c| format!("candidate={}", c)));
+        offer_url.set_query(Some(&query.join("&")));
+        let response = client::send(http.get(offer_url)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            bail!("the server doesn't offer direct connections (it needs --p2p)");
+        }
+        let offer: Offer = client::check(response).await?.json().await?;
+
+        let mut endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            socket,
+            quinn::default_runtime().context("No async runtime for QUIC")?,
+        )?;
+        endpoint.set_default_client_config(client_config(offer.sha256)?);
+        let attempts: Vec<_> = offer
+            .candidates
+            .iter()
+            .filter_map(|&addr| endpoint.connect(addr, SERVER_NAME).ok())
+            .collect();
+        if attempts.is_empty() {
+            bail!("the server sent no usable addresses");
+        }
+        let connection =
+            match tokio::time::timeout(CONNECT_TIMEOUT, futures_util::future::select_ok(attempts))
+                .await
+            {
+                Ok(Ok((connection, _))) => connection,
+                Ok(Err(e)) => bail!("{}", e),
+                Err(_) => bail!(
+                    "no handshake got through within {}s",
+                    CONNECT_TIMEOUT.as_secs()
+                ),
+            };
+        Ok(Self {
+            endpoint,
+            connection,
+        })
+    }
+
+    /// Where the connection ended up, for the user
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Downloads `url`'s file over HTTP/3 into `dest`, through `<dest>.part-direct`.
+    pub async fn download(
+        self,
+        url: &Url,
+        name: &str,
+        dest: &Path,
+        transfers: &Transfers,
+    ) -> anyhow::Result<()> {
+        let (mut driver, mut requests) =
+            h3::client::new(h3_quinn::Connection::new(self.connection)).await?;
+        let driver = tokio::spawn(async move {
+            std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+        });
+
+        let uri = match url.query() {
+            Some(query) => format!("https://{}{}?{}", SERVER_NAME, url.path(), query),
+            None => format!("https://{}{}", SERVER_NAME, url.path()),
+        };
+        let mut stream = requests
+            .send_request(hyper::Request::get(uri).body(())?)
+            .await?;
+        stream.finish().await?;
+        let response = stream.recv_response().await?;
+        if response.status() != StatusCode::OK {
+            bail!(
+                "Server answered {} over the direct connection",
+                response.status()
+            );
+        }
+        let total = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        let mut part = dest.as_os_str().to_owned();
+        part.push(".part-direct");
+        let part = PathBuf::from(part);
+        let mut file = fs::File::create(&part).await?;
+        let mut progress = transfers.start("GET", name, total);
+        while let Some(mut chunk) = stream.recv_data().await? {
+            let data = chunk.copy_to_bytes(chunk.remaining());
+            file.write_all(&data).await?;
+            progress.inc(data.len() as u64);
+        }
+        file.flush().await?;
+        drop(file);
+        // HTTP/3 doesn't hold the body to its Content-Length
+        if let Some(total) = total
+            && progress.bytes() != total
+        {
+            let _ = fs::remove_file(&part).await;
+            bail!(
+                "The direct connection ended after {} of {} bytes",
+                progress.bytes(),
+                total
+            );
+        }
+        fs::rename(&part, dest).await?;
+        progress.finish();
+
+        drop(requests);
+        self.endpoint.close(0u32.into(), b"done");
+        driver.await?;
+        self.endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+/// Binds the UDP socket QUIC will use and lists the addresses it may be reached at:
+/// the public one reported by STUN, then the one on the local network.
+///
+/// STUN has to run on the same socket, since the NAT mapping it reveals belongs to it.
+async fn gather(stun_server: &str) -> io::Result<(UdpSocket, Vec<SocketAddr>)> {
+    let stun_server = stun_server.to_string();
+    tokio::task::spawn_blocking(move || {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let port = socket.local_addr()?.port();
+        let mut candidates = Vec::new();
+        match stun(&socket, &stun_server) {
+            Ok(public) => candidates.push(public),
+            Err(e) => eprintln!(
+                "P2P: Cannot learn the public address from {}: {}; offering local addresses only",
+                stun_server, e
+            ),
+        }
+        let local = SocketAddr::new(local_ip().unwrap_or(Ipv4Addr::LOCALHOST.into()), port);
+        if !candidates.contains(&local) {
+            candidates.push(local);
+        }
+        socket.set_nonblocking(true)?;
+        Ok((socket, candidates))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// The address of the interface holding the default route.
+fn local_ip() -> Option<IpAddr> {
+    // Connecting a UDP socket sends nothing; it only picks the outgoing interface
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    probe.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    Some(probe.local_addr().ok()?.ip()).filter(|ip| !ip.is_unspecified())
+}
+
+/// Asks a STUN server (RFC 5389) which address and port `socket` appears to come from.
+fn stun(socket: &UdpSocket, server: &str) -> io::Result<SocketAddr> {
+    let server = server
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 address"))?;
+    let mut transaction = [0; 12];
+    getrandom::fill(&mut transaction)?;
+    let request = binding_request(&transaction);
+
+    socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+    let mut buf = [0; 512];
+    let mut mapped = None;
+    'attempts: for _ in 0..STUN_ATTEMPTS {
+        socket.send_to(&request, server)?;
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == server => {
+                    mapped = parse_binding_response(&buf[..len], &transaction);
+                    if mapped.is_some() {
+                        break 'attempts;
+                    }
+                }
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    socket.set_read_timeout(None)?;
+    mapped.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+}
+
+fn binding_request(transaction: &[u8; 12]) -> [u8; 20] {
+    let mut request = [0; 20];
+    request[..2].copy_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    // Bytes 2..4 hold the attribute length, zero here
+    request[4..8].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction);
+    request
+}
+
+/// The IPv4 XOR-MAPPED-ADDRESS of a binding success answering `transaction`.
+fn parse_binding_response(message: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    let u16_at = |at: usize| {
+        Some(u16::from_be_bytes(
+            message.get(at..at + 2)?.try_into().ok()?,
+        ))
+    };
+    if message.len() < 20
+        || u16_at(0)? != STUN_BINDING_SUCCESS
+        || message[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || message[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let end = message.len().min(20 + u16_at(2)? as usize);
+    let mut at = 20;
+    while at + 4 <= end {
+        let kind = u16_at(at)?;
+        let len = u16_at(at + 2)? as usize;
+        let value = message.get(at + 4..at + 4 + len)?;
+        // Family 0x01 is IPv4: a reserved byte, the family, the port and the address
+        if kind == STUN_XOR_MAPPED_ADDRESS && len == 8 && value[1] == 0x01 {
+            let port = u16::from_be_bytes([value[2], value[3]]) ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+            let ip = u32::from_be_bytes(value[4..8].try_into().ok()?) ^ STUN_MAGIC_COOKIE;
+            return Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port));
+        }
+        // Attributes are padded to four bytes
+        at += 4 + len.div_ceil(4) * 4;
+    }
+    None
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// QUIC client settings accepting only the certificate whose SHA-256 is `fingerprint`.
+fn client_config(fingerprint: String) -> anyhow::Result<quinn::ClientConfig> {
+    let provider = provider();
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert {
+            fingerprint,
+            provider,
+        }))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![http3::ALPN.to_vec()];
+    Ok(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls)?,
+    )))
+}
+
+/// Trusts the one self-signed certificate the server announced through the tunnel,
+/// which is as trustworthy as the tunnel's own HTTPS.
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = hex::encode(Sha256::digest(end_entity));
+        if self.fingerprint.is_empty() || fingerprint != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_xor_mapped_address() {
+        let transaction = [7; 12];
+        let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+        let mut message = vec![0x01, 0x01, 0, 24];
+        message.extend_from_slice(&cookie);
+        message.extend_from_slice(&transaction);
+        // An unrelated SOFTWARE attribute, padded, comes first
+        message.extend_from_slice(&[0x80, 0x22, 0, 5, b'h', b'o', b'l', b'o', b'd', 0, 0, 0]);
+        // 203.0.113.9:50000
+        let port = 50000u16 ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+        let ip = u32::from(Ipv4Addr::new(203, 0, 113, 9)) ^ STUN_MAGIC_COOKIE;
+        message.extend_from_slice(&[0x00, 0x20, 0, 8, 0, 0x01]);
+        message.extend_from_slice(&port.to_be_bytes());
+        message.extend_from_slice(&ip.to_be_bytes());
+
+        assert_eq!(
+            parse_binding_response(&message, &transaction),
+            Some("203.0.113.9:50000".parse().unwrap())
+        );
+        assert_eq!(parse_binding_response(&message, &[8; 12]), None);
+        assert_eq!(parse_binding_response(&message[..30], &transaction), None);
+    }
+
+    #[test]
+    fn request_carries_cookie_and_transaction() {
+        let request = binding_request(&[9; 12]);
+        assert_eq!(&request[..4], &[0x00, 0x01, 0, 0]);
+        assert_eq!(&request[4..8], &STUN_MAGIC_COOKIE.to_be_bytes());
+        assert_eq!(&request[8..], &[9; 12]);
+    }
+}
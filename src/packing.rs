@@ -0,0 +1,506 @@
+use crate::META_DIR;
+use crate::sandbox;
+use crate::storage::StagedFile;
+use hyper::HeaderMap;
+use hyper::header::ACCEPT_ENCODING;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+#[cfg(feature = "compression")]
+use std::io::Write;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+#[cfg(feature = "compression")]
+use std::task::ready;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+#[cfg(feature = "compression")]
+use tokio::io::{AsyncReadExt, sink};
+use tokio::sync::Mutex;
+#[cfg(feature = "compression")]
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+/// Files smaller than this are stored as they are; a zstd frame would save next to nothing
+const MIN_SIZE: u64 = 4096;
+
+/// zstd's default level, fast enough to run as each upload completes
+#[cfg(feature = "compression")]
+const LEVEL: i32 = 3;
+
+/// Compressed bytes read from disk at a time while decompressing
+#[cfg(feature = "compression")]
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Size of the file as it was uploaded
+    size: u64,
+    /// Size and modification time of the compressed file; once the file changes some
+    /// other way, such as a local program rewriting it, it is read as it is
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.len == metadata.len() && self.modified == metadata.modified().ok()
+    }
+}
+
+/// Shared files kept zstd-compressed on disk with `--compress-storage`, listed with
+/// their original size in the share's metadata directory.
+///
+/// A file is compressed in place once its upload completes, if that makes it at least
+/// a tenth smaller, and decompressed as it is read. Files compressed earlier are still
+/// read correctly after restarting without the flag.
+pub struct Packed {
+    entries: Mutex<BTreeMap<String, Entry>>,
+    path: PathBuf,
+}
+
+impl Packed {
+    /// Loads the list of compressed files in `root`'s metadata directory.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(META_DIR).join("packed.json");
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| {
+                anyhow::anyhow!("Invalid compressed file list {}: {}", path.display(), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if !cfg!(feature = "compression") && !entries.is_empty() {
+            anyhow::bail!(
+                "The share has files stored compressed with --compress-storage, which holodeck was built without (the `compression` feature)"
+            );
+        }
+        Ok(Self {
+            entries: Mutex::new(entries),
+            path,
+        })
+    }
+
+    /// The uploaded size of the shared file `name`, whose `metadata` is given, if it is
+    /// stored compressed.
+    pub async fn size(&self, name: &str, metadata: &Metadata) -> Option<u64> {
+        self.entries
+            .lock()
+            .await
+            .get(name)
+            .filter(|entry| entry.matches(metadata))
+            .map(|entry| entry.size)
+    }
+
+    /// Compresses the shared file `name` in place, through the staging directory, if
+    /// that saves at least a tenth of its size. Returns whether it did.
+    pub async fn pack(
+        &self,
+        root: &Path,
+        staging_dir: &Path,
+        name: &str,
+        fsync: bool,
+    ) -> io::Result<bool> {
+        let path = root.join(name);
+        let metadata = fs::symlink_metadata(&path).await?;
+        if !metadata.is_file()
+            || metadata.len() < MIN_SIZE
+            || self.size(name, &metadata).await.is_some()
+        {
+            return Ok(false);
+        }
+        let leaf = name.rsplit('/').next().unwrap_or(name);
+        let (staged, file) = StagedFile::create(staging_dir, leaf).await?;
+        let file = file.into_std().await;
+        let source = path.clone();
+        let packed = tokio::task::spawn_blocking(move || {
+            let file = compress(&source, file)?;
+            if fsync {
+                file.sync_all()?;
+            }
+            file.metadata().map(|metadata| metadata.len())
+        })
+        .await
+        .map_err(io::Error::other)??;
+        if packed > metadata.len() - metadata.len() / 10 {
+            // Dropping `staged` deletes the attempt
+            return Ok(false);
+        }
+
+        // Held while the file is swapped, so a reader sees it listed once it's there
+        let mut entries = self.entries.lock().await;
+        staged.persist(&path).await?;
+        let stored = fs::symlink_metadata(&path).await?;
+        let entry = Entry {
+            size: metadata.len(),
+            len: stored.len(),
+            modified: stored.modified().ok(),
+        };
+        entries.insert(name.to_string(), entry);
+        self.save(&entries).await?;
+        Ok(true)
+    }
+
+    /// Decompresses the shared file `name` in place if it is stored compressed, before
+    /// something appends to it.
+    pub async fn unpack(&self, root: &Path, staging_dir: &Path, name: &str) -> io::Result<()> {
+        let path = root.join(name);
+        let Ok(metadata) = fs::symlink_metadata(&path).await else {
+            return Ok(());
+        };
+        if self.size(name, &metadata).await.is_none() {
+            return Ok(());
+        }
+        let leaf = name.rsplit('/').next().unwrap_or(name);
+        let (staged, file) = StagedFile::create(staging_dir, leaf).await?;
+        let file = file.into_std().await;
+        let source = path.clone();
+        tokio::task::spawn_blocking(move || decompress(&source, file))
+            .await
+            .map_err(io::Error::other)??;
+        let mut entries = self.entries.lock().await;
+        staged.persist(&path).await?;
+        entries.remove(name);
+        self.save(&entries).await
+    }
+
+    /// Lists the shared file `name` as a compressed copy of a file of `size` bytes.
+    pub async fn record(&self, root: &Path, name: &str, size: u64) -> io::Result<()> {
+        let mut entries = self.entries.lock().await;
+        let stored = fs::symlink_metadata(root.join(name)).await?;
+        let entry = Entry {
+            size,
+            len: stored.len(),
+            modified: stored.modified().ok(),
+        };
+        entries.insert(name.to_string(), entry);
+        self.save(&entries).await
+    }
+
+    /// Moves the listing of `from` along with the file, renamed to `to`.
+    pub async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().await;
+        let Some(entry) = entries.remove(from) else {
+            return Ok(());
+        };
+        entries.insert(to.to_string(), entry);
+        self.save(&entries).await
+    }
+
+    /// Opens the shared file `name` to read what was uploaded, decompressing it if
+    /// need be.
+    pub async fn open(&self, root: &Path, name: &str) -> io::Result<Opened> {
+        let file = sandbox::open_beneath(root, name, sandbox::Access::Read).await?;
+        let metadata = file.metadata().await?;
+        self.contents(name, file, metadata).await
+    }
+
+    /// What `file`, the shared file `name` opened for reading, holds as uploaded.
+    pub async fn contents(
+        &self,
+        name: &str,
+        file: fs::File,
+        metadata: Metadata,
+    ) -> io::Result<Opened> {
+        Ok(match self.size(name, &metadata).await {
+            Some(size) => Opened {
+                contents: Contents::unpacking(file)?,
+                metadata,
+                size,
+            },
+            None => Opened {
+                size: metadata.len(),
+                contents: Contents::Plain(file),
+                metadata,
+            },
+        })
+    }
+
+    async fn save(&self, entries: &BTreeMap<String, Entry>) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(entries)?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, json).await?;
+        fs::rename(&temp, &self.path).await
+    }
+}
+
+/// Writes `source` zstd-compressed to `file`.
+#[cfg(feature = "compression")]
+fn compress(source: &Path, file: std::fs::File) -> io::Result<std::fs::File> {
+    let mut encoder = zstd::Encoder::new(file, LEVEL)?;
+    io::copy(&mut std::fs::File::open(source)?, &mut encoder)?;
+    encoder.finish()
+}
+
+/// Writes the zstd-compressed `source` to `file` decompressed.
+#[cfg(feature = "compression")]
+fn decompress(source: &Path, mut file: std::fs::File) -> io::Result<()> {
+    zstd::stream::copy_decode(std::fs::File::open(source)?, &mut file)?;
+    file.flush()
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_source: &Path, _file: std::fs::File) -> io::Result<std::fs::File> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_source: &Path, _file: std::fs::File) -> io::Result<()> {
+    Err(unsupported())
+}
+
+/// Not reached in practice: without the `compression` feature [`Packed::load`] refuses
+/// a share with compressed files, and none can be added.
+#[cfg(not(feature = "compression"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "holodeck was built without the `compression` feature",
+    )
+}
+
+/// A shared file opened by [`Packed::open`].
+pub struct Opened {
+    pub contents: Contents,
+    /// Of the file on disk, compressed or not
+    pub metadata: Metadata,
+    /// Bytes `contents` yields: the file's size as uploaded
+    pub size: u64,
+}
+
+/// The bytes of a shared file as uploaded.
+pub enum Contents {
+    Plain(fs::File),
+    #[cfg(feature = "compression")]
+    Packed(Box<Unpacking>),
+}
+
+impl Contents {
+    /// The contents of a compressed `file`.
+    #[cfg(feature = "compression")]
+    pub fn unpacking(file: fs::File) -> io::Result<Self> {
+        Ok(Self::Packed(Box::new(Unpacking::new(file)?)))
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn unpacking(_file: fs::File) -> io::Result<Self> {
+        Err(unsupported())
+    }
+
+    pub fn is_packed(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            #[cfg(feature = "compression")]
+            Self::Packed(_) => true,
+        }
+    }
+
+    /// Moves `offset` bytes into the contents; a compressed file is decompressed up to
+    /// there, as it can't be addressed by offsets.
+    pub async fn skip(&mut self, offset: u64) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.seek(SeekFrom::Start(offset)).await.map(drop),
+            #[cfg(feature = "compression")]
+            Self::Packed(unpacking) => {
+                let skipped =
+                    tokio::io::copy(&mut (&mut **unpacking).take(offset), &mut sink()).await?;
+                match skipped == offset {
+                    true => Ok(()),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for Contents {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(file) => Pin::new(file).poll_read(cx, buf),
+            #[cfg(feature = "compression")]
+            Self::Packed(unpacking) => Pin::new(&mut **unpacking).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Decompresses a zstd file as it is read.
+#[cfg(feature = "compression")]
+pub struct Unpacking {
+    file: fs::File,
+    decoder: Decoder<'static>,
+    input: Vec<u8>,
+    /// Compressed bytes in `input` not yet decompressed, from `pos` to `filled`
+    pos: usize,
+    filled: usize,
+    eof: bool,
+    /// Whether the decoder has reached the end of a frame, which a complete file ends with
+    finished: bool,
+}
+
+#[cfg(feature = "compression")]
+impl Unpacking {
+    fn new(file: fs::File) -> io::Result<Self> {
+        Ok(Self {
+            file,
+            decoder: Decoder::new()?,
+            input: vec![0; CHUNK],
+            pos: 0,
+            filled: 0,
+            eof: false,
+            finished: false,
+        })
+    }
+}
+
+#[cfg(feature = "compression")]
+impl AsyncRead for Unpacking {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if this.pos == this.filled && !this.eof {
+                let mut input = ReadBuf::new(&mut this.input);
+                ready!(Pin::new(&mut this.file).poll_read(cx, &mut input))?;
+                this.filled = input.filled().len();
+                this.pos = 0;
+                this.eof = this.filled == 0;
+            }
+            let mut input = InBuffer::around(&this.input[this.pos..this.filled]);
+            let mut output = OutBuffer::around(buf.initialize_unfilled());
+            let remaining = this.decoder.run(&mut input, &mut output)?;
+            let written = output.pos();
+            if input.pos() > 0 || written > 0 {
+                this.finished = remaining == 0;
+            }
+            this.pos += input.pos();
+            buf.advance(written);
+            if written > 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                // A frame cut short leaves the decoder waiting for more
+                return Poll::Ready(match this.finished {
+                    true => Ok(()),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "compressed file is truncated",
+                    )),
+                });
+            }
+        }
+    }
+}
+
+/// Whether the request's `Accept-Encoding` takes zstd, so a compressed file can be
+/// sent as stored with `Content-Encoding: zstd`.
+pub fn accepts_zstd(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("zstd"))
+                && params.all(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .is_none_or(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn files_are_read_back_as_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let staging = root.join(META_DIR).join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        let log = "GET /index.html 200\n".repeat(5_000);
+        std::fs::write(root.join("access.log"), &log).unwrap();
+        std::fs::write(root.join("tiny.txt"), "hi").unwrap();
+
+        let packed = Packed::load(root).unwrap();
+        assert!(
+            packed
+                .pack(root, &staging, "access.log", false)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !packed
+                .pack(root, &staging, "tiny.txt", false)
+                .await
+                .unwrap()
+        );
+        assert!(std::fs::metadata(root.join("access.log")).unwrap().len() < log.len() as u64 / 10);
+
+        // Listed across restarts, and read back decompressed
+        let packed = Packed::load(root).unwrap();
+        let mut opened = packed.open(root, "access.log").await.unwrap();
+        assert!(opened.contents.is_packed());
+        assert_eq!(opened.size, log.len() as u64);
+        let mut read = String::new();
+        opened.contents.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, log);
+
+        packed.rename("access.log", "old.log").await.unwrap();
+        std::fs::rename(root.join("access.log"), root.join("old.log")).unwrap();
+        packed.unpack(root, &staging, "old.log").await.unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("old.log")).unwrap(), log);
+        let opened = packed.open(root, "old.log").await.unwrap();
+        assert!(!opened.contents.is_packed());
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn random_data_stays_as_it_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut noise = vec![0; 64 * 1024];
+        getrandom::fill(&mut noise).unwrap();
+        std::fs::write(root.join("photo.jpg"), &noise).unwrap();
+
+        let packed = Packed::load(root).unwrap();
+        assert!(!packed.pack(root, root, "photo.jpg", false).await.unwrap());
+        assert_eq!(std::fs::read(root.join("photo.jpg")).unwrap(), noise);
+    }
+
+    #[test]
+    fn zstd_is_taken_unless_refused() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+            accepts_zstd(&headers)
+        };
+        assert!(accepts("gzip, deflate, br, zstd"));
+        assert!(accepts("zstd;q=0.5"));
+        assert!(!accepts("gzip, br"));
+        assert!(!accepts("zstd;q=0"));
+        assert!(!accepts_zstd(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,471 @@
+use crate::intrusion::Violation;
+use crate::paths::query_decode;
+use crate::router::Router;
+use crate::{AppState, BoxBody, full, website};
+use http_body_util::{BodyExt, Limited};
+use hyper::header::{CONTENT_TYPE, COOKIE, HeaderMap, LOCATION, RETRY_AFTER, SET_COOKIE};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Route the passcode form posts to
+pub const LOGIN: &str = "/_login";
+
+/// Route ending a browser's session
+pub const LOGOUT: &str = "/_logout";
+
+/// Default for `--session-ttl`
+pub const DEFAULT_SESSION_TTL: &str = "12h";
+
+/// Header carrying the passcode on scripted requests
+const HEADER: &str = "x-holodeck-passcode";
+
+/// Query parameter carrying the passcode, for links and tools that can't set headers
+const PARAM: &str = "passcode";
+
+/// Cookie holding a browser's session once it has entered the passcode
+const COOKIE_NAME: &str = "holodeck_session";
+
+/// Wrong passcodes a client may send within [`FAILURE_WINDOW`] before being turned away
+const MAX_FAILURES: u32 = 5;
+
+const FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Largest login form accepted
+const MAX_FORM_LEN: usize = 1024;
+
+/// A six-digit code printed at startup, or a password chosen with `--password`, that
+/// every request must present.
+///
+/// Browsers are asked for it once on a login page and then carry a session cookie
+/// until it expires or they log out; scripts send it in the `X-Holodeck-Passcode`
+/// header or a `passcode` query parameter. It keeps drive-by scanners out of a
+/// tunneled share without setting up accounts.
+pub struct Passcode {
+    code: String,
+    /// Chosen with `--password` rather than drawn at startup
+    chosen: bool,
+    /// How long a browser stays logged in
+    lifetime: Duration,
+    /// When each session cookie was handed out
+    sessions: Mutex<HashMap<String, Instant>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+/// What a request's session cookie amounts to
+#[derive(Debug, PartialEq)]
+enum Session {
+    Active,
+    Expired,
+    Missing,
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+impl Passcode {
+    pub fn generate() -> io::Result<Self> {
+        let n = getrandom::u32()?;
+        Ok(Self::new(format!("{:06}", n % 1_000_000)))
+    }
+
+    /// Protects the share with `password` instead of a generated code.
+    pub fn password(password: String) -> Self {
+        Self {
+            chosen: true,
+            ..Self::new(password)
+        }
+    }
+
+    fn new(code: String) -> Self {
+        Self {
+            code,
+            chosen: false,
+            lifetime: Duration::from_secs(12 * 60 * 60),
+            sessions: Mutex::default(),
+            failures: Mutex::default(),
+        }
+    }
+
+    /// Logs browsers out `lifetime` after they logged in.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Whether this is a password chosen with `--password`, which isn't printed.
+    pub fn is_password(&self) -> bool {
+        self.chosen
+    }
+
+    /// The response to send instead when the request carries neither the passcode nor a
+    /// session cookie: a passcode prompt, or 429 after too many wrong codes.
+    pub fn challenge(
+        &self,
+        headers: &HeaderMap,
+        query: Option<&str>,
+        path: &str,
+        client: &str,
+        state: &AppState,
+    ) -> Option<Response<BoxBody>> {
+        let session = self.session(headers);
+        if session == Session::Active {
+            return None;
+        }
+
+        let presented = headers
+            .get(HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| {
+                query
+                    .into_iter()
+                    .flat_map(|query| query.split('&'))
+                    .find_map(|pair| pair.strip_prefix(PARAM)?.strip_prefix('='))
+                    .map(query_decode)
+            });
+        match presented {
+            Some(code) => match self.verify(code.trim(), client) {
+                Ok(()) => None,
+                Err(status) => Some(text(status, "Wrong passcode\n")),
+            },
+            None if website::accepts_html(headers) => {
+                let message = match session {
+                    Session::Expired => "Your session has expired, please log in again.",
+                    _ => "",
+                };
+                Some(self.prompt(StatusCode::UNAUTHORIZED, path, message, state))
+            }
+            None => Some(text(
+                StatusCode::UNAUTHORIZED,
+                format!(
+                    "Passcode required: send it in the X-Holodeck-Passcode header or ?{}=\n",
+                    PARAM
+                ),
+            )),
+        }
+    }
+
+    /// Compares `code` with the passcode, counting failures per client: 401 for a wrong
+    /// code, and 429 for any code once a client has sent [`MAX_FAILURES`] wrong ones.
+    pub fn verify(&self, code: &str, client: &str) -> Result<(), StatusCode> {
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| f.since.elapsed() < FAILURE_WINDOW);
+        if failures
+            .get(client)
+            .is_some_and(|f| f.count >= MAX_FAILURES)
+        {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        if same_secret(code, &self.code) {
+            return Ok(());
+        }
+        failures
+            .entry(client.to_string())
+            .or_insert(Failures {
+                count: 0,
+                since: Instant::now(),
+            })
+            .count += 1;
+        Err(StatusCode::UNAUTHORIZED)
+    }
+
+    /// Finds the request's session cookie, forgetting it if it has expired.
+    fn session(&self, headers: &HeaderMap) -> Session {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut found = Session::Missing;
+        for token in session_cookies(headers) {
+            match sessions.get(token) {
+                Some(started) if started.elapsed() < self.lifetime => return Session::Active,
+                Some(_) => {
+                    sessions.remove(token);
+                    found = Session::Expired;
+                }
+                None => {}
+            }
+        }
+        found
+    }
+
+    fn start_session(&self) -> io::Result<String> {
+        let token = random_token()?;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, started| started.elapsed() < self.lifetime);
+        sessions.insert(token.clone(), Instant::now());
+        Ok(token)
+    }
+
+    /// The page asking a browser for the passcode, returning to `path` afterwards.
+    fn prompt(
+        &self,
+        status: StatusCode,
+        path: &str,
+        message: &str,
+        state: &AppState,
+    ) -> Response<BoxBody> {
+        let (intro, input, button) = if self.chosen {
+            (
+                "This share is password protected.",
+                "type=\"password\" autocomplete=\"current-password\"",
+                "Log in",
+            )
+        } else {
+            (
+                "Enter the passcode shown where holodeck was started.",
+                "inputmode=\"numeric\" autocomplete=\"off\"",
+                "Continue",
+            )
+        };
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>holodeck</title></head>\n\
+             <body>\n<form method=\"post\" action=\"{}{}?next={}\">\n\
+             <p>{}</p>\n{}\
+             <input name=\"{}\" {} autofocus>\n\
+             <button>{}</button>\n</form>\n</body>\n</html>\n",
+            state.base_path,
+            LOGIN,
+            escape(path),
+            intro,
+            if message.is_empty() {
+                String::new()
+            } else {
+                format!("<p>{}</p>\n", message)
+            },
+            PARAM,
+            input,
+            button
+        );
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(full(html))
+            .unwrap()
+    }
+}
+
+/// The session tokens in a request's cookies.
+fn session_cookies(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().strip_prefix(COOKIE_NAME)?.strip_prefix('='))
+}
+
+/// 128 random bits as hex, for session cookies and share tokens.
+pub fn random_token() -> io::Result<String> {
+    let mut token = [0; 16];
+    getrandom::fill(&mut token)?;
+    Ok(hex::encode(token))
+}
+
+/// Compares secrets in full either way, so timing doesn't reveal a matching prefix.
+pub fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Registers `POST /_login` and `/_logout` when `--passcode` or `--password` is set.
+pub fn routes(router: &mut Router) {
+    router
+        .post(LOGIN, async |req, cx| {
+            login(req, cx.client, &cx.state).await
+        })
+        .when(|_, state| state.passcode.is_some());
+    // GET too, so logging out is a link a browser user can type
+    router
+        .get(LOGOUT, async |req, cx| {
+            Ok(logout(&req, &cx.client, &cx.state))
+        })
+        .when(|_, state| state.passcode.is_some());
+    router
+        .post(LOGOUT, async |req, cx| {
+            Ok(logout(&req, &cx.client, &cx.state))
+        })
+        .when(|_, state| state.passcode.is_some());
+}
+
+/// Handles the passcode form: on success sets the session cookie and sends the browser
+/// back to the page it asked for (`?next=`), otherwise shows the form again.
+pub async fn login(
+    req: Request<BoxBody>,
+    client: String,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let Some(passcode) = &state.passcode else {
+        return Ok(crate::not_found());
+    };
+    // Only local paths, so the form can't be used to bounce visitors elsewhere
+    let next = req
+        .uri()
+        .query()
+        .and_then(|query| query.split_once("next=").map(|(_, next)| next.to_string()))
+        .filter(|next| next.starts_with('/') && !next.starts_with("//") && !next.contains('\\'))
+        .unwrap_or_else(|| format!("{}/", state.base_path));
+
+    let form = match Limited::new(req.into_body(), MAX_FORM_LEN).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(text(StatusCode::PAYLOAD_TOO_LARGE, "Request too large")),
+    };
+    let code = std::str::from_utf8(&form)
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(PARAM)?.strip_prefix('='))
+        .map(query_decode)
+        .unwrap_or_default();
+    let verified = passcode.verify(code.trim(), &client);
+    if verified.is_err() {
+        state
+            .intrusions
+            .record(Violation::AuthFailure, &client, &Method::POST, LOGIN);
+    }
+    match verified {
+        Ok(()) => {}
+        Err(StatusCode::TOO_MANY_REQUESTS) => {
+            return Ok(text(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many wrong passcodes\n",
+            ));
+        }
+        Err(status) => {
+            let message = if passcode.chosen {
+                "Wrong password, try again."
+            } else {
+                "Wrong passcode, try again."
+            };
+            return Ok(passcode.prompt(status, &next, message, state));
+        }
+    }
+
+    let token = passcode.start_session()?;
+    state
+        .transfers
+        .println(format!("LOGIN: Passcode accepted from {}", client));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(
+            SET_COOKIE,
+            session_cookie(&token, passcode.lifetime.as_secs(), state),
+        )
+        .header(LOCATION, next)
+        .body(full(""))
+        .unwrap())
+}
+
+/// Handles `/_logout`: forgets the browser's session, clears its cookie and sends it
+/// back to the login page.
+fn logout(req: &Request<BoxBody>, client: &str, state: &AppState) -> Response<BoxBody> {
+    let Some(passcode) = &state.passcode else {
+        return crate::not_found();
+    };
+    let mut sessions = passcode.sessions.lock().unwrap();
+    let mut ended = false;
+    for token in session_cookies(req.headers()) {
+        ended |= sessions.remove(token).is_some();
+    }
+    drop(sessions);
+    if ended {
+        state
+            .transfers
+            .println(format!("LOGOUT: Session of {} ended", client));
+    }
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(SET_COOKIE, session_cookie("", 0, state))
+        .header(LOCATION, format!("{}/", state.base_path))
+        .body(full(""))
+        .unwrap()
+}
+
+/// The `Set-Cookie` value giving a browser the session `token` for `max_age` seconds.
+fn session_cookie(token: &str, max_age: u64, state: &AppState) -> String {
+    let secure = if state.scheme == "https" {
+        "; Secure"
+    } else {
+        ""
+    };
+    format!(
+        "{}={}; Path={}/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        COOKIE_NAME, token, state.base_path, max_age, secure
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        response = response.header(RETRY_AFTER, FAILURE_WINDOW.as_secs());
+    }
+    response.body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_codes_lock_the_client_out() {
+        let passcode = Passcode::new("123456".to_string());
+        assert_eq!(passcode.verify("123456", "10.0.0.1"), Ok(()));
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(
+                passcode.verify("000000", "10.0.0.1"),
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+        assert_eq!(
+            passcode.verify("123456", "10.0.0.1"),
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(passcode.verify("123456", "10.0.0.2"), Ok(()));
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let passcode = Passcode::new("123456".to_string()).session_lifetime(Duration::ZERO);
+        let token = passcode.start_session().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            format!("theme=dark; {}={}", COOKIE_NAME, token)
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(passcode.session(&headers), Session::Expired);
+        assert_eq!(passcode.session(&headers), Session::Missing);
+
+        let passcode = passcode.session_lifetime(Duration::from_secs(60));
+        let token = passcode.start_session().unwrap();
+        headers.insert(
+            COOKIE,
+            format!("{}={}", COOKIE_NAME, token).parse().unwrap(),
+        );
+        assert_eq!(passcode.session(&headers), Session::Active);
+    }
+
+    #[test]
+    fn generated_codes_have_six_digits() {
+        let code = Passcode::generate().unwrap().code;
+        assert_eq!(code.len(), 6);
+        assert!(code.bytes().all(|b| b.is_ascii_digit()));
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+
+/// Why a requested filename can't be served or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidName {
+    Empty,
+    /// Contains a separator or `..`, so it could escape the share
+    Traversal,
+    /// A DOS device name such as `CON` or `com1.txt`
+    Reserved,
+    /// Characters or endings Windows can't store
+    Unrepresentable,
+}
+
+impl std::fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Empty => "Filename required in path",
+            Self::Traversal => "Invalid filename",
+            Self::Reserved => "Invalid filename: reserved device name",
+            Self::Unrepresentable => "Invalid filename: unsupported characters",
+        })
+    }
+}
+
+/// Device names Windows resolves regardless of directory or extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that `name` names a single file directly inside the share.
+///
+/// The rules are the same on every platform, so a share created on Linux can be
+/// served from Windows and uploads don't depend on the host OS.
+pub fn validate_filename(name: &str) -> Result<&str, InvalidName> {
+    if name.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    if name.contains("..") || name.contains(['/', '\\']) {
+        return Err(InvalidName::Traversal);
+    }
+    // A drive-relative name like `C:file` or an NTFS stream like `file:stream`
+    if name.contains([':', '<', '>', '"', '|', '?', '*']) || name.chars().any(char::is_control) {
+        return Err(InvalidName::Unrepresentable);
+    }
+    // Windows silently strips trailing dots and spaces, aliasing other names
+    if name.ends_with(['.', ' ']) {
+        return Err(InvalidName::Unrepresentable);
+    }
+
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return Err(InvalidName::Reserved);
+    }
+    Ok(name)
+}
+
+/// Checks each segment of a nested path like a top-level filename and joins them
+/// back with `/`, dropping empty segments.
+///
+/// The metadata directory is refused as the first segment.
+pub fn validate_path(relative: &str) -> Result<String, InvalidName> {
+    let segments = relative
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(validate_filename)
+        .collect::<Result<Vec<_>, _>>()?;
+    if segments.first() == Some(&crate::META_DIR) {
+        return Err(InvalidName::Traversal);
+    }
+    Ok(segments.join("/"))
+}
+
+/// Makes `path` absolute in a form that isn't limited to `MAX_PATH` on Windows.
+///
+/// `canonicalize` yields a `\\?\` verbatim path there, which paths joined onto it
+/// inherit. Other platforms have no such limit and get `path` back unchanged.
+pub fn long_path(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Percent-encodes the characters a URL path segment can't hold as they are.
+pub fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decodes a query string value: `%XX` escapes and `+` for a space. Malformed escapes
+/// are kept as they are and invalid UTF-8 is replaced.
+pub fn query_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_ordinary_names() {
+        for name in [
+            "report.pdf",
+            "archive.tar.gz",
+            ".env",
+            "a b c.txt",
+            "ünïcode.md",
+        ] {
+            assert_eq!(validate_filename(name), Ok(name));
+        }
+    }
+
+    #[test]
+    fn rejects_empty_name() {
+        assert_eq!(validate_filename(""), Err(InvalidName::Empty));
+    }
+
+    #[test]
+    fn rejects_traversal_with_either_separator() {
+        for name in [
+            "..",
+            "../etc/passwd",
+            "a..b",
+            "sub/file",
+            "sub\\file",
+            "..\\windows\\win.ini",
+            "\\\\server\\share",
+        ] {
+            assert_eq!(
+                validate_filename(name),
+                Err(InvalidName::Traversal),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_device_names_case_insensitively() {
+        for name in ["CON", "con", "Nul.txt", "com1", "LPT9.log", "aux .txt"] {
+            assert_eq!(
+                validate_filename(name),
+                Err(InvalidName::Reserved),
+                "{}",
+                name
+            );
+        }
+        for name in ["console", "com10", "nullable.txt", "my-con"] {
+            assert!(validate_filename(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_names_windows_cannot_store() {
+        for name in [
+            "C:evil",
+            "file.txt:stream",
+            "what?.txt",
+            "star*",
+            "quote\".txt",
+            "pipe|",
+            "angle<>",
+            "tab\there",
+            "trailing.",
+            "trailing ",
+        ] {
+            assert_eq!(
+                validate_filename(name),
+                Err(InvalidName::Unrepresentable),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(query_decode("disk+full%21"), "disk full!");
+        assert_eq!(query_decode("%C3%BCber"), "über");
+        assert_eq!(query_decode("100%"), "100%");
+        assert_eq!(query_decode("%zz%+1"), "%zz% 1");
+    }
+
+    #[test]
+    fn nested_paths_are_checked_per_segment() {
+        assert_eq!(
+            validate_path("docs//guide/intro.html").unwrap(),
+            "docs/guide/intro.html"
+        );
+        assert_eq!(validate_path("").unwrap(), "");
+        assert_eq!(validate_path("docs/../secret"), Err(InvalidName::Traversal));
+        assert_eq!(
+            validate_path(".holodeck/staging/x"),
+            Err(InvalidName::Traversal)
+        );
+    }
+
+    #[test]
+    fn long_path_keeps_the_same_file() {
+        let dir = std::env::temp_dir();
+        let long = long_path(&dir);
+        assert_eq!(
+            std::fs::canonicalize(&long).unwrap(),
+            std::fs::canonicalize(&dir).unwrap()
+        );
+        if cfg!(windows) {
+            assert!(long.to_string_lossy().starts_with(r"\\?\"));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// pico.sh's tunnel host, which custom domains must point at
+const TUNS_HOST: &str = "tuns.sh";
+
+/// Keeps startup from hanging on an unreachable resolver
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The DNS records pico.sh checks before serving a tunnel on `domain`: a CNAME to
+/// tuns.sh and a TXT record proving ownership with the SSH key's fingerprint.
+pub fn required_records(domain: &str, key_path: Option<&Path>) -> Vec<String> {
+    let fingerprint = key_path
+        .and_then(key_fingerprint)
+        .unwrap_or_else(|| "SHA256:<fingerprint of your SSH public key>".to_string());
+    vec![
+        format!("{}. CNAME {}.", domain, TUNS_HOST),
+        format!("_sish.{}. TXT \"{}\"", domain, fingerprint),
+    ]
+}
+
+/// OpenSSH-style `SHA256:...` fingerprint of the public key next to `key_path`.
+fn key_fingerprint(key_path: &Path) -> Option<String> {
+    let mut public = key_path.as_os_str().to_owned();
+    public.push(".pub");
+    let contents = std::fs::read_to_string(public).ok()?;
+    let blob = contents.split_whitespace().nth(1)?;
+    let blob = base64::engine::general_purpose::STANDARD
+        .decode(blob)
+        .ok()?;
+    Some(format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(blob))
+    ))
+}
+
+/// Describes whether `domain` already resolves to the same addresses as tuns.sh.
+pub async fn check_dns(domain: &str) -> String {
+    let lookups = async { tokio::join!(resolve(domain), resolve(TUNS_HOST)) };
+    let Ok((ours, theirs)) = tokio::time::timeout(DNS_TIMEOUT, lookups).await else {
+        return format!("DNS lookup for {} timed out", domain);
+    };
+    match (ours, theirs) {
+        (Err(e), _) => format!("{} does not resolve yet: {}", domain, e),
+        (_, Err(e)) => format!("Could not resolve {} to compare: {}", TUNS_HOST, e),
+        (Ok(ours), Ok(theirs)) if !ours.is_disjoint(&theirs) => {
+            format!("{} points at {}", domain, TUNS_HOST)
+        }
+        (Ok(_), Ok(_)) => format!(
+            "{} resolves, but not to {}; check the CNAME record",
+            domain, TUNS_HOST
+        ),
+    }
+}
+
+async fn resolve(host: &str) -> std::io::Result<HashSet<IpAddr>> {
+    Ok(tokio::net::lookup_host((host, 443))
+        .await?
+        .map(|addr| addr.ip())
+        .collect())
+}
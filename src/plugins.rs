@@ -0,0 +1,395 @@
+use crate::storage::WriteGuard;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use anyhow::Context;
+use hyper::{HeaderMap, Method, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+/// Instructions a hook may run per call before it is stopped, so a plugin stuck in a
+/// loop fails the request instead of hanging the server
+const FUEL: u64 = 100_000_000;
+
+/// A hook a plugin may export, each `(ptr: i32, len: i32) -> i64`.
+///
+/// The argument is a JSON document the host wrote into the plugin's memory at a
+/// buffer from its `alloc(len: i32) -> i32` export. The hook returns 0 to leave things
+/// as they are, or `ptr << 32 | len` of a JSON reply in its memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hook {
+    /// `{"method","path","query","client","headers"}` before routing; replying
+    /// `{"status": 403, "body": "..."}` answers the request instead of holodeck
+    Request,
+    /// `{"name","size","client"}` before an upload moves into the share; replying
+    /// `{"name": "..."}` stores it under that name
+    UploadComplete,
+    /// `{"files": [...]}` for the share listing; replying `{"files": [...]}` filters
+    /// or reorders it, names that aren't in the share are dropped
+    RewriteListing,
+}
+
+impl Hook {
+    const ALL: [Self; 3] = [Self::Request, Self::UploadComplete, Self::RewriteListing];
+
+    fn export(self) -> &'static str {
+        match self {
+            Self::Request => "on_request",
+            Self::UploadComplete => "on_upload_complete",
+            Self::RewriteListing => "rewrite_listing",
+        }
+    }
+}
+
+type HookFunc = TypedFunc<(i32, i32), i64>;
+
+#[derive(Serialize)]
+struct RequestInfo<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: Option<&'a str>,
+    client: &'a str,
+    headers: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    status: u16,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Serialize)]
+struct Upload<'a> {
+    name: &'a str,
+    size: u64,
+    client: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    files: Vec<String>,
+}
+
+/// WebAssembly modules loaded with `--plugin`, run in the order given.
+///
+/// Plugins are sandboxed: they get no imports, only the JSON they are handed, and a
+/// bounded amount of fuel per call. A hook that traps or replies with something
+/// unreadable fails the request rather than being skipped, since it may be guarding it.
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+}
+
+struct Plugin {
+    /// File name, for logs
+    name: String,
+    store: Mutex<Store<()>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    hooks: Vec<(Hook, HookFunc)>,
+}
+
+impl Plugins {
+    /// Compiles and instantiates the modules at `paths`, binary `.wasm` or `.wat` text.
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let plugins = paths
+            .iter()
+            .map(|path| {
+                Plugin::load(&engine, path)
+                    .with_context(|| format!("Invalid plugin {}", path.display()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { plugins })
+    }
+
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+
+    /// Runs the `on_request` hooks, giving the response of the first plugin that
+    /// answers the request itself.
+    pub fn on_request(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        client: &str,
+    ) -> Option<Response<BoxBody>> {
+        let info = RequestInfo {
+            method: method.as_str(),
+            path,
+            query,
+            client,
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+                .collect(),
+        };
+        for plugin in &self.plugins {
+            let reply = plugin.call(Hook::Request, &info).and_then(|reply| {
+                reply
+                    .map(|reply| {
+                        let reply: Reply = serde_json::from_slice(&reply)?;
+                        Ok(Response::builder()
+                            .status(StatusCode::from_u16(reply.status)?)
+                            .body(full(reply.body))?)
+                    })
+                    .transpose()
+            });
+            match reply {
+                Ok(None) => {}
+                Ok(Some(response)) => return Some(response),
+                Err(e) => {
+                    let e = e.context(format!(
+                        "Plugin {} failed on {} {}",
+                        plugin.name, method, path
+                    ));
+                    return Some(failed(&e));
+                }
+            }
+        }
+        None
+    }
+
+    /// Runs the `on_upload_complete` hooks, giving the name the upload of `name`
+    /// should be stored under.
+    pub fn upload_name(&self, name: &str, size: u64, client: &str) -> anyhow::Result<String> {
+        let mut name = name.to_string();
+        for plugin in &self.plugins {
+            let upload = Upload {
+                name: &name,
+                size,
+                client,
+            };
+            let reply = plugin
+                .call(Hook::UploadComplete, &upload)
+                .and_then(|reply| {
+                    reply
+                        .map(|reply| serde_json::from_slice::<Rename>(&reply))
+                        .transpose()
+                        .map_err(anyhow::Error::from)
+                })
+                .with_context(|| format!("Plugin {} failed", plugin.name))?;
+            if let Some(Rename { name: renamed }) = reply {
+                let valid = paths::validate_filename(&renamed).is_ok() && renamed != META_DIR;
+                anyhow::ensure!(
+                    valid,
+                    "Plugin {} renamed the upload to the invalid name '{}'",
+                    plugin.name,
+                    renamed
+                );
+                name = renamed;
+            }
+        }
+        Ok(name)
+    }
+
+    /// Runs the `rewrite_listing` hooks over the names in the share listing.
+    pub fn rewrite_listing(&self, files: Vec<String>) -> anyhow::Result<Vec<String>> {
+        let mut listing = Listing { files };
+        for plugin in &self.plugins {
+            let reply = plugin
+                .call(Hook::RewriteListing, &listing)
+                .and_then(|reply| {
+                    reply
+                        .map(|reply| serde_json::from_slice::<Listing>(&reply))
+                        .transpose()
+                        .map_err(anyhow::Error::from)
+                })
+                .with_context(|| format!("Plugin {} failed", plugin.name))?;
+            if let Some(rewritten) = reply {
+                // Plugins choose among the files, they can't make new ones appear
+                let files = rewritten
+                    .files
+                    .into_iter()
+                    .filter(|name| listing.files.contains(name))
+                    .collect();
+                listing = Listing { files };
+            }
+        }
+        Ok(listing.files)
+    }
+}
+
+impl Plugin {
+    fn load(engine: &Engine, path: &Path) -> anyhow::Result<Self> {
+        let module = Module::from_file(engine, path)?;
+        let mut store = Store::new(engine, ());
+        store.set_fuel(FUEL)?;
+        let instance = Instance::new(&mut store, &module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("No exported memory")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let mut hooks = Vec::new();
+        for hook in Hook::ALL {
+            if let Some(func) = instance.get_func(&mut store, hook.export()) {
+                hooks.push((hook, func.typed(&store)?));
+            }
+        }
+        anyhow::ensure!(
+            !hooks.is_empty(),
+            "Exports none of on_request, on_upload_complete, rewrite_listing"
+        );
+        Ok(Self {
+            name: path.file_name().map_or_else(
+                || path.display().to_string(),
+                |name| name.to_string_lossy().into(),
+            ),
+            store: Mutex::new(store),
+            memory,
+            alloc,
+            hooks,
+        })
+    }
+
+    /// Hands `input` to `hook`, giving its reply, or `None` when it has none or the
+    /// plugin doesn't export the hook.
+    fn call(&self, hook: Hook, input: &impl Serialize) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some((_, func)) = self.hooks.iter().find(|(h, _)| *h == hook) else {
+            return Ok(None);
+        };
+        let input = serde_json::to_vec(input)?;
+        let len = i32::try_from(input.len())?;
+
+        let mut store = self.store.lock().unwrap();
+        store.set_fuel(FUEL)?;
+        let ptr = self.alloc.call(&mut *store, len)?;
+        self.memory
+            .write(&mut *store, ptr as u32 as usize, &input)?;
+        let reply = func.call(&mut *store, (ptr, len))? as u64;
+        if reply == 0 {
+            return Ok(None);
+        }
+        let mut output = vec![0; (reply & 0xffff_ffff) as usize];
+        self.memory
+            .read(&*store, (reply >> 32) as usize, &mut output)?;
+        Ok(Some(output))
+    }
+}
+
+/// Where the upload of `name`, already locked by the caller, is to be stored, with
+/// the lock on that name when it is another one.
+pub fn upload_name<'a>(
+    name: &str,
+    size: u64,
+    client: &str,
+    state: &'a AppState,
+) -> anyhow::Result<(String, Option<WriteGuard<'a>>)> {
+    let Some(plugins) = &state.plugins else {
+        return Ok((name.to_string(), None));
+    };
+    let renamed = plugins.upload_name(name, size, client)?;
+    // Locks don't tell case apart, so a change of case is still covered by the caller's
+    if renamed.to_lowercase() == name.to_lowercase() {
+        return Ok((renamed, None));
+    }
+    let lock = state
+        .writes
+        .try_lock(&renamed)
+        .with_context(|| format!("'{}' is being written by another request", renamed))?;
+    Ok((renamed, Some(lock)))
+}
+
+/// The share listing as plugins want it shown.
+pub fn listing(files: Vec<String>, state: &AppState) -> anyhow::Result<Vec<String>> {
+    match &state.plugins {
+        Some(plugins) => plugins.rewrite_listing(files),
+        None => Ok(files),
+    }
+}
+
+/// Logs why a plugin failed and answers the request it was running for.
+pub fn failed(e: &anyhow::Error) -> Response<BoxBody> {
+    eprintln!("PLUGIN: {:#}", e);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(full("Plugin error"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    /// Refuses everything but GET, moves uploads to `renamed.txt` and lists only
+    /// `b.txt` and a file that doesn't exist.
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 16) "{\"status\":405,\"body\":\"read-only\"}")
+          (data (i32.const 64) "{\"name\":\"renamed.txt\"}")
+          (data (i32.const 96) "{\"files\":[\"b.txt\",\"missing.txt\"]}")
+          (func (export "alloc") (param $len i32) (result i32)
+            (global.get $next)
+            (global.set $next (i32.add (global.get $next) (local.get $len))))
+          ;; The method starts 11 bytes in, after {"method":"
+          (func (export "on_request") (param $ptr i32) (param $len i32) (result i64)
+            (if (result i64) (i32.eq (i32.load8_u offset=11 (local.get $ptr)) (i32.const 71))
+              (then (i64.const 0))
+              (else (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 33)))))
+          (func (export "on_upload_complete") (param i32 i32) (result i64)
+            (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 22)))
+          (func (export "rewrite_listing") (param i32 i32) (result i64)
+            (i64.or (i64.shl (i64.const 96) (i64.const 32)) (i64.const 33))))
+    "#;
+
+    const SPINNING: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "on_request") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    fn load(source: &str) -> Plugins {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plugin.wat");
+        std::fs::write(&path, source).unwrap();
+        Plugins::load(&[path]).unwrap()
+    }
+
+    fn on_request(plugins: &Plugins, method: Method) -> Option<Response<BoxBody>> {
+        plugins.on_request(&method, "/a.txt", None, &HeaderMap::new(), "127.0.0.1")
+    }
+
+    #[tokio::test]
+    async fn runs_hooks() {
+        let plugins = load(PLUGIN);
+
+        assert!(on_request(&plugins, Method::GET).is_none());
+        let response = on_request(&plugins, Method::POST).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "read-only");
+
+        assert_eq!(
+            plugins.upload_name("a.txt", 3, "127.0.0.1").unwrap(),
+            "renamed.txt"
+        );
+        let files = vec!["a.txt".to_string(), "b.txt".to_string()];
+        assert_eq!(plugins.rewrite_listing(files).unwrap(), ["b.txt"]);
+    }
+
+    #[test]
+    fn fails_requests_when_a_plugin_runs_out_of_fuel() {
+        let plugins = load(SPINNING);
+        let response = on_request(&plugins, Method::GET).unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // Hooks the plugin doesn't export leave things alone
+        assert_eq!(plugins.upload_name("a.txt", 3, "::1").unwrap(), "a.txt");
+    }
+}
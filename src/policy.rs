@@ -0,0 +1,270 @@
+use crate::access::{self, Role};
+use crate::{AppState, BoxBody, full, paths};
+use hyper::header::HeaderMap;
+use hyper::{Method, Response, StatusCode};
+use serde::Deserialize;
+
+/// What a folder allows, configured as `[[policy]]` in the config file.
+///
+/// A rule covers its folder and everything below it; a deeper rule overrides the
+/// settings it gives and inherits the others.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Folder of the share the rule covers, as in `/incoming`; `/` for the whole share
+    pub path: String,
+    /// Whether files may be downloaded and listed; `true` unless a parent rule says otherwise
+    pub download: Option<bool>,
+    /// Whether files may be uploaded or changed, renamed, copied into or deleted
+    pub upload: Option<bool>,
+    /// The least share link reaching the folder at all (`read`, `write` or `admin`);
+    /// needs `--share-links`
+    pub role: Option<Role>,
+}
+
+/// What a request does to the path it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Downloads, listings and anything else reading a file
+    Download,
+    /// Uploads and every other change: renames, copies, new folders and deletes
+    Upload,
+}
+
+impl Action {
+    /// What a request with `method` does to the file or folder its route names.
+    pub fn of(method: &Method) -> Self {
+        match *method == Method::GET || *method == Method::HEAD {
+            true => Self::Download,
+            false => Self::Upload,
+        }
+    }
+}
+
+/// The configured per-folder policies, checked once at startup.
+///
+/// The admin link of `--share-links` is bound by `role` rules only, so whoever runs
+/// the share can still tidy a drop box nobody else may download from.
+#[derive(Debug, Default)]
+pub struct Policies {
+    /// Rules by folder, lowercased, parents before the folders below them
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// Lowercased path segments of the folder, empty for the whole share
+    segments: Vec<String>,
+    config: PolicyConfig,
+}
+
+impl Policies {
+    pub fn new(configs: &[PolicyConfig], share_links: bool) -> anyhow::Result<Self> {
+        let mut rules = configs
+            .iter()
+            .map(|config| {
+                let path = paths::validate_path(config.path.trim_matches('/')).map_err(|e| {
+                    anyhow::anyhow!("Invalid path '{}' in [[policy]]: {}", config.path, e)
+                })?;
+                if config.role.is_some() && !share_links {
+                    anyhow::bail!(
+                        "The [[policy]] for '{}' sets a role, which needs --share-links",
+                        config.path
+                    );
+                }
+                let mut config = config.clone();
+                config.path = format!("/{}", path);
+                Ok(Rule {
+                    segments: segments(&path),
+                    config,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        rules.sort_by_key(|rule| rule.segments.len());
+        for (i, rule) in rules.iter().enumerate() {
+            if rules[..i]
+                .iter()
+                .any(|other| other.segments == rule.segments)
+            {
+                anyhow::bail!("Two [[policy]] rules for '{}'", rule.config.path);
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Why a request made with `role` may not take `action` on `path`, a path within
+    /// the share (empty for its top level), if it may not.
+    pub fn refusal(&self, action: Action, path: &str, role: Option<Role>) -> Option<String> {
+        let path = segments(path.trim_matches('/'));
+        let mut download = None;
+        let mut upload = None;
+        let mut needs = None;
+        for rule in &self.rules {
+            if !path.starts_with(&rule.segments) {
+                continue;
+            }
+            let config = &rule.config;
+            download = config
+                .download
+                .map(|allowed| (allowed, &config.path))
+                .or(download);
+            upload = config
+                .upload
+                .map(|allowed| (allowed, &config.path))
+                .or(upload);
+            needs = config.role.map(|role| (role, &config.path)).or(needs);
+        }
+
+        if let Some((needs, folder)) = needs
+            && role.is_none_or(|role| role < needs)
+        {
+            return Some(format!(
+                "'{}' needs the {} link of the share",
+                folder,
+                name(needs)
+            ));
+        }
+        if role == Some(Role::Admin) {
+            return None;
+        }
+        match (action, download, upload) {
+            (Action::Download, Some((false, folder)), _) => {
+                Some(format!("Downloads are not allowed from '{}'", folder))
+            }
+            (Action::Upload, _, Some((false, folder))) => {
+                Some(format!("Uploads are not allowed to '{}'", folder))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a request made with `role` may take `action` on `path`.
+    pub fn allows(&self, action: Action, path: &str, role: Option<Role>) -> bool {
+        self.refusal(action, path, role).is_none()
+    }
+
+    /// The `403 Forbidden` to send instead when a request made with `role` may not take
+    /// `action` on `path`.
+    pub fn challenge(
+        &self,
+        action: Action,
+        path: &str,
+        role: Option<Role>,
+    ) -> Option<Response<BoxBody>> {
+        let reason = self.refusal(action, path, role)?;
+        Some(forbidden(reason))
+    }
+}
+
+/// The role of the share token a request carries, if `--share-links` is on and the
+/// token is valid.
+pub fn requester(state: &AppState, headers: &HeaderMap, query: Option<&str>) -> Option<Role> {
+    let tokens = state.share_tokens.as_ref()?;
+    tokens.role(access::presented(headers, query)?)
+}
+
+fn forbidden(reason: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(full(format!("{}\n", reason)))
+        .unwrap()
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn name(role: Role) -> &'static str {
+    match role {
+        Role::Read => "read",
+        Role::Write => "write",
+        Role::Admin => "admin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, download: Option<bool>, upload: Option<bool>) -> PolicyConfig {
+        PolicyConfig {
+            path: path.to_string(),
+            download,
+            upload,
+            role: None,
+        }
+    }
+
+    fn exchange() -> Policies {
+        let mut private = rule("/private", None, None);
+        private.role = Some(Role::Admin);
+        Policies::new(
+            &[
+                rule("/", None, Some(false)),
+                rule("/incoming/", Some(false), Some(true)),
+                rule("outgoing", None, None),
+                private,
+            ],
+            true,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn deeper_rules_override_their_parents() {
+        let policies = exchange();
+        assert!(policies.allows(Action::Download, "", None));
+        assert!(!policies.allows(Action::Upload, "report.pdf", None));
+        assert!(policies.allows(Action::Upload, "incoming/scan.pdf", None));
+        assert!(policies.allows(Action::Upload, "Incoming/2024/scan.pdf", None));
+        assert!(!policies.allows(Action::Download, "incoming/scan.pdf", Some(Role::Write)));
+        assert!(!policies.allows(Action::Upload, "outgoing/build.zip", None));
+        assert!(policies.allows(Action::Download, "outgoing/build.zip", None));
+        // Only a whole segment matches
+        assert!(!policies.allows(Action::Upload, "incomingx/scan.pdf", None));
+        assert_eq!(
+            policies
+                .refusal(Action::Download, "incoming", None)
+                .unwrap(),
+            "Downloads are not allowed from '/incoming'"
+        );
+    }
+
+    #[test]
+    fn roles_bind_even_the_admin_link() {
+        let policies = exchange();
+        assert_eq!(
+            policies
+                .refusal(Action::Download, "private/keys.txt", Some(Role::Write))
+                .unwrap(),
+            "'/private' needs the admin link of the share"
+        );
+        assert!(!policies.allows(Action::Download, "PRIVATE", None));
+        assert!(policies.allows(Action::Upload, "private/keys.txt", Some(Role::Admin)));
+        assert!(policies.allows(Action::Download, "incoming/scan.pdf", Some(Role::Admin)));
+    }
+
+    #[test]
+    fn rules_are_checked() {
+        let err = |configs: &[PolicyConfig], share_links| {
+            Policies::new(configs, share_links).unwrap_err().to_string()
+        };
+        assert!(err(&[rule("/a/../b", None, None)], false).contains("Invalid path"));
+        assert!(err(&[rule("/a", None, None), rule("a/", None, None)], false).contains("Two"));
+        let mut admin = rule("/a", None, None);
+        admin.role = Some(Role::Admin);
+        assert!(err(&[admin], false).contains("--share-links"));
+        assert!(
+            Policies::new(&[], false)
+                .unwrap()
+                .allows(Action::Upload, "a", None)
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::ffi::CString;
+use std::io;
+
+/// Switches to `user` and/or `group` (names or numeric ids) for the rest of the
+/// process, so a server started as root to bind a low port doesn't serve as root.
+///
+/// Naming only a user also adopts that user's primary group. Fails if root could
+/// be regained afterwards.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+
+    if let Some(gid) = gid {
+        // Supplementary groups are inherited from root; keep only the target group
+        check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+        check(unsafe { libc::setgid(gid) }, "setgid")?;
+    }
+    if let Some((uid, _)) = user {
+        check(unsafe { libc::setuid(uid) }, "setuid")?;
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            anyhow::bail!("Privileges were not dropped: could switch back to root");
+        }
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int, call: &str) -> anyhow::Result<()> {
+    if result != 0 {
+        anyhow::bail!("{} failed: {}", call, io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resolves a user name or numeric uid to its uid and primary gid.
+fn lookup_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !entry.is_null() {
+        let entry = unsafe { &*entry };
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+    if let Ok(uid) = user.parse() {
+        let entry = unsafe { libc::getpwuid(uid) };
+        // An id without a passwd entry keeps the current primary group
+        let gid = if entry.is_null() {
+            unsafe { libc::getgid() }
+        } else {
+            unsafe { (*entry).pw_gid }
+        };
+        return Ok((uid, gid));
+    }
+    anyhow::bail!("Unknown user '{}'", user)
+}
+
+/// Resolves a group name or numeric gid.
+fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    group
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unknown group '{}'", group))
+}
@@ -0,0 +1,503 @@
+use crate::history::{History, Outcome, Record};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// How long the outcome of a finished upload stays available to [`Transfers::upload`]
+const UPLOAD_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Console display for all in-flight transfers.
+///
+/// Each transfer gets its own bar inside a shared `MultiProgress`, topped by a
+/// summary line counting active, completed and interrupted transfers. Log lines
+/// about transfers must go through [`Transfers::println`] so they are printed
+/// above the bars instead of tearing through them. A hidden display draws nothing
+/// and swallows those log lines.
+///
+/// Transfers are numbered as they start, so one still in flight can be looked up
+/// and cancelled by its id. Uploads can also carry an id chosen by the client, under
+/// which their progress is reported until a while after they end. Transfers that end
+/// are added to the [`History`], if kept.
+#[derive(Clone)]
+pub struct Transfers {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    multi: MultiProgress,
+    visible: bool,
+    history: Option<History>,
+    summary: Mutex<Summary>,
+    active: Mutex<Active>,
+}
+
+#[derive(Default)]
+struct Active {
+    last_id: u64,
+    transfers: BTreeMap<u64, ActiveTransfer>,
+    /// Uploads that ended, by client-chosen id, with when they ended
+    ended_uploads: HashMap<String, (Instant, UploadStatus)>,
+}
+
+/// What is known about a transfer still in flight
+struct ActiveTransfer {
+    direction: String,
+    filename: String,
+    client: Option<String>,
+    started: Instant,
+    bar: ProgressBar,
+    cancel: watch::Sender<bool>,
+    /// Set along with `cancel`, see [`TransferProgress::close_on_cancel`]
+    close: Option<watch::Sender<bool>>,
+    /// See [`TransferProgress::set_upload_id`]
+    upload_id: Option<String>,
+}
+
+impl ActiveTransfer {
+    fn status(&self, state: UploadState) -> UploadStatus {
+        UploadStatus {
+            bytes: self.bar.position(),
+            total: self.bar.length(),
+            state,
+        }
+    }
+}
+
+/// How far along an upload is, as reported by `GET /_progress/<id>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UploadStatus {
+    /// Bytes received so far
+    pub bytes: u64,
+    /// The size announced in `Content-Length`, if any
+    pub total: Option<u64>,
+    pub state: UploadState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    Receiving,
+    Completed,
+    Interrupted,
+}
+
+#[derive(Default)]
+struct Summary {
+    bar: Option<ProgressBar>,
+    active: usize,
+    completed: usize,
+    interrupted: usize,
+}
+
+impl Summary {
+    fn message(&self) -> String {
+        format!(
+            "Transfers: {} active, {} completed, {} interrupted",
+            self.active, self.completed, self.interrupted
+        )
+    }
+}
+
+impl Transfers {
+    pub fn new(visible: bool) -> Self {
+        Self::build(visible, None)
+    }
+
+    /// A display that also records every transfer ending in `history`.
+    pub fn recording(visible: bool, history: History) -> Self {
+        Self::build(visible, Some(history))
+    }
+
+    fn build(visible: bool, history: Option<History>) -> Self {
+        let multi = if visible {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        };
+        Self {
+            inner: Arc::new(Inner {
+                multi,
+                visible,
+                history,
+                summary: Mutex::new(Summary::default()),
+                active: Mutex::new(Active::default()),
+            }),
+        }
+    }
+
+    /// Starts tracking a transfer; `total` is the expected size when known.
+    pub fn start(&self, direction: &str, filename: &str, total: Option<u64>) -> TransferProgress {
+        let bar = match total {
+            Some(len) => ProgressBar::new(len).with_style(
+                ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {percent:>3}% {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {msg} {bytes} {bytes_per_sec}").unwrap(),
+            ),
+        };
+        bar.set_message(format!("{}: {}", direction, filename));
+
+        let mut summary = lock(&self.inner.summary);
+        summary.active += 1;
+        let message = summary.message();
+        let summary_bar = summary.bar.get_or_insert_with(|| {
+            let bar = self.inner.multi.insert(
+                0,
+                ProgressBar::new_spinner()
+                    .with_style(ProgressStyle::with_template("{msg}").unwrap()),
+            );
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar
+        });
+        summary_bar.set_message(message);
+
+        let bar = self.inner.multi.add(bar);
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        let (cancel, cancelled) = watch::channel(false);
+        let mut active = lock(&self.inner.active);
+        active.last_id += 1;
+        let id = active.last_id;
+        active.transfers.insert(
+            id,
+            ActiveTransfer {
+                direction: direction.to_string(),
+                filename: filename.to_string(),
+                client: None,
+                started: Instant::now(),
+                bar: bar.clone(),
+                cancel,
+                close: None,
+                upload_id: None,
+            },
+        );
+        TransferProgress {
+            id,
+            bar,
+            direction: direction.to_string(),
+            filename: filename.to_string(),
+            transfers: self.clone(),
+            cancelled,
+            finished: false,
+        }
+    }
+
+    /// The transfers in flight as `<id> <direction> <file> <client> <bytes>/<total>
+    /// <seconds>s` lines, oldest first.
+    pub fn list(&self) -> String {
+        let active = lock(&self.inner.active);
+        active
+            .transfers
+            .iter()
+            .map(|(id, transfer)| {
+                let total = transfer
+                    .bar
+                    .length()
+                    .map_or_else(|| "?".to_string(), |len| len.to_string());
+                format!(
+                    "{} {} {} {} {}/{} {}s\n",
+                    id,
+                    transfer.direction,
+                    transfer.filename,
+                    transfer.client.as_deref().unwrap_or("-"),
+                    transfer.bar.position(),
+                    total,
+                    transfer.started.elapsed().as_secs()
+                )
+            })
+            .collect()
+    }
+
+    /// Asks the transfer `id` to stop, giving what it was (`GET 'file'`), or `None`
+    /// when no such transfer is in flight.
+    pub fn cancel(&self, id: u64) -> Option<String> {
+        let active = lock(&self.inner.active);
+        let transfer = active.transfers.get(&id)?;
+        transfer.cancel.send_replace(true);
+        if let Some(close) = &transfer.close {
+            close.send_replace(true);
+        }
+        Some(format!("{} '{}'", transfer.direction, transfer.filename))
+    }
+
+    /// Progress of the upload the client tagged with `upload_id`, while it is in flight
+    /// and for a while after it ends.
+    pub fn upload(&self, upload_id: &str) -> Option<UploadStatus> {
+        let active = lock(&self.inner.active);
+        active
+            .transfers
+            .values()
+            .find(|transfer| transfer.upload_id.as_deref() == Some(upload_id))
+            .map(|transfer| transfer.status(UploadState::Receiving))
+            .or_else(|| {
+                active
+                    .ended_uploads
+                    .get(upload_id)
+                    .filter(|(ended, _)| ended.elapsed() < UPLOAD_RETENTION)
+                    .map(|(_, status)| status.clone())
+            })
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.inner.history.as_ref()
+    }
+
+    /// Prints a line without corrupting the progress display.
+    pub fn println(&self, line: impl AsRef<str>) {
+        if self.inner.visible {
+            self.inner.multi.suspend(|| println!("{}", line.as_ref()));
+        }
+    }
+
+    fn end(&self, id: u64, bar: &ProgressBar, completed: bool) {
+        let ended = {
+            let mut active = lock(&self.inner.active);
+            let transfer = active.transfers.remove(&id);
+            if let Some(transfer) = &transfer
+                && let Some(upload_id) = &transfer.upload_id
+            {
+                let state = match completed {
+                    true => UploadState::Completed,
+                    false => UploadState::Interrupted,
+                };
+                let status = transfer.status(state);
+                active
+                    .ended_uploads
+                    .retain(|_, (ended, _)| ended.elapsed() < UPLOAD_RETENTION);
+                active
+                    .ended_uploads
+                    .insert(upload_id.clone(), (Instant::now(), status));
+            }
+            transfer
+        };
+        if let (Some(history), Some(transfer)) = (&self.inner.history, ended) {
+            let outcome = match (completed, *transfer.cancel.borrow()) {
+                (true, _) => Outcome::Completed,
+                (false, true) => Outcome::Cancelled,
+                (false, false) => Outcome::Interrupted,
+            };
+            let record = Record {
+                ended: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+                direction: transfer.direction,
+                file: transfer.filename,
+                peer: transfer.client,
+                bytes: transfer.bar.position(),
+                total: transfer.bar.length(),
+                duration_ms: transfer.started.elapsed().as_millis() as u64,
+                outcome,
+            };
+            if let Err(e) = history.record(&record) {
+                self.println(format!("HISTORY: Error recording transfer: {}", e));
+            }
+        }
+        bar.finish_and_clear();
+        self.inner.multi.remove(bar);
+
+        let mut summary = lock(&self.inner.summary);
+        summary.active -= 1;
+        if completed {
+            summary.completed += 1;
+        } else {
+            summary.interrupted += 1;
+        }
+        let message = summary.message();
+        if summary.active == 0 {
+            // Nothing in flight: drop the summary line until the next transfer starts
+            if let Some(summary_bar) = summary.bar.take() {
+                summary_bar.finish_and_clear();
+                self.inner.multi.remove(&summary_bar);
+            }
+        } else if let Some(summary_bar) = &summary.bar {
+            summary_bar.set_message(message);
+        }
+    }
+}
+
+/// Locks the display's state even if a panicking connection left it poisoned: the
+/// state is only counters and bars, and without it no transfer could log again.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Progress of a single transfer, driven by the bytes actually streamed.
+///
+/// Dropping a transfer that was never finished counts it as interrupted.
+pub struct TransferProgress {
+    id: u64,
+    bar: ProgressBar,
+    direction: String,
+    filename: String,
+    transfers: Transfers,
+    cancelled: watch::Receiver<bool>,
+    finished: bool,
+}
+
+impl TransferProgress {
+    pub fn inc(&self, bytes: u64) {
+        self.bar.inc(bytes);
+    }
+
+    /// Total bytes transferred so far
+    pub fn bytes(&self) -> u64 {
+        self.bar.position()
+    }
+
+    /// Records who the transfer is with, for [`Transfers::list`].
+    pub fn set_client(&self, client: &str) {
+        let mut active = lock(&self.transfers.inner.active);
+        if let Some(transfer) = active.transfers.get_mut(&self.id) {
+            transfer.client = Some(client.to_string());
+        }
+    }
+
+    /// Tags an upload with an id the client chose, so its progress can be polled with
+    /// [`Transfers::upload`].
+    pub fn set_upload_id(&self, upload_id: &str) {
+        let mut active = lock(&self.transfers.inner.active);
+        if let Some(transfer) = active.transfers.get_mut(&self.id) {
+            transfer.upload_id = Some(upload_id.to_string());
+        }
+    }
+
+    /// Also sets `close` when the transfer is cancelled, so the connection carrying it
+    /// can be closed even while nothing is reading from it.
+    pub fn close_on_cancel(&self, close: watch::Sender<bool>) {
+        let mut active = lock(&self.transfers.inner.active);
+        if let Some(transfer) = active.transfers.get_mut(&self.id) {
+            transfer.close = Some(close);
+        }
+    }
+
+    /// Whether the transfer was cancelled with [`Transfers::cancel`].
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Resolves once the transfer is cancelled with [`Transfers::cancel`].
+    pub async fn cancelled(&self) {
+        let mut cancelled = self.cancelled.clone();
+        if cancelled.wait_for(|cancelled| *cancelled).await.is_err() {
+            // The transfer has ended, so it can't be cancelled anymore
+            std::future::pending().await
+        }
+    }
+
+    /// Prints a line above the progress display.
+    pub fn println(&self, line: impl AsRef<str>) {
+        self.transfers.println(line);
+    }
+
+    /// Ends a transfer the client abandoned midway, counting it as interrupted and
+    /// logging what became of the partial data.
+    pub fn abort(mut self, outcome: &str) {
+        self.finished = true;
+        let bytes = self.bytes();
+        self.transfers.end(self.id, &self.bar, false);
+        let by = match self.is_cancelled() {
+            true => "cancelled by the server",
+            false => "aborted by the client",
+        };
+        self.transfers.println(format!(
+            "{}: Transfer of '{}' {} after {} bytes; {}",
+            self.direction, self.filename, by, bytes, outcome
+        ));
+    }
+
+    /// Marks the transfer as complete and removes its bar.
+    pub fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.transfers.end(self.id, &self.bar, true);
+        }
+    }
+}
+
+impl Drop for TransferProgress {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Clearing the bar moves it to the end, so read the position first
+            let bytes = self.bytes();
+            self.transfers.end(self.id, &self.bar, false);
+            let how = match self.is_cancelled() {
+                true => "cancelled by the server",
+                false => "interrupted",
+            };
+            self.transfers.println(format!(
+                "{}: Transfer of '{}' {} after {} bytes",
+                self.direction, self.filename, how, bytes
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_in_flight_can_be_cancelled() {
+        let transfers = Transfers::new(false);
+        let mut progress = transfers.start("GET", "big.iso", Some(100));
+        progress.set_client("203.0.113.7");
+        progress.inc(40);
+        assert!(
+            transfers
+                .list()
+                .starts_with("1 GET big.iso 203.0.113.7 40/100 ")
+        );
+
+        assert_eq!(transfers.cancel(1).as_deref(), Some("GET 'big.iso'"));
+        assert!(progress.is_cancelled());
+        assert_eq!(transfers.cancel(2), None);
+
+        progress.finish();
+        assert_eq!(transfers.list(), "");
+        assert_eq!(transfers.cancel(1), None);
+    }
+
+    #[test]
+    fn tagged_uploads_report_progress_after_ending() {
+        let transfers = Transfers::new(false);
+        let mut progress = transfers.start("POST", "video.mp4", Some(100));
+        progress.set_upload_id("f3a9");
+        progress.inc(60);
+        assert_eq!(
+            transfers.upload("f3a9"),
+            Some(UploadStatus {
+                bytes: 60,
+                total: Some(100),
+                state: UploadState::Receiving,
+            })
+        );
+        assert_eq!(transfers.upload("other"), None);
+
+        progress.inc(40);
+        progress.finish();
+        assert_eq!(
+            transfers
+                .upload("f3a9")
+                .map(|status| (status.bytes, status.state)),
+            Some((100, UploadState::Completed))
+        );
+
+        let progress = transfers.start("POST", "video.mp4", None);
+        progress.set_upload_id("b7c2");
+        progress.inc(5);
+        drop(progress);
+        assert_eq!(
+            transfers
+                .upload("b7c2")
+                .map(|status| (status.bytes, status.state)),
+            Some((5, UploadState::Interrupted))
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::announce::AnnounceTarget;
+use crate::cli::{OutputMode, ProxyArgs};
+use crate::config::TunnelConfig;
+use crate::events::Events;
+use crate::{BoxBody, full, tunnel};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::{HOST, HeaderValue, LOCATION};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// Where proxied requests go.
+struct Upstream {
+    /// `host:port` to connect to, also sent as the Host header
+    authority: String,
+    /// Path prefix from the target URL, without a trailing slash
+    base_path: String,
+}
+
+impl Upstream {
+    fn parse(target: &str) -> anyhow::Result<Self> {
+        let uri: Uri = target.parse()?;
+        if uri.scheme_str() != Some("http") {
+            anyhow::bail!("Proxy target must be an http:// URL, got '{}'", target);
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow::anyhow!("Proxy target '{}' has no host", target))?;
+        let authority = match authority.port_u16() {
+            Some(_) => authority.to_string(),
+            None => format!("{}:80", authority.host()),
+        };
+        Ok(Self {
+            authority,
+            base_path: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// Runs `holodeck proxy`: forwards tunnel traffic to another local HTTP server
+/// instead of serving files.
+pub async fn run(
+    args: ProxyArgs,
+    bind: SocketAddr,
+    config: &TunnelConfig,
+    output: OutputMode,
+    announce: Option<AnnounceTarget>,
+) -> anyhow::Result<()> {
+    let upstream = Arc::new(Upstream::parse(&args.target)?);
+    let listener = TcpListener::bind(bind).await?;
+    let local_addr = listener.local_addr()?;
+
+    match output {
+        OutputMode::Normal => println!(
+            "Proxying http://{} to {}",
+            local_addr,
+            args.target.trim_end_matches('/')
+        ),
+        OutputMode::Porcelain => println!("LOCAL=http://{}", local_addr),
+        OutputMode::Quiet => {}
+    }
+
+    let (url_tx, url_rx) = watch::channel(None);
+    let tunnel = tunnel::from_config(config)?.map(|provider| {
+        tunnel::spawn_tunnel(
+            provider,
+            local_addr.port(),
+            output,
+            url_tx,
+            Events::new(),
+            None,
+        )
+    });
+    if let Some(target) = announce {
+        crate::announce_when_ready(
+            target,
+            local_addr.to_string(),
+            None,
+            url_rx,
+            tunnel.is_some(),
+        );
+    }
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let _ = stream.set_nodelay(true);
+        let upstream = upstream.clone();
+
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req| forward(req, peer, upstream.clone()));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                eprintln!("Error serving connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn forward(
+    mut req: Request<Incoming>,
+    peer: SocketAddr,
+    upstream: Arc<Upstream>,
+) -> Result<Response<BoxBody>, hyper::Error> {
+    let client = crate::client_addr(&req, peer);
+    let original_host = req.headers().get(HOST).cloned();
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let uri = format!("{}{}", upstream.base_path, path);
+    *req.uri_mut() = match uri.parse() {
+        Ok(uri) => uri,
+        Err(_) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid request path",
+            ));
+        }
+    };
+
+    let headers = req.headers_mut();
+    if let Ok(host) = HeaderValue::from_str(&upstream.authority) {
+        headers.insert(HOST, host);
+    }
+    if let Some(host) = original_host {
+        headers.insert("x-forwarded-host", host);
+    }
+    if let Ok(client) = HeaderValue::from_str(&client) {
+        headers.insert("x-forwarded-for", client);
+    }
+
+    // WebSocket and other upgrades (dev-server hot reload) are spliced after the 101
+    let client_upgrade = hyper::upgrade::on(&mut req);
+
+    let stream = match TcpStream::connect(&upstream.authority).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_GATEWAY,
+                &format!("Cannot reach {}: {}", upstream.authority, e),
+            ));
+        }
+    };
+    let _ = stream.set_nodelay(true);
+    let (mut sender, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+    {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_GATEWAY,
+                &format!("Upstream handshake failed: {}", e),
+            ));
+        }
+    };
+    tokio::spawn(conn.with_upgrades());
+
+    let mut response = match sender.send_request(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_GATEWAY,
+                &format!("Upstream request failed: {}", e),
+            ));
+        }
+    };
+
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let upstream_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            if let (Ok(client), Ok(upstream)) = tokio::join!(client_upgrade, upstream_upgrade) {
+                let _ = tokio::io::copy_bidirectional(
+                    &mut TokioIo::new(client),
+                    &mut TokioIo::new(upstream),
+                )
+                .await;
+            }
+        });
+    }
+
+    // Redirects to the upstream's own address would send visitors to localhost
+    let absolute = format!("http://{}", upstream.authority);
+    if let Some(location) = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        && let Some(relative) = location.strip_prefix(&absolute)
+        && let Ok(relative) =
+            HeaderValue::from_str(if relative.is_empty() { "/" } else { relative })
+    {
+        response.headers_mut().insert(LOCATION, relative);
+    }
+
+    Ok(response.map(|body| body.map_err(std::io::Error::other).boxed()))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<BoxBody> {
+    eprintln!("PROXY: {}", message);
+    Response::builder()
+        .status(status)
+        .body(full(message.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,132 @@
+use crate::cli::{OutputMode, PushArgs};
+use crate::client;
+use crate::mirror::{self, LocalHashes};
+use crate::paths;
+use crate::progress::Transfers;
+use anyhow::bail;
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+
+/// Size and modification time, which tell a file changed without reading it
+type Stamp = (u64, Option<SystemTime>);
+
+/// Uploads a local directory's changed files to a remote share.
+struct Pusher {
+    client: Client,
+    /// The remote share, ending in `/`, with any `?token=` it was given
+    remote: Url,
+    dir: PathBuf,
+    /// Files as they were when last found up to date on the remote
+    pushed: HashMap<String, Stamp>,
+    /// Changed files seen on the previous scan, uploaded once they stop changing
+    settling: HashMap<String, Stamp>,
+    hashes: LocalHashes,
+    transfers: Transfers,
+}
+
+/// Runs `holodeck push`: uploads the files of a directory that the remote share lacks
+/// or holds a different version of, then with `--watch` keeps checking the directory
+/// every `--interval` and uploads files as they change.
+///
+/// A changed file is only uploaded once it looks the same on two checks in a row, so
+/// a build still writing it doesn't get half a file published. Only top-level files
+/// are pushed, and files deleted locally stay on the remote.
+pub async fn run(args: PushArgs, output: OutputMode) -> anyhow::Result<()> {
+    let mut remote = Url::parse(&args.url)?;
+    if !remote.path().ends_with('/') {
+        remote.set_path(&format!("{}/", remote.path()));
+    }
+    if !fs::metadata(&args.dir).await?.is_dir() {
+        bail!("'{}' is not a directory", args.dir.display());
+    }
+
+    let mut pusher = Pusher {
+        client: Client::new(),
+        remote,
+        dir: args.dir,
+        pushed: HashMap::new(),
+        settling: HashMap::new(),
+        hashes: LocalHashes::default(),
+        transfers: Transfers::new(output == OutputMode::Normal),
+    };
+    // Whatever is there already is complete
+    let failed = pusher.scan(false).await?;
+    if !args.watch {
+        if failed > 0 {
+            bail!("{} files could not be uploaded", failed);
+        }
+        return Ok(());
+    }
+
+    if output == OutputMode::Normal {
+        println!(
+            "Watching {} and uploading changes to {}",
+            pusher.dir.display(),
+            pusher.remote
+        );
+    }
+    let mut interval = tokio::time::interval(args.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = pusher.scan(true).await {
+            eprintln!("PUSH: Error reading {}: {}", pusher.dir.display(), e);
+        }
+    }
+}
+
+impl Pusher {
+    /// Uploads the files that changed since the last scan; with `settle`, only those
+    /// that also didn't change since the scan before. Gives how many uploads failed.
+    async fn scan(&mut self, settle: bool) -> anyhow::Result<usize> {
+        let mut failed = 0;
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // Symlinks are skipped rather than followed out of the directory
+            let metadata = fs::symlink_metadata(entry.path()).await?;
+            if !metadata.is_file() || paths::validate_filename(&name).is_err() {
+                continue;
+            }
+            let stamp = (metadata.len(), metadata.modified().ok());
+            if self.pushed.get(&name) == Some(&stamp) {
+                continue;
+            }
+            if settle && self.settling.insert(name.clone(), stamp) != Some(stamp) {
+                continue;
+            }
+            match self.push(&name, &entry.path()).await {
+                Ok(()) => {
+                    self.settling.remove(&name);
+                    self.pushed.insert(name, stamp);
+                }
+                // Left to settle, so the next scan tries again
+                Err(e) => {
+                    eprintln!("PUSH: Error uploading '{}': {}", name, e);
+                    failed += 1;
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Uploads `path` as `name` unless the remote already has this content.
+    async fn push(&mut self, name: &str, path: &Path) -> anyhow::Result<()> {
+        let url = mirror::file_url(&self.remote, name);
+        let local = self.hashes.get(name, path).await?;
+        let remote = client::remote_sha256(&self.client, &url, name).await?;
+        if local.is_some() && local == remote {
+            return Ok(());
+        }
+        let len = client::post_file(&self.client, &url, path).await?;
+        self.transfers
+            .println(format!("PUSH: Uploaded '{}' ({} bytes)", name, len));
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use crate::cli::QrArgs;
+use crate::router::Router;
+use crate::{AppState, BoxBody, commands, full, paths};
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use qrcode::render::{svg, unicode};
+use qrcode::{Color, QrCode};
+use std::io;
+use tokio::fs;
+
+/// Pixels per module in PNG codes, big enough to scan off a screen at arm's length
+const PNG_SCALE: usize = 8;
+
+/// Light modules around the code that scanners need to find it, per the QR spec
+const QUIET_ZONE: usize = 4;
+
+/// Image format of a code asked for with `?qr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Svg,
+    Png,
+}
+
+impl Format {
+    /// The format `?qr` or `?qr=svg|png` asks for, `None` without a `qr` parameter.
+    fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let Some(value) = query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| match pair.split_once('=') {
+                Some(("qr", value)) => Some(value),
+                None if pair == "qr" => Some(""),
+                _ => None,
+            })
+        else {
+            return Ok(None);
+        };
+        match value {
+            "" | "svg" => Ok(Some(Self::Svg)),
+            "png" => Ok(Some(Self::Png)),
+            other => Err(format!(
+                "Unknown QR code format '{}': use svg or png",
+                other
+            )),
+        }
+    }
+}
+
+/// Registers `GET /<file>?qr`.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/{*path}", async |req, cx| {
+            get(req.headers(), &cx.path, req.uri().query(), &cx.state).await
+        })
+        .when(|req, _| is_qr(req.uri().query()))
+        .scoped("path");
+}
+
+/// Whether the query asks for a QR code instead of the file.
+fn is_qr(query: Option<&str>) -> bool {
+    Format::from_query(query) != Ok(None)
+}
+
+/// Answers `GET /<file>?qr` with a QR code of the file's full URL, so a phone can
+/// fetch it with one scan. The URL is the one [`commands::file_url`] gives.
+pub async fn get(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let format = match Format::from_query(query) {
+        Ok(Some(format)) => format,
+        Ok(None) => return Ok(crate::not_found()),
+        Err(e) => return Ok(text(StatusCode::BAD_REQUEST, e)),
+    };
+    let filename = path.trim_start_matches('/');
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    let is_file = fs::symlink_metadata(state.root.join(filename))
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    if filename == crate::META_DIR || !is_file {
+        return Ok(text(
+            StatusCode::NOT_FOUND,
+            format!("File '{}' not found", filename),
+        ));
+    }
+
+    let url = commands::file_url(headers, filename, query, state);
+    let code = QrCode::new(url.as_bytes()).map_err(io::Error::other)?;
+    let (content_type, body) = match format {
+        Format::Svg => (
+            "image/svg+xml",
+            code.render::<svg::Color>().build().into_bytes(),
+        ),
+        Format::Png => ("image/png", png(&code)?),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(full(body))
+        .unwrap())
+}
+
+/// Encodes `code` as a black-on-white grayscale PNG.
+fn png(code: &QrCode) -> io::Result<Vec<u8>> {
+    let modules = code.width();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+    let colors = code.to_colors();
+    let mut pixels = vec![0xff; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let (x, y) = (i % modules + QUIET_ZONE, i / modules + QUIET_ZONE);
+        for row in y * PNG_SCALE..(y + 1) * PNG_SCALE {
+            pixels[row * size + x * PNG_SCALE..][..PNG_SCALE].fill(0);
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&pixels).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    Ok(bytes)
+}
+
+/// `holodeck qr`: prints a QR code of a link in the terminal.
+pub fn run(args: QrArgs) -> anyhow::Result<()> {
+    let code = QrCode::new(args.url.as_bytes())?;
+    // Inverted, as terminals are usually light text on a dark background
+    let rendered = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    println!("{}\n{}", rendered, args.url);
+    Ok(())
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qr_parameter_picks_the_format() {
+        assert_eq!(Format::from_query(None), Ok(None));
+        assert_eq!(Format::from_query(Some("token=abc")), Ok(None));
+        assert_eq!(Format::from_query(Some("qr")), Ok(Some(Format::Svg)));
+        assert_eq!(
+            Format::from_query(Some("token=abc&qr=png")),
+            Ok(Some(Format::Png))
+        );
+        assert!(Format::from_query(Some("qr=gif")).is_err());
+    }
+
+    #[test]
+    fn png_has_a_quiet_zone_and_scaled_modules() {
+        let code = QrCode::new(b"https://abc123.lhr.life/report.pdf").unwrap();
+        let bytes = png(&code).unwrap();
+        let decoder = png::Decoder::new(bytes.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        let size = (code.width() + 2 * QUIET_ZONE) * PNG_SCALE;
+        assert_eq!((info.width, info.height), (size as u32, size as u32));
+
+        // Light border, then the top-left corner of a finder pattern
+        let edge = QUIET_ZONE * PNG_SCALE;
+        assert_eq!(pixels[(edge - 1) * size + edge - 1], 0xff);
+        assert_eq!(pixels[edge * size + edge], 0);
+    }
+}
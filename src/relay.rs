@@ -0,0 +1,532 @@
+use crate::passcode::random_token;
+use crate::router::Router;
+#[cfg(feature = "client")]
+use crate::tunnel::{TunnelProvider, UrlReporter};
+use crate::{AppState, BoxBody, full};
+use http_body_util::BodyExt;
+use hyper::header::{CONNECTION, HeaderValue, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "client")]
+use std::future::Future;
+use std::io;
+#[cfg(feature = "client")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(any(feature = "client", test))]
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "client")]
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+/// Route prefix shares relayed through a hub are reached under
+const PREFIX: &str = "/_relay";
+
+/// Protocol named in the `Upgrade` header of a peer's connections to its hub
+const PROTOCOL: &str = "holodeck-relay";
+
+/// How long a hub waits for a peer to open the connection a visitor needs
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a hub tells its peers it is still there
+const HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Longest peer name accepted
+const MAX_NAME_LEN: usize = 63;
+
+/// Messages from a hub to its peer on their control connection, JSON each terminated
+/// by a NUL byte, as bore does.
+#[derive(Serialize, Deserialize)]
+enum Message {
+    /// The public URL of the peer's share
+    Hello(String),
+    Heartbeat,
+    /// A visitor is waiting: open a connection to `/_relay/accept/<id>`
+    Connection(String),
+}
+
+/// Lets other holodeck instances that can't open a tunnel of their own register with
+/// this one (`--relay-hub`), which then proxies requests under `/_relay/<name>/` to
+/// them over connections they opened themselves.
+///
+/// A peer keeps a control connection open; for each visitor the hub asks it over that
+/// connection for a new one, on which the visitor's request is sent as is. Registering
+/// needs this server's credentials, while relayed requests only need the peer's.
+#[derive(Default)]
+pub struct RelayHub {
+    /// Control channels of the connected peers, by name, taking connection ids
+    peers: Mutex<HashMap<String, mpsc::UnboundedSender<String>>>,
+    /// Visitors waiting for their peer to connect, by connection id
+    pending: Mutex<HashMap<String, oneshot::Sender<Upgraded>>>,
+}
+
+impl RelayHub {
+    /// Asks the peer `name` for a connection, waiting until it arrives.
+    async fn open(&self, name: &str) -> Result<Upgraded, Response<BoxBody>> {
+        let id =
+            random_token().map_err(|e| text(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), sender);
+        let asked = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_some_and(|peer| peer.send(id.clone()).is_ok());
+        if !asked {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(text(
+                StatusCode::BAD_GATEWAY,
+                format!("No holodeck named '{}' is connected to this relay", name),
+            ));
+        }
+        match tokio::time::timeout(CONNECT_TIMEOUT, receiver).await {
+            Ok(Ok(upgraded)) => Ok(upgraded),
+            _ => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(text(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!("'{}' didn't answer in time", name),
+                ))
+            }
+        }
+    }
+}
+
+/// Registers the relay routes when `--relay-hub` is set: `/_relay/connect/<name>` and
+/// `/_relay/accept/<id>` for peers, and `/_relay/<name>/...` for their visitors.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/_relay/connect/{name}", async |req, cx| {
+            Ok(connect(req, cx.param("name"), &cx.client, cx.state.clone()))
+        })
+        .when(|_, state| state.relay_hub.is_some());
+    router
+        .get("/_relay/accept/{id}", async |req, cx| {
+            Ok(accept(req, cx.param("id"), &cx.state))
+        })
+        .when(|_, state| state.relay_hub.is_some());
+    // The peer redirects its bare base path to the one with a slash
+    for pattern in ["/_relay/{name}", "/_relay/{name}/{*rest}"] {
+        router
+            .any(pattern, async |req, cx| {
+                forward(req, cx.param("name"), &cx.client, &cx.state).await
+            })
+            .when(|_, state| state.relay_hub.is_some());
+    }
+}
+
+/// Whether a request to `route` goes to a peer, which checks its credentials itself.
+pub fn is_relayed(route: &str) -> bool {
+    route
+        .strip_prefix(PREFIX)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.split('/').next())
+        .is_some_and(|name| !["", "connect", "accept"].contains(&name))
+}
+
+/// Names are a single DNS-label-like path segment, so they read well in a URL.
+fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        && !["connect", "accept"].contains(&name)
+}
+
+/// Handles a peer registering as `name`: switches the connection to the relay protocol
+/// and keeps it as the peer's control connection until either side hangs up.
+fn connect(
+    mut req: Request<BoxBody>,
+    name: &str,
+    client: &str,
+    state: Arc<AppState>,
+) -> Response<BoxBody> {
+    let Some(hub) = &state.relay_hub else {
+        return crate::not_found();
+    };
+    if !is_valid_name(name) {
+        return text(StatusCode::BAD_REQUEST, "Invalid relay name");
+    }
+    if !wants_relay(&req) {
+        return text(
+            StatusCode::UPGRADE_REQUIRED,
+            format!("Expected 'Upgrade: {}'", PROTOCOL),
+        );
+    }
+    let (sender, mut connections) = mpsc::unbounded_channel();
+    {
+        let mut peers = hub.peers.lock().unwrap();
+        if peers.get(name).is_some_and(|peer| !peer.is_closed()) {
+            return text(
+                StatusCode::CONFLICT,
+                format!("Another holodeck is connected as '{}'", name),
+            );
+        }
+        peers.insert(name.to_string(), sender.clone());
+    }
+
+    let url = format!(
+        "{}{}/{}",
+        crate::base_url(req.headers(), &state),
+        PREFIX,
+        name
+    );
+    state.transfers.println(format!(
+        "RELAY: '{}' connected from {}, reachable at {}",
+        name, client, url
+    ));
+    let upgrade = hyper::upgrade::on(&mut req);
+    let name = name.to_string();
+    tokio::spawn(async move {
+        if let Ok(upgraded) = upgrade.await {
+            let mut control = TokioIo::new(upgraded);
+            if let Err(e) = control_loop(&mut control, &url, &mut connections).await {
+                tracing::debug!(error = %e, "relay control connection ended");
+            }
+        }
+        if let Some(hub) = &state.relay_hub {
+            let mut peers = hub.peers.lock().unwrap();
+            if peers
+                .get(&name)
+                .is_some_and(|peer| peer.same_channel(&sender))
+            {
+                peers.remove(&name);
+            }
+        }
+        state
+            .transfers
+            .println(format!("RELAY: '{}' disconnected", name));
+    });
+    switching_protocols()
+}
+
+/// Tells the peer its URL, then passes on the connections visitors need and heartbeats
+/// until the peer hangs up.
+async fn control_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    control: &mut S,
+    url: &str,
+    connections: &mut mpsc::UnboundedReceiver<String>,
+) -> anyhow::Result<()> {
+    send(control, &Message::Hello(url.to_string())).await?;
+    let mut heartbeat = tokio::time::interval(HEARTBEAT);
+    let mut buf = [0; 64];
+    loop {
+        tokio::select! {
+            id = connections.recv() => match id {
+                Some(id) => send(control, &Message::Connection(id)).await?,
+                None => return Ok(()),
+            },
+            _ = heartbeat.tick() => send(control, &Message::Heartbeat).await?,
+            read = control.read(&mut buf) => if read? == 0 {
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// Handles a peer opening the connection a visitor is waiting for.
+fn accept(mut req: Request<BoxBody>, id: &str, state: &AppState) -> Response<BoxBody> {
+    let Some(hub) = &state.relay_hub else {
+        return crate::not_found();
+    };
+    if !wants_relay(&req) {
+        return text(
+            StatusCode::UPGRADE_REQUIRED,
+            format!("Expected 'Upgrade: {}'", PROTOCOL),
+        );
+    }
+    let Some(waiting) = hub.pending.lock().unwrap().remove(id) else {
+        return crate::not_found();
+    };
+    let upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        if let Ok(upgraded) = upgrade.await {
+            let _ = waiting.send(upgraded);
+        }
+    });
+    switching_protocols()
+}
+
+/// Sends a visitor's request to the peer `name` and its response back, splicing the
+/// two connections after a `101` as the proxy does.
+async fn forward(
+    mut req: Request<BoxBody>,
+    name: &str,
+    client: &str,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let Some(hub) = &state.relay_hub else {
+        return Ok(crate::not_found());
+    };
+    let upgraded = match hub.open(name).await {
+        Ok(upgraded) => upgraded,
+        Err(response) => return Ok(response),
+    };
+    let (mut sender, connection) = match hyper::client::conn::http1::handshake(upgraded).await {
+        Ok(handshake) => handshake,
+        Err(e) => return Ok(text(StatusCode::BAD_GATEWAY, e.to_string())),
+    };
+    tokio::spawn(connection.with_upgrades());
+
+    let headers = req.headers_mut();
+    if !headers.contains_key("x-forwarded-proto") {
+        headers.insert("x-forwarded-proto", HeaderValue::from_static(state.scheme));
+    }
+    if let Ok(client) = HeaderValue::from_str(client) {
+        headers.insert("x-forwarded-for", client);
+    }
+    let visitor_upgrade = hyper::upgrade::on(&mut req);
+    let mut response = match sender.send_request(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            return Ok(text(
+                StatusCode::BAD_GATEWAY,
+                format!("'{}' failed to answer: {}", name, e),
+            ));
+        }
+    };
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let peer_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            if let (Ok(visitor), Ok(peer)) = tokio::join!(visitor_upgrade, peer_upgrade) {
+                let _ = tokio::io::copy_bidirectional(
+                    &mut TokioIo::new(visitor),
+                    &mut TokioIo::new(peer),
+                )
+                .await;
+            }
+        });
+    }
+    Ok(response.map(|body| body.map_err(io::Error::other).boxed()))
+}
+
+fn wants_relay(req: &Request<BoxBody>) -> bool {
+    req.headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|protocol| protocol.eq_ignore_ascii_case(PROTOCOL))
+}
+
+fn switching_protocols() -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, PROTOCOL)
+        .body(full(""))
+        .unwrap()
+}
+
+/// Reaches the internet through another holodeck started with `--relay-hub`, for
+/// machines that can't open a tunnel of their own (`--relay-via`).
+#[cfg(feature = "client")]
+pub struct RelayTunnel {
+    /// The hub's URL, without a trailing slash or query
+    hub: String,
+    /// `host[:port]` of the hub, the service its names are remembered under
+    authority: String,
+    /// Path of the hub's URL, for a hub served under `--base-path`
+    hub_path: String,
+    /// Query of the hub's URL, carrying the hub's token or passcode
+    query: Option<String>,
+    name: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "client")]
+impl RelayTunnel {
+    /// Relays through the hub at `url` under a random name, until another is asked for
+    /// with [`TunnelProvider::request_name`].
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| anyhow::anyhow!("Invalid relay URL '{}': {}", url, e))?;
+        if !["http", "https"].contains(&parsed.scheme()) {
+            anyhow::bail!("Relay URL must be http:// or https://, got '{}'", url);
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Relay URL '{}' has no host", url))?;
+        let authority = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let hub_path = parsed.path().trim_end_matches('/').to_string();
+        Ok(Self {
+            hub: format!("{}://{}{}", parsed.scheme(), authority, hub_path),
+            authority,
+            hub_path,
+            query: parsed.query().map(str::to_string),
+            name: random_token()?[..8].to_string(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// The path the hub serves this share under, to serve it under as well.
+    pub fn base_path(&self) -> String {
+        format!("{}{}/{}", self.hub_path, PREFIX, self.name)
+    }
+
+    /// A request for the hub's `route`, asking to switch to the relay protocol.
+    fn request(&self, route: &str) -> reqwest::RequestBuilder {
+        let mut url = format!("{}{}", self.hub, route);
+        if let Some(query) = &self.query {
+            url = format!("{}?{}", url, query);
+        }
+        self.client
+            .get(url)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, PROTOCOL)
+    }
+}
+
+/// Sends `request` to the hub, giving the connection once switched to the relay protocol.
+#[cfg(feature = "client")]
+async fn upgrade(request: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Upgraded> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        let reason = response.text().await.unwrap_or_default();
+        anyhow::bail!("the relay refused: {} {}", status, reason.trim());
+    }
+    Ok(response.upgrade().await?)
+}
+
+#[cfg(feature = "client")]
+impl TunnelProvider for RelayTunnel {
+    fn describe(&self, local_port: u16) -> Vec<String> {
+        vec![format!(
+            "\nRelaying local port {} through the holodeck at {} as '{}'",
+            local_port, self.hub, self.name
+        )]
+    }
+
+    fn run(
+        &self,
+        local_port: u16,
+        mut report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let route = format!("{}/connect/{}", PREFIX, self.name);
+            let mut control = BufReader::new(upgrade(self.request(&route)).await?);
+            while let Some(message) = recv(&mut control).await? {
+                match message {
+                    Message::Hello(url) => report_url(&url),
+                    Message::Heartbeat => {}
+                    Message::Connection(id) => {
+                        let request = self.request(&format!("{}/accept/{}", PREFIX, id));
+                        tokio::spawn(async move {
+                            if let Err(e) = splice(request, local_port).await {
+                                tracing::debug!(error = %e, "relay connection failed");
+                            }
+                        });
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn request_name(&mut self, service: Option<&str>, name: &str) -> bool {
+        if service.is_some_and(|service| service != self.authority) || !is_valid_name(name) {
+            return false;
+        }
+        self.name = name.to_string();
+        true
+    }
+}
+
+/// Opens the connection a visitor is waiting for and joins it to the local server.
+#[cfg(feature = "client")]
+async fn splice(request: reqwest::RequestBuilder, local_port: u16) -> anyhow::Result<()> {
+    let mut remote = upgrade(request).await?;
+    let mut local = TcpStream::connect(("127.0.0.1", local_port)).await?;
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await?;
+    Ok(())
+}
+
+async fn send<W: AsyncWrite + Unpin>(stream: &mut W, message: &Message) -> anyhow::Result<()> {
+    let mut frame = serde_json::to_vec(message)?;
+    frame.push(0);
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(any(feature = "client", test))]
+async fn recv<R: AsyncRead + Unpin>(stream: &mut BufReader<R>) -> anyhow::Result<Option<Message>> {
+    let mut frame = Vec::new();
+    if stream.read_until(0, &mut frame).await? == 0 {
+        return Ok(None);
+    }
+    if frame.last() == Some(&0) {
+        frame.pop();
+    }
+    Ok(Some(serde_json::from_slice(&frame)?))
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_peer_routes_are_relayed() {
+        assert!(is_relayed("/_relay/laptop/"));
+        assert!(is_relayed("/_relay/laptop/report.pdf"));
+        assert!(!is_relayed("/_relay/connect/laptop"));
+        assert!(!is_relayed("/_relay/accept/0f3a"));
+        assert!(!is_relayed("/_relay"));
+        assert!(!is_relayed("/_relayed.txt"));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn names_can_be_asked_for_at_each_hub() {
+        let mut tunnel = RelayTunnel::new("https://hub.example:8443/share/?token=ab12").unwrap();
+        assert_eq!(tunnel.hub, "https://hub.example:8443/share");
+        assert_eq!(tunnel.query.as_deref(), Some("token=ab12"));
+        assert_eq!(tunnel.name.len(), 8);
+
+        assert!(!tunnel.request_name(Some("bore.pub"), "laptop"));
+        assert!(!tunnel.request_name(None, "my laptop"));
+        assert!(!tunnel.request_name(None, "connect"));
+        assert!(tunnel.request_name(Some("hub.example:8443"), "laptop"));
+        assert_eq!(tunnel.base_path(), "/share/_relay/laptop");
+        assert!(RelayTunnel::new("ftp://hub.example").is_err());
+    }
+
+    #[tokio::test]
+    async fn hubs_greet_peers_then_pass_on_visitors() {
+        let (mut hub, peer) = tokio::io::duplex(1024);
+        let mut peer = BufReader::new(peer);
+        let (visitors, mut connections) = mpsc::unbounded_channel();
+        let control = tokio::spawn(async move {
+            control_loop(
+                &mut hub,
+                "https://hub.example/_relay/laptop",
+                &mut connections,
+            )
+            .await
+        });
+
+        let next = async |peer: &mut BufReader<_>| recv(peer).await.unwrap().unwrap();
+        assert!(matches!(
+            next(&mut peer).await,
+            Message::Hello(url) if url == "https://hub.example/_relay/laptop"
+        ));
+        assert!(matches!(next(&mut peer).await, Message::Heartbeat));
+        visitors.send("0f3a".to_string()).unwrap();
+        assert!(matches!(next(&mut peer).await, Message::Connection(id) if id == "0f3a"));
+
+        // The peer hanging up ends the control connection
+        drop(peer);
+        assert!(control.await.unwrap().is_ok());
+    }
+}
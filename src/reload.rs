@@ -0,0 +1,175 @@
+use crate::config::Config;
+use crate::{AppState, headers, mime, policy};
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+
+/// Reloads the config file whenever the process gets SIGHUP, e.g. from
+/// `kill -HUP <pid>` or `systemctl reload`.
+///
+/// MIME types, `[[header]]` and `[[policy]]` rules and `[alias]` entries take effect for the next
+/// request; transfers in flight and the tunnel carry on undisturbed. Webhooks, MQTT
+/// and tunnel settings are only read at startup. A config file that fails to parse
+/// is reported and the running settings are kept.
+#[cfg(unix)]
+pub async fn run(state: Arc<AppState>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!("RELOAD: Cannot listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let outcome = Config::load().and_then(|config| apply(&state, config));
+        match outcome {
+            Ok(()) => state.transfers.println(report(&outcome)),
+            Err(_) => eprintln!("{}", report(&outcome)),
+        }
+    }
+}
+
+/// Applies the reloadable settings of `config`, all of them or, on error, none.
+pub(crate) fn apply(state: &AppState, config: Config) -> anyhow::Result<()> {
+    let mime_types = Arc::new(mime::MimeTypes::new(&config.mime)?);
+    let headers = Arc::new(headers::HeaderRules::new(&config.header)?);
+    let policies = policy::Policies::new(&config.policy, state.share_tokens.is_some())?;
+    state.aliases.reconfigure(config.alias)?;
+    *state.mime_types.write().unwrap() = mime_types;
+    *state.headers.write().unwrap() = headers;
+    *state.policies.write().unwrap() = Arc::new(policies);
+    Ok(())
+}
+
+/// The line logged for the outcome of a reload.
+#[cfg(any(unix, test))]
+fn report(outcome: &anyhow::Result<()>) -> String {
+    match outcome {
+        Ok(()) => "RELOAD: Config file reloaded".to_string(),
+        Err(e) => format!("RELOAD: Keeping the current settings: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Action;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_configs_replace_the_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(dir.path().to_path_buf()).unwrap();
+        let outcome = apply(
+            &state,
+            config(
+                r#"
+                [mime]
+                log = "text/plain"
+
+                [[header]]
+                path = "/*.log"
+                set = { Cache-Control = "no-store" }
+
+                [[policy]]
+                path = "/incoming"
+                download = false
+
+                [alias]
+                latest = "build-42.zip"
+                "#,
+            ),
+        );
+        assert!(outcome.is_ok());
+        assert_eq!(report(&outcome), "RELOAD: Config file reloaded");
+        assert_eq!(state.mime_types().content_type("app.log"), "text/plain");
+        let mut headers = hyper::HeaderMap::new();
+        state.headers().apply("/app.log", &mut headers);
+        assert_eq!(headers["cache-control"], "no-store");
+        assert!(
+            !state
+                .policies()
+                .allows(Action::Download, "incoming/a", None)
+        );
+        assert_eq!(
+            state.aliases.resolve("latest").await.as_deref(),
+            Some("build-42.zip")
+        );
+
+        // Reloading an empty config drops them again
+        apply(&state, Config::default()).unwrap();
+        assert!(state.policies().is_empty());
+        assert_eq!(state.aliases.resolve("latest").await, None);
+    }
+
+    #[tokio::test]
+    async fn invalid_configs_keep_the_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(dir.path().to_path_buf()).unwrap();
+        apply(
+            &state,
+            config(
+                r#"
+                [[policy]]
+                path = "/incoming"
+                download = false
+
+                [alias]
+                latest = "build-42.zip"
+                "#,
+            ),
+        )
+        .unwrap();
+
+        // The policy is fine but the alias isn't, so neither is applied
+        let outcome = apply(
+            &state,
+            config(
+                r#"
+                [[policy]]
+                path = "/outgoing"
+                upload = false
+
+                [alias]
+                latest = "../etc/passwd"
+                "#,
+            ),
+        );
+        assert!(
+            report(&outcome).starts_with("RELOAD: Keeping the current settings: Invalid alias")
+        );
+        assert!(
+            !state
+                .policies()
+                .allows(Action::Download, "incoming/a", None)
+        );
+        assert!(state.policies().allows(Action::Upload, "outgoing/a", None));
+        assert_eq!(
+            state.aliases.resolve("latest").await.as_deref(),
+            Some("build-42.zip")
+        );
+
+        // A role needs --share-links, which this server doesn't have
+        let outcome = apply(
+            &state,
+            config("[[policy]]\npath = \"/private\"\nrole = \"admin\"\n"),
+        );
+        assert!(report(&outcome).contains("--share-links"));
+        assert!(
+            !state
+                .policies()
+                .allows(Action::Download, "incoming/a", None)
+        );
+
+        // TOML allows control characters a Content-Type can't hold
+        let outcome = apply(&state, config("[mime]\nholo = \"model/x-holo\\u0001\"\n"));
+        assert!(report(&outcome).contains("Invalid Content-Type for 'holo'"));
+        assert_eq!(
+            state.mime_types().content_type("scene.holo"),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::META_DIR;
+use crate::storage::WriteLocks;
+use crate::user_meta::Meta;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// A resumable upload that hasn't received all its bytes yet.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Size declared by the upload's `Content-Range`
+    pub total: u64,
+    /// Bytes written to `temp` so far
+    pub offset: u64,
+    /// Where the bytes so far are staged
+    pub temp: PathBuf,
+    /// `X-Meta-*` headers the upload was started with, given to the finished file
+    pub meta: Meta,
+    /// Seconds since the Unix epoch; untouched until then, the upload is given up
+    pub expires: u64,
+}
+
+/// The resumable uploads in progress, kept in the share's metadata directory so a
+/// restarted server knows how far each got and its clients can carry on from there.
+pub struct Resumable {
+    sessions: Mutex<BTreeMap<String, Session>>,
+    path: PathBuf,
+    /// How long a session lasts without being written to, `--stale-upload-age`
+    max_age: Duration,
+}
+
+impl Resumable {
+    /// Loads the sessions stored in `root`'s metadata directory, checked against
+    /// what the staging directory still holds.
+    pub fn load(root: &Path, max_age: Duration) -> anyhow::Result<Self> {
+        let path = root.join(META_DIR).join("uploads.json");
+        let mut sessions = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| {
+                anyhow::anyhow!("Invalid upload session file {}: {}", path.display(), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        restore(&mut sessions, now());
+        Ok(Self {
+            sessions: Mutex::new(sessions),
+            path,
+            max_age,
+        })
+    }
+
+    /// The session of an upload to `name`, if one is in progress.
+    pub async fn get(&self, name: &str) -> Option<Session> {
+        self.sessions.lock().await.get(name).cloned()
+    }
+
+    /// All sessions in progress, by file name.
+    pub async fn list(&self) -> Vec<(String, Session)> {
+        let sessions = self.sessions.lock().await;
+        sessions
+            .iter()
+            .map(|(name, session)| (name.clone(), session.clone()))
+            .collect()
+    }
+
+    /// Records that the upload to `name` has `offset` of its `total` bytes staged at
+    /// `temp`, starting its session if needed; `meta` replaces the upload's metadata
+    /// unless empty, so only the first request has to send it.
+    pub async fn progress(
+        &self,
+        name: &str,
+        total: u64,
+        offset: u64,
+        temp: &Path,
+        meta: Meta,
+    ) -> io::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.entry(name.to_string()).or_insert_with(|| Session {
+            total,
+            offset,
+            temp: temp.to_path_buf(),
+            meta: Meta::new(),
+            expires: 0,
+        });
+        session.total = total;
+        session.offset = offset;
+        session.temp = temp.to_path_buf();
+        if !meta.is_empty() {
+            session.meta = meta;
+        }
+        session.expires = now() + self.max_age.as_secs();
+        self.save(&sessions).await
+    }
+
+    /// Ends the session of the upload to `name`, completed or abandoned.
+    pub async fn finish(&self, name: &str) -> io::Result<Option<Session>> {
+        let mut sessions = self.sessions.lock().await;
+        let Some(session) = sessions.remove(name) else {
+            return Ok(None);
+        };
+        self.save(&sessions).await?;
+        Ok(Some(session))
+    }
+
+    /// Drops the sessions that expired, deleting what they staged, and those whose
+    /// staged file is gone, giving how many there were. Uploads being written to are
+    /// left alone.
+    pub async fn prune(&self, writes: &WriteLocks) -> io::Result<usize> {
+        let mut sessions = self.sessions.lock().await;
+        let now = now();
+        let mut stale = Vec::new();
+        for (name, session) in sessions.iter() {
+            let Some(_lock) = writes.try_lock(name) else {
+                continue;
+            };
+            if session.expires <= now {
+                match fs::remove_file(&session.temp).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => stale.push(name.clone()),
+                }
+            } else if fs::symlink_metadata(&session.temp).await.is_err() {
+                stale.push(name.clone());
+            }
+        }
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        for name in &stale {
+            sessions.remove(name);
+        }
+        self.save(&sessions).await?;
+        Ok(stale.len())
+    }
+
+    async fn save(&self, sessions: &BTreeMap<String, Session>) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(sessions)?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, json).await?;
+        fs::rename(&temp, &self.path).await
+    }
+}
+
+/// Reconciles sessions saved by a previous run with their staged files: expired
+/// sessions and those whose file is gone are dropped, bytes written after the last
+/// save by a request that never got its answer are cut off, and bytes lost in a crash
+/// before reaching the disk are asked for again.
+fn restore(sessions: &mut BTreeMap<String, Session>, now: u64) {
+    sessions.retain(|_, session| {
+        if session.expires <= now {
+            let _ = std::fs::remove_file(&session.temp);
+            return false;
+        }
+        let Ok(metadata) = std::fs::symlink_metadata(&session.temp) else {
+            return false;
+        };
+        if metadata.len() > session.offset {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&session.temp)
+                .and_then(|file| file.set_len(session.offset))
+                .is_ok()
+        } else {
+            session.offset = metadata.len();
+            true
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[tokio::test]
+    async fn sessions_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp = dir.path().join("big.bin.resume");
+        std::fs::write(&temp, "0123456789").unwrap();
+        let resumable = Resumable::load(dir.path(), DAY).unwrap();
+        let meta = Meta::from([("build".to_string(), "42".to_string())]);
+        resumable
+            .progress("big.bin", 100, 10, &temp, meta.clone())
+            .await
+            .unwrap();
+        resumable
+            .progress("big.bin", 100, 10, &temp, Meta::new())
+            .await
+            .unwrap();
+
+        // The server went down while writing bytes it never answered for
+        std::fs::write(&temp, "0123456789abc").unwrap();
+        let restarted = Resumable::load(dir.path(), DAY).unwrap();
+        let session = restarted.get("big.bin").await.unwrap();
+        assert_eq!((session.total, session.offset), (100, 10));
+        assert_eq!(session.meta, meta);
+        assert_eq!(std::fs::read(&temp).unwrap(), b"0123456789");
+
+        assert_eq!(
+            restarted.finish("big.bin").await.unwrap().unwrap().total,
+            100
+        );
+        assert!(
+            Resumable::load(dir.path(), DAY)
+                .unwrap()
+                .list()
+                .await
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn stale_sessions_are_dropped_on_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = |name: &str, offset, expires| Session {
+            total: 100,
+            offset,
+            temp: dir.path().join(name),
+            meta: Meta::new(),
+            expires,
+        };
+        std::fs::write(dir.path().join("expired"), "data").unwrap();
+        std::fs::write(dir.path().join("short"), "data").unwrap();
+        let mut sessions = BTreeMap::from([
+            ("expired".to_string(), session("expired", 4, 99)),
+            ("missing".to_string(), session("missing", 4, 200)),
+            ("short".to_string(), session("short", 10, 200)),
+        ]);
+
+        restore(&mut sessions, 100);
+        assert!(!dir.path().join("expired").exists());
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), ["short"]);
+        assert_eq!(sessions["short"].offset, 4);
+    }
+
+    #[tokio::test]
+    async fn prune_skips_uploads_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let resumable = Resumable::load(dir.path(), Duration::ZERO).unwrap();
+        for name in ["a.bin", "b.bin"] {
+            let temp = dir.path().join(format!("{}.resume", name));
+            std::fs::write(&temp, "data").unwrap();
+            resumable
+                .progress(name, 100, 4, &temp, Meta::new())
+                .await
+                .unwrap();
+        }
+
+        let writes = WriteLocks::default();
+        let _uploading = writes.try_lock("b.bin").unwrap();
+        assert_eq!(resumable.prune(&writes).await.unwrap(), 1);
+        assert!(!dir.path().join("a.bin.resume").exists());
+        let left: Vec<_> = resumable
+            .list()
+            .await
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(left, ["b.bin"]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, full, paths};
+use hyper::{Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Route prefix deleting a folder tree, as in `/_rmdir/builds/old`
+const RMDIR: &str = "/_rmdir/";
+
+/// How long a confirmation token stays valid
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Makes tokens issued within the same clock tick differ
+static TOKEN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Outstanding confirmation tokens for folder deletes.
+///
+/// Deleting a tree takes two calls: the first only reports what would go and hands out
+/// a token, the second presents it. The token is tied to the folder and to the file
+/// count and size it summarized, so a tree that grew in between isn't deleted blind.
+#[derive(Default)]
+pub struct Confirmations {
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+struct Pending {
+    folder: String,
+    summary: Summary,
+    issued: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Summary {
+    files: u64,
+    bytes: u64,
+}
+
+impl Confirmations {
+    fn issue(&self, folder: &str, summary: Summary) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(folder);
+        hasher.update(format!("{:?}", SystemTime::now()));
+        hasher.update(TOKEN_COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        let token = hex::encode(&hasher.finalize()[..8]);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.issued.elapsed() < TOKEN_LIFETIME);
+        pending.insert(
+            token.clone(),
+            Pending {
+                folder: folder.to_string(),
+                summary,
+                issued: Instant::now(),
+            },
+        );
+        token
+    }
+
+    /// Consumes `token` if it was issued for `folder` and hasn't expired.
+    fn redeem(&self, token: &str, folder: &str) -> Option<Summary> {
+        let pending = self.pending.lock().unwrap().remove(token)?;
+        (pending.folder == folder && pending.issued.elapsed() < TOKEN_LIFETIME)
+            .then_some(pending.summary)
+    }
+}
+
+/// Registers `DELETE /_rmdir/<folder>`.
+pub fn routes(router: &mut Router) {
+    router
+        .delete("/_rmdir/{*folder}", async |req, cx| {
+            rmdir(&cx.path, req.uri().query(), &cx.state).await
+        })
+        .scoped("folder");
+}
+
+/// Deletes a folder and everything in it, in two steps:
+///
+/// - `DELETE /_rmdir/<folder>` answers `202 Accepted` with the file count, total size
+///   and a confirmation token valid for five minutes
+/// - `DELETE /_rmdir/<folder>?confirm=<token>` deletes the tree for good
+///
+/// Needs `--allow-delete`. Folder deletes bypass the trash.
+pub async fn rmdir(
+    path: &str,
+    query: Option<&str>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    if state.trash.is_none() {
+        return Ok(crate::not_found());
+    }
+    let requested = path.strip_prefix(RMDIR).unwrap_or_default();
+    let folder = match paths::validate_path(requested) {
+        Ok(folder) if folder.is_empty() => {
+            return Ok(text(
+                StatusCode::BAD_REQUEST,
+                "Folder name required in path",
+            ));
+        }
+        Ok(folder) => folder,
+        Err(e) => return Ok(text(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let dir = state.root.join(&folder);
+    if !is_real_dir(&state.root, &folder) {
+        return Ok(text(
+            StatusCode::NOT_FOUND,
+            format!("Folder '{}' not found", folder),
+        ));
+    }
+    let summary = match summarize(dir.clone()).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("RMDIR: Error reading folder '{}': {}", folder, e);
+            return Ok(text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error reading folder: {}", e),
+            ));
+        }
+    };
+
+    let token = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("confirm="));
+    let Some(token) = token else {
+        let token = state.confirmations.issue(&folder, summary);
+        return Ok(text(
+            StatusCode::ACCEPTED,
+            format!(
+                "Folder '{}' holds {} files ({} bytes). To delete it, repeat within {} minutes \
+                 with ?confirm={}\n",
+                folder,
+                summary.files,
+                summary.bytes,
+                TOKEN_LIFETIME.as_secs() / 60,
+                token
+            ),
+        ));
+    };
+    match state.confirmations.redeem(token, &folder) {
+        Some(confirmed) if confirmed == summary => {}
+        Some(_) => {
+            return Ok(text(
+                StatusCode::CONFLICT,
+                format!(
+                    "Folder '{}' changed since the token was issued; request a new one",
+                    folder
+                ),
+            ));
+        }
+        None => {
+            return Ok(text(
+                StatusCode::FORBIDDEN,
+                "Invalid or expired confirmation token",
+            ));
+        }
+    }
+
+    // Symlinks inside the tree are removed, never followed
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        eprintln!("RMDIR: Error deleting folder '{}': {}", folder, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting folder: {}", e),
+        ));
+    }
+    state.transfers.println(format!(
+        "RMDIR: Deleted folder '{}' ({} files, {} bytes)",
+        folder, summary.files, summary.bytes
+    ));
+    Ok(text(
+        StatusCode::OK,
+        format!(
+            "Deleted folder '{}' ({} files, {} bytes)\n",
+            folder, summary.files, summary.bytes
+        ),
+    ))
+}
+
+/// Whether `relative` and every folder above it are real directories, not symlinks.
+fn is_real_dir(root: &Path, relative: &str) -> bool {
+    let mut dir = root.to_path_buf();
+    relative.split('/').all(|segment| {
+        dir.push(segment);
+        std::fs::symlink_metadata(&dir).is_ok_and(|metadata| metadata.is_dir())
+    })
+}
+
+/// Counts the files below `dir` and their total size, without following symlinks.
+async fn summarize(dir: PathBuf) -> io::Result<Summary> {
+    tokio::task::spawn_blocking(move || {
+        let mut summary = Summary { files: 0, bytes: 0 };
+        let mut pending = vec![dir];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    summary.files += 1;
+                    summary.bytes += metadata.len();
+                }
+            }
+        }
+        Ok(summary)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
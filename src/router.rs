@@ -0,0 +1,404 @@
+use crate::{AppState, BoxBody};
+use futures_util::FutureExt;
+use futures_util::future::BoxFuture;
+use hyper::{Method, Request, Response};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+type Handler = Box<
+    dyn Fn(Request<BoxBody>, Context) -> BoxFuture<'static, io::Result<Response<BoxBody>>>
+        + Send
+        + Sync,
+>;
+
+/// Decides whether a route applies beyond its method and path, as in
+/// `--search` being enabled or the query asking for `?qr`.
+type Guard = fn(&Request<BoxBody>, &AppState) -> bool;
+
+/// Maps a method and path pattern to a handler, trying routes in the order they were
+/// registered and answering `404` when none applies.
+///
+/// Patterns are `/`-separated segments, each a literal, a `{name}` matching one
+/// non-empty segment, or a final `{*name}` matching the rest of the path, possibly
+/// empty: `/_progress/{id}`, `/_alias/{*slug}`, `/{*path}`. Each module registers its
+/// own routes with a `routes` function.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+/// One registered route, refined with [`Route::when`].
+pub struct Route {
+    /// `None` for routes handling every method themselves
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    guard: Option<Guard>,
+    /// The parameter holding the share path the route acts on, set by [`Route::scoped`]
+    scope: Option<&'static str>,
+    handler: Handler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+    Rest(&'static str),
+}
+
+/// What a handler gets besides the request.
+pub struct Context {
+    /// The request path within `--base-path`, starting with `/`
+    pub path: String,
+    pub client: String,
+    pub state: Arc<AppState>,
+    params: Vec<(&'static str, String)>,
+}
+
+impl Context {
+    /// The path segment captured by `{name}` or `{*name}`; empty for a name the
+    /// pattern doesn't have.
+    pub fn param(&self, name: &str) -> &str {
+        self.params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map_or("", |(_, value)| value)
+    }
+}
+
+impl Router {
+    pub fn get<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(Some(Method::GET), pattern, handler)
+    }
+
+    pub fn head<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(Some(Method::HEAD), pattern, handler)
+    }
+
+    pub fn post<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(Some(Method::POST), pattern, handler)
+    }
+
+    pub fn patch<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(Some(Method::PATCH), pattern, handler)
+    }
+
+    pub fn delete<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(Some(Method::DELETE), pattern, handler)
+    }
+
+    /// A route whose handler answers every method, typically a small API dispatching
+    /// on the method itself.
+    pub fn any<H, F>(&mut self, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.add(None, pattern, handler)
+    }
+
+    fn add<H, F>(&mut self, method: Option<Method>, pattern: &'static str, handler: H) -> &mut Route
+    where
+        H: Fn(Request<BoxBody>, Context) -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<Response<BoxBody>>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            segments: parse(pattern),
+            guard: None,
+            scope: None,
+            handler: Box::new(move |req, cx| handler(req, cx).boxed()),
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    /// Hands the request to the first route matching its method, `path` and guard.
+    pub async fn dispatch(
+        &self,
+        req: Request<BoxBody>,
+        path: &str,
+        client: String,
+        state: Arc<AppState>,
+    ) -> io::Result<Response<BoxBody>> {
+        let Some((route, params)) = self.find(&req, path, &state) else {
+            return Ok(crate::not_found());
+        };
+        let cx = Context {
+            path: path.to_string(),
+            client,
+            state,
+            params,
+        };
+        (route.handler)(req, cx).await
+    }
+
+    /// Each route's method, `None` for any, and pattern.
+    #[cfg(test)]
+    pub fn patterns(&self) -> Vec<(Option<Method>, String)> {
+        self.routes
+            .iter()
+            .map(|route| (route.method.clone(), route.pattern()))
+            .collect()
+    }
+
+    /// The patterns of the routes a `method` request for `path` could take, guards
+    /// aside.
+    #[cfg(test)]
+    pub fn matching(&self, method: &Method, path: &str) -> Vec<String> {
+        self.routes
+            .iter()
+            .filter(|route| route.method.as_ref().is_none_or(|m| m == method))
+            .filter(|route| route.matches(path).is_some())
+            .map(Route::pattern)
+            .collect()
+    }
+
+    /// The share path the request acts on, when the route it would take is
+    /// [scoped](Route::scoped).
+    pub fn scope(&self, req: &Request<BoxBody>, path: &str, state: &AppState) -> Option<String> {
+        let (route, params) = self.find(req, path, state)?;
+        let name = route.scope?;
+        params
+            .into_iter()
+            .find_map(|(param, value)| (param == name).then_some(value))
+    }
+
+    /// The first route matching the request's method, `path` and guard, with the
+    /// parameters it captured.
+    fn find(
+        &self,
+        req: &Request<BoxBody>,
+        path: &str,
+        state: &AppState,
+    ) -> Option<(&Route, Vec<(&'static str, String)>)> {
+        self.routes.iter().find_map(|route| {
+            if route
+                .method
+                .as_ref()
+                .is_some_and(|method| method != req.method())
+            {
+                return None;
+            }
+            let params = route.matches(path)?;
+            if route.guard.is_some_and(|guard| !guard(req, state)) {
+                return None;
+            }
+            Some((route, params))
+        })
+    }
+}
+
+impl Route {
+    /// Only takes the route when `guard` holds, letting later routes have the request
+    /// otherwise.
+    pub fn when(&mut self, guard: Guard) -> &mut Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Marks the `{*name}` parameter as the file or folder of the share the route
+    /// acts on, so `[[policy]]` rules apply to it before the handler runs.
+    pub fn scoped(&mut self, param: &'static str) -> &mut Self {
+        self.scope = Some(param);
+        self
+    }
+
+    #[cfg(test)]
+    fn pattern(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => format!("/{}", literal),
+                Segment::Param(name) => format!("/{{{}}}", name),
+                Segment::Rest(name) => format!("/{{*{}}}", name),
+            })
+            .collect()
+    }
+
+    /// The captured parameters when `path` matches the pattern.
+    fn matches(&self, path: &str) -> Option<Vec<(&'static str, String)>> {
+        let mut rest = path.strip_prefix('/')?;
+        let mut params = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Segment::Rest(name) = segment {
+                params.push((*name, rest.to_string()));
+                return Some(params);
+            }
+            let (head, tail) = match rest.split_once('/') {
+                Some((head, tail)) => (head, Some(tail)),
+                None => (rest, None),
+            };
+            match segment {
+                Segment::Literal(literal) if *literal == head => {}
+                Segment::Param(name) if !head.is_empty() => params.push((*name, head.to_string())),
+                _ => return None,
+            }
+            match (tail, i + 1 == self.segments.len()) {
+                (None, true) => return Some(params),
+                (Some(tail), false) => rest = tail,
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+fn parse(pattern: &'static str) -> Vec<Segment> {
+    let segments: Vec<Segment> = pattern
+        .strip_prefix('/')
+        .expect("route patterns start with /")
+        .split('/')
+        .map(|segment| {
+            match segment
+                .strip_prefix('{')
+                .and_then(|segment| segment.strip_suffix('}'))
+            {
+                Some(name) => match name.strip_prefix('*') {
+                    Some(name) => Segment::Rest(name),
+                    None => Segment::Param(name),
+                },
+                None => Segment::Literal(segment),
+            }
+        })
+        .collect();
+    assert!(
+        !segments[..segments.len() - 1]
+            .iter()
+            .any(|segment| matches!(segment, Segment::Rest(_))),
+        "{{*name}} can only end a route pattern: {}",
+        pattern
+    );
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::full;
+    use http_body_util::BodyExt;
+    use hyper::StatusCode;
+
+    fn request(method: Method, path: &str) -> Request<BoxBody> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(full(""))
+            .unwrap()
+    }
+
+    /// Routes answering with what they matched, as `<route> <params>`
+    fn router() -> Router {
+        let mut router = Router::default();
+        router.get("/_progress/{id}", async |_, cx| {
+            Ok(echo(format!("progress {}", cx.param("id"))))
+        });
+        router.any("/_alias", async |req, _| {
+            Ok(echo(format!("alias {}", req.method())))
+        });
+        router.any("/_alias/{*slug}", async |req, cx| {
+            Ok(echo(format!("alias {} {}", req.method(), cx.param("slug"))))
+        });
+        router
+            .get("/{*path}", async |_, cx| {
+                Ok(echo(format!("qr {}", cx.param("path"))))
+            })
+            .when(|req, _| req.uri().query() == Some("qr"));
+        router
+            .get("/{*path}", async |_, cx| {
+                Ok(echo(format!("file {}", cx.path)))
+            })
+            .scoped("path");
+        router
+    }
+
+    fn echo(body: String) -> Response<BoxBody> {
+        Response::new(full(body))
+    }
+
+    async fn dispatch(router: &Router, method: Method, uri: &str) -> (StatusCode, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(dir.path().to_path_buf()).unwrap());
+        let req = request(method, uri);
+        let path = req.uri().path().to_string();
+        let response = router
+            .dispatch(req, &path, "127.0.0.1".to_string(), state)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn routes_match_in_order_with_params_and_guards() {
+        let router = router();
+        let cases = [
+            (Method::GET, "/_progress/f3a9", "progress f3a9"),
+            (Method::GET, "/_progress/a/b", "file /_progress/a/b"),
+            (Method::GET, "/_progress/", "file /_progress/"),
+            (Method::PUT, "/_alias", "alias PUT"),
+            (Method::DELETE, "/_alias/latest", "alias DELETE latest"),
+            (Method::GET, "/docs/report.pdf?qr", "qr docs/report.pdf"),
+            (Method::GET, "/docs/report.pdf", "file /docs/report.pdf"),
+            (Method::GET, "/", "file /"),
+        ];
+        for (method, uri, expected) in cases {
+            assert_eq!(
+                dispatch(&router, method, uri).await,
+                (StatusCode::OK, expected.to_string()),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unmatched_requests_fall_back_to_not_found() {
+        let (status, _) = dispatch(&router(), Method::POST, "/report.pdf").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn scoped_routes_name_the_path_they_act_on() {
+        let router = router();
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(dir.path().to_path_buf()).unwrap();
+        let scope = |method, uri| {
+            let req = request(method, uri);
+            router.scope(&req, req.uri().path(), &state)
+        };
+        assert_eq!(
+            scope(Method::GET, "/docs/report.pdf"),
+            Some("docs/report.pdf".to_string())
+        );
+        assert_eq!(scope(Method::GET, "/docs/report.pdf?qr"), None);
+        assert_eq!(scope(Method::GET, "/_alias/latest"), None);
+        assert_eq!(scope(Method::POST, "/report.pdf"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only end a route pattern")]
+    fn rest_segments_end_the_pattern() {
+        parse("/{*path}/raw");
+    }
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::path::Path;
+
+/// How a file inside the share is opened by [`open_beneath`].
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    Read,
+    /// Write at the end, creating the file if needed
+    Append,
+}
+
+/// Confines the whole process with Landlock: full access beneath `writable`,
+/// read-only access beneath `readable`, nothing anywhere else.
+///
+/// Returns whether the kernel enforced every restriction; older kernels enforce
+/// a subset and kernels without Landlock none at all.
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+pub fn restrict(writable: &[&Path], readable: &[&Path]) -> anyhow::Result<bool> {
+    use landlock::{
+        ABI, Access as _, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+        path_beneath_rules,
+    };
+
+    let abi = ABI::V5;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(writable, AccessFs::from_all(abi)))?
+        .add_rules(path_beneath_rules(readable, AccessFs::from_read(abi)))?
+        .restrict_self()?;
+    Ok(status.ruleset == RulesetStatus::FullyEnforced)
+}
+
+#[cfg(all(target_os = "linux", not(feature = "sandbox")))]
+pub fn restrict(_writable: &[&Path], _readable: &[&Path]) -> anyhow::Result<bool> {
+    anyhow::bail!("holodeck was built without filesystem sandboxing (the `sandbox` feature)")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_writable: &[&Path], _readable: &[&Path]) -> anyhow::Result<bool> {
+    anyhow::bail!("filesystem sandboxing is only available on Linux")
+}
+
+/// Opens `name` inside `dir`, refusing to resolve through symlinks or `..` that
+/// lead outside it.
+///
+/// Uses `openat2(RESOLVE_BENEATH)` on Linux 5.6+, so a symlink planted in the
+/// share can't expose the rest of the filesystem; elsewhere `name` is opened
+/// normally and callers rely on filename validation alone.
+pub async fn open_beneath(dir: &Path, name: &str, access: Access) -> io::Result<tokio::fs::File> {
+    let dir = dir.to_path_buf();
+    let name = name.to_string();
+    let file = tokio::task::spawn_blocking(move || {
+        #[cfg(target_os = "linux")]
+        match openat2_beneath(&dir, &name, access) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {}
+            result => return result,
+        }
+        let mut options = std::fs::OpenOptions::new();
+        match access {
+            Access::Read => options.read(true),
+            Access::Append => options.create(true).append(true),
+        };
+        options.open(dir.join(&name))
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(tokio::fs::File::from_std(file))
+}
+
+#[cfg(target_os = "linux")]
+fn openat2_beneath(dir: &Path, name: &str, access: Access) -> io::Result<std::fs::File> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let dir = std::fs::File::open(dir)?;
+    let name = CString::new(name).map_err(io::Error::other)?;
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = match access {
+        Access::Read => libc::O_RDONLY,
+        Access::Append => libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT,
+    } as u64
+        | libc::O_CLOEXEC as u64;
+    how.mode = match access {
+        Access::Read => 0,
+        Access::Append => 0o666,
+    };
+    how.resolve = libc::RESOLVE_BENEATH;
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd as i32) })
+}
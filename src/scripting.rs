@@ -0,0 +1,250 @@
+use crate::{AppState, BoxBody, full};
+use hyper::{HeaderMap, Method, Response, StatusCode};
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+/// Operations a callback may run per call before it is stopped, so a script stuck in
+/// a loop fails the request instead of hanging it
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// What `reject()` gives back, with the reason shown to the client
+#[derive(Debug, Clone)]
+struct Rejection(String);
+
+/// What a callback decided about the operation it was asked about.
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    /// `true`, or no return value
+    Accept,
+    /// `false`, or `reject("reason")`
+    Reject(String),
+    /// A string: the name to use instead
+    Rewrite(String),
+}
+
+/// A Rhai script loaded with `--script`, whose callbacks vet requests:
+///
+/// - `on_auth(request)` with `method`, `path`, `query`, `client` and `headers`, for
+///   every request before it is routed
+/// - `on_upload(upload)` with `method`, `name`, `size` (from `Content-Length`, `()` when
+///   unknown) and `client`, before a POST or PATCH writes anything
+/// - `on_download(download)` with `name` and `client`, before a file is served
+///
+/// Each may accept (`true` or nothing), reject (`false` or `reject("reason")`), and
+/// the last two may rewrite the operation by returning another file name. Callbacks
+/// the script doesn't define accept everything; one that fails rejects the request.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine
+            .register_type_with_name::<Rejection>("Rejection")
+            .register_fn("reject", |reason: &str| Rejection(reason.to_string()))
+            .register_fn("reject", || Rejection(String::new()));
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow::anyhow!("Invalid script {}: {}", path.display(), e))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Runs `callback` on `arg`, accepting when the script doesn't define it.
+    fn call(&self, callback: &str, arg: Map) -> Result<Verdict, String> {
+        if !self
+            .ast
+            .iter_functions()
+            .any(|f| f.name == callback && f.params.len() == 1)
+        {
+            return Ok(Verdict::Accept);
+        }
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, callback, (arg,))
+            .map_err(|e| format!("{} failed: {}", callback, e))?;
+        if result.is_unit() {
+            Ok(Verdict::Accept)
+        } else if let Some(accept) = result.clone().try_cast::<bool>() {
+            Ok(match accept {
+                true => Verdict::Accept,
+                false => Verdict::Reject(String::new()),
+            })
+        } else if let Some(Rejection(reason)) = result.clone().try_cast::<Rejection>() {
+            Ok(Verdict::Reject(reason))
+        } else if result.is_string() {
+            Ok(Verdict::Rewrite(result.to_string()))
+        } else {
+            Err(format!(
+                "{} returned a {}; expected a bool, a file name or reject()",
+                callback,
+                result.type_name()
+            ))
+        }
+    }
+
+    /// Runs `on_auth`, giving the response for a request the script turns away.
+    pub fn on_auth(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        client: &str,
+    ) -> Option<Response<BoxBody>> {
+        let headers: Map = headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str().into(), value.to_str().ok()?.into())))
+            .collect();
+        let mut request = Map::new();
+        request.insert("method".into(), method.as_str().into());
+        request.insert("path".into(), path.into());
+        request.insert("query".into(), query.map_or(Dynamic::UNIT, Into::into));
+        request.insert("client".into(), client.into());
+        request.insert("headers".into(), headers.into());
+        match self.call("on_auth", request) {
+            Ok(Verdict::Accept) => None,
+            Ok(Verdict::Reject(reason)) => Some(rejected(&reason)),
+            Ok(Verdict::Rewrite(_)) => Some(failed(&format!(
+                "on_auth for {} {} returned a file name; expected a bool or reject()",
+                method, path
+            ))),
+            Err(e) => Some(failed(&e)),
+        }
+    }
+}
+
+/// The name `on_upload` has the upload of `name` stored under, or the response
+/// when it turns the upload away.
+pub fn upload_name(
+    method: &Method,
+    name: &str,
+    headers: &HeaderMap,
+    client: &str,
+    state: &AppState,
+) -> ControlFlow<Response<BoxBody>, String> {
+    let Some(script) = &state.script else {
+        return ControlFlow::Continue(name.to_string());
+    };
+    let size = headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok());
+    let mut upload = Map::new();
+    upload.insert("method".into(), method.as_str().into());
+    upload.insert("name".into(), name.into());
+    upload.insert("size".into(), size.map_or(Dynamic::UNIT, Dynamic::from));
+    upload.insert("client".into(), client.into());
+    decide(script.call("on_upload", upload), name)
+}
+
+/// The file `on_download` has served for a download of `name`, or the response when
+/// it turns the download away.
+pub fn download_name(
+    name: &str,
+    client: &str,
+    state: &AppState,
+) -> ControlFlow<Response<BoxBody>, String> {
+    let Some(script) = &state.script else {
+        return ControlFlow::Continue(name.to_string());
+    };
+    let mut download = Map::new();
+    download.insert("name".into(), name.into());
+    download.insert("client".into(), client.into());
+    decide(script.call("on_download", download), name)
+}
+
+fn decide(verdict: Result<Verdict, String>, name: &str) -> ControlFlow<Response<BoxBody>, String> {
+    match verdict {
+        Ok(Verdict::Accept) => ControlFlow::Continue(name.to_string()),
+        // Checked like any name from a client by the caller
+        Ok(Verdict::Rewrite(name)) => ControlFlow::Continue(name),
+        Ok(Verdict::Reject(reason)) => ControlFlow::Break(rejected(&reason)),
+        Err(e) => ControlFlow::Break(failed(&e)),
+    }
+}
+
+fn rejected(reason: &str) -> Response<BoxBody> {
+    let reason = if reason.is_empty() {
+        "Rejected by the server's policy script"
+    } else {
+        reason
+    };
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(full(reason.to_string()))
+        .unwrap()
+}
+
+fn failed(e: &str) -> Response<BoxBody> {
+    eprintln!("SCRIPT: {}", e);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(full("Script error"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(source: &str) -> Script {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.rhai");
+        std::fs::write(&path, source).unwrap();
+        Script::load(&path).unwrap()
+    }
+
+    fn upload(script: &Script, name: &str, size: i64) -> Result<Verdict, String> {
+        let mut upload = Map::new();
+        upload.insert("name".into(), name.into());
+        upload.insert("size".into(), size.into());
+        script.call("on_upload", upload)
+    }
+
+    #[test]
+    fn callbacks_accept_reject_or_rewrite() {
+        let script = load(
+            r#"
+            fn on_upload(upload) {
+                if upload.name.ends_with(".exe") { return reject("No executables"); }
+                if upload.size > 100 { return false; }
+                if upload.name == "latest.txt" { return "archive.txt"; }
+            }
+            "#,
+        );
+        assert_eq!(upload(&script, "a.txt", 10), Ok(Verdict::Accept));
+        assert_eq!(
+            upload(&script, "setup.exe", 10),
+            Ok(Verdict::Reject("No executables".into()))
+        );
+        assert_eq!(
+            upload(&script, "big.bin", 1000),
+            Ok(Verdict::Reject(String::new()))
+        );
+        assert_eq!(
+            upload(&script, "latest.txt", 10),
+            Ok(Verdict::Rewrite("archive.txt".into()))
+        );
+        // Callbacks the script leaves out accept everything
+        assert_eq!(script.call("on_download", Map::new()), Ok(Verdict::Accept));
+    }
+
+    #[test]
+    fn runaway_or_broken_callbacks_fail() {
+        let script = load(
+            r#"
+            fn on_upload(upload) { loop {} }
+            fn on_download(download) { 42 }
+            "#,
+        );
+        assert!(upload(&script, "a.txt", 10).is_err());
+        assert!(script.call("on_download", Map::new()).is_err());
+
+        let response = script.on_auth(&Method::GET, "/", None, &HeaderMap::new(), "::1");
+        assert!(response.is_none());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::packing::Packed;
+use crate::policy::{self, Action};
+use crate::router::Router;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, STORED, STRING, Schema, TEXT, Value};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term, doc};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+
+/// Route of the search endpoint
+pub const ROUTE: &str = "/_search";
+
+/// Extensions of the files worth indexing
+const TEXT_EXTENSIONS: &[&str] = &["txt", "text", "md", "markdown", "log", "csv"];
+
+/// How much of each file is indexed, so a huge log doesn't take over memory
+const MAX_INDEXED_LEN: u64 = 16 * 1024 * 1024;
+
+/// Memory the index writer may buffer before flushing a segment (tantivy's minimum)
+const WRITER_MEMORY: usize = 15_000_000;
+
+/// Results given when `limit=` is absent, and the most it may ask for
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// Length of the preview shown for each match, in characters
+const SNIPPET_LEN: usize = 200;
+
+/// Size and modification time of a file when it was indexed
+type Stamp = (u64, Option<SystemTime>);
+
+/// In-memory full-text index of the share's text, Markdown and log files, behind
+/// `GET /_search?q=`.
+///
+/// The index is brought up to date on each search: only files that are new or
+/// changed since, judging by size and modification time, are read again.
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    name: Field,
+    body: Field,
+    indexed: Mutex<Indexed>,
+}
+
+struct Indexed {
+    writer: IndexWriter,
+    files: HashMap<String, Stamp>,
+}
+
+#[derive(Serialize)]
+struct Hit {
+    name: String,
+    score: f32,
+    /// Matching passage, with the matched terms between `**`
+    snippet: String,
+}
+
+impl SearchIndex {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut schema = Schema::builder();
+        let name = schema.add_text_field("name", STRING | STORED);
+        let body = schema.add_text_field("body", TEXT);
+        let index = Index::create_in_ram(schema.build());
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            index,
+            reader,
+            name,
+            body,
+            indexed: Mutex::new(Indexed {
+                writer,
+                files: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Indexes new and changed text files in `root` and drops those that are gone.
+    async fn refresh(&self, root: &Path, packed: &Packed) -> anyhow::Result<()> {
+        let mut indexed = self.indexed.lock().await;
+        let mut seen = HashMap::new();
+        let mut changed = false;
+        let mut entries = fs::read_dir(root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // Not followed through symlinks, like every other read of the share
+            let metadata = entry.metadata().await?;
+            if name == META_DIR || !metadata.is_file() || !is_text(&name) {
+                continue;
+            }
+            let stamp = (metadata.len(), metadata.modified().ok());
+            if indexed.files.get(&name) != Some(&stamp) {
+                let text = read_text(root, &name, packed).await?;
+                indexed
+                    .writer
+                    .delete_term(Term::from_field_text(self.name, &name));
+                indexed
+                    .writer
+                    .add_document(doc!(self.name => name.as_str(), self.body => text))?;
+                changed = true;
+            }
+            seen.insert(name, stamp);
+        }
+        for name in indexed.files.keys() {
+            if !seen.contains_key(name) {
+                indexed
+                    .writer
+                    .delete_term(Term::from_field_text(self.name, name));
+                changed = true;
+            }
+        }
+        indexed.files = seen;
+        if changed {
+            indexed.writer.commit()?;
+            self.reader.reload()?;
+        }
+        Ok(())
+    }
+
+    /// The best matches for `query` with a preview of each, reading the matched
+    /// files again for their snippets.
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        root: &Path,
+        packed: &Packed,
+    ) -> anyhow::Result<Vec<Hit>> {
+        self.refresh(root, packed).await?;
+        let searcher = self.reader.searcher();
+        // Typos in the query syntax shouldn't turn a search into an error
+        let (query, _) =
+            QueryParser::for_index(&self.index, vec![self.body]).parse_query_lenient(query);
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, self.body)?;
+        snippets.set_max_num_chars(SNIPPET_LEN);
+
+        let mut hits = Vec::new();
+        for (score, address) in searcher.search(&*query, &TopDocs::with_limit(limit))? {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let Some(name) = doc.get_first(self.name).and_then(|v| v.as_str()) else {
+                continue;
+            };
+            let text = read_text(root, name, packed).await.unwrap_or_default();
+            let snippet = snippets.snippet(&text);
+            let mut preview = String::new();
+            let mut end = 0;
+            for range in snippet.highlighted() {
+                preview.push_str(&snippet.fragment()[end..range.start]);
+                preview.push_str("**");
+                preview.push_str(&snippet.fragment()[range.clone()]);
+                preview.push_str("**");
+                end = range.end;
+            }
+            preview.push_str(&snippet.fragment()[end..]);
+            hits.push(Hit {
+                name: name.to_string(),
+                score,
+                snippet: preview.split_whitespace().collect::<Vec<_>>().join(" "),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+/// Whether `name` looks like a text file worth indexing.
+fn is_text(name: &str) -> bool {
+    name.rsplit_once('.').is_some_and(|(_, extension)| {
+        TEXT_EXTENSIONS
+            .iter()
+            .any(|text| extension.eq_ignore_ascii_case(text))
+    })
+}
+
+/// The start of the text file `name` in `root`, decompressed if need be and lossily
+/// decoded.
+async fn read_text(root: &Path, name: &str, packed: &Packed) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    packed
+        .open(root, name)
+        .await?
+        .contents
+        .take(MAX_INDEXED_LEN)
+        .read_to_end(&mut bytes)
+        .await?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Registers `GET /_search` when `--search` is on.
+pub fn routes(router: &mut Router) {
+    router
+        .get(ROUTE, async |req, cx| {
+            get(req.headers(), req.uri().query(), &cx.state).await
+        })
+        .when(|_, state| state.search.is_some());
+}
+
+/// Answers `GET /_search?q=<query>[&limit=<n>]` with a JSON array of the matching
+/// files, best first, each with `name`, `score` and `snippet`. Files a `[[policy]]`
+/// keeps the requester from downloading are left out.
+pub async fn get(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let Some(index) = &state.search else {
+        return Ok(crate::not_found());
+    };
+    let param = |key: &str| {
+        query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+            .map(paths::query_decode)
+    };
+    let Some(q) = param("q").filter(|q| !q.trim().is_empty()) else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("Missing search query: use /_search?q=<words>"))
+            .unwrap());
+    };
+    let limit = match param("limit").map(|limit| limit.parse::<usize>()) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("limit must be between 1 and {}", MAX_LIMIT)))
+                .unwrap());
+        }
+    };
+
+    let role = policy::requester(state, headers, query);
+    let policies = state.policies();
+    match index.search(&q, limit, &state.root, &state.packed).await {
+        Ok(mut hits) => {
+            hits.retain(|hit| policies.allows(Action::Download, &hit.name, role));
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(
+                    serde_json::to_string(&hits).map_err(io::Error::other)?,
+                ))
+                .unwrap())
+        }
+        Err(e) => {
+            eprintln!("SEARCH: Error searching for '{}': {}", q, e);
+            Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(format!("Error searching: {}", e)))
+                .unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finds_text_files_and_follows_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(
+            root.join("app.log"),
+            "started\nERROR disk full on /dev/sda1\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("notes.md"), "The disk was replaced on Monday").unwrap();
+        std::fs::write(root.join("image.png"), "disk disk disk").unwrap();
+
+        let packed = Packed::load(root).unwrap();
+        let index = SearchIndex::new().unwrap();
+        let hits = index.search("disk", 10, root, &packed).await.unwrap();
+        let mut names: Vec<&str> = hits.iter().map(|hit| hit.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["app.log", "notes.md"]);
+
+        let hits = index.search("full", 10, root, &packed).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "started ERROR disk **full** on /dev/sda1");
+
+        std::fs::remove_file(root.join("app.log")).unwrap();
+        std::fs::write(root.join("notes.md"), "Nothing to see").unwrap();
+        assert!(
+            index
+                .search("disk", 10, root, &packed)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, full, paths};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+/// Where the public key is served, in minisign's format
+pub const PUBKEY: &str = "/_pubkey";
+
+/// Suffix of the detached signature served next to every file
+const SUFFIX: &str = ".sig";
+
+/// Size and modification time, which tell a file changed without reading it
+type Stamp = (u64, Option<SystemTime>);
+
+/// Signs downloads with an Ed25519 key, producing minisign signatures of the file's
+/// BLAKE2b-512 digest so recipients can check them with `minisign -V`.
+pub struct Signer {
+    key: Ed25519KeyPair,
+    /// minisign's key number, which ties a signature to the public key that checks it
+    key_id: [u8; 8],
+    /// Signatures made so far, reused while the file's size and modification time hold
+    signatures: Mutex<HashMap<String, (Stamp, String)>>,
+}
+
+impl Signer {
+    /// Loads the key seed kept at `path`, creating a new key there on first use.
+    pub fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        let seed = match std::fs::read_to_string(path) {
+            Ok(contents) => hex::decode(contents.trim())
+                .ok()
+                .filter(|seed| seed.len() == 32)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("{} is not a holodeck signing key", path.display())
+                })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut seed = vec![0; 32];
+                getrandom::fill(&mut seed)?;
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                write_private(path, format!("{}\n", hex::encode(&seed)).as_bytes())?;
+                seed
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::from_seed(&seed)
+    }
+
+    fn from_seed(seed: &[u8]) -> anyhow::Result<Self> {
+        let key = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|e| anyhow::anyhow!("invalid signing key: {}", e))?;
+        let digest = Sha256::digest(key.public_key().as_ref());
+        Ok(Self {
+            key_id: digest[..8].try_into().expect("8 bytes"),
+            key,
+            signatures: Mutex::default(),
+        })
+    }
+
+    /// The key id, as minisign shows it in key and signature comments.
+    pub fn key_id(&self) -> String {
+        format!("{:016X}", u64::from_le_bytes(self.key_id))
+    }
+
+    /// The public key file, as `minisign -G` would write it.
+    pub fn public_key(&self) -> String {
+        let blob = [b"Ed", &self.key_id[..], self.key.public_key().as_ref()].concat();
+        format!(
+            "untrusted comment: minisign public key {}\n{}\n",
+            self.key_id(),
+            STANDARD.encode(blob)
+        )
+    }
+
+    /// The signature file for `name`, whose contents hash to `digest`.
+    fn signature(&self, name: &str, digest: &[u8; 64]) -> String {
+        let signature = self.key.sign(digest);
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let trusted = format!("timestamp:{}\tfile:{}\thashed", timestamp, name);
+        // The global signature covers the trusted comment, so it can't be swapped
+        let global = self
+            .key
+            .sign(&[signature.as_ref(), trusted.as_bytes()].concat());
+        format!(
+            "untrusted comment: signature from holodeck secret key\n{}\ntrusted comment: {}\n{}\n",
+            STANDARD.encode([b"ED", &self.key_id[..], signature.as_ref()].concat()),
+            trusted,
+            STANDARD.encode(global.as_ref())
+        )
+    }
+}
+
+/// Whether `path` asks for a file's detached signature.
+pub fn is_signature(path: &str) -> bool {
+    path.len() > 1 + SUFFIX.len() && path.ends_with(SUFFIX)
+}
+
+/// Registers `GET /_pubkey` and `GET /<file>.sig` when downloads are signed.
+pub fn routes(router: &mut Router) {
+    router
+        .get(PUBKEY, async |_, cx| Ok(pubkey(&cx.state)))
+        .when(|_, state| state.signer.is_some());
+    router
+        .get("/{*path}", async |req, cx| {
+            get(
+                req.headers(),
+                &cx.path,
+                req.uri().query(),
+                cx.client,
+                &cx.state,
+            )
+            .await
+        })
+        .when(|req, state| state.signer.is_some() && is_signature(req.uri().path()))
+        .scoped("path");
+}
+
+/// Serves the public key, for `minisign -V -p`.
+pub fn pubkey(state: &AppState) -> Response<BoxBody> {
+    match &state.signer {
+        Some(signer) => text(StatusCode::OK, signer.public_key()),
+        None => crate::not_found(),
+    }
+}
+
+/// Serves the detached signature of the file `path` names without its `.sig`,
+/// signing it on first request.
+pub async fn get(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    client: String,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let Some(signer) = &state.signer else {
+        return Ok(crate::not_found());
+    };
+    // A .sig file the user shared takes precedence
+    if paths::validate_filename(&path[1..]).is_ok()
+        && tokio::fs::try_exists(state.root.join(&path[1..])).await?
+    {
+        return crate::get_file(headers, path, query, client, state).await;
+    }
+
+    let name = &path[1..path.len() - SUFFIX.len()];
+    if let Err(e) = paths::validate_filename(name) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    // What is signed is the file as downloaded, decompressed if stored compressed
+    let (mut file, stamp) = match state.packed.open(&state.root, name).await {
+        Ok(opened) if opened.metadata.is_file() => {
+            let metadata = &opened.metadata;
+            let stamp = (metadata.len(), metadata.modified().ok());
+            (opened.contents, stamp)
+        }
+        _ => return Ok(text(StatusCode::NOT_FOUND, "File not found")),
+    };
+
+    let cached = signer.signatures.lock().unwrap().get(name).cloned();
+    if let Some((signed, signature)) = cached
+        && signed == stamp
+    {
+        return Ok(text(StatusCode::OK, signature));
+    }
+    let mut hasher = Blake2b::new();
+    let mut buffer = vec![0; state.io_buffer];
+    loop {
+        match file.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buffer[..n]),
+            Err(e) => {
+                eprintln!("GET: Error signing '{}': {}", name, e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error signing file",
+                ));
+            }
+        }
+    }
+    let signature = signer.signature(name, &hasher.finalize());
+    signer
+        .signatures
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), (stamp, signature.clone()));
+    Ok(text(StatusCode::OK, signature))
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full(body))
+        .unwrap()
+}
+
+/// Writes a private key readable by the current user only.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// BLAKE2b-512 (RFC 7693), the digest minisign signs instead of the whole file.
+struct Blake2b {
+    h: [u64; 8],
+    /// Bytes compressed so far
+    counter: u128,
+    block: [u8; 128],
+    /// Bytes waiting in `block`; the last block is only compressed by `finalize`
+    filled: usize,
+}
+
+impl Blake2b {
+    fn new() -> Self {
+        let mut h = IV;
+        // No key, 64-byte output
+        h[0] ^= 0x0101_0000 ^ 64;
+        Self {
+            h,
+            counter: 0,
+            block: [0; 128],
+            filled: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.filled == self.block.len() {
+                self.counter += self.filled as u128;
+                self.compress(false);
+                self.filled = 0;
+            }
+            let n = data.len().min(self.block.len() - self.filled);
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+        }
+    }
+
+    fn finalize(mut self) -> [u8; 64] {
+        self.counter += self.filled as u128;
+        self.block[self.filled..].fill(0);
+        self.compress(true);
+        let mut digest = [0; 64];
+        for (chunk, word) in digest.chunks_exact_mut(8).zip(self.h) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, last: bool) {
+        let mut m = [0u64; 16];
+        for (word, chunk) in m.iter_mut().zip(self.block.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.counter as u64;
+        v[13] ^= (self.counter >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for round in 0..12 {
+            let s = &SIGMA[round % 10];
+            mix(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            mix(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            mix(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            mix(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            mix(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            mix(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            mix(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            mix(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+/// BLAKE2b's G function, mixing two message words into four state words.
+fn mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{ED25519, UnparsedPublicKey};
+
+    fn blake2b(data: &[u8]) -> String {
+        let mut hasher = Blake2b::new();
+        hasher.update(data);
+        hex::encode(hasher.finalize())
+    }
+
+    #[test]
+    fn blake2b_matches_rfc_7693() {
+        assert_eq!(
+            blake2b(b"abc"),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+        assert_eq!(
+            blake2b(b""),
+            "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+             d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+        );
+        // Split updates straddling block boundaries give the same digest
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut hasher = Blake2b::new();
+        for chunk in data.chunks(77) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hex::encode(hasher.finalize()), blake2b(&data));
+    }
+
+    #[test]
+    fn signatures_verify_against_the_public_key() {
+        let signer = Signer::from_seed(&[7; 32]).unwrap();
+        let public = signer.public_key();
+        let public = STANDARD.decode(public.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(&public[..2], b"Ed");
+        assert_eq!(public[2..10], signer.key_id);
+        let public = UnparsedPublicKey::new(&ED25519, &public[10..]);
+
+        let mut hasher = Blake2b::new();
+        hasher.update(b"hello");
+        let digest = hasher.finalize();
+        let file = signer.signature("hello.txt", &digest);
+        let lines: Vec<&str> = file.lines().collect();
+        assert_eq!(lines.len(), 4);
+        let signature = STANDARD.decode(lines[1]).unwrap();
+        assert_eq!(&signature[..10], [b"ED", &signer.key_id[..]].concat());
+        public.verify(&digest, &signature[10..]).unwrap();
+
+        let trusted = lines[2].strip_prefix("trusted comment: ").unwrap();
+        assert!(trusted.ends_with("\tfile:hello.txt\thashed"));
+        let global = STANDARD.decode(lines[3]).unwrap();
+        public
+            .verify(&[&signature[10..], trusted.as_bytes()].concat(), &global)
+            .unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+use crate::cli::{ExportArgs, ImportArgs};
+use crate::storage::{self, StagedDir};
+use crate::{META_DIR, SHARED_DIR, paths};
+use anyhow::{Context, bail};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+/// Entries of the metadata directory left out of snapshots: uploads in flight, which
+/// don't survive a move, and the mirror's download area
+const SKIPPED: [&str; 3] = ["staging", "mirror", "uploads.json"];
+
+/// zstd's default level
+const LEVEL: i32 = 3;
+
+/// Files written to or read from a snapshot.
+#[derive(Debug, Default)]
+struct Summary {
+    files: u64,
+    bytes: u64,
+}
+
+/// Runs `holodeck export`: writes the share in the current directory, its metadata
+/// directory included, to a `.tar.zst` snapshot.
+pub fn export(args: ExportArgs) -> anyhow::Result<()> {
+    let summary = write(Path::new(SHARED_DIR), &args.archive)
+        .with_context(|| format!("Can't export to {}", args.archive.display()))?;
+    println!(
+        "Exported {} files ({} bytes) to {}",
+        summary.files,
+        summary.bytes,
+        args.archive.display()
+    );
+    Ok(())
+}
+
+/// Runs `holodeck import`: restores a snapshot made by `holodeck export` into the
+/// current directory.
+pub async fn import(args: ImportArgs) -> anyhow::Result<()> {
+    let summary = restore(Path::new(SHARED_DIR), &args.archive, args.force)
+        .await
+        .with_context(|| format!("Can't import {}", args.archive.display()))?;
+    println!(
+        "Imported {} files ({} bytes) from {}",
+        summary.files,
+        summary.bytes,
+        args.archive.display()
+    );
+    Ok(())
+}
+
+/// Writes `root` to `archive`, removing what was written if that fails midway.
+fn write(root: &Path, archive: &Path) -> io::Result<Summary> {
+    let file = std::fs::File::create(archive)?;
+    // The snapshot may be written into the share it is taken of
+    let itself = archive.canonicalize()?;
+    let written = (|| {
+        let mut builder = tar::Builder::new(zstd::Encoder::new(file, LEVEL)?);
+        let mut summary = Summary::default();
+        add_dir(&mut builder, root, "", &itself, &mut summary)?;
+        builder.into_inner()?.finish()?.sync_all()?;
+        Ok(summary)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(archive);
+    }
+    written
+}
+
+/// Adds the folder `relative` of `root` and everything below it, in name order.
+///
+/// Each file's modification time goes in a PAX `mtime` record too, as the registries
+/// in the metadata directory recognize files by it to the nanosecond.
+fn add_dir(
+    builder: &mut tar::Builder<impl io::Write>,
+    root: &Path,
+    relative: &str,
+    itself: &Path,
+    summary: &mut Summary,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Ok(name) = entry.file_name().into_string() else {
+            eprintln!("Skipping {:?}: not a UTF-8 name", entry.path());
+            continue;
+        };
+        if relative == META_DIR && SKIPPED.contains(&name.as_str()) {
+            continue;
+        }
+        let name = match relative {
+            "" => name,
+            _ => format!("{}/{}", relative, name),
+        };
+        // Not followed through symlinks, like every other read of the share
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            builder.append_dir(&name, entry.path())?;
+            add_dir(builder, root, &name, itself, summary)?;
+        } else if metadata.is_file() {
+            if entry.path().canonicalize()? == itself {
+                continue;
+            }
+            if let Ok(modified) = metadata.modified() {
+                let mtime = pax_mtime(modified);
+                builder.append_pax_extensions([("mtime", mtime.as_bytes())])?;
+            }
+            builder.append_path_with_name(entry.path(), &name)?;
+            summary.files += 1;
+            summary.bytes += metadata.len();
+        } else {
+            eprintln!("Skipping '{}': only files and folders are exported", name);
+        }
+    }
+    Ok(())
+}
+
+/// Extracts `archive` in the staging directory, then moves its files into `root`.
+///
+/// Unless `force`, `root` must not hold anything besides its metadata directory yet.
+async fn restore(root: &Path, archive: &Path, force: bool) -> anyhow::Result<Summary> {
+    let file = std::fs::File::open(archive)?;
+    if !force && let Some(name) = shared_entry(root).await? {
+        bail!(
+            "the current directory already holds '{}'; import into an empty folder, or pass \
+             --force to replace the files the snapshot also holds",
+            name
+        );
+    }
+
+    let staging = root.join(META_DIR).join("staging");
+    fs::create_dir_all(&staging).await?;
+    let unpacked = StagedDir::create(&staging, "_import").await?;
+    let into = unpacked.path().to_path_buf();
+    let (dirs, files) = tokio::task::spawn_blocking(move || extract(file, &into)).await??;
+
+    // Everything is checked before the first move, so a clash doesn't leave the
+    // snapshot half restored. Existing symlinks are never followed.
+    for name in files.keys() {
+        let segments: Vec<&str> = name.split('/').collect();
+        for depth in 1..=segments.len() {
+            let relative = segments[..depth].join("/");
+            let is_last = depth == segments.len();
+            match fs::symlink_metadata(root.join(&relative)).await {
+                Ok(metadata) if is_last && metadata.is_file() => {}
+                Ok(metadata) if !is_last && metadata.is_dir() => {}
+                Ok(_) => bail!(
+                    "'{}' already exists in the share as a different kind of entry",
+                    relative
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    for dir in &dirs {
+        fs::create_dir_all(root.join(dir)).await?;
+    }
+    let mut summary = Summary::default();
+    for (name, size) in &files {
+        let dest = root.join(name);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        storage::move_file(&unpacked.path().join(name), &dest).await?;
+        summary.files += 1;
+        summary.bytes += size;
+    }
+    Ok(summary)
+}
+
+/// Unpacks a snapshot below `into`, returning its folders and its files with their
+/// sizes.
+fn extract(
+    file: std::fs::File,
+    into: &Path,
+) -> anyhow::Result<(Vec<String>, BTreeMap<String, u64>)> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    let mut dirs = Vec::new();
+    let mut files = BTreeMap::new();
+    for entry in archive.entries().context("not a holodeck snapshot")? {
+        let mut entry = entry.context("damaged snapshot")?;
+        let path = entry.path()?;
+        let name = path
+            .to_str()
+            .with_context(|| format!("entry name is not UTF-8: {:?}", path))?
+            .to_string();
+        let Some(name) = entry_name(&name)? else {
+            continue;
+        };
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => {
+                std::fs::create_dir_all(into.join(&name))?;
+                dirs.push(name);
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let modified = modified(&mut entry)?;
+                let dest = into.join(&name);
+                if let Some(parent) = dest.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut out = std::fs::File::create(&dest)?;
+                let size = io::copy(&mut entry, &mut out)?;
+                out.set_modified(modified)?;
+                out.sync_all()?;
+                files.insert(name, size);
+            }
+            tar::EntryType::XGlobalHeader => {}
+            _ => bail!(
+                "unsupported entry '{}': only files and folders are imported",
+                name
+            ),
+        }
+    }
+    Ok((dirs, files))
+}
+
+/// The path in the share an entry goes to, checked like an uploaded filename except
+/// that the metadata directory is allowed; `None` for one left out of snapshots.
+fn entry_name(name: &str) -> anyhow::Result<Option<String>> {
+    let invalid = |e| anyhow::anyhow!("{}: '{}'", e, name);
+    if name.starts_with('/') {
+        return Err(invalid(paths::InvalidName::Traversal));
+    }
+    // Archives made with `tar -C dir .` prefix every entry with `./`
+    let name = name.trim_start_matches("./");
+    let (meta, rest) = match name.split_once('/') {
+        Some((META_DIR, rest)) => (true, rest),
+        _ if name.trim_end_matches('/') == META_DIR => (true, ""),
+        _ => (false, name),
+    };
+    let path = paths::validate_path(rest).map_err(invalid)?;
+    if !meta {
+        return Ok(Some(path).filter(|path| !path.is_empty()));
+    }
+    let first = path.split('/').next().unwrap_or_default();
+    if SKIPPED.contains(&first) {
+        return Ok(None);
+    }
+    Ok(Some(match path.is_empty() {
+        true => META_DIR.to_string(),
+        false => format!("{}/{}", META_DIR, path),
+    }))
+}
+
+/// The modification time of `entry`, from its PAX `mtime` record if it has one.
+fn modified(entry: &mut tar::Entry<impl Read>) -> io::Result<SystemTime> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if extension.key() == Ok("mtime")
+                && let Some(modified) = extension.value().ok().and_then(parse_pax_mtime)
+            {
+                return Ok(modified);
+            }
+        }
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?))
+}
+
+/// `<seconds>.<nanoseconds>` since the Unix epoch, as PAX records times.
+fn pax_mtime(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:09}", since.as_secs(), since.subsec_nanos())
+}
+
+fn parse_pax_mtime(value: &str) -> Option<SystemTime> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let digits = fraction.get(..9).unwrap_or(fraction);
+    let nanos = match digits {
+        "" => 0,
+        _ => format!("{:0<9}", digits).parse().ok()?,
+    };
+    Some(UNIX_EPOCH + Duration::new(seconds.parse().ok()?, nanos))
+}
+
+/// The first thing in `root` besides the metadata directory, if any.
+async fn shared_entry(root: &Path) -> io::Result<Option<String>> {
+    let mut entries = fs::read_dir(root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name != META_DIR {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn snapshots_carry_files_and_metadata() {
+        let source = tempfile::tempdir().unwrap();
+        let root = source.path();
+        std::fs::create_dir_all(root.join("docs/empty")).unwrap();
+        std::fs::create_dir_all(root.join(META_DIR).join("staging")).unwrap();
+        std::fs::write(root.join("report.pdf"), "%PDF-1.7").unwrap();
+        std::fs::write(root.join("docs/intro.md"), "# Intro").unwrap();
+        std::fs::write(root.join(META_DIR).join("tags.json"), "{}").unwrap();
+        std::fs::write(root.join(META_DIR).join("staging/upload.part"), "half").unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("report.pdf"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        // Taken from inside the share, which leaves the snapshot itself out
+        let archive = root.join("share.tar.zst");
+        let summary = write(root, &archive).unwrap();
+        assert_eq!((summary.files, summary.bytes), (3, 17));
+
+        let target = tempfile::tempdir().unwrap();
+        let restored = restore(target.path(), &archive, false).await.unwrap();
+        assert_eq!(restored.files, 3);
+        let read = |name: &str| std::fs::read_to_string(target.path().join(name)).unwrap();
+        assert_eq!(read("report.pdf"), "%PDF-1.7");
+        assert_eq!(read("docs/intro.md"), "# Intro");
+        assert_eq!(read(".holodeck/tags.json"), "{}");
+        assert!(target.path().join("docs/empty").is_dir());
+        let metadata = std::fs::metadata(target.path().join("report.pdf")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        let staging = target.path().join(META_DIR).join("staging");
+        assert_eq!(std::fs::read_dir(staging).unwrap().count(), 0);
+
+        // A share with files of its own is only replaced when asked to
+        assert!(restore(target.path(), &archive, false).await.is_err());
+        std::fs::write(target.path().join("report.pdf"), "changed").unwrap();
+        restore(target.path(), &archive, true).await.unwrap();
+        assert_eq!(read("report.pdf"), "%PDF-1.7");
+    }
+
+    #[test]
+    fn entry_names_are_checked() {
+        let name = |name: &str| entry_name(name).ok().flatten();
+        assert_eq!(name("./docs/intro.md").as_deref(), Some("docs/intro.md"));
+        assert_eq!(
+            name(".holodeck/tags.json").as_deref(),
+            Some(".holodeck/tags.json")
+        );
+        assert_eq!(name(".holodeck/staging/upload.part"), None);
+        assert!(entry_name("../etc/passwd").is_err());
+        assert!(entry_name("/etc/passwd").is_err());
+        assert!(entry_name("docs/.holodeck/../x").is_err());
+    }
+
+    #[test]
+    fn pax_times_keep_nanoseconds() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+        assert_eq!(pax_mtime(time), "1700000000.000000005");
+        assert_eq!(parse_pax_mtime(&pax_mtime(time)), Some(time));
+        assert_eq!(
+            parse_pax_mtime("1700000000.5"),
+            Some(UNIX_EPOCH + Duration::new(1_700_000_000, 500_000_000))
+        );
+        assert_eq!(
+            parse_pax_mtime("1700000000"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use hyper::header::{CONTENT_LENGTH, HeaderMap};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Distinguishes concurrent uploads of the same name in the staging directory
+static STAGING_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An upload being written to the staging directory.
+///
+/// The partial file is deleted when this guard is dropped without being
+/// persisted, so failed or aborted uploads never linger.
+pub struct StagedFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl StagedFile {
+    pub async fn create(staging_dir: &Path, filename: &str) -> io::Result<(Self, fs::File)> {
+        let id = STAGING_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = staging_dir.join(format!("{}.{}-{}.part", filename, std::process::id(), id));
+        let file = fs::File::create(&path).await?;
+        let staged = Self {
+            path,
+            persisted: false,
+        };
+        Ok((staged, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the finished upload to `dest`. The file handle must be closed first.
+    pub async fn persist(mut self, dest: &Path) -> io::Result<()> {
+        move_file(&self.path, dest).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+/// A directory in the staging area for multi-file uploads, removed together
+/// with anything left in it when dropped.
+#[cfg(feature = "archives")]
+pub struct StagedDir {
+    path: PathBuf,
+}
+
+#[cfg(feature = "archives")]
+impl StagedDir {
+    pub async fn create(staging_dir: &Path, name: &str) -> io::Result<Self> {
+        let id = STAGING_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = staging_dir.join(format!("{}.{}-{}.dir", name, std::process::id(), id));
+        fs::create_dir(&path).await?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(feature = "archives")]
+impl Drop for StagedDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Names with a write in progress, so a second POST or PATCH to the same file is
+/// refused instead of interleaving with the first.
+#[derive(Default)]
+pub struct WriteLocks {
+    held: Mutex<HashSet<String>>,
+}
+
+impl WriteLocks {
+    /// Claims `filename` until the guard is dropped, or `None` if another request holds it.
+    pub fn try_lock(&self, filename: &str) -> Option<WriteGuard<'_>> {
+        // Case-insensitive filesystems treat `A.txt` and `a.txt` as one file
+        let key = filename.to_lowercase();
+        if !self.held.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        Some(WriteGuard { locks: self, key })
+    }
+}
+
+pub struct WriteGuard<'a> {
+    locks: &'a WriteLocks,
+    key: String,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Renames `src` to `dest`, falling back to copy-and-delete across filesystems.
+pub async fn move_file(src: &Path, dest: &Path) -> io::Result<()> {
+    match rename(src, dest).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(src, dest).await?;
+            fs::remove_file(src).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Windows refuses to replace a file while another process has it open, which
+/// virus scanners and the search indexer routinely do for a moment after a write.
+#[cfg(windows)]
+async fn rename(src: &Path, dest: &Path) -> io::Result<()> {
+    let mut attempts = 0;
+    loop {
+        match fs::rename(src, dest).await {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && attempts < 5 => {
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(50 * attempts)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(windows))]
+async fn rename(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::rename(src, dest).await
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Flushes a directory's entries to stable storage so files renamed or created
+/// in it survive a power loss.
+#[cfg(unix)]
+pub async fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || std::fs::File::open(dir)?.sync_all())
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(not(unix))]
+pub async fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directory handles can't be synced on this platform; the file sync is all we get
+    Ok(())
+}
+
+/// Whether `dir`'s filesystem can take `incoming` more bytes written to `path`.
+///
+/// Space held by an existing file at `path` counts as free since the upload replaces it.
+/// If the free space can't be determined the upload is let through.
+pub async fn has_room_for(dir: &Path, path: &Path, incoming: u64) -> bool {
+    let Some(available) = available_space(dir).await else {
+        return true;
+    };
+    let replaced = fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+    available.saturating_add(replaced) >= incoming
+}
+
+/// Whether `err` means the filesystem, or the user's quota on it, is full.
+pub fn is_disk_full(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// Writes that failed because the disk filled up, counted for `GET /_admin/storage`.
+///
+/// Each one is also warned about on the console, since a full disk fails every upload
+/// after it until someone frees space, and with a trace event for log collectors.
+#[derive(Default)]
+pub struct DiskFull {
+    count: AtomicU64,
+}
+
+impl DiskFull {
+    /// Warns that storing `filename` ran out of space in `dir`.
+    pub fn alarm(&self, method: &str, filename: &str, dir: &Path) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::error!(method, file = filename, dir = %dir.display(), count, "disk full");
+        eprintln!(
+            "WARNING: DISK FULL: {} of '{}' aborted, no space left in {} ({} so far); free some space",
+            method,
+            filename,
+            dir.display(),
+            count
+        );
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Free bytes on `dir`'s filesystem, if they can be determined.
+pub async fn available_space(dir: &Path) -> Option<u64> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || fs4::available_space(dir))
+        .await
+        .ok()?
+        .ok()
+}
+
+/// SHA-256 of a file's current contents on disk
+pub async fn sha256_file(path: &Path) -> io::Result<Vec<u8>> {
+    sha256(fs::File::open(path).await?).await
+}
+
+/// SHA-256 of everything `reader` yields
+pub async fn sha256(mut reader: impl AsyncRead + Unpin) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// The `Content-Length` a request declared, if any.
+pub fn declared_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Whether `received` bytes fit the `declared` length: not past it while the body is
+/// still arriving, and all of it once the body has `ended`. hyper holds HTTP/1.1 and
+/// HTTP/2 bodies to their `Content-Length`, but the HTTP/3 listener passes bodies on as
+/// they come, and a short body must never be stored as if it were the whole file.
+pub fn fits_length(declared: u64, received: u64, ended: bool) -> bool {
+    match ended {
+        true => received == declared,
+        false => received <= declared,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_disks_are_recognized() {
+        use std::io::Write;
+        let mut full = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
+        let e = full.write_all(b"upload").unwrap_err();
+        assert!(is_disk_full(&e));
+        assert!(!is_disk_full(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+
+        let disk_full = DiskFull::default();
+        disk_full.alarm("POST", "big.iso", Path::new("/srv/share"));
+        assert_eq!(disk_full.count(), 1);
+    }
+
+    #[test]
+    fn bodies_must_fit_their_length() {
+        // Still arriving
+        assert!(fits_length(10, 4, false));
+        assert!(fits_length(10, 10, false));
+        assert!(!fits_length(10, 11, false));
+        // Ended short, exact or over
+        assert!(!fits_length(10, 4, true));
+        assert!(fits_length(10, 10, true));
+        assert!(!fits_length(10, 11, true));
+        assert!(fits_length(0, 0, true));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(declared_length(&headers), None);
+        headers.insert(CONTENT_LENGTH, "42".parse().unwrap());
+        assert_eq!(declared_length(&headers), Some(42));
+        headers.insert(CONTENT_LENGTH, "-1".parse().unwrap());
+        assert_eq!(declared_length(&headers), None);
+    }
+
+    /// Uploads as the HTTP/3 listener hands them over, with the body passed on as it
+    /// comes rather than held to its `Content-Length` by hyper.
+    #[tokio::test]
+    async fn uploads_not_matching_their_length_leave_nothing() {
+        use crate::{AppState, BoxBody};
+        use bytes::Bytes;
+        use http_body_util::{BodyExt, StreamBody};
+        use hyper::body::Frame;
+        use hyper::{Method, Request, StatusCode};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(dir.path().to_path_buf()).unwrap());
+        let upload = |method: Method, declared: &str, chunks: &[&'static str]| {
+            let frames = chunks
+                .iter()
+                .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+                .collect::<Vec<io::Result<_>>>();
+            let body: BoxBody = StreamBody::new(futures_util::stream::iter(frames)).boxed();
+            let request = Request::builder()
+                .method(method)
+                .uri("/report.txt")
+                .header(CONTENT_LENGTH, declared)
+                .body(body)
+                .unwrap();
+            crate::handle_request(request, "127.0.0.1:1".parse().unwrap(), state.clone())
+        };
+
+        for (declared, chunks) in [("10", &["hello"][..]), ("4", &["hel", "lo"][..])] {
+            let response = upload(Method::POST, declared, chunks).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(!dir.path().join("report.txt").exists());
+        }
+        let response = upload(Method::POST, "5", &["hel", "lo"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Appending past the declared length keeps the file as it was
+        let response = upload(Method::PATCH, "2", &[" world"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("report.txt")).unwrap(),
+            "hello"
+        );
+        let staging = std::fs::read_dir(&state.staging_dir).unwrap();
+        assert_eq!(staging.count(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long accepting pauses after an error that isn't the client's doing, such as
+/// running out of file descriptors, before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Why a connection ended before the client was done with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The client disconnected mid-request or mid-response
+    ClientGone,
+    /// The client sent something that isn't HTTP
+    Malformed,
+    /// The client stalled past a timeout
+    Timeout,
+    /// The TLS handshake failed: a scanner, or a plain-HTTP client on the TLS port
+    Tls,
+    /// Anything else, such as a failed write
+    Error,
+    /// The handler panicked
+    Panic,
+}
+
+impl Failure {
+    const ALL: [Self; 6] = [
+        Self::ClientGone,
+        Self::Malformed,
+        Self::Timeout,
+        Self::Tls,
+        Self::Error,
+        Self::Panic,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientGone => "client-gone",
+            Self::Malformed => "malformed",
+            Self::Timeout => "timeout",
+            Self::Tls => "tls",
+            Self::Error => "error",
+            Self::Panic => "panicked",
+        }
+    }
+}
+
+impl From<hyper::Error> for Failure {
+    fn from(err: hyper::Error) -> Self {
+        if err.is_parse() || err.is_parse_status() || err.is_parse_too_large() {
+            Self::Malformed
+        } else if err.is_timeout() {
+            Self::Timeout
+        } else if err.is_incomplete_message()
+            || err.is_canceled()
+            || err.is_closed()
+            || err.is_body_write_aborted()
+        {
+            Self::ClientGone
+        } else {
+            Self::Error
+        }
+    }
+}
+
+/// Runs every accepted connection in its own task, so a panicking handler only takes
+/// its own connection down, and counts how connections end for `GET /_admin/connections`
+/// instead of printing each failure.
+#[derive(Default)]
+pub struct Supervisor {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    served: AtomicU64,
+    accept_errors: AtomicU64,
+    failures: [AtomicU64; Failure::ALL.len()],
+}
+
+impl Supervisor {
+    /// Waits for the next connection, riding out errors that only affect one
+    /// connection or pass with time rather than ending the listener.
+    pub async fn accept(&self, listener: &TcpListener) -> (TcpStream, SocketAddr) {
+        loop {
+            match listener.accept().await {
+                Ok(accepted) => return accepted,
+                Err(e) => {
+                    self.accept_errors.fetch_add(1, Ordering::Relaxed);
+                    if !is_per_connection(&e) {
+                        eprintln!("Error accepting connection, retrying: {}", e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves `connection` in a task of its own, counting how it ends.
+    pub fn spawn<F>(self: &Arc<Self>, peer: SocketAddr, connection: F)
+    where
+        F: Future<Output = Result<(), Failure>> + Send + 'static,
+    {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let outcome = match AssertUnwindSafe(connection).catch_unwind().await {
+                Ok(outcome) => outcome,
+                Err(panic) => {
+                    eprintln!(
+                        "Connection from {} panicked: {}",
+                        peer,
+                        panic_message(&*panic)
+                    );
+                    Err(Failure::Panic)
+                }
+            };
+            match outcome {
+                Ok(()) => supervisor.served.fetch_add(1, Ordering::Relaxed),
+                Err(failure) => {
+                    tracing::debug!(%peer, failure = failure.as_str(), "connection failed");
+                    supervisor.failures[failure as usize].fetch_add(1, Ordering::Relaxed)
+                }
+            };
+            supervisor.active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    /// The counters as `<name> <count>` lines.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "accepted {}\nactive {}\nserved {}\naccept-errors {}\n",
+            self.accepted.load(Ordering::Relaxed),
+            self.active.load(Ordering::Relaxed),
+            self.served.load(Ordering::Relaxed),
+            self.accept_errors.load(Ordering::Relaxed),
+        );
+        for failure in Failure::ALL {
+            let count = self.failures[failure as usize].load(Ordering::Relaxed);
+            report.push_str(&format!("{} {}\n", failure.as_str(), count));
+        }
+        report
+    }
+}
+
+/// Whether an accept error concerns only the connection being accepted, such as a
+/// client resetting it while it was queued.
+fn is_per_connection(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_connections_are_counted_not_fatal() {
+        let supervisor = Arc::new(Supervisor::default());
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        supervisor.spawn(peer, async { panic!("handler bug") });
+        supervisor.spawn(peer, async { Err(Failure::ClientGone) });
+        supervisor.spawn(peer, async { Ok(()) });
+        while supervisor.active.load(Ordering::Relaxed) > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let report = supervisor.report();
+        assert!(
+            report.starts_with("accepted 3\nactive 0\nserved 1\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("\nclient-gone 1\n"), "{}", report);
+        assert!(report.ends_with("\npanicked 1\n"), "{}", report);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::AppState;
+use crate::storage::WriteLocks;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// How often the staging directory is checked for stale uploads
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Default for `--stale-upload-age`
+pub const DEFAULT_MAX_AGE: &str = "1d";
+
+/// Clears the staging directory of uploads nobody has written to for `max_age`, at
+/// startup and then every ten minutes: `.part` files and `.dir` folders left behind by
+/// a crash, and `.resume` files of resumable uploads the client never finished.
+///
+/// Only names holodeck itself stages are touched, since `--staging-dir` may point
+/// anywhere, and a name with a write in progress is always left alone. The sessions of
+/// resumable uploads swept away are forgotten too.
+pub async fn run(state: Arc<AppState>, max_age: Duration) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match sweep(&state.staging_dir, max_age, &state.writes).await {
+            Ok((0, _)) => {}
+            Ok((count, bytes)) => state.transfers.println(format!(
+                "SWEEP: Removed {} stale partial uploads ({} bytes)",
+                count, bytes
+            )),
+            Err(e) => eprintln!("SWEEP: Error cleaning the staging directory: {}", e),
+        }
+        if let Err(e) = state.resumable.prune(&state.writes).await {
+            eprintln!("SWEEP: Error expiring resumable uploads: {}", e);
+        }
+    }
+}
+
+/// Removes the stale entries of `dir`, giving how many there were and their size.
+async fn sweep(dir: &Path, max_age: Duration, writes: &WriteLocks) -> io::Result<(usize, u64)> {
+    let now = SystemTime::now();
+    let (mut count, mut bytes) = (0, 0);
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Some(upload) = upload_name(&name) else {
+            continue;
+        };
+        let metadata = fs::symlink_metadata(entry.path()).await?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        // Held until the entry is gone, so a PATCH resuming it can't slip in between
+        let Some(_lock) = writes.try_lock(upload) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            fs::remove_dir_all(entry.path()).await?;
+        } else {
+            fs::remove_file(entry.path()).await?;
+            bytes += metadata.len();
+        }
+        count += 1;
+    }
+    Ok((count, bytes))
+}
+
+/// The upload a staging entry belongs to, or `None` for names holodeck doesn't stage.
+fn upload_name(entry: &str) -> Option<&str> {
+    if let Some(name) = entry.strip_suffix(".resume") {
+        return Some(name);
+    }
+    // `<name>.<pid>-<n>.part` or `.dir`, from `StagedFile` and `StagedDir`
+    let staged = entry
+        .strip_suffix(".part")
+        .or_else(|| entry.strip_suffix(".dir"))?;
+    let (name, id) = staged.rsplit_once('.')?;
+    let (pid, n) = id.split_once('-')?;
+    (pid.parse::<u32>().is_ok() && n.parse::<u64>().is_ok()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_staged_names() {
+        assert_eq!(upload_name("report.pdf.4242-7.part"), Some("report.pdf"));
+        assert_eq!(upload_name("_batch.4242-8.dir"), Some("_batch"));
+        assert_eq!(upload_name("big.bin.resume"), Some("big.bin"));
+        assert_eq!(upload_name("notes.part"), None);
+        assert_eq!(upload_name("photo.jpg"), None);
+    }
+
+    #[tokio::test]
+    async fn removes_only_stale_unlocked_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        for name in ["a.bin.1-1.part", "b.bin.resume", "c.bin.resume", "keep.txt"] {
+            let file = std::fs::File::create(dir.path().join(name)).unwrap();
+            file.set_len(10).unwrap();
+            file.set_modified(old).unwrap();
+        }
+        std::fs::write(dir.path().join("fresh.bin.resume"), "new").unwrap();
+
+        let writes = WriteLocks::default();
+        let _uploading = writes.try_lock("c.bin").unwrap();
+        let swept = sweep(dir.path(), Duration::from_secs(60 * 60), &writes)
+            .await
+            .unwrap();
+        assert_eq!(swept, (2, 20));
+
+        let mut left: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["c.bin.resume", "fresh.bin.resume", "keep.txt"]);
+    }
+}
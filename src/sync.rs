@@ -0,0 +1,393 @@
+use crate::cli::{OutputMode, SyncArgs};
+use crate::client;
+use crate::mirror::{self, LocalHashes};
+use crate::progress::Transfers;
+use crate::{META_DIR, SHARED_DIR, paths, storage, trash};
+use anyhow::{Context, bail};
+use reqwest::header::{LAST_MODIFIED, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+/// What both sides held after the last sync with one remote: file name to hex SHA-256.
+///
+/// Comparing each side with it tells an edit from a file the other side never had,
+/// and a deletion from a file that is new on the other side.
+#[derive(Default, Serialize, Deserialize)]
+struct Base {
+    files: BTreeMap<String, String>,
+}
+
+/// What reconciling one file takes.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    /// Both sides hold the same content, or neither holds the file
+    InSync,
+    Download,
+    Upload,
+    DeleteLocal,
+    DeleteRemote,
+    /// Both sides changed the file since the last sync
+    Conflict,
+}
+
+/// Decides from the SHA-256 on each side and at the last sync which side changed.
+/// An edit wins over a deletion made on the other side.
+fn plan(local: Option<&str>, remote: Option<&str>, base: Option<&str>) -> Action {
+    if local == remote {
+        Action::InSync
+    } else if local == base {
+        match remote {
+            Some(_) => Action::Download,
+            None => Action::DeleteLocal,
+        }
+    } else if remote == base {
+        match local {
+            Some(_) => Action::Upload,
+            None => Action::DeleteRemote,
+        }
+    } else {
+        match (local, remote) {
+            (Some(_), Some(_)) => Action::Conflict,
+            (None, _) => Action::Download,
+            (_, None) => Action::Upload,
+        }
+    }
+}
+
+/// A two-way sync between the local share and one remote share.
+struct Session {
+    client: Client,
+    /// The remote share, ending in `/`, with any `?token=` it was given
+    remote: Url,
+    root: PathBuf,
+    /// Where downloads are assembled before replacing the local file
+    incoming: PathBuf,
+    /// Where `base` is saved between runs
+    base_path: PathBuf,
+    base: Base,
+    hashes: LocalHashes,
+    segments: u64,
+    transfers: Transfers,
+}
+
+/// Runs `holodeck sync`: reconciles the top-level files of the local share with a
+/// remote holodeck in both directions, once or every `--interval`.
+///
+/// Each side's SHA-256 is compared with the one recorded at the last sync, kept in
+/// `.holodeck/sync`, so new files, edits and deletions travel the right way. A file
+/// changed on both sides keeps the newer copy under its name and the other as a
+/// `.conflict-` copy on both sides. Deleted local files go to the share's trash;
+/// deleting remote files needs `--allow-delete` on the remote.
+pub async fn run(args: SyncArgs, output: OutputMode) -> anyhow::Result<()> {
+    let mut remote = Url::parse(&args.url)?;
+    if !remote.path().ends_with('/') {
+        remote.set_path(&format!("{}/", remote.path()));
+    }
+    let root = PathBuf::from(SHARED_DIR);
+    let dir = root.join(META_DIR).join("sync");
+    let incoming = dir.join("incoming");
+    fs::create_dir_all(&incoming).await?;
+
+    // One base per remote, whatever token it was reached with
+    let mut id = remote.clone();
+    id.set_query(None);
+    let base_path = dir.join(format!(
+        "{}.json",
+        &hex::encode(Sha256::digest(id.as_str()))[..16]
+    ));
+    let base = match fs::read(&base_path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format!("Invalid sync state in {}", base_path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Base::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut session = Session {
+        client: Client::new(),
+        remote,
+        root,
+        incoming,
+        base_path,
+        base,
+        hashes: LocalHashes::default(),
+        segments: args.segments,
+        transfers: Transfers::new(output == OutputMode::Normal),
+    };
+    let Some(period) = args.interval else {
+        return session.round().await;
+    };
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = session.round().await {
+            eprintln!("SYNC: Error syncing with {}: {}", session.remote, e);
+        }
+    }
+}
+
+impl Session {
+    /// Reconciles every file either side holds or held at the last sync.
+    async fn round(&mut self) -> anyhow::Result<()> {
+        let remote = self.remote_files().await?;
+        let local = self.local_files().await?;
+        let names: BTreeSet<String> = remote
+            .keys()
+            .chain(local.keys())
+            .chain(self.base.files.keys())
+            .cloned()
+            .collect();
+        for name in names {
+            let (local, remote) = (local.get(&name).cloned(), remote.get(&name).cloned());
+            if let Err(e) = self.reconcile(&name, local, remote).await {
+                eprintln!("SYNC: Error syncing '{}': {}", name, e);
+            }
+        }
+        let json = serde_json::to_vec_pretty(&self.base)?;
+        fs::write(&self.base_path, json).await?;
+        Ok(())
+    }
+
+    async fn reconcile(
+        &mut self,
+        name: &str,
+        local: Option<String>,
+        remote: Option<String>,
+    ) -> anyhow::Result<()> {
+        let base = self.base.files.get(name).map(String::as_str);
+        match plan(local.as_deref(), remote.as_deref(), base) {
+            Action::InSync => self.record(name, local),
+            Action::Download => {
+                let remote = remote.unwrap_or_default();
+                self.download(name, name, &remote).await?;
+                self.record(name, Some(remote));
+            }
+            Action::Upload => {
+                self.upload(name).await?;
+                self.record(name, local);
+            }
+            Action::DeleteLocal => {
+                let path = self.root.join(name);
+                trash::discard(&trash::dir(&self.root), &path, name).await?;
+                self.transfers.println(format!(
+                    "SYNC: Deleted '{}' locally (kept in the trash)",
+                    name
+                ));
+                self.record(name, None);
+            }
+            Action::DeleteRemote => {
+                let url = mirror::file_url(&self.remote, name);
+                let response = client::send(self.client.delete(url)).await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    bail!("the remote refused to delete it; it needs --allow-delete");
+                }
+                client::check(response).await?;
+                self.transfers
+                    .println(format!("SYNC: Deleted '{}' on the remote", name));
+                self.record(name, None);
+            }
+            Action::Conflict => {
+                let (local, remote) = (local.unwrap_or_default(), remote.unwrap_or_default());
+                self.resolve_conflict(name, local, remote).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the newer copy under `name` on both sides and the other one next to it.
+    async fn resolve_conflict(
+        &mut self,
+        name: &str,
+        local: String,
+        remote: String,
+    ) -> anyhow::Result<()> {
+        let path = self.root.join(name);
+        let local_modified = fs::metadata(&path).await?.modified()?;
+        let remote_newer = self
+            .remote_modified(name)
+            .await?
+            .is_some_and(|remote_modified| remote_modified > local_modified);
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        let aside = if remote_newer {
+            let aside = conflict_name(name, "local", secs);
+            storage::move_file(&path, &self.root.join(&aside)).await?;
+            self.upload(&aside).await?;
+            self.download(name, name, &remote).await?;
+            self.record(name, Some(remote));
+            self.record(&aside, Some(local));
+            aside
+        } else {
+            let aside = conflict_name(name, "remote", secs);
+            self.download(name, &aside, &remote).await?;
+            self.upload(&aside).await?;
+            self.upload(name).await?;
+            self.record(name, Some(local));
+            self.record(&aside, Some(remote));
+            aside
+        };
+        self.transfers.println(format!(
+            "SYNC: '{}' changed on both sides; kept the newer {} copy, the other is now '{}'",
+            name,
+            if remote_newer { "remote" } else { "local" },
+            aside
+        ));
+        Ok(())
+    }
+
+    fn record(&mut self, name: &str, sha256: Option<String>) {
+        match sha256 {
+            Some(sha256) => self.base.files.insert(name.to_string(), sha256),
+            None => self.base.files.remove(name),
+        };
+    }
+
+    /// SHA-256 of the remote share's top-level files.
+    async fn remote_files(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        for name in mirror::list(&self.client, &self.remote).await? {
+            let url = mirror::file_url(&self.remote, &name);
+            // Folders have no checksum; they are not synced
+            if let Some(sha256) = client::remote_sha256(&self.client, &url, &name).await? {
+                files.insert(name, sha256);
+            }
+        }
+        Ok(files)
+    }
+
+    /// SHA-256 of the local share's top-level files.
+    async fn local_files(&mut self) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name == META_DIR
+                || paths::validate_filename(&name).is_err()
+                || !entry.file_type().await?.is_file()
+            {
+                continue;
+            }
+            if let Some(sha256) = self.hashes.get(&name, &entry.path()).await? {
+                files.insert(name, sha256);
+            }
+        }
+        Ok(files)
+    }
+
+    /// When the remote copy of `name` was last modified, if the server says.
+    async fn remote_modified(&self, name: &str) -> anyhow::Result<Option<SystemTime>> {
+        let url = mirror::file_url(&self.remote, name);
+        let request = self.client.get(url).header(RANGE, "bytes=0-0");
+        let response = client::check(client::send(request).await?).await?;
+        Ok(response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok()))
+    }
+
+    /// Downloads the remote `name` and stores it locally as `local_name`, once its
+    /// content matches `sha256`.
+    async fn download(&mut self, name: &str, local_name: &str, sha256: &str) -> anyhow::Result<()> {
+        let url = mirror::file_url(&self.remote, name);
+        let staged = self.incoming.join(local_name);
+        client::download(
+            &self.client,
+            &url,
+            name,
+            &staged,
+            self.segments,
+            &self.transfers,
+        )
+        .await?;
+        let received = hex::encode(storage::sha256_file(&staged).await?);
+        if received != sha256 {
+            fs::remove_file(&staged).await?;
+            bail!(
+                "checksum mismatch (server has {}, received {}); it changed during the download",
+                sha256,
+                received
+            );
+        }
+        let dest = self.root.join(local_name);
+        storage::move_file(&staged, &dest).await?;
+        let size = fs::metadata(&dest).await?.len();
+        self.transfers.println(format!(
+            "SYNC: Downloaded '{}' ({} bytes)",
+            local_name, size
+        ));
+        Ok(())
+    }
+
+    /// Uploads the local `name`, replacing the remote copy.
+    async fn upload(&self, name: &str) -> anyhow::Result<()> {
+        let url = mirror::file_url(&self.remote, name);
+        let len = client::post_file(&self.client, &url, &self.root.join(name)).await?;
+        self.transfers
+            .println(format!("SYNC: Uploaded '{}' ({} bytes)", name, len));
+        Ok(())
+    }
+}
+
+/// `report.conflict-local-1700000000.txt` for `report.txt`.
+fn conflict_name(name: &str, side: &str, secs: u64) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!(
+            "{}.conflict-{}-{}{}",
+            &name[..dot],
+            side,
+            secs,
+            &name[dot..]
+        ),
+        _ => format!("{}.conflict-{}-{}", name, side, secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_by_comparing_with_last_sync() {
+        let (a, b, c) = (Some("a"), Some("b"), Some("c"));
+        assert_eq!(plan(a, a, None), Action::InSync);
+        assert_eq!(plan(None, None, a), Action::InSync);
+        // New on one side
+        assert_eq!(plan(None, a, None), Action::Download);
+        assert_eq!(plan(a, None, None), Action::Upload);
+        // Edited on one side
+        assert_eq!(plan(a, b, a), Action::Download);
+        assert_eq!(plan(b, a, a), Action::Upload);
+        // Deleted on one side
+        assert_eq!(plan(a, None, a), Action::DeleteLocal);
+        assert_eq!(plan(None, a, a), Action::DeleteRemote);
+        // Both sides changed
+        assert_eq!(plan(b, c, a), Action::Conflict);
+        assert_eq!(plan(a, b, None), Action::Conflict);
+        assert_eq!(plan(None, b, a), Action::Download);
+        assert_eq!(plan(b, None, a), Action::Upload);
+    }
+
+    #[test]
+    fn conflict_copies_keep_the_extension() {
+        assert_eq!(
+            conflict_name("report.txt", "local", 7),
+            "report.conflict-local-7.txt"
+        );
+        assert_eq!(
+            conflict_name("Makefile", "remote", 7),
+            "Makefile.conflict-remote-7"
+        );
+        assert_eq!(conflict_name(".env", "local", 7), ".env.conflict-local-7");
+    }
+}
@@ -0,0 +1,262 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use http_body_util::{BodyExt, Limited};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Route prefix of the tagging API
+const API: &str = "/_tags";
+
+/// Largest `POST /_tags/<file>` body accepted
+const MAX_BODY_LEN: usize = 4096;
+
+/// Most tags one file may carry
+const MAX_TAGS: usize = 32;
+
+/// Longest tag accepted
+const MAX_TAG_LEN: usize = 64;
+
+/// Labels like `release` or `q3-report` attached to files in the share, so listings
+/// can be filtered with `?tag=`.
+///
+/// Tags belong to the name: they move with a rename, stay when the file is replaced
+/// and go when it is deleted. They are kept in the share's metadata directory.
+pub struct Tags {
+    stored: Mutex<BTreeMap<String, BTreeSet<String>>>,
+    path: PathBuf,
+}
+
+impl Tags {
+    /// Loads the tags stored in `root`'s metadata directory.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(META_DIR).join("tags.json");
+        let stored = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!("Invalid tag file {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            stored: Mutex::new(stored),
+            path,
+        })
+    }
+
+    /// Every tagged name with its tags.
+    pub async fn all(&self) -> BTreeMap<String, BTreeSet<String>> {
+        self.stored.lock().await.clone()
+    }
+
+    /// Moves the tags of `from` along with the file, renamed to `to`.
+    pub async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut stored = self.stored.lock().await;
+        let Some(tags) = stored.remove(from) else {
+            return Ok(());
+        };
+        stored.insert(to.to_string(), tags);
+        self.save(&stored).await
+    }
+
+    /// Forgets the tags of a deleted file.
+    pub async fn remove(&self, name: &str) -> io::Result<()> {
+        let mut stored = self.stored.lock().await;
+        if stored.remove(name).is_none() {
+            return Ok(());
+        }
+        self.save(&stored).await
+    }
+
+    async fn save(&self, stored: &BTreeMap<String, BTreeSet<String>>) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(stored)?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, json).await?;
+        fs::rename(&temp, &self.path).await
+    }
+}
+
+/// Registers the tagging API, `/_tags` and everything below it.
+pub fn routes(router: &mut Router) {
+    for pattern in [API, "/_tags/{*name}"] {
+        router
+            .any(pattern, async |req, cx| {
+                handle(req.method().clone(), &cx.path, req, &cx.state).await
+            })
+            .scoped("name");
+    }
+}
+
+/// The tags `?tag=` asks a listing for; a name must carry all of them.
+pub fn from_query(query: Option<&str>) -> Vec<String> {
+    query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("tag="))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether a name carrying `tags` passes the `wanted` filter.
+pub fn matches(tags: Option<&BTreeSet<String>>, wanted: &[String]) -> bool {
+    wanted
+        .iter()
+        .all(|tag| tags.is_some_and(|tags| tags.contains(tag)))
+}
+
+/// Handles the tagging API:
+///
+/// - `GET /_tags` lists tagged names as `name: tag, tag` lines
+/// - `GET /_tags/<name>` lists the tags of one name, one per line
+/// - `POST /_tags/<name>` with tags separated by commas or spaces adds them
+/// - `DELETE /_tags/<name>/<tag>` removes one tag, `DELETE /_tags/<name>` all of them
+pub async fn handle(
+    method: Method,
+    path: &str,
+    req: Request<BoxBody>,
+    state: &AppState,
+) -> io::Result<Response<BoxBody>> {
+    let tags = &state.tags;
+    let rest = path
+        .strip_prefix(API)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+    let (name, tag) = match rest.split_once('/') {
+        Some((name, tag)) => (name, Some(tag)),
+        None => (rest, None),
+    };
+    if !name.is_empty() && (paths::validate_filename(name).is_err() || name == META_DIR) {
+        return Ok(text(StatusCode::BAD_REQUEST, "Invalid filename"));
+    }
+
+    match (method, name, tag) {
+        (Method::GET, "", None) => {
+            let body: String = tags
+                .stored
+                .lock()
+                .await
+                .iter()
+                .map(|(name, tags)| format!("{}: {}\n", name, join(tags)))
+                .collect();
+            Ok(text(StatusCode::OK, body))
+        }
+        (Method::GET, name, None) => {
+            let body: String = tags
+                .stored
+                .lock()
+                .await
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|tag| format!("{}\n", tag))
+                .collect();
+            Ok(text(StatusCode::OK, body))
+        }
+        (Method::POST, name, None) if !name.is_empty() => {
+            if fs::symlink_metadata(state.root.join(name)).await.is_err() {
+                return Ok(text(
+                    StatusCode::NOT_FOUND,
+                    format!("File '{}' not found", name),
+                ));
+            }
+            let body = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(text(StatusCode::PAYLOAD_TOO_LARGE, "Too many tags")),
+            };
+            let added: Vec<String> = String::from_utf8_lossy(&body)
+                .split([',', ' ', '\t', '\r', '\n'])
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_lowercase)
+                .collect();
+            if added.is_empty() {
+                return Ok(text(StatusCode::BAD_REQUEST, "No tags given"));
+            }
+            if let Some(invalid) = added.iter().find(|tag| !is_valid(tag)) {
+                return Ok(text(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Invalid tag '{}': use up to {} letters, digits, '.', '_' or '-'",
+                        invalid, MAX_TAG_LEN
+                    ),
+                ));
+            }
+
+            let mut stored = tags.stored.lock().await;
+            let mut updated = stored.get(name).cloned().unwrap_or_default();
+            updated.extend(added);
+            if updated.len() > MAX_TAGS {
+                return Ok(text(
+                    StatusCode::BAD_REQUEST,
+                    format!("A file can carry at most {} tags", MAX_TAGS),
+                ));
+            }
+            let body = format!("{}: {}\n", name, join(&updated));
+            stored.insert(name.to_string(), updated);
+            if let Err(e) = tags.save(&stored).await {
+                eprintln!("TAGS: Error saving tags: {}", e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error saving tags: {}", e),
+                ));
+            }
+            state
+                .transfers
+                .println(format!("TAGS: {}", body.trim_end()));
+            Ok(text(StatusCode::OK, body))
+        }
+        (Method::DELETE, name, tag) if !name.is_empty() => {
+            let mut stored = tags.stored.lock().await;
+            let removed = match (stored.get_mut(name), tag) {
+                (Some(tags), Some(tag)) => {
+                    let removed = tags.remove(&tag.to_lowercase());
+                    if tags.is_empty() {
+                        stored.remove(name);
+                    }
+                    removed
+                }
+                (Some(_), None) => stored.remove(name).is_some(),
+                (None, _) => false,
+            };
+            if !removed {
+                return Ok(text(StatusCode::NOT_FOUND, "No such tag"));
+            }
+            if let Err(e) = tags.save(&stored).await {
+                eprintln!("TAGS: Error saving tags: {}", e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error saving tags: {}", e),
+                ));
+            }
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(full(""))
+                .unwrap())
+        }
+        _ => Ok(crate::not_found()),
+    }
+}
+
+/// Tags are kept lowercase, so `Release` and `release` are one tag.
+fn is_valid(tag: &str) -> bool {
+    tag.len() <= MAX_TAG_LEN
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+/// `tag, tag` for listings.
+pub fn join(tags: &BTreeSet<String>) -> String {
+    tags.iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
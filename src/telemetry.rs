@@ -0,0 +1,77 @@
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Keeps trace export running; dropping it flushes spans still buffered.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Sets up logging to stderr when `RUST_LOG` is set and, with the `otel` feature,
+/// OTLP span export when `OTEL_EXPORTER_OTLP_ENDPOINT` (or the traces-specific
+/// variant) is set.
+pub fn init() -> Telemetry {
+    let fmt = std::env::var("RUST_LOG").is_ok().then(|| {
+        tracing_subscriber::fmt::layer().with_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+    });
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = otlp_provider();
+        let otel = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider as _;
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("holodeck"))
+                .with_filter(EnvFilter::new("holodeck=info"))
+        });
+        tracing_subscriber::registry().with(fmt).with(otel).init();
+        Telemetry { provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::registry().with(fmt).init();
+        Telemetry {}
+    }
+}
+
+#[cfg(feature = "otel")]
+fn otlp_provider() -> Option<opentelemetry_sdk::trace::SdkTracerProvider> {
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+        .or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+        .ok()?;
+    // The exporter reads the remaining OTEL_* variables (headers, timeout) itself
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("Not exporting traces to {}: {}", endpoint, e);
+            return None;
+        }
+    };
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name("holodeck")
+        .build();
+    Some(
+        opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build(),
+    )
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Error flushing traces: {}", e);
+        }
+    }
+}
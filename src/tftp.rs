@@ -0,0 +1,370 @@
+use crate::events::Event;
+use crate::packing::Contents;
+use crate::policy::Action;
+use crate::{AppState, paths};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+/// Opcodes (RFC 1350, RFC 2347)
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+/// Error codes
+const UNDEFINED: u16 = 0;
+const FILE_NOT_FOUND: u16 = 1;
+const ACCESS_VIOLATION: u16 = 2;
+const ILLEGAL_OPERATION: u16 = 4;
+
+/// Block size without a `blksize` option, and the largest one allowed (RFC 2348)
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 65464;
+
+/// Wait for each acknowledgement without a `timeout` option (RFC 2349)
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Times a block is sent before the client is given up on
+const ATTEMPTS: u32 = 5;
+
+/// Largest request accepted; names and options fit easily
+const MAX_REQUEST_LEN: usize = 2048;
+
+/// A read request: the file and the RFC 2347 options the client proposed.
+#[derive(Debug, PartialEq, Eq)]
+struct ReadRequest {
+    filename: String,
+    options: Vec<(String, String)>,
+}
+
+/// Transfer settings after option negotiation.
+#[derive(Debug, PartialEq, Eq)]
+struct Options {
+    block_size: usize,
+    timeout: Duration,
+    /// Options to acknowledge with an OACK, in the order the client sent them
+    accepted: Vec<(String, String)>,
+}
+
+/// Serves the share read-only over TFTP for `--tftp`, for bootloaders and firmware
+/// updaters that speak nothing else.
+///
+/// Each read request is answered from a fresh socket, as the protocol expects.
+/// Write requests are refused. `netascii` transfers are sent unconverted, like
+/// `octet`, which is what they are in practice for firmware images.
+pub async fn serve(socket: UdpSocket, state: Arc<AppState>) {
+    let local = match socket.local_addr() {
+        Ok(local) => local.ip(),
+        Err(e) => {
+            eprintln!("TFTP: Error reading the listener address: {}", e);
+            return;
+        }
+    };
+    let mut buf = vec![0; MAX_REQUEST_LEN];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("TFTP: Error receiving a request: {}", e);
+                continue;
+            }
+        };
+        let packet = buf[..len].to_vec();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(&packet, local, peer, &state).await {
+                eprintln!("TFTP: Error serving {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn answer(
+    packet: &[u8],
+    local: IpAddr,
+    peer: SocketAddr,
+    state: &AppState,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind((local, 0)).await?;
+    socket.connect(peer).await?;
+    let request = match opcode(packet) {
+        Some(RRQ) => parse_request(&packet[2..]),
+        Some(WRQ) => {
+            return refuse(&socket, ACCESS_VIOLATION, "This server is read-only").await;
+        }
+        _ => return refuse(&socket, ILLEGAL_OPERATION, "Expected a read request").await,
+    };
+    let Some(request) = request else {
+        return refuse(&socket, ILLEGAL_OPERATION, "Malformed read request").await;
+    };
+
+    let name = match paths::validate_path(request.filename.trim_start_matches('/')) {
+        Ok(name) if !name.is_empty() => name,
+        _ => return refuse(&socket, ACCESS_VIOLATION, "Invalid file name").await,
+    };
+    // TFTP carries no share token, so it gets what a request without one would
+    if let Some(reason) = state.policies().refusal(Action::Download, &name, None) {
+        return refuse(&socket, ACCESS_VIOLATION, &reason).await;
+    }
+    let (mut file, len) = match state.packed.open(&state.root, &name).await {
+        Ok(opened) if opened.metadata.is_file() => (opened.contents, opened.size),
+        _ => return refuse(&socket, FILE_NOT_FOUND, "File not found").await,
+    };
+    let options = negotiate(&request.options, len);
+
+    let client = peer.ip().to_string();
+    let mut progress = state.transfers.start("TFTP", &name, Some(len));
+    progress.set_client(&client);
+    if !options.accepted.is_empty() {
+        let mut oack = OACK.to_be_bytes().to_vec();
+        for (option, value) in &options.accepted {
+            for field in [option, value] {
+                oack.extend_from_slice(field.as_bytes());
+                oack.push(0);
+            }
+        }
+        send_until_acked(&socket, &oack, 0, options.timeout).await?;
+    }
+
+    let mut block: u16 = 1;
+    let mut data = vec![0; options.block_size];
+    loop {
+        if progress.is_cancelled() {
+            return refuse(&socket, UNDEFINED, "Transfer cancelled by the server").await;
+        }
+        let read = read_block(&mut file, &mut data).await?;
+        let mut packet = Vec::with_capacity(4 + read);
+        packet.extend_from_slice(&DATA.to_be_bytes());
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(&data[..read]);
+        send_until_acked(&socket, &packet, block, options.timeout).await?;
+        progress.inc(read as u64);
+        // A short block, empty if need be, ends the transfer
+        if read < options.block_size {
+            break;
+        }
+        // Files beyond 65535 blocks roll over, as most clients expect
+        block = block.wrapping_add(1);
+    }
+    progress.println(format!(
+        "TFTP: Served file '{}' ({} bytes) to {}",
+        name, len, client
+    ));
+    state.events.emit(Event::DownloadCompleted {
+        file: name,
+        bytes: progress.bytes(),
+        peer: client,
+    });
+    progress.finish();
+    Ok(())
+}
+
+fn opcode(packet: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(packet.get(..2)?.try_into().ok()?))
+}
+
+/// Parses the NUL-terminated filename, mode and option pairs following the opcode.
+fn parse_request(body: &[u8]) -> Option<ReadRequest> {
+    let fields: Vec<&str> = body
+        .strip_suffix(&[0])?
+        .split(|&b| b == 0)
+        .map(|field| std::str::from_utf8(field).ok())
+        .collect::<Option<_>>()?;
+    let [filename, mode, options @ ..] = fields.as_slice() else {
+        return None;
+    };
+    if !mode.eq_ignore_ascii_case("octet") && !mode.eq_ignore_ascii_case("netascii") {
+        return None;
+    }
+    Some(ReadRequest {
+        filename: filename.to_string(),
+        options: options
+            .chunks_exact(2)
+            .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].to_string()))
+            .collect(),
+    })
+}
+
+/// Accepts the `blksize`, `timeout` and `tsize` options and ignores the rest.
+fn negotiate(proposed: &[(String, String)], len: u64) -> Options {
+    let mut options = Options {
+        block_size: DEFAULT_BLOCK_SIZE,
+        timeout: DEFAULT_TIMEOUT,
+        accepted: Vec::new(),
+    };
+    for (option, value) in proposed {
+        match option.as_str() {
+            "blksize" => {
+                let Ok(size) = value.parse::<usize>() else {
+                    continue;
+                };
+                if size < 8 {
+                    continue;
+                }
+                options.block_size = size.min(MAX_BLOCK_SIZE);
+                options
+                    .accepted
+                    .push((option.clone(), options.block_size.to_string()));
+            }
+            "timeout" => {
+                let Ok(secs @ 1..=255) = value.parse::<u64>() else {
+                    continue;
+                };
+                options.timeout = Duration::from_secs(secs);
+                options.accepted.push((option.clone(), value.clone()));
+            }
+            "tsize" => options.accepted.push((option.clone(), len.to_string())),
+            _ => {}
+        }
+    }
+    options
+}
+
+/// Fills `data` from `file`, short only at the end of the file.
+async fn read_block(file: &mut Contents, data: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Sends `packet` until the client acknowledges `block`, resending it whenever the
+/// acknowledgement is late.
+async fn send_until_acked(
+    socket: &UdpSocket,
+    packet: &[u8],
+    block: u16,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut reply = [0; MAX_REQUEST_LEN];
+    for _ in 0..ATTEMPTS {
+        socket.send(packet).await?;
+        let deadline = Instant::now() + timeout;
+        // Duplicate acknowledgements of earlier blocks are ignored rather than
+        // answered, which would double every packet from then on
+        while let Ok(received) = timeout_at(deadline, socket.recv(&mut reply)).await {
+            let reply = &reply[..received?];
+            match opcode(reply) {
+                Some(ACK) if reply.get(2..4) == Some(&block.to_be_bytes()) => return Ok(()),
+                Some(ERROR) => {
+                    let message = String::from_utf8_lossy(reply.get(4..).unwrap_or_default());
+                    return Err(io::Error::other(format!(
+                        "client gave up: {}",
+                        message.trim_end_matches('\0')
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no acknowledgement of block {}", block),
+    ))
+}
+
+/// Sends an error packet, which ends the transfer.
+async fn refuse(socket: &UdpSocket, code: u16, message: &str) -> io::Result<()> {
+    let mut packet = ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    socket.send(&packet).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_read_requests_and_negotiates_options() {
+        let request =
+            parse_request(b"/boot/vmlinuz\0octet\0blksize\x001468\0tsize\x000\0").unwrap();
+        assert_eq!(request.filename, "/boot/vmlinuz");
+        let options = negotiate(&request.options, 4_000_000);
+        assert_eq!(options.block_size, 1468);
+        assert_eq!(
+            options.accepted,
+            [
+                ("blksize".to_string(), "1468".to_string()),
+                ("tsize".to_string(), "4000000".to_string())
+            ]
+        );
+
+        assert!(parse_request(b"file\0mail\0").is_none());
+        assert!(parse_request(b"file\0octet").is_none());
+        let plain = parse_request(b"file\0NETASCII\0").unwrap();
+        assert_eq!(negotiate(&plain.options, 10).block_size, DEFAULT_BLOCK_SIZE);
+        assert!(negotiate(&plain.options, 10).accepted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn serves_files_block_by_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents: Vec<u8> = (0..1024u32).map(|i| i as u8).collect();
+        std::fs::write(dir.path().join("firmware.bin"), &contents).unwrap();
+        let state = Arc::new(AppState::new(dir.path().to_path_buf()).unwrap());
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(serve(server, state));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(b"\0\x01firmware.bin\0octet\0", addr)
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        // 512 + 512 + an empty block closing the transfer
+        for block in 1..=3u16 {
+            let (len, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf[..4],
+                [&DATA.to_be_bytes()[..], &block.to_be_bytes()].concat()
+            );
+            received.extend_from_slice(&buf[4..len]);
+            let ack = [&ACK.to_be_bytes()[..], &block.to_be_bytes()].concat();
+            client.send_to(&ack, from).await.unwrap();
+        }
+        assert_eq!(received, contents);
+
+        client
+            .send_to(b"\0\x01../etc/passwd\0octet\0", addr)
+            .await
+            .unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(opcode(&buf[..len]), Some(ERROR));
+        client
+            .send_to(b"\0\x02upload.bin\0octet\0", addr)
+            .await
+            .unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..4], [0, 5, 0, 2]);
+        assert!(len > 4);
+    }
+
+    #[test]
+    fn tftp_refuses_credentials() {
+        use clap::Parser;
+        let parse = |extra: &[&str]| {
+            let args = ["holodeck", "--tftp", "0.0.0.0:69"].iter().chain(extra);
+            crate::cli::Cli::try_parse_from(args)
+        };
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--passcode"]).is_err());
+        assert!(parse(&["--password", "hunter2"]).is_err());
+        assert!(parse(&["--share-links"]).is_err());
+    }
+}
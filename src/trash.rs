@@ -0,0 +1,264 @@
+use crate::router::Router;
+use crate::storage;
+use crate::{AppState, BoxBody, META_DIR, full, paths};
+use hyper::{Method, Response, StatusCode};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+/// Route prefix of the trash API
+const API: &str = "/_trash";
+
+/// How often expired entries are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Tells apart files deleted within the same second
+static DELETE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Where `DELETE` puts files, so a mistaken delete can be undone for a while.
+///
+/// Entries are named `<unix time>-<n>-<original name>` inside `.holodeck/trash`, so
+/// the trash survives restarts without an index of its own.
+pub struct Trash {
+    dir: PathBuf,
+    /// Zero unlinks files right away
+    retention: Duration,
+}
+
+/// A file waiting in the trash
+struct Entry {
+    id: String,
+    name: String,
+    deleted: SystemTime,
+}
+
+impl Entry {
+    fn parse(id: String) -> Option<Self> {
+        let mut parts = id.splitn(3, '-');
+        let secs = parts.next()?.parse().ok()?;
+        parts.next()?.parse::<u64>().ok()?;
+        let name = parts.next()?.to_string();
+        Some(Self {
+            deleted: UNIX_EPOCH + Duration::from_secs(secs),
+            name,
+            id,
+        })
+    }
+}
+
+impl Trash {
+    pub fn new(root: &Path, retention: Duration) -> Self {
+        Self {
+            dir: dir(root),
+            retention,
+        }
+    }
+
+    async fn entries(&self) -> io::Result<Vec<Entry>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut found = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(entry) = entry.file_name().into_string().ok().and_then(Entry::parse) {
+                found.push(entry);
+            }
+        }
+        found.sort_by_key(|entry| entry.deleted);
+        Ok(found)
+    }
+
+    /// Removes entries older than the retention period.
+    async fn purge(&self) -> io::Result<()> {
+        let now = SystemTime::now();
+        for entry in self.entries().await? {
+            let age = now.duration_since(entry.deleted).unwrap_or_default();
+            if age > self.retention {
+                fs::remove_file(self.dir.join(&entry.id)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Purges expired entries now and then every hour.
+    pub async fn run(&self) {
+        if self.retention.is_zero() {
+            return;
+        }
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.purge().await {
+                eprintln!("TRASH: Error purging expired files: {}", e);
+            }
+        }
+    }
+}
+
+/// The trash folder of the share at `root`.
+pub fn dir(root: &Path) -> PathBuf {
+    root.join(META_DIR).join("trash")
+}
+
+/// Moves `file_path` into the trash folder `dir` as a deleted copy of `name`.
+pub async fn discard(dir: &Path, file_path: &Path, name: &str) -> io::Result<()> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let n = DELETE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let id = format!("{}-{}-{}", secs, n, name);
+    fs::create_dir_all(dir).await?;
+    storage::move_file(file_path, &dir.join(id)).await
+}
+
+/// Deletes a shared file, moving it to the trash unless retention is zero.
+pub async fn delete(path: &str, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let Some(trash) = &state.trash else {
+        return Ok(crate::not_found());
+    };
+    let filename = path.trim_start_matches('/');
+    if let Err(e) = paths::validate_filename(filename) {
+        return Ok(text(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    if filename == META_DIR {
+        return Ok(text(StatusCode::BAD_REQUEST, "Invalid filename"));
+    }
+    let Some(_lock) = state.writes.try_lock(filename) else {
+        return Ok(crate::write_in_progress(filename));
+    };
+
+    let file_path = state.root.join(filename);
+    match fs::symlink_metadata(&file_path).await {
+        Ok(metadata) if metadata.is_file() => {}
+        _ => {
+            return Ok(text(
+                StatusCode::NOT_FOUND,
+                format!("File '{}' not found", filename),
+            ));
+        }
+    }
+
+    let result = if trash.retention.is_zero() {
+        fs::remove_file(&file_path).await
+    } else {
+        discard(&trash.dir, &file_path, filename).await
+    };
+    if let Err(e) = result {
+        eprintln!("DELETE: Error deleting file '{}': {}", filename, e);
+        return Ok(text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting file: {}", e),
+        ));
+    }
+
+    if let Err(e) = state.tags.remove(filename).await {
+        eprintln!("DELETE: Error removing the tags of '{}': {}", filename, e);
+    }
+    state
+        .transfers
+        .println(format!("DELETE: Deleted file '{}'", filename));
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(full(""))
+        .unwrap())
+}
+
+/// Registers the trash API, `/_trash` and everything below it.
+pub fn routes(router: &mut Router) {
+    for pattern in [API, "/_trash/{*name}"] {
+        router.any(pattern, async |req, cx| {
+            handle(req.method().clone(), &cx.path, &cx.state).await
+        });
+    }
+}
+
+/// Handles the trash API:
+///
+/// - `GET /_trash` lists deleted files as `<id> <name> (deleted <n>s ago)` lines
+/// - `POST /_trash/<id>` restores one under its original name
+pub async fn handle(method: Method, path: &str, state: &AppState) -> io::Result<Response<BoxBody>> {
+    let Some(trash) = state
+        .trash
+        .as_ref()
+        .filter(|trash| !trash.retention.is_zero())
+    else {
+        return Ok(crate::not_found());
+    };
+    let id = path
+        .strip_prefix(API)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    match (method, id) {
+        (Method::GET, "") => {
+            let now = SystemTime::now();
+            let body: String = trash
+                .entries()
+                .await?
+                .into_iter()
+                .map(|entry| {
+                    let ago = now.duration_since(entry.deleted).unwrap_or_default();
+                    format!(
+                        "{} {} (deleted {}s ago)\n",
+                        entry.id,
+                        entry.name,
+                        ago.as_secs()
+                    )
+                })
+                .collect();
+            Ok(text(StatusCode::OK, body))
+        }
+        (Method::POST, id) if !id.is_empty() => {
+            let entry = match paths::validate_filename(id) {
+                Ok(id) => Entry::parse(id.to_string()),
+                Err(_) => None,
+            };
+            let Some(entry) = entry else {
+                return Ok(text(
+                    StatusCode::NOT_FOUND,
+                    format!("No trash entry '{}'", id),
+                ));
+            };
+            let Some(_lock) = state.writes.try_lock(&entry.name) else {
+                return Ok(crate::write_in_progress(&entry.name));
+            };
+            let source = trash.dir.join(&entry.id);
+            if fs::symlink_metadata(&source).await.is_err() {
+                return Ok(text(
+                    StatusCode::NOT_FOUND,
+                    format!("No trash entry '{}'", id),
+                ));
+            }
+            let dest = state.root.join(&entry.name);
+            if fs::symlink_metadata(&dest).await.is_ok() {
+                return Ok(text(
+                    StatusCode::CONFLICT,
+                    format!("File '{}' already exists", entry.name),
+                ));
+            }
+            if let Err(e) = storage::move_file(&source, &dest).await {
+                eprintln!("TRASH: Error restoring '{}': {}", entry.name, e);
+                return Ok(text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error restoring file: {}", e),
+                ));
+            }
+            state
+                .transfers
+                .println(format!("TRASH: Restored file '{}'", entry.name));
+            Ok(text(
+                StatusCode::OK,
+                format!("File '{}' restored\n", entry.name),
+            ))
+        }
+        _ => Ok(crate::not_found()),
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<bytes::Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
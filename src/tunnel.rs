@@ -0,0 +1,575 @@
+use crate::cli::OutputMode;
+use crate::config::TunnelConfig;
+use crate::events::{Event, Events};
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+use reverse_ssh::{ReverseSshClient, ReverseSshConfig};
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Called by a provider with the external URL once it is known.
+pub type UrlReporter = Box<dyn FnMut(&str) + Send>;
+
+/// A way of exposing the local HTTP server on a public URL.
+pub trait TunnelProvider: Send + Sync + 'static {
+    /// Lines describing the connection, printed before it is attempted
+    fn describe(&self, local_port: u16) -> Vec<String>;
+
+    /// Forwards public traffic to `local_port` until the tunnel closes, passing the
+    /// external URL to `report_url` as soon as the provider announces it.
+    fn run(
+        &self,
+        local_port: u16,
+        report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>>;
+
+    /// Asks for `name` from the next run on, returning whether the provider can: the
+    /// name given in `TUNNEL_NAME` when `service` is `None`, otherwise the one `service`
+    /// handed out last time, to be given up for a fresh one if it has been taken since.
+    fn request_name(&mut self, _service: Option<&str>, _name: &str) -> bool {
+        false
+    }
+}
+
+/// Whether this build includes any tunnel provider
+pub const AVAILABLE: bool = cfg!(any(
+    feature = "tunnel-lhr",
+    feature = "tunnel-pico",
+    feature = "tunnel-bore"
+));
+
+/// Public URL domains announced by the compiled-in SSH tunnel services
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+const TUNNEL_DOMAINS: &[&str] = &[
+    #[cfg(feature = "tunnel-lhr")]
+    ".lhr.life",
+    #[cfg(feature = "tunnel-lhr")]
+    ".lhr.rocks",
+    #[cfg(feature = "tunnel-lhr")]
+    ".localhost.run",
+    #[cfg(feature = "tunnel-pico")]
+    ".tuns.sh",
+];
+
+/// Names accepted in `[tunnel] providers`
+pub(crate) const PROVIDER_NAMES: &[&str] = &[
+    #[cfg(feature = "tunnel-pico")]
+    "pico",
+    #[cfg(feature = "tunnel-lhr")]
+    "localhost.run",
+    #[cfg(feature = "tunnel-bore")]
+    "bore",
+    #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+    "ssh",
+];
+
+/// The tunnel to open, if any: the `[tunnel] providers` chain from the config file,
+/// or otherwise a reverse SSH tunnel when `SSH_SERVER` or `[tunnel] ssh_server` is set.
+pub(crate) fn from_config(
+    config: &TunnelConfig,
+) -> anyhow::Result<Option<Box<dyn TunnelProvider>>> {
+    let mut providers = config
+        .providers
+        .iter()
+        .map(|name| Ok((name.clone(), provider_named(name, config)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    match providers.len() {
+        0 => Ok(from_env(config)),
+        1 => Ok(providers.pop().map(|(_, provider)| provider)),
+        _ => Ok(Some(Box::new(FallbackTunnel {
+            providers,
+            timeout: Duration::from_secs(config.provider_timeout),
+        }))),
+    }
+}
+
+fn from_env(config: &TunnelConfig) -> Option<Box<dyn TunnelProvider>> {
+    #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+    if let Some(ssh) = SshTunnel::from_env(config) {
+        return Some(Box::new(ssh));
+    }
+    #[cfg(not(any(feature = "tunnel-lhr", feature = "tunnel-pico")))]
+    if let Some(server) = env::var("SSH_SERVER").ok().or(config.ssh_server.clone()) {
+        eprintln!(
+            "Ignoring SSH_SERVER '{}': holodeck was built without SSH tunnel support",
+            server
+        );
+    }
+    None
+}
+
+/// Resolves a `[tunnel] providers` entry.
+#[cfg_attr(
+    not(all(
+        feature = "tunnel-lhr",
+        feature = "tunnel-pico",
+        feature = "tunnel-bore"
+    )),
+    allow(unused_variables)
+)]
+fn provider_named(name: &str, config: &TunnelConfig) -> anyhow::Result<Box<dyn TunnelProvider>> {
+    match name {
+        #[cfg(feature = "tunnel-pico")]
+        "pico" | "tuns.sh" => Ok(Box::new(SshTunnel::with_server(
+            "tuns.sh".to_string(),
+            "localhost",
+            config,
+        ))),
+        #[cfg(feature = "tunnel-lhr")]
+        "localhost.run" | "lhr" => Ok(Box::new(SshTunnel::with_server(
+            "localhost.run".to_string(),
+            "nokey",
+            config,
+        ))),
+        #[cfg(feature = "tunnel-bore")]
+        "bore" => Ok(Box::new(crate::bore::BoreTunnel::new(
+            config.bore_server.clone(),
+        ))),
+        #[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+        "ssh" => match SshTunnel::from_env(config) {
+            Some(ssh) => Ok(Box::new(ssh)),
+            None => anyhow::bail!(
+                "Tunnel provider 'ssh' requires SSH_SERVER or [tunnel] ssh_server to be set"
+            ),
+        },
+        _ => anyhow::bail!(
+            "Unknown tunnel provider '{}' (available in this build: {})",
+            name,
+            PROVIDER_NAMES.join(", ")
+        ),
+    }
+}
+
+/// Tries each provider in turn until one reports a URL within `timeout`, then stays
+/// with it for the rest of the session.
+struct FallbackTunnel {
+    providers: Vec<(String, Box<dyn TunnelProvider>)>,
+    timeout: Duration,
+}
+
+impl TunnelProvider for FallbackTunnel {
+    fn describe(&self, _local_port: u16) -> Vec<String> {
+        let names: Vec<_> = self
+            .providers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        vec![format!(
+            "\nTrying tunnel providers in order: {} (up to {}s each)",
+            names.join(", "),
+            self.timeout.as_secs()
+        )]
+    }
+
+    fn run(
+        &self,
+        local_port: u16,
+        mut report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let mut failures = Vec::new();
+            for (name, provider) in &self.providers {
+                let (url_tx, mut url_rx) = mpsc::unbounded_channel();
+                let run = provider.run(
+                    local_port,
+                    Box::new(move |url: &str| {
+                        let _ = url_tx.send(url.to_string());
+                    }),
+                );
+                tokio::pin!(run);
+
+                let url = tokio::select! {
+                    result = &mut run => Err(match result {
+                        Ok(()) => anyhow::anyhow!("closed before reporting a URL"),
+                        Err(e) => e,
+                    }),
+                    url = tokio::time::timeout(self.timeout, url_rx.recv()) => match url {
+                        Ok(Some(url)) => Ok(url),
+                        Ok(None) => Err(anyhow::anyhow!("closed before reporting a URL")),
+                        Err(_) => Err(anyhow::anyhow!("no URL within {}s", self.timeout.as_secs())),
+                    },
+                };
+                match url {
+                    Ok(url) => {
+                        report_url(&url);
+                        return run.await;
+                    }
+                    Err(e) => {
+                        eprintln!("Tunnel provider '{}' failed: {}", name, e);
+                        failures.push(format!("{}: {}", name, e));
+                    }
+                }
+            }
+            anyhow::bail!("No tunnel provider came up ({})", failures.join("; "))
+        })
+    }
+
+    fn request_name(&mut self, service: Option<&str>, name: &str) -> bool {
+        self.providers
+            .iter_mut()
+            .fold(false, |requested, (_, provider)| {
+                provider.request_name(service, name) || requested
+            })
+    }
+}
+
+/// Reverse SSH tunnel (localhost.run, pico.sh and similar services), configured from
+/// `SSH_*` variables or the `ssh_*` settings in `[tunnel]`.
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+pub struct SshTunnel {
+    server_addr: String,
+    server_port: u16,
+    username: String,
+    key_path: Option<String>,
+    password: Option<String>,
+    remote_port: u32,
+    url_patterns: Vec<regex::Regex>,
+}
+
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+impl SshTunnel {
+    /// Returns `None` when neither `SSH_SERVER` nor `[tunnel] ssh_server` is set.
+    pub fn from_env(config: &TunnelConfig) -> Option<Self> {
+        // Check if SSH server is configured
+        let server_addr = env::var("SSH_SERVER")
+            .ok()
+            .or_else(|| config.ssh_server.clone())?;
+        Some(Self::with_server(server_addr, "localhost", config))
+    }
+
+    /// Connects to `server_addr`, taking credentials and ports from the `SSH_*`
+    /// variables, then from `config`, and falling back to `default_user`.
+    fn with_server(server_addr: String, default_user: &str, config: &TunnelConfig) -> Self {
+        Self {
+            server_addr,
+            server_port: env::var("SSH_PORT")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .or(config.ssh_port)
+                .unwrap_or(22),
+            username: env::var("SSH_USER")
+                .ok()
+                .or_else(|| config.ssh_user.clone())
+                .unwrap_or_else(|| default_user.to_string()),
+            key_path: env::var("SSH_KEY_PATH").ok().or_else(|| {
+                config
+                    .ssh_key
+                    .as_ref()
+                    .map(|key| key.to_string_lossy().into_owned())
+            }),
+            password: env::var("SSH_PASSWORD").ok(),
+            remote_port: env::var("REMOTE_PORT")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(80),
+            url_patterns: config.url_patterns(),
+        }
+    }
+}
+
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+impl TunnelProvider for SshTunnel {
+    fn describe(&self, local_port: u16) -> Vec<String> {
+        let auth = match &self.key_path {
+            Some(key) => format!("Using SSH key: {}", key),
+            None => "Using password authentication".to_string(),
+        };
+        vec![
+            format!(
+                "\nConnecting to SSH server: {}:{}",
+                self.server_addr, self.server_port
+            ),
+            auth,
+            format!(
+                "Forwarding remote port {} to local port {}",
+                self.remote_port, local_port
+            ),
+        ]
+    }
+
+    fn run(
+        &self,
+        local_port: u16,
+        mut report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        let config = ReverseSshConfig {
+            server_addr: self.server_addr.clone(),
+            server_port: self.server_port,
+            username: self.username.clone(),
+            key_path: self.key_path.clone(),
+            password: self.password.clone(),
+            remote_port: self.remote_port,
+            local_addr: "127.0.0.1".to_string(),
+            local_port,
+        };
+
+        let url_patterns = self.url_patterns.clone();
+        Box::pin(async move {
+            let mut client = ReverseSshClient::new(config);
+            client
+                .run_with_message_handler(move |message| {
+                    for line in message.lines() {
+                        if let Some(url) = extract_tunnel_url(line, &url_patterns) {
+                            report_url(url);
+                        }
+                    }
+                })
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Finds the tunnel URL in a line of the provider's welcome banner.
+#[cfg(any(feature = "tunnel-lhr", feature = "tunnel-pico"))]
+fn extract_tunnel_url<'a>(line: &'a str, url_patterns: &[regex::Regex]) -> Option<&'a str> {
+    let trimmed = line.trim();
+
+    if let Some(found) = url_patterns
+        .iter()
+        .find_map(|pattern| pattern.find(trimmed))
+    {
+        return Some(found.as_str());
+    }
+
+    // Check if this line contains the tunnel URL
+    if !(trimmed.contains("http://") || trimmed.contains("https://"))
+        || !TUNNEL_DOMAINS.iter().any(|domain| trimmed.contains(domain))
+    {
+        return None;
+    }
+
+    let url_part = &trimmed[trimmed.find("http")?..];
+    // Find the end of the URL
+    let url_end = url_part
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .unwrap_or(url_part.len());
+    Some(&url_part[..url_end])
+}
+
+/// Times the tunnel is reopened when its URL doesn't lead back to the server
+const MAX_RETUNNELS: u32 = 2;
+
+/// How long one self-test request may take
+#[cfg(feature = "client")]
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches `/_health` through the external URL once the tunnel reports it, catching a
+/// tunnel service that hands out a URL but routes nothing (or someone else) to it.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct SelfTest {
+    /// The instance id `/_health` must answer with
+    instance: String,
+    /// `/_health` under `--base-path`
+    path: String,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl SelfTest {
+    pub fn new(instance: String, base_path: &str) -> Self {
+        Self {
+            instance,
+            path: format!("{}{}", base_path, crate::health::ROUTE),
+            attempts: 3,
+            retry_delay: Duration::from_secs(3),
+        }
+    }
+
+    /// Tries `attempts` times, `retry_delay` apart, before declaring the URL unreachable.
+    pub fn with_retries(mut self, attempts: u32, retry_delay: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Whether `url` leads to this server; the error says what happened instead.
+    #[cfg(feature = "client")]
+    async fn check(&self, url: &str) -> Result<(), String> {
+        let target = format!("{}{}", url.trim_end_matches('/'), self.path);
+        let client = reqwest::Client::builder()
+            .timeout(SELF_TEST_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut error = String::new();
+        for attempt in 0..self.attempts {
+            if attempt > 0 {
+                tokio::time::sleep(self.retry_delay).await;
+            }
+            error = match client.get(&target).send().await {
+                Ok(response)
+                    if response
+                        .headers()
+                        .get(crate::health::INSTANCE_HEADER)
+                        .is_some_and(|id| id == self.instance.as_str()) =>
+                {
+                    return Ok(());
+                }
+                Ok(response) => format!(
+                    "{} answered {} without this server's instance id",
+                    target,
+                    response.status()
+                ),
+                Err(e) => format!("{:#}", anyhow::Error::from(e)),
+            };
+        }
+        Err(error)
+    }
+
+    #[cfg(not(feature = "client"))]
+    async fn check(&self, _url: &str) -> Result<(), String> {
+        Err("this build can't make HTTP requests".to_string())
+    }
+}
+
+/// Runs `provider` in the background, announcing its external URL on `url_tx`, the
+/// console and `events`. A tunnel-down event is emitted when the provider returns.
+///
+/// With `self_test`, the URL is checked to lead back to the server; when it doesn't,
+/// a warning is printed and the tunnel reopened, up to [`MAX_RETUNNELS`] times.
+pub fn spawn_tunnel(
+    provider: Box<dyn TunnelProvider>,
+    local_port: u16,
+    output: OutputMode,
+    url_tx: watch::Sender<Option<String>>,
+    events: Events,
+    self_test: Option<SelfTest>,
+) -> JoinHandle<()> {
+    if output == OutputMode::Normal {
+        for line in provider.describe(local_port) {
+            println!("{}", line);
+        }
+    }
+
+    let span = tracing::info_span!("tunnel", url = tracing::field::Empty);
+    let url_span = span.clone();
+    let task = async move {
+        let mut retunnels = 0;
+        let result = loop {
+            let (found_tx, mut found_rx) = mpsc::unbounded_channel();
+            let run = provider.run(
+                local_port,
+                Box::new(move |url: &str| {
+                    let _ = found_tx.send(url.to_string());
+                }),
+            );
+            tokio::pin!(run);
+
+            // Providers may repeat their banner; only the first URL counts
+            let mut announced = false;
+            let mut check = None;
+            // `None` when the tunnel should be reopened
+            let ended = loop {
+                tokio::select! {
+                    result = &mut run => {
+                        // A URL reported right before the provider returned
+                        if !announced && let Ok(url) = found_rx.try_recv() {
+                            announce(&url, output, &url_tx, &events, &url_span);
+                        }
+                        break Some(result);
+                    }
+                    Some(url) = found_rx.recv(), if !announced => {
+                        announced = true;
+                        announce(&url, output, &url_tx, &events, &url_span);
+                        check = self_test.clone().filter(|_| can_check(&url)).map(|test| {
+                            Box::pin(async move { test.check(&url).await.map_err(|e| (url, e)) })
+                        });
+                    }
+                    checked = async { check.as_mut().unwrap().await }, if check.is_some() => {
+                        check = None;
+                        match checked {
+                            Ok(()) if output == OutputMode::Normal => {
+                                println!("Self-test passed: the external URL reaches this server");
+                            }
+                            Ok(()) => {}
+                            Err((url, e)) => {
+                                eprintln!("WARNING: {} does not reach this server: {}", url, e);
+                                tracing::warn!(url, error = e, "tunnel self-test failed");
+                                if retunnels < MAX_RETUNNELS {
+                                    break None;
+                                }
+                                eprintln!(
+                                    "WARNING: Keeping the tunnel, but it may not be reachable"
+                                );
+                            }
+                        }
+                    }
+                }
+            };
+            let Some(result) = ended else {
+                retunnels += 1;
+                eprintln!(
+                    "Reopening the tunnel (attempt {} of {})",
+                    retunnels, MAX_RETUNNELS
+                );
+                continue;
+            };
+            break result;
+        };
+        match &result {
+            Ok(_) if output == OutputMode::Normal => println!("Tunnel closed"),
+            Ok(_) => {}
+            Err(e) => eprintln!("Tunnel error: {}", e),
+        }
+        if let Err(e) = &result {
+            tracing::warn!(error = %e, "tunnel failed");
+        }
+        events.emit(Event::TunnelDown {
+            error: result.err().map(|e| e.to_string()),
+        });
+    };
+    tokio::spawn(task.instrument(span))
+}
+
+/// Displays the tunnel URL prominently and publishes it.
+fn announce(
+    url: &str,
+    output: OutputMode,
+    url_tx: &watch::Sender<Option<String>>,
+    events: &Events,
+    span: &tracing::Span,
+) {
+    match output {
+        OutputMode::Normal => {
+            println!("\n╔════════════════════════════════════════════════════════════════╗");
+            println!("║                    TUNNEL ACTIVE                               ║");
+            println!("╠════════════════════════════════════════════════════════════════╣");
+            println!("║  External URL: {:<48} ║", url);
+            println!("╚════════════════════════════════════════════════════════════════╝\n");
+        }
+        OutputMode::Porcelain => println!("URL={}", url),
+        OutputMode::Quiet => {}
+    }
+    span.record("url", url);
+    span.in_scope(|| tracing::info!(url, "tunnel up"));
+    url_tx.send_replace(Some(url.to_string()));
+    events.emit(Event::TunnelUp {
+        url: url.to_string(),
+    });
+}
+
+/// Whether this build can fetch `url`: any request needs the `client` feature, and
+/// HTTPS the `tls` feature.
+fn can_check(url: &str) -> bool {
+    if !cfg!(feature = "client") {
+        eprintln!(
+            "Not checking that {} reaches this server: this build can't make HTTP requests",
+            url
+        );
+        return false;
+    }
+    if url.starts_with("https://") && !cfg!(feature = "tls") {
+        eprintln!(
+            "Not checking that {} reaches this server: this build can't make HTTPS requests",
+            url
+        );
+        return false;
+    }
+    true
+}
@@ -0,0 +1,195 @@
+use crate::META_DIR;
+use crate::tunnel::TunnelProvider;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// URLs remembered per tunnel service
+const MAX_HISTORY: usize = 20;
+
+/// The name each tunnel service last handed out, and the URLs it led to, kept in the
+/// share's metadata directory so a restarted share can ask for the same name again.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TunnelNames {
+    /// By service, such as `bore.pub` or `lhr.life`
+    services: BTreeMap<String, Service>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Service {
+    name: String,
+    /// Oldest first
+    history: Vec<UsedUrl>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsedUrl {
+    url: String,
+    /// Seconds since the Unix epoch
+    at: u64,
+}
+
+impl TunnelNames {
+    /// Reads the names remembered for the share at `root`; a missing or unreadable
+    /// file only means starting afresh.
+    pub fn load(root: &Path) -> Self {
+        let path = root.join(META_DIR).join("tunnels.json");
+        let names = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                eprintln!(
+                    "Ignoring invalid tunnel name file {}: {}",
+                    path.display(),
+                    e
+                );
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                eprintln!("Can't read {}: {}", path.display(), e);
+                Self::default()
+            }
+        };
+        Self { path, ..names }
+    }
+
+    /// Asks `provider` for the name in `TUNNEL_NAME`, or else for those remembered
+    /// unless `new_name`, returning lines telling what it will ask for. Services that
+    /// assign names themselves, like the SSH ones, are only remembered for the record.
+    pub fn reuse(&self, provider: &mut dyn TunnelProvider, new_name: bool) -> Vec<String> {
+        if let Ok(name) = std::env::var("TUNNEL_NAME") {
+            return vec![if provider.request_name(None, &name) {
+                format!("Asking for the tunnel name '{}' (TUNNEL_NAME)", name)
+            } else {
+                format!(
+                    "Ignoring TUNNEL_NAME '{}': the tunnel provider can't choose that name",
+                    name
+                )
+            }];
+        }
+        if new_name {
+            return Vec::new();
+        }
+        self.services
+            .iter()
+            .filter(|(service, remembered)| provider.request_name(Some(service), &remembered.name))
+            .map(|(service, remembered)| {
+                format!(
+                    "Asking {} for the name '{}' used last time (--new-name for a fresh one)",
+                    service, remembered.name
+                )
+            })
+            .collect()
+    }
+
+    /// Remembers every URL the tunnel announces from now on.
+    pub fn record(mut self, mut urls: watch::Receiver<Option<String>>) {
+        tokio::spawn(async move {
+            while urls.changed().await.is_ok() {
+                let Some(url) = urls.borrow_and_update().clone() else {
+                    continue;
+                };
+                if self.add(&url, now())
+                    && let Err(e) = self.save().await
+                {
+                    eprintln!("Can't save {}: {}", self.path.display(), e);
+                }
+            }
+        });
+    }
+
+    /// Records `url` as the latest for its service, returning whether it names one.
+    fn add(&mut self, url: &str, at: u64) -> bool {
+        let Some((service, name)) = name_in(url) else {
+            return false;
+        };
+        let remembered = self.services.entry(service).or_insert_with(|| Service {
+            name: String::new(),
+            history: Vec::new(),
+        });
+        remembered.name = name;
+        remembered.history.push(UsedUrl {
+            url: url.to_string(),
+            at,
+        });
+        let excess = remembered.history.len().saturating_sub(MAX_HISTORY);
+        remembered.history.drain(..excess);
+        true
+    }
+
+    async fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(self)?;
+        let temp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, &self.path).await
+    }
+}
+
+/// The service a tunnel URL belongs to and the name it was given there: the port for
+/// `http://bore.pub:41234`, the first label for `https://abc123.lhr.life`, and the
+/// segment after `/_relay/` for a holodeck relay hub.
+fn name_in(url: &str) -> Option<(String, String)> {
+    let rest = url.split_once("://")?.1;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if let Some((_, relayed)) = format!("/{}", path).split_once("/_relay/") {
+        let name = relayed.split('/').next().unwrap_or_default();
+        return (!name.is_empty()).then(|| (authority.to_string(), name.to_string()));
+    }
+    if let Some((host, port)) = authority.rsplit_once(':') {
+        return Some((host.to_string(), port.to_string()));
+    }
+    let (name, service) = authority.split_once('.')?;
+    service
+        .contains('.')
+        .then(|| (service.to_string(), name.to_string()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_the_port_or_the_subdomain() {
+        let name = |url| name_in(url).map(|(service, name)| format!("{} {}", service, name));
+        assert_eq!(name("http://bore.pub:41234").unwrap(), "bore.pub 41234");
+        assert_eq!(name("https://abc123.lhr.life/").unwrap(), "lhr.life abc123");
+        assert_eq!(
+            name("https://me-share.tuns.sh").unwrap(),
+            "tuns.sh me-share"
+        );
+        assert_eq!(
+            name("https://hub.example:8443/share/_relay/laptop").unwrap(),
+            "hub.example:8443 laptop"
+        );
+        assert_eq!(name("https://example.com"), None);
+        assert_eq!(name("not a url"), None);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_urls_per_service() {
+        let mut names = TunnelNames::default();
+        for port in 0..MAX_HISTORY + 5 {
+            assert!(names.add(&format!("http://bore.pub:{}", 40000 + port), port as u64));
+        }
+        assert!(names.add("https://abc123.lhr.life", 0));
+        assert!(!names.add("https://example.com", 0));
+
+        let bore = &names.services["bore.pub"];
+        assert_eq!(bore.name, (40000 + MAX_HISTORY + 4).to_string());
+        assert_eq!(bore.history.len(), MAX_HISTORY);
+        assert_eq!(bore.history[0].url, "http://bore.pub:40005");
+        assert_eq!(names.services["lhr.life"].name, "abc123");
+    }
+}
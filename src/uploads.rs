@@ -0,0 +1,127 @@
+use crate::progress::{TransferProgress, UploadState, UploadStatus};
+use crate::router::Router;
+use crate::{AppState, BoxBody, full};
+use bytes::Bytes;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, HeaderMap};
+use hyper::{Response, StatusCode};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Header an upload is tagged with so its progress can be looked up
+const UPLOAD_ID: &str = "x-upload-id";
+
+/// Longest upload id accepted
+const MAX_ID_LEN: usize = 64;
+
+/// How often a progress stream reports
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a progress stream waits for an upload that hasn't started yet
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Registers `GET /_progress/<id>`.
+pub fn routes(router: &mut Router) {
+    router.get("/_progress/{id}", async |req, cx| {
+        Ok(get(cx.param("id"), req.headers(), &cx.state))
+    });
+}
+
+/// Tags an upload with the `X-Upload-Id` its client sent, if any.
+pub fn tag(progress: &TransferProgress, headers: &HeaderMap) {
+    if let Some(upload_id) = headers
+        .get(UPLOAD_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|upload_id| is_valid_id(upload_id))
+    {
+        progress.set_upload_id(upload_id);
+    }
+}
+
+/// Ids are chosen by clients, typically a UUID: short, and safe in a URL and a log line.
+fn is_valid_id(upload_id: &str) -> bool {
+    (1..=MAX_ID_LEN).contains(&upload_id.len())
+        && upload_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Answers `GET /_progress/<id>` with the bytes received so far by the upload sent
+/// with `X-Upload-Id: <id>`, as JSON, or as a stream of server-sent events until the
+/// upload ends when the client accepts `text/event-stream`.
+fn get(upload_id: &str, headers: &HeaderMap, state: &Arc<AppState>) -> Response<BoxBody> {
+    if !is_valid_id(upload_id) {
+        return text(StatusCode::BAD_REQUEST, "Invalid upload id\n");
+    }
+    let wants_events = headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if wants_events {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .header("X-Accel-Buffering", "no")
+            .body(events(upload_id.to_string(), state.clone()))
+            .unwrap();
+    }
+    match state.transfers.upload(upload_id) {
+        Some(status) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(full(serde_json::to_vec(&status).unwrap()))
+            .unwrap(),
+        None => text(
+            StatusCode::NOT_FOUND,
+            format!("No upload with id '{}'\n", upload_id),
+        ),
+    }
+}
+
+/// Reports the upload whenever it moved on, ending once it has ended; a stream opened
+/// before the upload starts waits for it up to [`START_TIMEOUT`].
+fn events(upload_id: String, state: Arc<AppState>) -> BoxBody {
+    let opened = Instant::now();
+    // The last status sent, `None` once the upload has ended
+    let stream =
+        futures_util::stream::unfold(Some(None), move |previous: Option<Option<UploadStatus>>| {
+            let upload_id = upload_id.clone();
+            let state = state.clone();
+            async move {
+                let previous = previous?;
+                let status = loop {
+                    match state.transfers.upload(&upload_id) {
+                        Some(status) if previous.as_ref() != Some(&status) => break status,
+                        None if opened.elapsed() > START_TIMEOUT => return None,
+                        _ => tokio::time::sleep(INTERVAL).await,
+                    }
+                };
+                let event = format!("data: {}\n\n", serde_json::to_string(&status).unwrap());
+                let next = (status.state == UploadState::Receiving).then_some(Some(status));
+                Some((Ok(Frame::data(Bytes::from(event))), next))
+            }
+        });
+    StreamBody::new(stream).boxed()
+}
+
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<BoxBody> {
+    Response::builder().status(status).body(full(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_ids_are_url_safe() {
+        assert!(is_valid_id("0b6f2c1e-8f7d-4e3a-9c55-2d1b7a6e4f90"));
+        assert!(is_valid_id("photos_1"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("../etc"));
+        assert!(!is_valid_id("a b"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::META_DIR;
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Prefix of the headers carrying user metadata, on uploads and downloads alike
+const PREFIX: &str = "x-meta-";
+
+/// Most entries kept for one file, so metadata stays a label rather than storage
+const MAX_ENTRIES: usize = 32;
+
+/// Metadata of one file, keyed by header name without the `X-Meta-` prefix
+pub type Meta = BTreeMap<String, String>;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Size and modification time of the file the metadata came with; once the
+    /// file changes it no longer carries it
+    len: u64,
+    modified: Option<SystemTime>,
+    meta: Meta,
+}
+
+/// Metadata given with `X-Meta-*` headers when a file is uploaded (a build number,
+/// a git commit, who uploaded it), kept in the share's metadata directory and sent
+/// back with the file.
+pub struct UserMeta {
+    entries: Mutex<BTreeMap<String, Entry>>,
+    path: PathBuf,
+}
+
+impl UserMeta {
+    /// Loads the metadata stored in `root`'s metadata directory.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(META_DIR).join("metadata.json");
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!("Invalid metadata file {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            entries: Mutex::new(entries),
+            path,
+        })
+    }
+
+    /// Attaches `meta` to the shared file `name` as it is now, replacing what it had.
+    pub async fn set(&self, root: &Path, name: &str, meta: Meta) -> io::Result<()> {
+        let mut entries = self.entries.lock().await;
+        if meta.is_empty() {
+            if entries.remove(name).is_none() {
+                return Ok(());
+            }
+        } else {
+            let metadata = fs::symlink_metadata(root.join(name)).await?;
+            let entry = Entry {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                meta,
+            };
+            entries.insert(name.to_string(), entry);
+        }
+        self.save(&entries).await
+    }
+
+    /// What the shared file `name` was uploaded with, unless it has changed since.
+    pub async fn get(&self, name: &str, metadata: &std::fs::Metadata) -> Meta {
+        self.entries
+            .lock()
+            .await
+            .get(name)
+            .filter(|entry| {
+                entry.len == metadata.len() && entry.modified == metadata.modified().ok()
+            })
+            .map(|entry| entry.meta.clone())
+            .unwrap_or_default()
+    }
+
+    /// Moves the metadata of `from` along with the file, renamed to `to`.
+    pub async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().await;
+        let Some(entry) = entries.remove(from) else {
+            return Ok(());
+        };
+        entries.insert(to.to_string(), entry);
+        self.save(&entries).await
+    }
+
+    async fn save(&self, entries: &BTreeMap<String, Entry>) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec_pretty(entries)?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, json).await?;
+        fs::rename(&temp, &self.path).await
+    }
+}
+
+/// The `X-Meta-*` headers of an upload.
+pub fn from_headers(headers: &HeaderMap) -> Meta {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(PREFIX)?;
+            Some((key.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .take(MAX_ENTRIES)
+        .collect()
+}
+
+/// Sends `meta` back as `X-Meta-*` headers.
+pub fn to_headers(meta: &Meta, headers: &mut HeaderMap) {
+    for (key, value) in meta {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(format!("{}{}", PREFIX, key)),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
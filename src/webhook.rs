@@ -0,0 +1,74 @@
+#[cfg(feature = "client")]
+use crate::events::Events;
+use serde::Deserialize;
+#[cfg(feature = "client")]
+use serde_json::json;
+#[cfg(feature = "client")]
+use tokio::sync::broadcast::error::RecvError;
+
+/// A chat webhook notified on transfer events, configured as `[[webhook]]` in the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Payload flavour; inferred from the URL when omitted
+    pub format: Option<WebhookFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    Slack,
+    Discord,
+}
+
+#[cfg(feature = "client")]
+impl WebhookConfig {
+    fn format(&self) -> WebhookFormat {
+        self.format.unwrap_or_else(|| {
+            if self.url.contains("discord.com") || self.url.contains("discordapp.com") {
+                WebhookFormat::Discord
+            } else {
+                WebhookFormat::Slack
+            }
+        })
+    }
+}
+
+/// Posts a one-line message to every configured webhook for each event.
+#[cfg(feature = "client")]
+pub fn spawn_notifier(events: &Events, webhooks: Vec<WebhookConfig>) {
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let mut rx = events.subscribe();
+    let client = reqwest::Client::new();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let text = event.summary();
+            for webhook in &webhooks {
+                let payload = match webhook.format() {
+                    WebhookFormat::Slack => json!({ "text": text }),
+                    WebhookFormat::Discord => json!({ "content": text }),
+                };
+                let result = client
+                    .post(&webhook.url)
+                    .json(&payload)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    eprintln!("Error sending webhook notification: {}", e);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,228 @@
+use crate::router::Router;
+use crate::{AppState, BoxBody, Download, full, sandbox, stream_file};
+use crate::{conditional, packing, paths};
+use hyper::header::{
+    ACCEPT, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HeaderMap, VARY,
+};
+use hyper::{Response, StatusCode};
+use std::ops::ControlFlow;
+use tokio::fs;
+
+/// Page served for a directory, and for unknown routes in `--spa` mode
+const INDEX: &str = "index.html";
+
+/// Fingerprinted assets change name whenever their content does
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Everything else is revalidated against its ETag on each use
+const REVALIDATE: &str = "no-cache";
+
+/// Registers the website's `GET` routes when `--website` is on.
+pub fn routes(router: &mut Router) {
+    router
+        .get("/{*path}", async |req, cx| {
+            get(
+                req.headers(),
+                &cx.path,
+                req.uri().query(),
+                cx.client,
+                &cx.state,
+            )
+            .await
+        })
+        .when(|_, state| state.website)
+        .scoped("path");
+}
+
+/// Serves a GET as part of a static site: nested paths, `index.html` for directories,
+/// inline content and cache headers suited to a built frontend.
+///
+/// Used for every GET in `--website`/`--spa` mode, otherwise for paths [`in_site`].
+pub async fn get(
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    client: String,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let relative = path.trim_start_matches('/');
+    let name = match paths::validate_path(relative) {
+        Ok(name) => name,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(e.to_string()))
+                .unwrap());
+        }
+    };
+    let directory = name.is_empty() || relative.ends_with('/');
+    let target = match (directory, name.is_empty()) {
+        (true, true) => INDEX.to_string(),
+        (true, false) => format!("{}/{}", name, INDEX),
+        (false, _) => name,
+    };
+
+    match open(state, &target).await {
+        Some((file, metadata)) if metadata.is_file() => {
+            serve_file(headers, query, file, metadata, target, client, state).await
+        }
+        // Relative links in the directory's index.html only resolve under a trailing slash
+        Some(_) if !directory => Ok(crate::redirect(format!("{}{}/", state.base_path, path))),
+        _ if state.spa && accepts_html(headers) => match open(state, INDEX).await {
+            Some((file, metadata)) if metadata.is_file() => {
+                let index = INDEX.to_string();
+                serve_file(headers, query, file, metadata, index, client, state).await
+            }
+            _ => Ok(crate::file_not_found(INDEX)),
+        },
+        _ => Ok(crate::file_not_found(&target)),
+    }
+}
+
+/// Whether a GET for `relative` should be served as a page rather than a download:
+/// it names a directory holding an `index.html`, or lies below one.
+///
+/// Top-level files stay downloads even when the share root itself has an index.
+pub async fn in_site(state: &AppState, relative: &str) -> bool {
+    let Ok(name) = paths::validate_path(relative) else {
+        return false;
+    };
+    let segments: Vec<&str> = name.split('/').filter(|s| !s.is_empty()).collect();
+    let shallowest = if segments.len() == 1 { 1 } else { 0 };
+    for depth in shallowest..=segments.len() {
+        let index = match segments[..depth].join("/") {
+            dir if dir.is_empty() => INDEX.to_string(),
+            dir => format!("{}/{}", dir, INDEX),
+        };
+        if matches!(open(state, &index).await, Some((_, metadata)) if metadata.is_file()) {
+            return true;
+        }
+    }
+    false
+}
+
+async fn open(state: &AppState, name: &str) -> Option<(fs::File, std::fs::Metadata)> {
+    let file = sandbox::open_beneath(&state.root, name, sandbox::Access::Read)
+        .await
+        .ok()?;
+    let metadata = file.metadata().await.ok()?;
+    Some((file, metadata))
+}
+
+/// Page navigations ask for HTML; scripts, images and `fetch` calls don't.
+pub fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Sends a file compressed on disk as it is to browsers that accept zstd, which is
+/// most of them, and decompressed to the rest.
+async fn serve_file(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    file: fs::File,
+    metadata: std::fs::Metadata,
+    name: String,
+    client: String,
+    state: &AppState,
+) -> std::io::Result<Response<BoxBody>> {
+    let packed = state.packed.size(&name, &metadata).await;
+    let stored = packed.is_some() && packing::accepts_zstd(headers);
+    let (file, len) = match packed {
+        Some(size) if !stored => (packing::Contents::unpacking(file)?, size),
+        _ => (packing::Contents::Plain(file), metadata.len()),
+    };
+    let etag = crate::conditional::etag(&metadata);
+    let cache_control = if is_fingerprinted(&name) {
+        IMMUTABLE
+    } else {
+        REVALIDATE
+    };
+    let mut response = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, cache_control);
+    if packed.is_some() {
+        response = response.header(VARY, "accept-encoding");
+    }
+    if stored {
+        response = response.header(CONTENT_ENCODING, "zstd");
+    }
+
+    if conditional::is_fresh(headers, &etag) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(full(""))
+            .unwrap());
+    }
+
+    let quota = match crate::reserve_quota(headers, query, &name, len, state) {
+        ControlFlow::Continue(quota) => quota,
+        ControlFlow::Break(response) => return Ok(response),
+    };
+    let progress = state.transfers.start("GET", &name, Some(len));
+    progress.set_client(&client);
+    let _ = crate::CLOSE_CONNECTION.try_with(|close| progress.close_on_cancel(close.clone()));
+    Ok(response
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, state.mime_types().content_type(&name))
+        .header(CONTENT_LENGTH, len)
+        .body(stream_file(Download {
+            file,
+            span: tracing::info_span!("download", file = %name, bytes = len),
+            filename: name,
+            len,
+            progress,
+            events: state.events.clone(),
+            client,
+            chunk_size: state.io_buffer,
+            compressor: None,
+            quota,
+            // Pages load many assets in parallel, so they aren't capped per client
+            _slot: None,
+        }))
+        .unwrap())
+}
+
+/// Whether the file name carries a bundler's content hash, as in `index-BvK2a1xY.js`
+/// or `main.3f2a9c1b.css`.
+fn is_fingerprinted(name: &str) -> bool {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+    stem.split(['.', '-', '_']).any(|part| {
+        part.len() >= 8
+            && part.chars().all(|c| c.is_ascii_alphanumeric())
+            && part.chars().any(|c| c.is_ascii_digit())
+            && part.chars().any(|c| c.is_ascii_alphabetic())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_bundler_hashes() {
+        for name in [
+            "assets/index-BvK2a1xY.js",
+            "static/js/main.3f2a9c1b.js",
+            "app.8e0d62a1.chunk.css",
+        ] {
+            assert!(is_fingerprinted(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn leaves_plain_names_revalidated() {
+        for name in [
+            "index.html",
+            "favicon.ico",
+            "jquery-3.7.1.min.js",
+            "build-20240601.tar.gz",
+            "background-image.png",
+        ] {
+            assert!(!is_fingerprinted(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! In-process harness: serves a temporary directory on a random local port and
+//! talks to it over plain HTTP/1.1, optionally behind a mock tunnel provider.
+
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use holodeck::AppState;
+use holodeck::tunnel::{TunnelProvider, UrlReporter};
+use http_body_util::{BodyExt, Full};
+use hyper::{HeaderMap, Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+
+/// A holodeck server sharing a fresh temporary directory.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: Arc<AppState>,
+    dir: TempDir,
+    server: AbortHandle,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::serve(tempfile::tempdir().unwrap()).await
+    }
+
+    /// Stops this server and starts a new one on the same directory, as if the
+    /// process had been restarted; it listens on another port.
+    pub async fn restart(self) -> Self {
+        self.server.abort();
+        Self::serve(self.dir).await
+    }
+
+    async fn serve(dir: TempDir) -> Self {
+        let state = Arc::new(AppState::new(dir.path().to_path_buf()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(holodeck::serve(listener, state.clone())).abort_handle();
+        Self {
+            addr,
+            state,
+            dir,
+            server,
+        }
+    }
+
+    /// The shared directory
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path, &[], Bytes::new()).await
+    }
+
+    pub async fn post(&self, path: &str, body: impl Into<Bytes>) -> TestResponse {
+        self.request(Method::POST, path, &[], body.into()).await
+    }
+
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Bytes,
+    ) -> TestResponse {
+        send(self.addr, method, path, headers, body).await
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Sends one request on a fresh connection and collects the whole response.
+///
+/// The path is written to the request line verbatim, so traversal attempts reach
+/// the server exactly as a hostile client would send them.
+pub async fn send(
+    addr: SocketAddr,
+    method: Method,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Bytes,
+) -> TestResponse {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header("host", addr.to_string());
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = sender
+        .send_request(request.body(Full::new(body)).unwrap())
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    TestResponse {
+        status,
+        headers,
+        body,
+    }
+}
+
+/// Tunnel provider that "exposes" the server at its local address and stays up
+/// until `close` is notified.
+pub struct MockTunnel {
+    pub close: Arc<Notify>,
+    pub error: Option<String>,
+}
+
+impl MockTunnel {
+    pub fn new() -> Self {
+        Self {
+            close: Arc::new(Notify::new()),
+            error: None,
+        }
+    }
+}
+
+impl TunnelProvider for MockTunnel {
+    fn describe(&self, local_port: u16) -> Vec<String> {
+        vec![format!("Mock tunnel to local port {}", local_port)]
+    }
+
+    fn run(
+        &self,
+        local_port: u16,
+        mut report_url: UrlReporter,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let url = format!("http://127.0.0.1:{}", local_port);
+            report_url(&url);
+            // Providers may repeat their banner; only the first URL counts
+            report_url("http://ignored.invalid");
+            self.close.notified().await;
+            match &self.error {
+                Some(error) => Err(anyhow::anyhow!("{}", error)),
+                None => Ok(()),
+            }
+        })
+    }
+}
@@ -0,0 +1,1128 @@
+mod common;
+
+use bytes::Bytes;
+use common::TestServer;
+use hyper::{Method, StatusCode};
+
+#[tokio::test]
+async fn upload_then_download_round_trips() {
+    let server = TestServer::start().await;
+
+    let response = server.post("/notes.txt", "hello holodeck").await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert_eq!(
+        response.text(),
+        "File 'notes.txt' uploaded successfully (14 bytes)"
+    );
+    assert_eq!(
+        std::fs::read(server.root().join("notes.txt")).unwrap(),
+        b"hello holodeck"
+    );
+
+    let response = server.get("/notes.txt").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.body, "hello holodeck");
+    assert_eq!(response.headers["content-type"], "text/plain");
+    assert_eq!(response.headers["content-length"], "14");
+    assert_eq!(
+        response.headers["content-disposition"],
+        "attachment; filename=\"notes.txt\""
+    );
+}
+
+#[tokio::test]
+async fn upload_replaces_existing_file() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("data.bin"), "old contents").unwrap();
+
+    let response = server.post("/data.bin", "new").await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert_eq!(
+        std::fs::read(server.root().join("data.bin")).unwrap(),
+        b"new"
+    );
+}
+
+#[tokio::test]
+async fn listing_shows_files_but_not_metadata_dir() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("a.txt"), "a").unwrap();
+    std::fs::write(server.root().join("b.txt"), "b").unwrap();
+
+    let response = server.get("/").await;
+    assert_eq!(response.status, StatusCode::OK);
+    let listing = response.text();
+    assert!(listing.contains("a.txt"), "{}", listing);
+    assert!(listing.contains("b.txt"), "{}", listing);
+    assert!(!listing.contains(".holodeck"), "{}", listing);
+}
+
+#[tokio::test]
+async fn listing_exports_csv_and_ndjson() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("b,c.txt"), "abc").unwrap();
+    std::fs::write(server.root().join("a.txt"), "").unwrap();
+    std::fs::create_dir(server.root().join("docs")).unwrap();
+
+    let response = server.get("/?format=csv").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "text/csv; charset=utf-8");
+    let csv = response.text();
+    let rows: Vec<Vec<&str>> = csv
+        .lines()
+        .map(|line| line.rsplitn(4, ',').collect())
+        .collect();
+    assert_eq!(rows.len(), 4, "{}", csv);
+    assert_eq!(rows[0], ["sha256", "mtime", "size", "name"]);
+    assert_eq!(
+        rows[1][0],
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(rows[1][2..], ["0", "a.txt"]);
+    assert_eq!(rows[2][2..], ["3", "\"b,c.txt\""]);
+    assert!(rows[2][1].ends_with('Z'), "{}", csv);
+    assert_eq!(rows[3][0], "");
+    assert_eq!(rows[3][2..], ["", "docs"]);
+
+    let response = server.get("/?format=ndjson").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "application/x-ndjson");
+    let rows: Vec<serde_json::Value> = response
+        .text()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[1]["name"], "b,c.txt");
+    assert_eq!(rows[1]["size"], 3);
+    assert_eq!(
+        rows[1]["sha256"],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert!(rows[2]["sha256"].is_null());
+
+    let response = server.get("/?format=xml").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn listings_come_in_pages() {
+    let server = TestServer::start().await;
+    for name in ["d.txt", "a.txt", "c.txt", "b.txt"] {
+        std::fs::write(server.root().join(name), name).unwrap();
+    }
+
+    let response = server.get("/?offset=1&limit=2").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["x-total-count"], "4");
+    assert_eq!(response.text(), "Available files:\nb.txt\nc.txt\n");
+
+    let response = server.get("/?format=ndjson&offset=3").await;
+    assert_eq!(response.headers["x-total-count"], "4");
+    let rows: Vec<serde_json::Value> = response
+        .text()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["name"], "d.txt");
+
+    assert!(!server.get("/").await.headers.contains_key("x-total-count"));
+    let response = server.get("/?limit=0").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn cursors_and_streams_list_large_shares() {
+    let server = TestServer::start().await;
+    for name in ["d.txt", "a.txt", "c b.txt", "b.txt"] {
+        std::fs::write(server.root().join(name), name).unwrap();
+    }
+
+    let response = server.get("/?limit=2").await;
+    assert_eq!(response.text(), "Available files:\na.txt\nb.txt\n");
+    assert_eq!(response.headers["x-next-cursor"], "b.txt");
+    let response = server.get("/?cursor=b.txt&limit=1").await;
+    assert_eq!(response.text(), "Available files:\nc b.txt\n");
+    let cursor = response.headers["x-next-cursor"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(cursor, "c%20b.txt");
+    let response = server
+        .get(&format!("/?format=csv&cursor={}&limit=1", cursor))
+        .await;
+    let csv = response.text();
+    assert!(csv.lines().nth(1).unwrap().starts_with("d.txt,5,"));
+    assert_eq!(csv.lines().count(), 2);
+    assert!(!response.headers.contains_key("x-next-cursor"));
+
+    let response = server.get("/?format=ndjson&stream").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "application/x-ndjson");
+    let mut names: Vec<String> = response
+        .text()
+        .lines()
+        .map(|line| {
+            let row: serde_json::Value = serde_json::from_str(line).unwrap();
+            row["name"].as_str().unwrap().to_string()
+        })
+        .collect();
+    names.sort();
+    assert_eq!(names, ["a.txt", "b.txt", "c b.txt", "d.txt"]);
+
+    let response = server.get("/?format=csv&stream").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = server.get("/?format=ndjson&stream&limit=2").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn reloaded_settings_apply_to_the_next_request() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("scene.holo"), "ok").unwrap();
+    assert_eq!(
+        server.get("/scene.holo").await.headers["content-type"],
+        "application/octet-stream"
+    );
+
+    server
+        .state
+        .reconfigure("[mime]\nholo = \"model/x-holodeck\"\n")
+        .unwrap();
+    assert_eq!(
+        server.get("/scene.holo").await.headers["content-type"],
+        "model/x-holodeck"
+    );
+
+    assert!(server.state.reconfigure("[mime\n").is_err());
+    assert_eq!(
+        server.get("/scene.holo").await.headers["content-type"],
+        "model/x-holodeck"
+    );
+}
+
+#[tokio::test]
+async fn listings_leave_out_what_policies_deny() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("public.txt"), "hello").unwrap();
+    std::fs::create_dir(server.root().join("drop")).unwrap();
+    server
+        .state
+        .reconfigure("[[policy]]\npath = \"/drop\"\ndownload = false\n")
+        .unwrap();
+
+    let names = |ndjson: String| -> Vec<String> {
+        ndjson
+            .lines()
+            .map(|line| {
+                let row: serde_json::Value = serde_json::from_str(line).unwrap();
+                row["name"].as_str().unwrap().to_string()
+            })
+            .collect()
+    };
+    assert_eq!(
+        server.get("/").await.text(),
+        "Available files:\npublic.txt\n"
+    );
+    assert_eq!(
+        names(server.get("/?format=ndjson").await.text()),
+        ["public.txt"]
+    );
+    assert_eq!(
+        names(server.get("/?format=ndjson&stream").await.text()),
+        ["public.txt"]
+    );
+    let csv = server.get("/?format=csv&limit=5").await;
+    assert_eq!(csv.headers["x-total-count"], "1");
+    assert!(!csv.text().contains("drop"));
+}
+
+#[tokio::test]
+async fn tags_filter_listings() {
+    let server = TestServer::start().await;
+    for name in ["app-1.0.zip", "app-1.1.zip", "notes.txt"] {
+        std::fs::write(server.root().join(name), name).unwrap();
+    }
+
+    let response = server.post("/_tags/app-1.0.zip", "release, v1").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "app-1.0.zip: release, v1\n");
+    server.post("/_tags/app-1.1.zip", "Release").await;
+    let response = server.post("/_tags/missing.zip", "release").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    let response = server.post("/_tags/notes.txt", "no/slashes").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+    let response = server.get("/?tag=release").await;
+    let listing = response.text();
+    let mut lines: Vec<&str> = listing.lines().collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "Available files:",
+            "app-1.0.zip\t[release, v1]",
+            "app-1.1.zip\t[release]"
+        ]
+    );
+    let response = server.get("/?tag=release&tag=v1&format=ndjson").await;
+    let row: serde_json::Value = serde_json::from_str(response.text().trim()).unwrap();
+    assert_eq!(row["name"], "app-1.0.zip");
+    assert_eq!(row["tags"], serde_json::json!(["release", "v1"]));
+
+    let response = server
+        .request(Method::DELETE, "/_tags/app-1.0.zip/v1", &[], Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::NO_CONTENT);
+    let response = server
+        .request(
+            Method::POST,
+            "/_rename",
+            &[("content-type", "application/json")],
+            Bytes::from(r#"{"from": "app-1.1.zip", "to": "app-1.1-final.zip"}"#),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    let response = server.get("/_tags").await;
+    assert_eq!(
+        response.text(),
+        "app-1.0.zip: release\napp-1.1-final.zip: release\n"
+    );
+}
+
+#[tokio::test]
+async fn uploads_carry_their_metadata() {
+    let server = TestServer::start().await;
+    let headers = [("X-Meta-Build", "42"), ("X-Meta-Git-Sha", "1a2b3c")];
+    let response = server
+        .request(
+            Method::POST,
+            "/app.tar.gz",
+            &headers,
+            Bytes::from("tarball"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+
+    let response = server.get("/app.tar.gz").await;
+    assert_eq!(response.body, "tarball");
+    assert_eq!(response.headers["x-meta-build"], "42");
+    assert_eq!(response.headers["x-meta-git-sha"], "1a2b3c");
+
+    let response = server
+        .request(Method::HEAD, "/app.tar.gz", &[], Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-length"], "7");
+    assert_eq!(response.headers["x-meta-build"], "42");
+
+    let response = server.get("/?format=ndjson").await;
+    let row: serde_json::Value = serde_json::from_str(response.text().trim()).unwrap();
+    assert_eq!(row["meta"]["git-sha"], "1a2b3c");
+
+    // A new upload brings its own metadata, or none
+    server.post("/app.tar.gz", "tarball v2").await;
+    let response = server.get("/app.tar.gz").await;
+    assert!(!response.headers.contains_key("x-meta-build"));
+}
+
+#[tokio::test]
+async fn traversal_attempts_are_rejected() {
+    let server = TestServer::start().await;
+    let outside = server.root().parent().unwrap().join("outside.txt");
+
+    for path in [
+        "/../outside.txt",
+        "/..",
+        "/a..b",
+        "/sub/file.txt",
+        "/%2e%2e",
+    ] {
+        let response = server.get(path).await;
+        assert_ne!(response.status, StatusCode::OK, "GET {}", path);
+    }
+    for path in ["/../outside.txt", "/sub/file.txt", "/.holodeck"] {
+        let response = server.post(path, "x").await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "POST {}", path);
+        let response = server
+            .request(Method::PATCH, path, &[], Bytes::from("x"))
+            .await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "PATCH {}", path);
+    }
+    assert!(!outside.exists());
+}
+
+#[tokio::test]
+async fn missing_file_is_not_found() {
+    let server = TestServer::start().await;
+
+    let response = server.get("/nope.txt").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+
+    // Directories are not downloadable either
+    std::fs::create_dir(server.root().join("subdir")).unwrap();
+    let response = server.get("/subdir").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn upload_requires_a_filename() {
+    let server = TestServer::start().await;
+
+    let response = server.post("/", "x").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.text(), "Filename required in path");
+}
+
+#[tokio::test]
+async fn unsupported_methods_are_not_found() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("keep.txt"), "keep").unwrap();
+
+    let response = server
+        .request(Method::DELETE, "/keep.txt", &[], Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert!(server.root().join("keep.txt").exists());
+}
+
+#[tokio::test]
+async fn interrupted_upload_leaves_no_partial_file() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events().subscribe();
+
+    // Declares more bytes than it sends, then hangs up
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    tokio::io::AsyncWriteExt::write_all(
+        &mut stream,
+        b"POST /partial.bin HTTP/1.1\r\nHost: test\r\nContent-Length: 100\r\n\r\nonly ten b",
+    )
+    .await
+    .unwrap();
+    drop(stream);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    assert!(!server.root().join("partial.bin").exists());
+    let staged = std::fs::read_dir(server.root().join(".holodeck/staging"))
+        .unwrap()
+        .count();
+    assert_eq!(staged, 0);
+    assert_eq!(
+        events.recv().await.unwrap().summary(),
+        "partial.bin upload by 127.0.0.1 aborted after 10 B"
+    );
+}
+
+#[tokio::test]
+async fn interrupted_patch_rolls_back_its_partial_append() {
+    let server = TestServer::start().await;
+    std::fs::write(server.root().join("log.txt"), "kept\n").unwrap();
+
+    // More than the write buffer, so part of it reaches the file before the hang-up
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    let mut request =
+        b"PATCH /log.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 4194304\r\n\r\n".to_vec();
+    request.resize(request.len() + 2 * 1024 * 1024, b'x');
+    tokio::io::AsyncWriteExt::write_all(&mut stream, &request)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    drop(stream);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    assert_eq!(
+        std::fs::read(server.root().join("log.txt")).unwrap(),
+        b"kept\n"
+    );
+}
+
+#[tokio::test]
+async fn patch_appends_to_existing_file() {
+    let server = TestServer::start().await;
+    server.post("/log.txt", "one\n").await;
+
+    let response = server
+        .request(Method::PATCH, "/log.txt", &[], Bytes::from("two\n"))
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(
+        std::fs::read_to_string(server.root().join("log.txt")).unwrap(),
+        "one\ntwo\n"
+    );
+}
+
+#[tokio::test]
+async fn patch_resumes_upload_in_pieces() {
+    let server = TestServer::start().await;
+
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 0-4/10")],
+            Bytes::from("01234"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(!server.root().join("big.bin").exists());
+
+    // Resending the first piece is a conflict that reports where to resume
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 0-4/10")],
+            Bytes::from("01234"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    assert_eq!(response.headers["x-upload-offset"], "5");
+
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 5-9/10")],
+            Bytes::from("56789"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert_eq!(
+        std::fs::read_to_string(server.root().join("big.bin")).unwrap(),
+        "0123456789"
+    );
+}
+
+#[tokio::test]
+async fn resumable_uploads_survive_a_restart() {
+    let server = TestServer::start().await;
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 0-4/10"), ("x-meta-build", "42")],
+            Bytes::from("01234"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+
+    let server = server.restart().await;
+    // A different total is another upload, which would clobber this one
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 0-9/20")],
+            Bytes::from("0123456789"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    assert_eq!(response.headers["x-upload-offset"], "5");
+
+    let response = server
+        .request(
+            Method::PATCH,
+            "/big.bin",
+            &[("content-range", "bytes 5-9/10")],
+            Bytes::from("56789"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    let response = server.get("/big.bin").await;
+    assert_eq!(response.text(), "0123456789");
+    assert_eq!(response.headers["x-meta-build"], "42");
+}
+
+#[tokio::test]
+async fn patch_rejects_malformed_range() {
+    let server = TestServer::start().await;
+
+    for range in ["bytes=0-4/10", "bytes 4-0/10", "items 0-4/10"] {
+        let response = server
+            .request(
+                Method::PATCH,
+                "/f.bin",
+                &[("content-range", range)],
+                Bytes::from("01234"),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", range);
+    }
+}
+
+#[tokio::test]
+async fn completed_transfers_emit_events() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events().subscribe();
+
+    server.post("/e.txt", "event").await;
+    let event = events.recv().await.unwrap();
+    assert_eq!(
+        serde_json::to_value(&event).unwrap(),
+        serde_json::json!({
+            "event": "upload_completed",
+            "file": "e.txt",
+            "bytes": 5,
+            "peer": "127.0.0.1",
+        })
+    );
+
+    server.get("/e.txt").await;
+    let event = events.recv().await.unwrap();
+    assert_eq!(event.summary(), "e.txt downloaded by 127.0.0.1, 5 B");
+}
+
+#[tokio::test]
+async fn empty_downloads_complete() {
+    let server = TestServer::start().await;
+    server.post("/empty.txt", "").await;
+    let mut events = server.state.events().subscribe();
+
+    let response = server.get("/empty.txt").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.is_empty());
+    let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv())
+        .await
+        .expect("no event for the empty download")
+        .unwrap();
+    assert_eq!(event.summary(), "empty.txt downloaded by 127.0.0.1, 0 B");
+}
+
+#[tokio::test]
+async fn finished_transfers_are_kept_in_the_history() {
+    let server = TestServer::start().await;
+    server.post("/deliverable.zip", "final cut").await;
+    server.get("/deliverable.zip").await;
+
+    // The download is recorded once its body is done, just after the client has it
+    let path = server.root().join(".holodeck/history.jsonl");
+    let mut records = Vec::new();
+    for _ in 0..50 {
+        records = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if records.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(records.len(), 2);
+    for (record, direction) in records.iter().zip(["POST", "GET"]) {
+        assert_eq!(record["direction"], direction);
+        assert_eq!(record["file"], "deliverable.zip");
+        assert_eq!(record["peer"], "127.0.0.1");
+        assert_eq!(record["bytes"], 9);
+        assert_eq!(record["outcome"], "completed");
+    }
+}
+
+#[tokio::test]
+async fn alias_follows_its_target() {
+    let server = TestServer::start().await;
+    server.post("/build-1.tar.gz", "first").await;
+    server.post("/build-2.tar.gz", "second").await;
+
+    let response = server
+        .request(
+            Method::PUT,
+            "/_alias/latest",
+            &[],
+            Bytes::from("build-1.tar.gz"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert_eq!(server.get("/latest").await.body, "first");
+
+    let response = server
+        .request(
+            Method::PUT,
+            "/_alias/latest",
+            &[],
+            Bytes::from("build-2.tar.gz"),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(server.get("/latest").await.body, "second");
+    assert_eq!(
+        server.get("/_alias").await.text(),
+        "latest -> build-2.tar.gz\n"
+    );
+
+    let response = server
+        .request(Method::DELETE, "/_alias/latest", &[], Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::NO_CONTENT);
+    assert_eq!(server.get("/latest").await.status, StatusCode::NOT_FOUND);
+}
+
+#[cfg(feature = "archives")]
+fn zip(entries: &[(&str, &str)]) -> Bytes {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, contents) in entries {
+        zip.start_file(*name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+    }
+    Bytes::from(zip.finish().unwrap().into_inner())
+}
+
+#[cfg(feature = "archives")]
+#[tokio::test]
+async fn batch_upload_extracts_folders() {
+    let server = TestServer::start().await;
+
+    let archive = zip(&[
+        ("site/index.html", "<h1>hi</h1>"),
+        ("site/js/app.js", "go()"),
+    ]);
+    let response = server.post("/_batch", archive).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    assert_eq!(
+        std::fs::read_to_string(server.root().join("site/js/app.js")).unwrap(),
+        "go()"
+    );
+    assert!(server.root().join("site/index.html").exists());
+}
+
+#[cfg(feature = "archives")]
+#[tokio::test]
+async fn batch_upload_rejects_escaping_entries() {
+    let server = TestServer::start().await;
+
+    for name in [
+        "../evil.txt",
+        "ok/../../evil.txt",
+        "/etc/evil.txt",
+        ".holodeck/x",
+    ] {
+        let archive = zip(&[("fine.txt", "fine"), (name, "evil")]);
+        let response = server.post("/_batch", archive).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+    assert!(!server.root().join("fine.txt").exists());
+    assert!(!server.root().parent().unwrap().join("evil.txt").exists());
+
+    let response = server.post("/_batch", "not an archive").await;
+    assert_eq!(response.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn exists_copies_known_content() {
+    use sha2::{Digest, Sha256};
+    let server = TestServer::start().await;
+    server.post("/artifact-1.bin", "same bytes").await;
+
+    let sha256 = hex::encode(Sha256::digest("same bytes"));
+    let request = format!(r#"{{"sha256": "{}", "name": "artifact-2.bin"}}"#, sha256);
+    let response = server.post("/_exists", request).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    assert_eq!(
+        std::fs::read(server.root().join("artifact-2.bin")).unwrap(),
+        b"same bytes"
+    );
+
+    let sha256 = hex::encode(Sha256::digest("other bytes"));
+    let request = format!(r#"{{"sha256": "{}", "name": "artifact-3.bin"}}"#, sha256);
+    let response = server.post("/_exists", request).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert!(!server.root().join("artifact-3.bin").exists());
+}
+
+#[tokio::test]
+async fn concurrent_writes_to_one_file_conflict() {
+    let server = TestServer::start().await;
+
+    // Holds the name while it waits for the rest of its body
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    tokio::io::AsyncWriteExt::write_all(
+        &mut stream,
+        b"POST /shared.log HTTP/1.1\r\nHost: test\r\nContent-Length: 100\r\n\r\nfirst",
+    )
+    .await
+    .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let response = server.post("/shared.log", "second").await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    let response = server
+        .request(Method::PATCH, "/SHARED.log", &[], Bytes::from("third"))
+        .await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    assert_eq!(
+        server.post("/other.log", "x").await.status,
+        StatusCode::CREATED
+    );
+
+    drop(stream);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let response = server.post("/shared.log", "second").await;
+    assert_eq!(response.status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn rename_moves_files_between_folders() {
+    let server = TestServer::start().await;
+    server.post("/draft.txt", "text").await;
+    server.post("/taken.txt", "other").await;
+
+    let response = server
+        .post(
+            "/_rename",
+            r#"{"from": "draft.txt", "to": "docs/final.txt"}"#,
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert!(!server.root().join("draft.txt").exists());
+    assert_eq!(
+        std::fs::read(server.root().join("docs/final.txt")).unwrap(),
+        b"text"
+    );
+
+    let response = server
+        .post(
+            "/_rename",
+            r#"{"from": "docs/final.txt", "to": "taken.txt"}"#,
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    for to in ["../escaped.txt", ".holodeck/x", "docs/../../x", ""] {
+        let request = format!(r#"{{"from": "docs/final.txt", "to": "{}"}}"#, to);
+        let response = server.post("/_rename", request).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", to);
+    }
+    assert!(server.root().join("docs/final.txt").exists());
+}
+
+#[tokio::test]
+async fn copy_snapshots_a_file() {
+    let server = TestServer::start().await;
+    server.post("/latest.bin", "v1.2").await;
+
+    let response = server
+        .post(
+            "/_copy",
+            r#"{"from": "latest.bin", "to": "releases/1.2.bin"}"#,
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    server.post("/latest.bin", "v1.3").await;
+    assert_eq!(
+        std::fs::read(server.root().join("releases/1.2.bin")).unwrap(),
+        b"v1.2"
+    );
+
+    let response = server
+        .post(
+            "/_copy",
+            r#"{"from": "latest.bin", "to": "releases/1.2.bin"}"#,
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    let response = server
+        .post("/_copy", r#"{"from": "missing.bin", "to": "x.bin"}"#)
+        .await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn mkdir_creates_nested_folders() {
+    let server = TestServer::start().await;
+
+    let response = server.post("/_mkdir/reports/2024", "").await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert!(server.root().join("reports/2024").is_dir());
+    let response = server.post("/_mkdir/reports/2024", "").await;
+    assert_eq!(response.status, StatusCode::OK);
+
+    server.post("/notes.txt", "x").await;
+    let response = server.post("/_mkdir/notes.txt/sub", "").await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    for path in ["/_mkdir/../outside", "/_mkdir/.holodeck/x", "/_mkdir/"] {
+        let response = server.post(path, "").await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn feed_lists_shared_files() {
+    let server = TestServer::start().await;
+    server.post("/nightly&build.zip", "zip").await;
+
+    let response = server.get("/feed.xml").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(
+        response.headers["content-type"],
+        "application/rss+xml; charset=utf-8"
+    );
+    let feed = response.text();
+    assert!(
+        feed.contains("<title>nightly&amp;build.zip</title>"),
+        "{}",
+        feed
+    );
+    assert!(
+        feed.contains("/nightly&amp;build.zip\" length=\"3\""),
+        "{}",
+        feed
+    );
+}
+
+#[tokio::test]
+async fn cmd_offers_download_commands() {
+    let server = TestServer::start().await;
+    server.post("/report.pdf", "pdf").await;
+
+    let response = server.get("/_cmd/report.pdf").await;
+    assert_eq!(response.status, StatusCode::OK);
+    let commands = response.text();
+    let url = format!("'http://{}/report.pdf'", server.addr);
+    assert!(
+        commands.contains(&format!("curl -fL -o 'report.pdf' {}", url)),
+        "{}",
+        commands
+    );
+    assert!(commands.contains("Invoke-WebRequest"), "{}", commands);
+
+    let response = server.get("/_cmd/missing.pdf").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[cfg(feature = "qr")]
+#[tokio::test]
+async fn qr_codes_link_to_files() {
+    let server = TestServer::start().await;
+    server.post("/report.pdf", "%PDF").await;
+
+    let response = server.get("/report.pdf?qr").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "image/svg+xml");
+    assert!(response.text().contains("<svg"));
+
+    let response = server.get("/report.pdf?qr=png").await;
+    assert_eq!(response.headers["content-type"], "image/png");
+    assert!(response.body.starts_with(b"\x89PNG"));
+
+    let response = server.get("/report.pdf?qr=gif").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = server.get("/missing.pdf?qr").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn range_requests_return_partial_content() {
+    let server = TestServer::start().await;
+    server.post("/digits.txt", "0123456789").await;
+
+    let range = async |value: &str| {
+        server
+            .request(
+                Method::GET,
+                "/digits.txt",
+                &[("Range", value)],
+                Bytes::new(),
+            )
+            .await
+    };
+    let response = range("bytes=2-5").await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers["content-range"], "bytes 2-5/10");
+    assert!(response.headers.contains_key("last-modified"));
+    assert_eq!(response.text(), "2345");
+
+    assert_eq!(range("bytes=7-").await.text(), "789");
+    assert_eq!(range("bytes=-3").await.text(), "789");
+    let response = range("bytes=10-").await;
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers["content-range"], "bytes */10");
+    // Several ranges at once aren't supported, so the whole file is sent
+    let response = range("bytes=0-1,4-5").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "0123456789");
+}
+
+#[tokio::test]
+async fn sha256_reports_checksums() {
+    let server = TestServer::start().await;
+    server.post("/hello.txt", "hello").await;
+
+    let response = server.get("/_sha256/hello.txt").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(
+        response.text().trim(),
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+    let response = server.get("/_sha256/missing.txt").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn follow_streams_lines_as_the_file_grows() {
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = TestServer::start().await;
+    let path = server.root().join("build.log");
+    std::fs::write(&path, "step 1\nstep").unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(b"GET /build.log?follow=sse HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut received = String::new();
+    let mut read_until = async |stream: &mut tokio::net::TcpStream, wanted: &str| {
+        let mut buf = [0; 1024];
+        while !received.contains(wanted) {
+            let read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buf))
+                    .await
+                    .expect("follow stalled")
+                    .unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..read]));
+        }
+        received.clone()
+    };
+    let head = read_until(&mut stream, "data: step 1\n\n").await;
+    assert!(head.contains("content-type: text/event-stream"));
+    assert!(!head.contains("data: step\n"));
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(b" 2\n").unwrap();
+    read_until(&mut stream, "data: step 2\n\n").await;
+
+    let response = server.get("/build.log?follow=xml").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn upload_progress_is_reported_by_id() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = TestServer::start().await;
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(
+            b"POST /video.mp4 HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\
+              X-Upload-Id: 0b6f2c1e\r\n\r\nfirst",
+        )
+        .await
+        .unwrap();
+
+    let mut status = String::new();
+    for _ in 0..50 {
+        let response = server.get("/_progress/0b6f2c1e").await;
+        if response.status == StatusCode::OK {
+            assert_eq!(response.headers["content-type"], "application/json");
+            status = response.text();
+            if status.contains("\"bytes\":5") {
+                break;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(status, r#"{"bytes":5,"total":10,"state":"receiving"}"#);
+
+    stream.write_all(b", end").await.unwrap();
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await.unwrap();
+    assert!(String::from_utf8_lossy(&buf[..read]).starts_with("HTTP/1.1 201"));
+
+    let response = server.get("/_progress/0b6f2c1e").await;
+    assert_eq!(
+        response.text(),
+        r#"{"bytes":10,"total":10,"state":"completed"}"#
+    );
+    assert_eq!(
+        server.get("/_progress/unknown").await.status,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        server.get("/_progress/a%20b").await.status,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn health_identifies_the_instance() {
+    let server = TestServer::start().await;
+    let response = server.get("/_health").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "ok\n");
+    assert_eq!(response.headers["x-holodeck-instance"].len(), 32);
+}
+
+#[tokio::test]
+async fn conditional_uploads_detect_conflicts() {
+    let server = TestServer::start().await;
+
+    let create_only = [("If-None-Match", "*")];
+    let response = server
+        .request(Method::POST, "/plan.md", &create_only, Bytes::from("v1"))
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    let response = server
+        .request(Method::POST, "/plan.md", &create_only, Bytes::from("other"))
+        .await;
+    assert_eq!(response.status, StatusCode::PRECONDITION_FAILED);
+
+    // Two people download the same version; the second upload must not clobber the first
+    let etag = server.get("/plan.md").await.headers["etag"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let seen = [("If-Match", etag.as_str())];
+    let response = server
+        .request(Method::POST, "/plan.md", &seen, Bytes::from("v2 by alice"))
+        .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    let updated = response.headers["etag"].to_str().unwrap().to_string();
+    assert_ne!(updated, etag);
+    let response = server
+        .request(Method::POST, "/plan.md", &seen, Bytes::from("v2 by bob"))
+        .await;
+    assert_eq!(response.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(response.headers["etag"], updated.as_str());
+    let response = server
+        .request(Method::PATCH, "/plan.md", &seen, Bytes::from(" + bob"))
+        .await;
+    assert_eq!(response.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(
+        std::fs::read_to_string(server.root().join("plan.md")).unwrap(),
+        "v2 by alice"
+    );
+    assert_eq!(
+        server.get("/plan.md").await.headers["etag"],
+        updated.as_str()
+    );
+}
+
+#[tokio::test]
+async fn unchanged_downloads_answer_not_modified() {
+    let server = TestServer::start().await;
+    server.post("/build.zip", "v1").await;
+    let etag = server.get("/build.zip").await.headers["etag"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let cached = [("If-None-Match", etag.as_str())];
+    let response = server
+        .request(Method::GET, "/build.zip", &cached, Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers["etag"], etag.as_str());
+    assert_eq!(response.text(), "");
+
+    server.post("/build.zip", "v2").await;
+    let response = server
+        .request(Method::GET, "/build.zip", &cached, Bytes::new())
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "v2");
+}
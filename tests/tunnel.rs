@@ -0,0 +1,130 @@
+mod common;
+
+use common::{MockTunnel, TestServer};
+use holodeck::cli::OutputMode;
+use holodeck::events::Event;
+use holodeck::tunnel;
+#[cfg(feature = "client")]
+use holodeck::tunnel::SelfTest;
+use hyper::{Method, StatusCode};
+use tokio::sync::watch;
+
+#[tokio::test]
+async fn tunnel_url_is_published_and_reachable() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events().subscribe();
+    let provider = MockTunnel::new();
+    let close = provider.close.clone();
+
+    let (url_tx, mut url_rx) = watch::channel(None);
+    let handle = tunnel::spawn_tunnel(
+        Box::new(provider),
+        server.addr.port(),
+        OutputMode::Quiet,
+        url_tx,
+        server.state.events().clone(),
+        Some(server.state.self_test()),
+    );
+
+    let url = url_rx
+        .wait_for(Option::is_some)
+        .await
+        .unwrap()
+        .clone()
+        .unwrap();
+    assert_eq!(url, format!("http://127.0.0.1:{}", server.addr.port()));
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::TunnelUp { url: up } if up == url
+    ));
+
+    // Requests through the tunnel carry the original client as the last hop of
+    // X-Forwarded-For; the hops before it are whatever the client sent
+    let addr = url.trim_start_matches("http://").parse().unwrap();
+    let response = common::send(
+        addr,
+        Method::POST,
+        "/via-tunnel.txt",
+        &[("x-forwarded-for", "10.0.0.1, 203.0.113.7")],
+        "through".into(),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::CREATED);
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::UploadCompleted { peer, .. } if peer == "203.0.113.7"
+    ));
+
+    close.notify_one();
+    handle.await.unwrap();
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::TunnelDown { error: None }
+    ));
+}
+
+#[tokio::test]
+async fn tunnel_failure_is_reported() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events().subscribe();
+    let mut provider = MockTunnel::new();
+    provider.error = Some("connection reset".to_string());
+    let close = provider.close.clone();
+
+    let (url_tx, _url_rx) = watch::channel(None);
+    let handle = tunnel::spawn_tunnel(
+        Box::new(provider),
+        server.addr.port(),
+        OutputMode::Quiet,
+        url_tx,
+        server.state.events().clone(),
+        None,
+    );
+    close.notify_one();
+    handle.await.unwrap();
+
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::TunnelUp { .. }
+    ));
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::TunnelDown { error: Some(e) } if e == "connection reset"
+    ));
+}
+
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn tunnel_not_leading_back_is_reopened() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events().subscribe();
+    let provider = MockTunnel::new();
+    let close = provider.close.clone();
+
+    // The URL leads to a holodeck, but not the one expected
+    let self_test = SelfTest::new("another-instance".to_string(), "")
+        .with_retries(1, std::time::Duration::ZERO);
+    let (url_tx, _url_rx) = watch::channel(None);
+    let handle = tunnel::spawn_tunnel(
+        Box::new(provider),
+        server.addr.port(),
+        OutputMode::Quiet,
+        url_tx,
+        server.state.events().clone(),
+        Some(self_test),
+    );
+
+    // The first tunnel and two reopened ones, after which the last one is kept
+    for _ in 0..3 {
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            Event::TunnelUp { .. }
+        ));
+    }
+    close.notify_one();
+    handle.await.unwrap();
+    assert!(matches!(
+        events.recv().await.unwrap(),
+        Event::TunnelDown { error: None }
+    ));
+}